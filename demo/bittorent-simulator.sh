@@ -19,7 +19,7 @@ mkdir -p "$BASE_DIR/01/files"
 server_pids=""
 
 section "Let's start peer 1"
-launch_bg "./$BIN --server-addr=127.0.0.1:4001 --peer-id=1 --dht-filename=$BASE_DIR/01/dht --working-dir=$BASE_DIR/01/files --share-dir=$BASE_DIR/01/files seed" "$LOG"
+launch_bg "./$BIN --server-addr=127.0.0.1:4001 --dht-filename=$BASE_DIR/01/dht --working-dir=$BASE_DIR/01/files --share-dir=$BASE_DIR/01/files seed" "$LOG"
 server_pid1=$!
 sleep 0.3
 
@@ -38,8 +38,8 @@ for i in $(seq 2 20); do
 
     mkdir -p "$BASE_DIR/$pi/files"
 
-    ./$BIN  --server-addr="127.0.0.1:40${pi}" --peer-id=${i} --dht-filename=$BASE_DIR/${pi}/dht --working-dir=$BASE_DIR/${pi}/files --share-dir=$BASE_DIR/${pi}/files bootstrap "127.0.0.1:40${pp}" #>/dev/null
-    ./$BIN --server-addr="127.0.0.1:40${pi}" --peer-id=${i} --dht-filename=$BASE_DIR/${pi}/dht --working-dir=$BASE_DIR/${pi}/files --share-dir=$BASE_DIR/${pi}/files seed >$BASE_DIR/log${pi} &
+    ./$BIN  --server-addr="127.0.0.1:40${pi}" --dht-filename=$BASE_DIR/${pi}/dht --working-dir=$BASE_DIR/${pi}/files --share-dir=$BASE_DIR/${pi}/files bootstrap "127.0.0.1:40${pp}" #>/dev/null
+    ./$BIN --server-addr="127.0.0.1:40${pi}" --dht-filename=$BASE_DIR/${pi}/dht --working-dir=$BASE_DIR/${pi}/files --share-dir=$BASE_DIR/${pi}/files seed >$BASE_DIR/log${pi} &
     server_pids="$server_pids $!"
     sleep 0.3
 
@@ -56,13 +56,13 @@ mkdir -p "$BASE_DIR/24/files"
 cp "$BASE_DIR/21/files/test.png" "$BASE_DIR/24/files/test.png"
 
 section "Bootstrap peers 21, 22, 23, 24 (from peer 20)"
-launch "./$BIN --server-addr=\"127.0.0.1:4021\" --peer-id=21 --dht-filename=$BASE_DIR/21/dht bootstrap 127.0.0.1:4020"
+launch "./$BIN --server-addr=\"127.0.0.1:4021\" --dht-filename=$BASE_DIR/21/dht bootstrap 127.0.0.1:4020"
 section "Bootstrap a new peer 22 (from peer 20)"
-launch "./$BIN --server-addr=\"127.0.0.1:4022\" --peer-id=22 --dht-filename=$BASE_DIR/22/dht bootstrap 127.0.0.1:4020"
+launch "./$BIN --server-addr=\"127.0.0.1:4022\" --dht-filename=$BASE_DIR/22/dht bootstrap 127.0.0.1:4020"
 section "Bootstrap a new peer 23 (from peer 20)"
-launch "./$BIN --server-addr=\"127.0.0.1:4023\" --peer-id=23 --dht-filename=$BASE_DIR/23/dht bootstrap 127.0.0.1:4020"
+launch "./$BIN --server-addr=\"127.0.0.1:4023\" --dht-filename=$BASE_DIR/23/dht bootstrap 127.0.0.1:4020"
 section "Bootstrap a new peer 24 (from peer 20)"
-launch "./$BIN --server-addr=\"127.0.0.1:4024\" --peer-id=24 --dht-filename=$BASE_DIR/24/dht bootstrap 127.0.0.1:4020"
+launch "./$BIN --server-addr=\"127.0.0.1:4024\" --dht-filename=$BASE_DIR/24/dht bootstrap 127.0.0.1:4020"
 
 LOG1="$BASE_DIR/log01"
 LOG2="$BASE_DIR/log02"
@@ -70,13 +70,13 @@ LOG3="$BASE_DIR/log03"
 LOG4="$BASE_DIR/log04"
 
 section "These 4 peers will now serve a copy of the file"
-launch_bg "./$BIN --server-addr=\"127.0.0.1:4021\" --peer-id=21 --dht-filename=$BASE_DIR/21/dht --working-dir=$BASE_DIR/21/files --share-dir=$BASE_DIR/21/files share-file \"$BASE_DIR/21/files/test.png\"" "$LOG1"
+launch_bg "./$BIN --server-addr=\"127.0.0.1:4021\" --dht-filename=$BASE_DIR/21/dht --working-dir=$BASE_DIR/21/files --share-dir=$BASE_DIR/21/files share-file \"$BASE_DIR/21/files/test.png\"" "$LOG1"
 server_pids="$server_pids $!"
-launch_bg "./$BIN --server-addr=\"127.0.0.1:4022\" --peer-id=22 --dht-filename=$BASE_DIR/22/dht --working-dir=$BASE_DIR/22/files --share-dir=$BASE_DIR/22/files share-file \"$BASE_DIR/22/files/test.png\"" "$LOG2"
+launch_bg "./$BIN --server-addr=\"127.0.0.1:4022\" --dht-filename=$BASE_DIR/22/dht --working-dir=$BASE_DIR/22/files --share-dir=$BASE_DIR/22/files share-file \"$BASE_DIR/22/files/test.png\"" "$LOG2"
 server_pids="$server_pids $!"
-launch_bg "./$BIN --server-addr=\"127.0.0.1:4023\" --peer-id=23 --dht-filename=$BASE_DIR/23/dht --working-dir=$BASE_DIR/23/files --share-dir=$BASE_DIR/23/files share-file \"$BASE_DIR/23/files/test.png\"" "$LOG3"
+launch_bg "./$BIN --server-addr=\"127.0.0.1:4023\" --dht-filename=$BASE_DIR/23/dht --working-dir=$BASE_DIR/23/files --share-dir=$BASE_DIR/23/files share-file \"$BASE_DIR/23/files/test.png\"" "$LOG3"
 server_pids="$server_pids $!"
-launch_bg "./$BIN --server-addr=\"127.0.0.1:4024\" --peer-id=24 --dht-filename=$BASE_DIR/24/dht --working-dir=$BASE_DIR/24/files --share-dir=$BASE_DIR/24/files share-file \"$BASE_DIR/24/files/test.png\"" "$LOG4"
+launch_bg "./$BIN --server-addr=\"127.0.0.1:4024\" --dht-filename=$BASE_DIR/24/dht --working-dir=$BASE_DIR/24/files --share-dir=$BASE_DIR/24/files share-file \"$BASE_DIR/24/files/test.png\"" "$LOG4"
 server_pids="$server_pids $!"
 sleep 1
 
@@ -89,10 +89,10 @@ launch "\ls -l $BASE_DIR/24/files/"
 mkdir -p "$BASE_DIR/25/files"
 
 section "Bootstrap the peer receiver 25 (from peer 20)"
-launch "./$BIN --server-addr=\"127.0.0.1:4025\" --peer-id=25 --dht-filename=$BASE_DIR/25/dht bootstrap 127.0.0.1:4020"
+launch "./$BIN --server-addr=\"127.0.0.1:4025\" --dht-filename=$BASE_DIR/25/dht bootstrap 127.0.0.1:4020"
 
 section "Now, we have a 24 peers network, let's try to download a file from a new peer"
-launch "./$BIN --server-addr=\"127.0.0.1:4025\" --peer-id=25 --dht-filename=$BASE_DIR/25/dht --working-dir=$BASE_DIR/25/files --share-dir=$BASE_DIR/25/files --max-hop=99 download 1458724153"
+launch "./$BIN --server-addr=\"127.0.0.1:4025\" --dht-filename=$BASE_DIR/25/dht --working-dir=$BASE_DIR/25/files --share-dir=$BASE_DIR/25/files --max-hop=99 download 1458724153"
 
 section "View of the seeders"
 launch "cat $LOG1"
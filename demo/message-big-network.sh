@@ -19,9 +19,10 @@ mkdir -p "$BASE_DIR/01/files"
 server_pids=""
 
 section "Let's start peer 1"
-launch_bg "./$BIN --server-addr=127.0.0.1:4001 --peer-id=1 --dht-filename=$BASE_DIR/01/dht --working-dir=$BASE_DIR/01/files --share-dir=$BASE_DIR/01/files seed" "$LOG"
+launch_bg "./$BIN --server-addr=127.0.0.1:4001 --dht-filename=$BASE_DIR/01/dht --working-dir=$BASE_DIR/01/files --share-dir=$BASE_DIR/01/files seed" "$LOG"
 server_pids="$server_pids $!"
 sleep 0.3
+ID_1=$(./$BIN --dht-filename=$BASE_DIR/01/dht id)
 
 section "Let's bootstrap 30 peers, as a chain"
 previous=1
@@ -38,8 +39,8 @@ for i in $(seq 2 30); do
 
     mkdir -p "$BASE_DIR/$pi/files"
 
-    ./$BIN  --server-addr="127.0.0.1:40${pi}" --peer-id=${i} --dht-filename=$BASE_DIR/${pi}/dht --working-dir=$BASE_DIR/${pi}/files --share-dir=$BASE_DIR/${pi}/files bootstrap "127.0.0.1:40${pp}" #>/dev/null
-    ./$BIN --server-addr="127.0.0.1:40${pi}" --peer-id=${i} --dht-filename=$BASE_DIR/${pi}/dht --working-dir=$BASE_DIR/${pi}/files --share-dir=$BASE_DIR/${pi}/files seed >$BASE_DIR/log${pi} &
+    ./$BIN  --server-addr="127.0.0.1:40${pi}" --dht-filename=$BASE_DIR/${pi}/dht --working-dir=$BASE_DIR/${pi}/files --share-dir=$BASE_DIR/${pi}/files bootstrap "127.0.0.1:40${pp}" #>/dev/null
+    ./$BIN --server-addr="127.0.0.1:40${pi}" --dht-filename=$BASE_DIR/${pi}/dht --working-dir=$BASE_DIR/${pi}/files --share-dir=$BASE_DIR/${pi}/files seed >$BASE_DIR/log${pi} &
     server_pids="$server_pids $!"
     sleep 0.3
 
@@ -49,10 +50,10 @@ done
 mkdir -p "$BASE_DIR/31/files"
 
 section "Bootstrap a new peer 31 (from peer 30)"
-launch "./$BIN --server-addr=\"127.0.0.1:4031\" --peer-id=31 --dht-filename=$BASE_DIR/31/dht bootstrap 127.0.0.1:4030"
+launch "./$BIN --server-addr=\"127.0.0.1:4031\" --dht-filename=$BASE_DIR/31/dht bootstrap 127.0.0.1:4030"
 
 section "Now, we have this network 31 -> 30 -> ... -> 1, let's send a message from 31 to 1"
-launch "./$BIN --server-addr=\"127.0.0.1:4031\" --peer-id=31 --dht-filename=$BASE_DIR/31/dht --max-hop=99 message 1 \"Heeello this is server 31!\""
+launch "./$BIN --server-addr=\"127.0.0.1:4031\" --dht-filename=$BASE_DIR/31/dht --max-hop=99 message $ID_1 \"Heeello this is server 31!\""
 
 section "Peer 1 should have received a ping from 31 and get its message"
 launch "cat $LOG"
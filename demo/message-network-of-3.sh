@@ -17,23 +17,24 @@ launch "mkdir -p $BASE_DIR/a/files $BASE_DIR/b/files $BASE_DIR/c/files"
 LOG="$BASE_DIR/log"
 
 section "Launch peer A"
-launch_bg "./$BIN --server-addr=\"127.0.0.1:4001\" --peer-id=1 --dht-filename=$BASE_DIR/a.dht --share-dir=$BASE_DIR/a/files --working-dir=$BASE_DIR/a/files seed" "$LOG"
+launch_bg "./$BIN --server-addr=\"127.0.0.1:4001\" --dht-filename=$BASE_DIR/a.dht --share-dir=$BASE_DIR/a/files --working-dir=$BASE_DIR/a/files seed" "$LOG"
 server_pid1=$!
 sleep 1
+A_ID=$(./$BIN --dht-filename=$BASE_DIR/a.dht id)
 
 section "Bootstrap peer B (from peer A)"
-launch "./$BIN --server-addr=\"127.0.0.1:4002\" --peer-id=2 --dht-filename=$BASE_DIR/b.dht bootstrap 127.0.0.1:4001"
+launch "./$BIN --server-addr=\"127.0.0.1:4002\" --dht-filename=$BASE_DIR/b.dht bootstrap 127.0.0.1:4001"
 
 section "Launch peer B"
-launch_bg "./$BIN --server-addr=\"127.0.0.1:4002\" --peer-id=2 --dht-filename=$BASE_DIR/b.dht  --share-dir=$BASE_DIR/b/files --working-dir=$BASE_DIR/b/files seed" "/dev/null"
+launch_bg "./$BIN --server-addr=\"127.0.0.1:4002\" --dht-filename=$BASE_DIR/b.dht  --share-dir=$BASE_DIR/b/files --working-dir=$BASE_DIR/b/files seed" "/dev/null"
 server_pid2=$!
 sleep 1
 
 section "Bootstrap peer C (from peer B)"
-launch "./$BIN --server-addr=\"127.0.0.1:4003\" --peer-id=3 --dht-filename=$BASE_DIR/c.dht bootstrap 127.0.0.1:4002"
+launch "./$BIN --server-addr=\"127.0.0.1:4003\" --dht-filename=$BASE_DIR/c.dht bootstrap 127.0.0.1:4002"
 
 section "Now, we have this network C -> B -> A, let's send a message from C to A"
-launch "./$BIN --server-addr=\"127.0.0.1:4003\" --peer-id=3 --dht-filename=$BASE_DIR/c.dht message 1 \"Hello A, this is C speaking!\""
+launch "./$BIN --server-addr=\"127.0.0.1:4003\" --dht-filename=$BASE_DIR/c.dht message $A_ID \"Hello A, this is C speaking!\""
 
 section "Peer A (seed server) should have received a ping from C and get its message"
 launch "cat $LOG"
@@ -19,7 +19,7 @@ mkdir -p "$BASE_DIR/01/files"
 server_pids=""
 
 section "Let's start peer 1"
-launch_bg "./$BIN --server-addr=127.0.0.1:4001 --peer-id=1 --dht-filename=$BASE_DIR/01/dht --working-dir=$BASE_DIR/01/files --share-dir=$BASE_DIR/01/files seed" "$LOG"
+launch_bg "./$BIN --server-addr=127.0.0.1:4001 --dht-filename=$BASE_DIR/01/dht --working-dir=$BASE_DIR/01/files --share-dir=$BASE_DIR/01/files seed" "$LOG"
 server_pid1=$!
 sleep 0.3
 
@@ -38,8 +38,8 @@ for i in $(seq 2 30); do
 
     mkdir -p "$BASE_DIR/$pi/files"
 
-    ./$BIN  --server-addr="127.0.0.1:40${pi}" --peer-id=${i} --dht-filename=$BASE_DIR/${pi}/dht --working-dir=$BASE_DIR/${pi}/files --share-dir=$BASE_DIR/${pi}/files bootstrap "127.0.0.1:40${pp}" #>/dev/null
-    ./$BIN --server-addr="127.0.0.1:40${pi}" --peer-id=${i} --dht-filename=$BASE_DIR/${pi}/dht --working-dir=$BASE_DIR/${pi}/files --share-dir=$BASE_DIR/${pi}/files seed >$BASE_DIR/log${pi} &
+    ./$BIN  --server-addr="127.0.0.1:40${pi}" --dht-filename=$BASE_DIR/${pi}/dht --working-dir=$BASE_DIR/${pi}/files --share-dir=$BASE_DIR/${pi}/files bootstrap "127.0.0.1:40${pp}" #>/dev/null
+    ./$BIN --server-addr="127.0.0.1:40${pi}" --dht-filename=$BASE_DIR/${pi}/dht --working-dir=$BASE_DIR/${pi}/files --share-dir=$BASE_DIR/${pi}/files seed >$BASE_DIR/log${pi} &
     server_pids="$server_pids $!"
     sleep 0.3
 
@@ -49,23 +49,23 @@ done
 mkdir -p "$BASE_DIR/31/files"
 
 section "Bootstrap a new peer 31 (from peer 30)"
-launch "./$BIN --server-addr=\"127.0.0.1:4031\" --peer-id=31 --dht-filename=$BASE_DIR/31/dht bootstrap 127.0.0.1:4030"
+launch "./$BIN --server-addr=\"127.0.0.1:4031\" --dht-filename=$BASE_DIR/31/dht bootstrap 127.0.0.1:4030"
 
 section "Now, we have this network 31 -> 30 -> ... -> 1, let's try to get the message (not there yet)"
-launch "./$BIN --server-addr=\"127.0.0.1:4031\" --peer-id=31 --dht-filename=$BASE_DIR/31/dht --max-hop=99 find-value 42"
+launch "./$BIN --server-addr=\"127.0.0.1:4031\" --dht-filename=$BASE_DIR/31/dht --max-hop=99 find-value 42"
 
 section "Stop seeding for server 1"
 launch "kill $server_pid1 &>/dev/null"
 
 section "Let server 1 store a value in the network"
-launch "./$BIN --server-addr=127.0.0.1:4001 --peer-id=1 --dht-filename=$BASE_DIR/01/dht --working-dir=$BASE_DIR/01/files --share-dir=$BASE_DIR/01/files --max-hop=99 store-value 42 \"answer to everything\""
+launch "./$BIN --server-addr=127.0.0.1:4001 --dht-filename=$BASE_DIR/01/dht --working-dir=$BASE_DIR/01/files --share-dir=$BASE_DIR/01/files --max-hop=99 store-value 42 \"answer to everything\""
 
 section "Put server 1 in seed mode again"
-launch_bg "./$BIN --server-addr=127.0.0.1:4001 --peer-id=1 --dht-filename=$BASE_DIR/01/dht --working-dir=$BASE_DIR/01/files --share-dir=$BASE_DIR/01/files seed" "$LOG"
+launch_bg "./$BIN --server-addr=127.0.0.1:4001 --dht-filename=$BASE_DIR/01/dht --working-dir=$BASE_DIR/01/files --share-dir=$BASE_DIR/01/files seed" "$LOG"
 sleep 0.5
 
 section "Let's search again, this time the value has been found!"
-launch "./$BIN --server-addr=\"127.0.0.1:4031\" --peer-id=31 --dht-filename=$BASE_DIR/31/dht --max-hop=99 find-value 42"
+launch "./$BIN --server-addr=\"127.0.0.1:4031\" --dht-filename=$BASE_DIR/31/dht --max-hop=99 find-value 42"
 
 section "Stop the seed peers"
 launch "kill $server_pids &>/dev/null"
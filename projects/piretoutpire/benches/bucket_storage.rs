@@ -0,0 +1,96 @@
+// Compare the BucketArray used by the routing table, against the BucketTree it
+// replaced. Run with `cargo bench`.
+#![feature(test)]
+
+extern crate test;
+
+use piretoutpire::{
+    dht::{
+        bucket_array::BucketArray,
+        bucket_tree::BucketTree,
+        peer_node::{IpFamilies, PeerNode},
+    },
+    utils::distance,
+};
+use std::net::SocketAddr;
+use test::Bencher;
+use tokio::runtime::Runtime;
+
+// Number of peers we try to add. Most of them are dropped, as buckets are full.
+const NB_PEERS: u32 = 10_000;
+
+// Number of peers returned by a lookup.
+const NB_CLOSEST: usize = 4;
+
+// Spread ids over the whole range, the same way on each run.
+fn peer_ids() -> impl Iterator<Item = u32> {
+    (0..NB_PEERS).map(|idx| idx.wrapping_mul(2_654_435_761))
+}
+
+fn dummy_addr() -> SocketAddr {
+    "127.0.0.1:4000".parse().expect("valid address")
+}
+
+fn filled_tree(rt: &Runtime) -> BucketTree {
+    rt.block_on(async {
+        let mut tree = BucketTree::new();
+        for id in peer_ids() {
+            tree.add_peer_node(PeerNode::new(id, dummy_addr())).await;
+        }
+        tree
+    })
+}
+
+fn filled_array() -> BucketArray {
+    let mut array = BucketArray::new();
+    for id in peer_ids() {
+        array.add_peer_node(PeerNode::new(id, dummy_addr()));
+    }
+    array
+}
+
+#[bench]
+fn bench_tree_add_peers(b: &mut Bencher) {
+    let rt = Runtime::new().expect("tokio runtime");
+    b.iter(|| filled_tree(&rt));
+}
+
+#[bench]
+fn bench_array_add_peers(b: &mut Bencher) {
+    b.iter(filled_array);
+}
+
+// What the routing table did with the tree: collect every peer and sort them.
+#[bench]
+fn bench_tree_closest_peers(b: &mut Bencher) {
+    let rt = Runtime::new().expect("tokio runtime");
+    let tree = filled_tree(&rt);
+    b.iter(|| {
+        rt.block_on(async {
+            let mut found = Vec::new();
+            for target in peer_ids().take(100) {
+                let mut peers = tree.get_all_peers().await.collect::<Vec<_>>();
+                peers.sort_by_key(|peer| distance(peer.id(), target));
+                peers.truncate(NB_CLOSEST);
+                found.push(peers);
+            }
+            found
+        })
+    });
+}
+
+#[bench]
+fn bench_array_closest_peers(b: &mut Bencher) {
+    let array = filled_array();
+    b.iter(|| {
+        peer_ids()
+            .take(100)
+            .map(|target| {
+                let mut peers = array.search_closest_peers_to(target, NB_CLOSEST, IpFamilies::ALL);
+                peers.sort_by_key(|peer| distance(peer.id(), target));
+                peers.truncate(NB_CLOSEST);
+                peers
+            })
+            .collect::<Vec<_>>()
+    });
+}
@@ -1,23 +1,67 @@
 use clap::{Parser, Subcommand};
 use colored::Colorize;
-use errors::AnyResult;
-use piretoutpire::manager::manager::Manager;
-use rand::Rng;
-use std::{net::SocketAddr, path::Path};
+use errors::{bail, AnyResult};
+use piretoutpire::{
+    dht::{
+        identity::is_node_key,
+        immutable::ImmutableHash,
+        ip_id::{is_id_valid_for_ip, IpIdPolicy},
+        mutable::MutableHash,
+        quota::{
+            StorageQuota, DEFAULT_MAX_ENTRIES_PER_SENDER, DEFAULT_MAX_PEERS_PER_FILE,
+            DEFAULT_MAX_STORAGE_BYTES, DEFAULT_MAX_VALUES_PER_KEY, DEFAULT_MAX_VALUE_SIZE,
+        },
+    },
+    manager::{deadline::CancelToken, lookup_trace::LookupTrace, mailbox::MessageDelivery, manager::Manager},
+    network::{
+        protocol::StoredValue,
+        retry::{
+            RetryOn, RetryPolicy, DEFAULT_BASE_DELAY_MS, DEFAULT_JITTER, DEFAULT_MAX_ATTEMPTS,
+            DEFAULT_MAX_DELAY_MS,
+        },
+    },
+    utils::load_or_generate_keypair,
+};
+use std::{fs, net::SocketAddr, path::Path, time::Duration};
 
 #[derive(Parser)]
 #[clap(name = "PireToutPire")]
 #[clap(about = "CLI for the p2p network", long_about = None)]
 
 struct Cli {
-    /// Listening address for receiving commands.
+    /// Listening address for receiving commands, IPv4 or IPv6 (like
+    /// [::1]:4000).
     #[clap(default_value_t = String::from("127.0.0.1:4000"))]
     #[clap(long, value_name = "host:port")]
     server_addr: String,
 
-    /// Peer id (empty = random).
-    #[clap(long, value_name = "id")]
-    peer_id: Option<u32>,
+    /// Second listening address, on the other IP family than the server
+    /// address, to be reachable on both IPv4 and IPv6 (dual-stack).
+    #[clap(long, value_name = "host:port")]
+    alt_server_addr: Option<String>,
+
+    /// Address told to other peers to reach this one, when it's not the
+    /// listening one (behind a NAT, or listening on 0.0.0.0). Can be given
+    /// once per IP family. By default, it's learned from what other peers
+    /// report.
+    #[clap(long, value_name = "host:port")]
+    advertise_addr: Vec<String>,
+
+    /// Address of a peer relaying the requests sent to this one, when it can't
+    /// accept connections (behind a NAT or a firewall). Nothing is listened
+    /// on, and the relay address is told to other peers.
+    #[clap(long, value_name = "host:port", conflicts_with = "accept-relays")]
+    relay: Option<String>,
+
+    /// Relay the requests sent to peers which can't accept connections, when
+    /// they ask for it.
+    #[clap(long)]
+    accept_relays: bool,
+
+    /// File holding the secret key of this peer, its id is derived from it
+    /// (default is the dht filename with a .key extension, created if missing).
+    #[clap(long, value_name = "path")]
+    key_file: Option<String>,
 
     /// Max hop (empty = default behavior, search until not closer).
     /// Setting this option will enable a more greedy strategy for peers
@@ -34,18 +78,90 @@ struct Cli {
     #[clap(long, value_name = "ms")]
     connection_timeout: Option<u64>,
 
-    /// Max wait time for sending a query (default is 200 ms).
+    /// Max wait time for sending a control query: ping, find node, store...
+    /// (default is 200 ms).
     #[clap(long, value_name = "ms")]
     write_timeout: Option<u64>,
 
-    /// Max wait time for receiving a query (default is 200 ms).
+    /// Max wait time for receiving a control query: ping, find node, store...
+    /// (default is 200 ms).
     #[clap(long, value_name = "ms")]
     read_timeout: Option<u64>,
 
+    /// Max wait time for sending a file chunk query (default is 2 sec).
+    #[clap(long, value_name = "ms")]
+    bulk_write_timeout: Option<u64>,
+
+    /// Max wait time for receiving a file chunk query (default is 2 sec).
+    #[clap(long, value_name = "ms")]
+    bulk_read_timeout: Option<u64>,
+
+    /// Adapt the wait time for receiving a control query to the round-trip
+    /// time measured for each peer, up to the read timeout.
+    #[clap(long)]
+    adaptive_timeouts: bool,
+
     /// Frequency at which the dht is dump into the disk (default is 30 sec).
     #[clap(long, value_name = "ms")]
     dht_dump_frequency: Option<u64>,
 
+    /// Max number of attempts of a request to another peer, the first one
+    /// included (default is 3).
+    #[clap(long, value_name = "nb")]
+    retry_max_attempts: Option<u32>,
+
+    /// Wait time before trying a failed request again, doubled after each
+    /// attempt, up to 1 sec (default is 50 ms).
+    #[clap(long, value_name = "ms")]
+    retry_base_delay: Option<u64>,
+
+    /// Part of the wait time between two attempts which is random, from 0 to 1
+    /// (default is 0.5).
+    #[clap(long, value_name = "ratio")]
+    retry_jitter: Option<f64>,
+
+    /// Which failed requests are tried again: connection (only the failures to
+    /// connect, the request was never sent) or network (also the timeouts and
+    /// broken connections, a peer may then receive a request twice).
+    #[clap(default_value_t = RetryOn::Connection)]
+    #[clap(long, value_name = "errors", value_parser)]
+    retry_on: RetryOn,
+
+    /// How long a file announcement is kept, unless its owner announces it
+    /// again (default is 30 min). Shared files are announced again before it
+    /// elapses.
+    #[clap(long, value_name = "sec")]
+    announce_ttl: Option<u64>,
+
+    /// How long a message is kept for a peer which couldn't be reached, unless
+    /// it fetches it (default is 3 days).
+    #[clap(long, value_name = "sec")]
+    mail_ttl: Option<u64>,
+
+    /// Max size of all values, file announcements and mails stored for other
+    /// peers (default is 16 Mo).
+    #[clap(long, value_name = "bytes")]
+    max_storage_bytes: Option<usize>,
+
+    /// Max size of a single value or mail stored for another peer (default is
+    /// 64 Ko).
+    #[clap(long, value_name = "bytes")]
+    max_value_size: Option<usize>,
+
+    /// Max number of values, file announcements and mails a single peer can
+    /// store on us (default is 256).
+    #[clap(long, value_name = "nb")]
+    max_entries_per_sender: Option<usize>,
+
+    /// Max number of peers kept for a given file (default is 64).
+    #[clap(long, value_name = "nb")]
+    max_peers_per_file: Option<usize>,
+
+    /// Max number of values kept for a given key, the oldest are dropped
+    /// (default is 16).
+    #[clap(long, value_name = "nb")]
+    max_values_per_key: Option<usize>,
+
     /// Config file for dht.
     #[clap(default_value_t = String::from("/tmp/dht"))]
     #[clap(long, value_name = "dht-filename")]
@@ -68,6 +184,32 @@ struct Cli {
     #[clap(long, value_name = "disable-recent-peers-cache", action)]
     disable_recent_peers_cache: bool,
 
+    /// Search values, file peers and message recipients through many disjoint
+    /// paths, so a single poisoned peer can't steer the whole lookup.
+    #[clap(long, value_name = "secure-lookup", action)]
+    secure_lookup: bool,
+
+    /// What to do with peers whose id doesn't match their IP address: disabled,
+    /// deprioritise (only kept in the recent peers cache) or enforce (refused).
+    /// When enabled, a single IP address also gets few routing table entries,
+    /// and a new key file gets an id matching the server address (or the relay
+    /// one).
+    #[clap(default_value_t = IpIdPolicy::Disabled)]
+    #[clap(long, value_name = "policy", value_parser)]
+    ip_id_policy: IpIdPolicy,
+
+    /// Max duration of the whole command, whatever the number of requests it
+    /// makes (empty = default behavior, no limit). A command can also be
+    /// cancelled with Ctrl-C.
+    #[clap(long, value_name = "ms")]
+    deadline: Option<u64>,
+
+    /// Print the trace of the find-node or find-value lookup as JSON: the peers
+    /// queried at each hop, what they answered, and the best distance found.
+    /// Written into the given file, or on the standard output with "-".
+    #[clap(long, value_name = "path")]
+    trace: Option<String>,
+
     #[clap(subcommand)]
     command: Command,
 }
@@ -78,6 +220,10 @@ pub enum Command {
     #[clap(name = "seed")]
     Seed,
 
+    /// Print the id of this peer, derived from its key
+    #[clap(name = "id")]
+    Id,
+
     /// Ping a user from its peer id
     #[clap(arg_required_else_help = true)]
     #[clap(name = "ping")]
@@ -167,6 +313,15 @@ pub enum Command {
         value: String,
     },
 
+    /// Store an immutable value on the dht, its key is the sha256 of the value.
+    #[clap(arg_required_else_help = true)]
+    #[clap(name = "store-immutable")]
+    StoreImmutable {
+        /// Value
+        #[clap(value_parser)]
+        value: String,
+    },
+
     /// Find a value on the dht.
     #[clap(arg_required_else_help = true)]
     #[clap(name = "find-value")]
@@ -176,6 +331,40 @@ pub enum Command {
         key: u32,
     },
 
+    /// Find an immutable value on the dht, drop values not matching its key.
+    #[clap(arg_required_else_help = true)]
+    #[clap(name = "find-immutable")]
+    FindImmutable {
+        /// Key, the sha256 of the value in hexadecimal
+        #[clap(value_parser = parse_hash)]
+        key: ImmutableHash,
+    },
+
+    /// Publish a new version of a signed mutable value on the dht.
+    #[clap(arg_required_else_help = true)]
+    #[clap(name = "put-mutable")]
+    PutMutable {
+        /// File holding the secret key signing the value (created if missing)
+        #[clap(long, value_name = "path")]
+        key_file: String,
+        /// Salt, allowing a single key to own many values
+        #[clap(default_value_t = String::new())]
+        #[clap(long, value_name = "salt")]
+        salt: String,
+        /// Value
+        #[clap(value_parser)]
+        value: String,
+    },
+
+    /// Find the most recent version of a signed mutable value on the dht.
+    #[clap(arg_required_else_help = true)]
+    #[clap(name = "get-mutable")]
+    GetMutable {
+        /// Key, the sha256 of the owner public key and salt in hexadecimal
+        #[clap(value_parser = parse_hash)]
+        key: MutableHash,
+    },
+
     /// Send a message to a given peer
     #[clap(arg_required_else_help = true)]
     #[clap(name = "message")]
@@ -188,6 +377,22 @@ pub enum Command {
         message: String,
     },
 
+    /// Send a message to a given peer through other peers, as an onion
+    #[clap(arg_required_else_help = true)]
+    #[clap(name = "forward")]
+    Forward {
+        /// Number of peers the message goes through before the target
+        #[clap(default_value_t = 3)]
+        #[clap(long, value_name = "nb")]
+        hops: usize,
+        /// Peer id
+        #[clap(value_parser)]
+        target: u32,
+        /// Message
+        #[clap(value_parser)]
+        message: String,
+    },
+
     /// Send to closest node the crc of the file we're sharing
     #[clap(arg_required_else_help = true)]
     #[clap(name = "announce")]
@@ -213,28 +418,112 @@ pub enum Command {
 
 // Mode ------------------------------------------------------------------------
 
-// Generate a random peer id.
-fn get_random_id() -> u32 {
-    let mut rng = rand::thread_rng();
-    rng.gen::<u32>()
-}
-
 #[tokio::main]
 async fn main() -> AnyResult<()> {
     let args = Cli::parse();
-    let peer_id = args.peer_id.unwrap_or_else(|| get_random_id());
+    let key_file = args
+        .key_file
+        .unwrap_or_else(|| format!("{}.key", &args.dht_filename));
     let own_addr: SocketAddr = args.server_addr.parse()?;
+    let alt_addr = match &args.alt_server_addr {
+        Some(alt_addr) => Some(alt_addr.parse::<SocketAddr>()?),
+        None => None,
+    };
+    if alt_addr.map_or(false, |alt_addr| alt_addr.is_ipv4() == own_addr.is_ipv4()) {
+        bail!("the alternative server address must be on the other IP family than the server address");
+    }
+    let advertise_addrs = args
+        .advertise_addr
+        .iter()
+        .map(|addr| addr.parse::<SocketAddr>())
+        .collect::<Result<Vec<_>, _>>()?;
+    let relay_addr = match &args.relay {
+        Some(relay_addr) => Some(relay_addr.parse::<SocketAddr>()?),
+        None => None,
+    };
+    // Other peers check the id against the address we're seen from, the one of
+    // the relay for a relayed peer.
+    let public_ip = match relay_addr {
+        Some(relay_addr) => relay_addr.ip(),
+        None => advertise_addrs
+            .iter()
+            .find(|addr| addr.is_ipv4() == own_addr.is_ipv4())
+            .map_or(own_addr.ip(), |addr| addr.ip()),
+    };
+    let restricted_ip = Some(public_ip).filter(|_| args.ip_id_policy != IpIdPolicy::Disabled);
+    let keypair = load_or_generate_keypair(Path::new(&key_file), restricted_ip)?;
+    if !is_node_key(&keypair.public) {
+        bail!(
+            "the key in {} doesn't solve the id puzzle, remove it to get a new one",
+            key_file
+        );
+    }
+
+    let mut manager = Manager::new(
+        keypair,
+        own_addr,
+        alt_addr,
+        args.dht_filename.clone(),
+        args.working_dir,
+    );
+    if args.command == Command::Id {
+        println!("{}", manager.id());
+        return Ok(());
+    }
 
-    let mut manager = Manager::new(peer_id, own_addr, args.dht_filename.clone(), args.working_dir);
     manager.set_max_hop(args.max_hop);
+    manager.set_deadline(args.deadline);
+    let cancel_token = CancelToken::new();
+    manager.set_cancel_token(cancel_token.clone());
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            cancel_token.cancel();
+        }
+    });
+    manager.set_secure_lookup(args.secure_lookup);
+    manager.set_accept_relays(args.accept_relays).await;
+    manager.set_ip_id_policy(args.ip_id_policy).await;
+    if restricted_ip.is_some() && !is_id_valid_for_ip(manager.id(), public_ip) {
+        eprintln!(
+            "The id {} doesn't match the address {}, other peers may refuse it",
+            manager.id(),
+            public_ip
+        );
+    }
     manager
         .set_recent_peers_cache_enable(!args.disable_recent_peers_cache)
         .await;
+    manager.set_advertise_addrs(advertise_addrs).await;
     manager.set_slowness(args.slowness).await;
     manager.set_connection_timeout(args.connection_timeout).await;
     manager.set_write_timeout(args.write_timeout).await;
     manager.set_read_timeout(args.read_timeout).await;
+    manager.set_bulk_write_timeout(args.bulk_write_timeout).await;
+    manager.set_bulk_read_timeout(args.bulk_read_timeout).await;
+    manager.set_adaptive_timeouts(args.adaptive_timeouts).await;
     manager.set_dht_dump_frequency(args.dht_dump_frequency).await;
+    manager.set_announce_ttl(args.announce_ttl).await;
+    manager.set_mail_ttl(args.mail_ttl).await;
+    manager
+        .set_retry_policy(RetryPolicy {
+            max_attempts: args.retry_max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1),
+            base_delay: Duration::from_millis(args.retry_base_delay.unwrap_or(DEFAULT_BASE_DELAY_MS)),
+            max_delay: Duration::from_millis(DEFAULT_MAX_DELAY_MS),
+            jitter: args.retry_jitter.unwrap_or(DEFAULT_JITTER),
+            retry_on: args.retry_on,
+        })
+        .await;
+    manager
+        .set_storage_quota(StorageQuota {
+            max_total_bytes: args.max_storage_bytes.unwrap_or(DEFAULT_MAX_STORAGE_BYTES),
+            max_value_size: args.max_value_size.unwrap_or(DEFAULT_MAX_VALUE_SIZE),
+            max_entries_per_sender: args
+                .max_entries_per_sender
+                .unwrap_or(DEFAULT_MAX_ENTRIES_PER_SENDER),
+            max_peers_per_file: args.max_peers_per_file.unwrap_or(DEFAULT_MAX_PEERS_PER_FILE),
+            max_values_per_key: args.max_values_per_key.unwrap_or(DEFAULT_MAX_VALUES_PER_KEY),
+        })
+        .await;
 
     if manager.load_dht(Path::new(&args.dht_filename)).await.is_err() {
         println!(
@@ -242,18 +531,32 @@ async fn main() -> AnyResult<()> {
             &args.dht_filename
         );
     }
+    if let Some(relay_addr) = relay_addr {
+        manager.set_relay(relay_addr).await?;
+        println!("Relayed by {} on {}", relay_addr, manager.public_addr().await);
+    }
 
+    let addrs = std::iter::once(manager.addr())
+        .chain(manager.alt_addr())
+        .map(|addr| addr.to_string())
+        .collect::<Vec<_>>();
     let info = format!(
         "Peer ID: {}, Peer address: {}, Known peers: {}",
         manager.id(),
-        manager.addr(),
+        addrs.join(", "),
         manager.known_peers_count().await
     );
     println!("{}", info.truecolor(135, 138, 139).italic());
 
     match args.command {
+        Command::Id => unreachable!(),
         Command::Seed => {
             manager.share_dir(&args.share_dir).await?;
+            // Not being able to fetch our mails shouldn't stop us from seeding.
+            match manager.fetch_mails().await {
+                Ok(mails) => print_mails(mails),
+                Err(err) => eprintln!("Can't fetch mails: {}", err),
+            }
             manager.start_server().await?;
         }
         Command::Bootstrap { peer_addr } => {
@@ -264,6 +567,7 @@ async fn main() -> AnyResult<()> {
                 peer_found,
                 manager.known_peers_count().await
             );
+            print_mails(manager.fetch_mails().await?);
         }
         Command::Ping { target } => {
             let succeed = manager.ping(target).await?;
@@ -271,7 +575,14 @@ async fn main() -> AnyResult<()> {
             manager.dump_dht().await?;
         }
         Command::FindNode { target } => {
-            let peers = manager.find_node(target).await?;
+            let peers = match &args.trace {
+                Some(path) => {
+                    let (peers, trace) = manager.find_node_traced(target).await?;
+                    write_trace(path, &trace)?;
+                    peers
+                }
+                None => manager.find_node(target).await?,
+            };
             println!("Node found are: {:?}", peers);
             manager.dump_dht().await?;
         }
@@ -311,13 +622,59 @@ async fn main() -> AnyResult<()> {
             println!("{} peers store this value", nb_ack);
             manager.dump_dht().await?;
         }
+        Command::StoreImmutable { value } => {
+            let (key, nb_ack) = manager.store_immutable(value).await?;
+            println!("{} peers store this value at key {}", nb_ack, to_hex(&key));
+            manager.dump_dht().await?;
+        }
         Command::FindValue { key } => {
-            let value = manager.find_value(key).await?;
-            println!("Value found is: {:?}", value);
+            let values = match &args.trace {
+                Some(path) => {
+                    let (values, trace) = manager.find_value_traced(key).await?;
+                    write_trace(path, &trace)?;
+                    values
+                }
+                None => manager.find_value(key).await?,
+            };
+            print_values(values);
+        }
+        Command::FindImmutable { key } => print_values(manager.find_immutable(key).await?),
+        Command::PutMutable {
+            key_file,
+            salt,
+            value,
+        } => {
+            let keypair = load_or_generate_keypair(Path::new(&key_file), None)?;
+            let (key, nb_ack) = manager.put_mutable(&keypair, salt, value).await?;
+            println!("{} peers store this value at key {}", nb_ack, to_hex(&key));
+            manager.dump_dht().await?;
         }
-        Command::Message { target, message } => {
-            let succeed = manager.send_message(target, message).await?;
-            println!("Message send and acknowledge: {}", succeed);
+        Command::GetMutable { key } => match manager.get_mutable(key).await? {
+            Some(value) => println!(
+                "Value found is: {:?}, seq {}, signed by {}",
+                value.value,
+                value.seq,
+                to_hex(&value.public_key)
+            ),
+            None => println!("No value found"),
+        },
+        Command::Message { target, message } => match manager.send_message(target, message).await? {
+            MessageDelivery::Delivered => println!("Message send and acknowledge"),
+            MessageDelivery::Stored(nb_ack) => {
+                println!(
+                    "Peer unreachable, {} peers keep the message until it comes back",
+                    nb_ack
+                )
+            }
+            MessageDelivery::Lost => println!("Peer unreachable, message lost"),
+        },
+        Command::Forward {
+            hops,
+            target,
+            message,
+        } => {
+            let succeed = manager.forward_message(target, message, hops).await?;
+            println!("Message taken by the first hop: {}", succeed);
         }
         Command::Announce { crc } => {
             let nb_ack = manager.announce(crc).await?;
@@ -325,16 +682,82 @@ async fn main() -> AnyResult<()> {
         }
         Command::GetPeers { crc } => {
             let peers = manager.get_peers(crc).await?;
-            println!("Peers who own this file are: {:?}", peers);
+            println!("Peers who own this file");
+            for (peer, responder) in peers {
+                println!("  * {} ({}), reported by {}", peer.id, peer.addr, responder.id);
+            }
         }
         Command::List => {
             let peers = manager.known_peers().await;
             println!("Peers list");
             for peer in peers {
-                println!("  * {} ({})", peer.id(), peer.addr());
+                let rtt = match peer.srtt() {
+                    Some(srtt) => format!("{} ms", srtt.as_millis()),
+                    None => "unknown".to_string(),
+                };
+                let addrs = peer.addrs().map(|addr| addr.to_string()).collect::<Vec<_>>();
+                println!(
+                    "  * {} ({}), reputation: {}, rtt: {}",
+                    peer.id(),
+                    addrs.join(", "),
+                    peer.reputation(),
+                    rtt
+                );
             }
         }
     }
 
     Ok(())
 }
+
+// Print the values found for a key, with their origin.
+fn print_values(values: Vec<StoredValue>) {
+    if values.is_empty() {
+        println!("No value found");
+    }
+    for value in values {
+        println!(
+            "Value found is: {:?}, published by {} at {}",
+            value.value, value.publisher, value.timestamp
+        );
+    }
+}
+
+// Write the trace of a lookup as JSON into a file, or on the standard output
+// with "-".
+fn write_trace(path: &str, trace: &LookupTrace) -> AnyResult<()> {
+    let trace = serde_json::to_string_pretty(trace)?;
+    if path == "-" {
+        println!("{}", trace);
+    } else {
+        fs::write(path, trace)?;
+    }
+    Ok(())
+}
+
+// Write bytes in hexadecimal.
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Read the hexadecimal hash of an immutable or a mutable value.
+fn parse_hash(value: &str) -> Result<[u8; 32], String> {
+    let mut hash = [0u8; 32];
+    if value.len() != 2 * hash.len() || !value.is_ascii() {
+        return Err(format!("expected {} hexadecimal characters", 2 * hash.len()));
+    }
+    for (idx, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[2 * idx..2 * idx + 2], 16).map_err(|err| err.to_string())?;
+    }
+    Ok(hash)
+}
+
+// Print the mails received while we couldn't be reached.
+fn print_mails(mails: Vec<StoredValue>) {
+    for mail in mails {
+        println!(
+            "Mail from {}, sent at {}: \"{}\"",
+            mail.publisher, mail.timestamp, mail.value
+        );
+    }
+}
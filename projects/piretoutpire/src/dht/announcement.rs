@@ -0,0 +1,63 @@
+use super::identity::{is_node_key, node_id};
+use crate::{
+    network::protocol::{Announcement, Peer},
+    utils::u32_to_u8_array,
+};
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer};
+use std::net::SocketAddr;
+
+// What the owner of a file signs: the file crc, the address it's reached on and
+// the time it announced it. Other peers can forward the announcement, but can't
+// change where the file is, nor keep it alive longer.
+fn signed_payload(crc: u32, owner: &Peer, announced_at: u32) -> Vec<u8> {
+    let mut res = u32_to_u8_array(crc).to_vec();
+    res.extend(Vec::<u8>::from(owner.clone()));
+    res.extend(u32_to_u8_array(announced_at));
+    res
+}
+
+impl Announcement {
+    // Create the announcement that we own a file, reachable at a given address.
+    pub fn new(keypair: &Keypair, crc: u32, addr: SocketAddr, announced_at: u32) -> Self {
+        let owner = Peer {
+            id: node_id(&keypair.public),
+            addr,
+        };
+        let signature = keypair.sign(&signed_payload(crc, &owner, announced_at));
+        Self {
+            owner,
+            announced_at,
+            public_key: keypair.public.to_bytes().to_vec(),
+            signature: signature.to_bytes().to_vec(),
+        }
+    }
+
+    // Create the announcement of an owner we can't prove, only known from a
+    // lookup. It's never announced further.
+    pub fn unsigned(owner: Peer, announced_at: u32) -> Self {
+        Self {
+            owner,
+            announced_at,
+            public_key: vec![],
+            signature: vec![],
+        }
+    }
+
+    // Check the announcement of a file has been signed by its owner, the node
+    // owning the public key.
+    pub fn has_valid_signature(&self, crc: u32) -> bool {
+        let public_key = match PublicKey::from_bytes(&self.public_key) {
+            Ok(public_key) => public_key,
+            Err(_) => return false,
+        };
+        let signature = match Signature::from_bytes(&self.signature) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        is_node_key(&public_key)
+            && node_id(&public_key) == self.owner.id
+            && public_key
+                .verify_strict(&signed_payload(crc, &self.owner, self.announced_at), &signature)
+                .is_ok()
+    }
+}
@@ -0,0 +1,245 @@
+use super::{
+    bucket_tree::InsertResult,
+    peer_node::{IpFamilies, PeerEvent, PeerNode, PeerStatus},
+};
+use crate::utils::distance;
+use std::{net::SocketAddr, time::Duration};
+
+// Maximum nodes by bucket. Bittorent use 8.
+const BUCKET_SIZE: usize = 4;
+
+// Maximum number of buckets. The last bucket then holds ids of the lowest
+// range, which can't hold more than BUCKET_SIZE different ids, so it never has
+// to be split.
+const MAX_BUCKETS: usize = (u32::BITS - BUCKET_SIZE.trailing_zeros()) as usize + 1;
+
+// Same as the BucketTree, but flatten into an array of buckets. There's no
+// lock to take, as the whole routing table is already behind the context mutex.
+//
+// Buckets hold the same ranges as the tree, which splits them on their middle
+// point: the first one holds [2^31 - 1, 2^32 - 1], the second one
+// [2^30 - 1, 2^31 - 1), and so on. It means buckets are indexed by the number
+// of leading zeros of id + 1.
+//
+// The last bucket holds all the ids from 0 to the start of the previous one,
+// just like the left leaf of the tree. When it's full, it's split into 2: the
+// furthest ids stay at this index, and the others are moved to a new last
+// bucket. Other buckets never split.
+//
+// This is what the array would looks like, with a bucket size of 4:
+//
+//   0: [2^31 + 5, 2^31 + 12, 2^32 - 2, 2^32 - 1]
+//   1: [2^30 + 8, 2^31 - 2]
+//   2: [2^29 - 1, 2^29 + 3, 2^30 - 6]
+//   3: [0, 12]
+//
+// Just like the tree, it stores more values close to 0.
+#[derive(Debug)]
+pub struct BucketArray {
+    buckets: Vec<Vec<PeerNode>>,
+}
+
+impl Default for BucketArray {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Public interface.
+impl BucketArray {
+    // Initialize a new array, with a single bucket holding every ids.
+    pub fn new() -> Self {
+        Self {
+            buckets: vec![Vec::with_capacity(BUCKET_SIZE)],
+        }
+    }
+
+    // Add a new peer info into the array.
+    // Returns if an insertion has been made.
+    pub fn add_peer_node(&mut self, peer_node: PeerNode) -> InsertResult {
+        let mut idx = self.bucket_index(peer_node.id());
+        let bucket = &mut self.buckets[idx];
+
+        // Already exists
+        if bucket.iter().any(|peer| peer.id() == peer_node.id()) {
+            return InsertResult::AlreadyExists;
+        }
+
+        // Enough room for a new peer
+        if bucket.len() < BUCKET_SIZE {
+            insert_sorted(bucket, peer_node);
+            return InsertResult::Succeed;
+        }
+
+        // Not enough room, try to replace a bad peer first
+        if let Some(pos) = bucket.iter().position(|peer| peer.status() == PeerStatus::Bad) {
+            bucket.remove(pos);
+            insert_sorted(bucket, peer_node);
+            return InsertResult::Succeed;
+        }
+
+        // Bucket is full, no other choice but to split the last one, until
+        // there's room for the new value. Others can't be split.
+        loop {
+            if idx != self.last_index() || self.buckets.len() == MAX_BUCKETS {
+                return InsertResult::NoRoom;
+            }
+            self.split_last();
+
+            idx = self.bucket_index(peer_node.id());
+            let bucket = &mut self.buckets[idx];
+            if bucket.len() < BUCKET_SIZE {
+                insert_sorted(bucket, peer_node);
+                return InsertResult::Succeed;
+            }
+        }
+    }
+
+    // Search the closest peers
+    pub fn search_closest_peers(&self, nb: usize) -> impl Iterator<Item = PeerNode> {
+        let mut res = Vec::new();
+        for bucket in self.buckets.iter().rev() {
+            res.extend(bucket.iter().map(Clone::clone));
+            if res.len() >= nb {
+                break;
+            }
+        }
+        res.into_iter()
+    }
+
+    // Search the closest peers from a target, by looking only at the buckets
+    // which may hold them. Peers are grouped by the highest bit of their
+    // distance to the target, closest group first. Whole groups are returned,
+    // until at least nb peers have been found, so they can be reordered inside
+    // a group. Peers with a low reputation, or without any address on the given
+    // families, are not counted, as they're only used when there's nobody else.
+    pub fn search_closest_peers_to(&self, target: u32, nb: usize, families: IpFamilies) -> Vec<PeerNode> {
+        let mut visited = vec![false; self.buckets.len()];
+        let mut res = Vec::new();
+        for nb_common_bits in (0..=u32::BITS).rev() {
+            // Ids sharing exactly this many highest bits with the target are in
+            // a range of consecutive ids, so in consecutive buckets.
+            let (first, last) = match nb_common_bits {
+                32 => (target, target),
+                _ => {
+                    let low_bits = u32::MAX >> nb_common_bits >> 1;
+                    let first = (target ^ (1 << (31 - nb_common_bits))) & !low_bits;
+                    (first, first | low_bits)
+                }
+            };
+            let range = self.bucket_index(last)..=self.bucket_index(first);
+            for (bucket, visited) in self.buckets[range.clone()].iter().zip(&mut visited[range]) {
+                if !*visited {
+                    *visited = true;
+                    res.extend(bucket.iter().map(Clone::clone));
+                }
+            }
+
+            let nb_found = res
+                .iter()
+                .filter(|peer| {
+                    distance(peer.id(), target).leading_zeros() >= nb_common_bits
+                        && !peer.has_low_reputation()
+                        && peer.addr_in(families).is_some()
+                })
+                .count();
+            if nb_found >= nb {
+                break;
+            }
+        }
+        res
+    }
+
+    // Return all contained peers.
+    pub fn get_all_peers(&self) -> impl Iterator<Item = PeerNode> {
+        self.search_closest_peers(usize::MAX)
+    }
+
+    // Flag that we requested a peer. A peer which is requested a lot, but never
+    // answer will be considred bad.
+    pub fn peer_was_requested(&mut self, target: u32) {
+        self.modify_exact_peer(target, PeerNode::update_last_request);
+    }
+
+    // Flag that the peer correctly responded, hence is alive.
+    pub fn peer_has_responded(&mut self, target: u32) {
+        self.modify_exact_peer(target, PeerNode::update_last_response);
+    }
+
+    // Flag that the peer sent forged data, so it can be replaced.
+    pub fn peer_has_lied(&mut self, target: u32) {
+        self.modify_exact_peer(target, PeerNode::flag_as_liar);
+    }
+
+    // Update the reputation of a peer from the outcome of a request.
+    pub fn rate_peer(&mut self, target: u32, event: PeerEvent) {
+        self.modify_exact_peer(target, |peer| peer.rate(event));
+    }
+
+    // Record the round-trip time of a request made to a peer.
+    pub fn peer_rtt_measured(&mut self, target: u32, rtt: Duration) {
+        self.modify_exact_peer(target, |peer| peer.update_rtt(rtt));
+    }
+
+    // Record another address of a peer, on a family not known yet.
+    pub fn peer_addr_found(&mut self, target: u32, addr: SocketAddr) {
+        self.modify_exact_peer(target, |peer| {
+            peer.add_addr(addr);
+        });
+    }
+
+    // Check if a peer is in the array.
+    pub fn contains(&self, target: u32) -> bool {
+        self.buckets[self.bucket_index(target)]
+            .iter()
+            .any(|peer| peer.id() == target)
+    }
+
+    // Remove a peer from the array, the bucket is not merged back.
+    pub fn remove_peer(&mut self, target: u32) {
+        let idx = self.bucket_index(target);
+        self.buckets[idx].retain(|peer| peer.id() != target);
+    }
+}
+
+// Private methods.
+impl BucketArray {
+    // Index of the last bucket.
+    fn last_index(&self) -> usize {
+        self.buckets.len() - 1
+    }
+
+    // Index of the bucket which may hold a given id.
+    fn bucket_index(&self, id: u32) -> usize {
+        (id.saturating_add(1).leading_zeros() as usize).min(self.last_index())
+    }
+
+    // Apply a patch on a given peer, if it exists.
+    fn modify_exact_peer(&mut self, target: u32, patch: impl Fn(&mut PeerNode)) {
+        let idx = self.bucket_index(target);
+        if let Some(peer) = self.buckets[idx].iter_mut().find(|peer| peer.id() == target) {
+            patch(peer);
+        }
+    }
+
+    // Split the last bucket in two, keeping at its place the ids with exactly
+    // its number of leading zeros, and moving the others in a new last bucket.
+    fn split_last(&mut self) {
+        let last = self.last_index();
+        let (kept, moved) = self.buckets[last]
+            .drain(..)
+            .partition(|peer| peer.id().saturating_add(1).leading_zeros() as usize == last);
+        self.buckets[last] = kept;
+        self.buckets.push(moved);
+    }
+}
+
+// Insert a peer in a bucket, keeping it sorted by id.
+fn insert_sorted(bucket: &mut Vec<PeerNode>, peer_node: PeerNode) {
+    let pos = bucket.partition_point(|peer| peer.id() < peer_node.id());
+    bucket.insert(pos, peer_node);
+}
+
+#[cfg(test)]
+#[path = "bucket_array_test.rs"]
+mod bucket_array_test;
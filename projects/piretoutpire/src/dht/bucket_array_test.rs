@@ -0,0 +1,106 @@
+use super::*;
+use crate::utils::distance;
+use errors::AnyResult;
+
+#[test]
+fn test_construct_array() -> AnyResult<()> {
+    let dummy_addr = "127.0.0.1:4000".parse()?;
+    let middle = 1 << 31;
+
+    let mut array = BucketArray::new();
+    // Insert any value
+    assert_eq!(
+        InsertResult::Succeed,
+        array.add_peer_node(PeerNode::new(middle, dummy_addr))
+    );
+
+    // Ensure it can't be inserted twice
+    assert_eq!(
+        InsertResult::AlreadyExists,
+        array.add_peer_node(PeerNode::new(middle, dummy_addr))
+    );
+
+    // Fill the furthest bucket, it can't be split anymore
+    for idx in 1..BUCKET_SIZE {
+        assert_eq!(
+            InsertResult::Succeed,
+            array.add_peer_node(PeerNode::new(middle + idx as u32, dummy_addr))
+        );
+    }
+    assert_eq!(
+        InsertResult::Succeed,
+        array.add_peer_node(PeerNode::new(1, dummy_addr))
+    );
+    assert_eq!(
+        InsertResult::NoRoom,
+        array.add_peer_node(PeerNode::new(middle + BUCKET_SIZE as u32, dummy_addr))
+    );
+
+    // Closest ids are still accepted, down to the last bucket
+    for idx in 0..BUCKET_SIZE {
+        if idx != 1 {
+            assert_eq!(
+                InsertResult::Succeed,
+                array.add_peer_node(PeerNode::new(idx as u32, dummy_addr))
+            );
+        }
+    }
+    assert_eq!(2 * BUCKET_SIZE, array.get_all_peers().count());
+
+    Ok(())
+}
+
+#[test]
+fn test_closest_peers() -> AnyResult<()> {
+    let dummy_addr = "127.0.0.1:4000".parse()?;
+    let mut array = BucketArray::new();
+
+    for idx in 0..=10 {
+        assert_eq!(
+            InsertResult::Succeed,
+            array.add_peer_node(PeerNode::new(idx as u32, dummy_addr))
+        );
+    }
+    for idx in 15..=18 {
+        assert_eq!(
+            InsertResult::Succeed,
+            array.add_peer_node(PeerNode::new(idx as u32, dummy_addr))
+        );
+    }
+
+    let peers = array.search_closest_peers(100);
+    assert_eq!(15, peers.count());
+
+    Ok(())
+}
+
+#[test]
+fn test_closest_peers_to() -> AnyResult<()> {
+    let dummy_addr = "127.0.0.1:4000".parse()?;
+    let mut array = BucketArray::new();
+    for idx in 0..1000u32 {
+        array.add_peer_node(PeerNode::new(idx.wrapping_mul(2_654_435_761), dummy_addr));
+    }
+    let all_peers = array.get_all_peers().collect::<Vec<_>>();
+
+    // The closest peers are always part of the searched ones.
+    for target in [0, 1, 42, 1 << 20, 1 << 31, u32::MAX] {
+        for nb in [1, 4, 10] {
+            let mut expected = all_peers.iter().map(|peer| peer.id()).collect::<Vec<_>>();
+            expected.sort_by_key(|id| distance(*id, target));
+            expected.truncate(nb);
+
+            let mut found = array
+                .search_closest_peers_to(target, nb, IpFamilies::ALL)
+                .iter()
+                .map(|peer| peer.id())
+                .collect::<Vec<_>>();
+            assert!(found.len() >= nb);
+            found.sort_by_key(|id| distance(*id, target));
+            found.truncate(nb);
+            assert_eq!(expected, found);
+        }
+    }
+
+    Ok(())
+}
@@ -1,6 +1,6 @@
-use super::peer_node::PeerNode;
+use super::peer_node::{PeerEvent, PeerNode};
 use crate::{dht::peer_node::PeerStatus, utils::middle_point};
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 use tokio::sync::Mutex;
 
 // Maximum nodes by bucket. Bittorent use 8.
@@ -224,6 +224,38 @@ impl BucketTree {
         })
         .await;
     }
+
+    // Flag that the peer sent forged data, so it can be replaced.
+    pub async fn peer_has_lied(&mut self, target: u32) {
+        self.modify_exact_peer(target, |peer| {
+            peer.flag_as_liar();
+        })
+        .await;
+    }
+
+    // Update the reputation of a peer from the outcome of a request.
+    pub async fn rate_peer(&mut self, target: u32, event: PeerEvent) {
+        self.modify_exact_peer(target, |peer| {
+            peer.rate(event);
+        })
+        .await;
+    }
+
+    // Record the round-trip time of a request made to a peer.
+    pub async fn peer_rtt_measured(&mut self, target: u32, rtt: Duration) {
+        self.modify_exact_peer(target, |peer| {
+            peer.update_rtt(rtt);
+        })
+        .await;
+    }
+
+    // Remove a peer from the tree, the bucket is not merged back.
+    pub async fn remove_peer(&mut self, target: u32) {
+        self.modify_bucket_of(target, |peers| {
+            peers.retain(|peer| peer.id() != target);
+        })
+        .await;
+    }
 }
 
 // Private methods.
@@ -252,8 +284,18 @@ impl BucketTree {
         unreachable!()
     }
 
-    // Mark the peer has being requested (false), or responded (true).
+    // Apply a patch on a given peer, if it exists.
     async fn modify_exact_peer(&mut self, target: u32, patch: impl Fn(&mut PeerNode)) {
+        self.modify_bucket_of(target, |peers| {
+            if let Some(peer) = peers.iter_mut().find(|peer| peer.id() == target) {
+                patch(peer);
+            }
+        })
+        .await;
+    }
+
+    // Apply a patch on the bucket which may hold a given peer.
+    async fn modify_bucket_of(&mut self, target: u32, patch: impl Fn(&mut Vec<PeerNode>)) {
         let mut queue = Vec::new();
         queue.push(Arc::clone(&self.root));
         while let Some(rc_tree_node) = queue.pop() {
@@ -264,15 +306,22 @@ impl BucketTree {
                     drop(tree_node);
                     let mut tree_node = rc_tree_node.lock().await;
                     if let LeafOrChildren::Leaf(bucket) = &mut tree_node.children {
-                        if let Some(peer) = bucket.peers.iter_mut().find(|peer| peer.id() == target) {
-                            patch(peer);
-                        }
+                        patch(&mut bucket.peers);
                     }
                 }
                 LeafOrChildren::Children(rc_left, _) => {
                     let left = rc_left.lock().await;
                     if target < left.end {
                         queue.push(Arc::clone(&rc_left));
+                        continue;
+                    }
+
+                    // The peer can only be in the bucket of this node.
+                    drop(left);
+                    drop(tree_node);
+                    let mut tree_node = rc_tree_node.lock().await;
+                    if let LeafOrChildren::Children(_, bucket) = &mut tree_node.children {
+                        patch(&mut bucket.peers);
                     }
                 }
             }
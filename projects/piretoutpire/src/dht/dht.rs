@@ -1,13 +1,23 @@
-use super::{peer_node::PeerNode, routing_table::RoutingTable};
-use crate::network::protocol::Peer;
+use super::{
+    ip_id::IpIdPolicy,
+    mutable::{mutable_key, MutableHash, MutableRejected},
+    peer_node::{IpFamilies, PeerEvent, PeerNode},
+    quota::{QuotaExceeded, StorageQuota},
+    routing_table::RoutingTable,
+};
+use crate::{
+    network::protocol::{Announcement, MutableValue, Peer, StoredValue},
+    utils::{distance, unix_timestamp},
+};
 use errors::AnyResult;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::HashMap,
     fs::OpenOptions,
-    io::BufReader,
+    io::{BufReader, BufWriter, Write},
     net::SocketAddr,
     path::Path,
+    time::Duration,
 };
 
 // The DHT is a way to handle a collaborative hash map. It allows to maintain a
@@ -15,8 +25,73 @@ use std::{
 #[derive(Debug)]
 pub struct DistributedHashTable {
     routing_table: RoutingTable,
-    kv_store: HashMap<u32, String>,
-    files_store: HashMap<u32, HashSet<Peer>>,
+    kv_store: HashMap<u32, Vec<StoredValue>>,
+    mutable_store: HashMap<u32, Vec<MutableValue>>,
+    files_store: HashMap<u32, HashMap<Peer, Announcement>>,
+    mailboxes: HashMap<u32 /*recipient*/, Vec<StoredValue>>,
+    quota: StorageQuota,
+    announce_ttl: Duration,
+    mail_ttl: Duration,
+    own_families: IpFamilies,
+}
+
+// Number of closest peers on which a value is stored.
+const REPLICATION_SIZE: usize = 4;
+
+// Time after which a file announcement is forgotten, unless its owner announces
+// it again.
+pub const DEFAULT_ANNOUNCE_TTL: Duration = Duration::from_secs(30 * 60); // 30 min
+
+// Time after which a mail is dropped, if its recipient didn't fetch it.
+pub const DEFAULT_MAIL_TTL: Duration = Duration::from_secs(3 * 24 * 60 * 60); // 3 days
+
+// Values and file announcements a newly known peer should hold, because it's
+// now one of the closest peers to their keys, and closer than we are. Only the
+// announcements signed by their owner are part of it.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Replication {
+    pub values: Vec<(u32 /*key*/, StoredValue /*value*/)>,
+    pub mutable_values: Vec<MutableValue>,
+    pub file_peers: Vec<(u32 /*crc*/, Announcement)>,
+    // The peer to replicate on, and all the peers we know, to tell for which
+    // keys it's one of the closest.
+    peer_id: u32,
+    known_peers: Vec<u32>,
+}
+
+impl Replication {
+    // Check if there's nothing to replicate.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty() && self.mutable_values.is_empty() && self.file_peers.is_empty()
+    }
+
+    // Only keep the entries for which the peer is one of the closest known
+    // peers. It's not done by add_node, to keep it out of the dht lock.
+    pub fn retain_closest(&mut self) {
+        let (peer_id, known_peers) = (self.peer_id, &self.known_peers);
+        let is_closest = |key: u32| {
+            let peer_distance = distance(peer_id, key);
+            let nb_closer = known_peers
+                .iter()
+                .filter(|id| distance(**id, key) < peer_distance)
+                .take(REPLICATION_SIZE)
+                .count();
+            nb_closer < REPLICATION_SIZE
+        };
+        self.values.retain(|(key, _)| is_closest(*key));
+        self.mutable_values.retain(|mutable| is_closest(mutable.key()));
+        self.file_peers.retain(|(crc, _)| is_closest(*crc));
+    }
+}
+
+// The stores sharing the storage quota. The same key can be used in each of
+// them, for unrelated entries.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Keyspace {
+    Values,
+    Mutable,
+    Files,
+    Mails,
 }
 
 // Intermediary structure to serialize and deserialize dht peers.
@@ -24,8 +99,14 @@ pub struct DistributedHashTable {
 struct Config {
     peers: Vec<PeerNode>,
     peers_lru: Vec<PeerNode>,
-    kv_store: HashMap<u32, String>,
-    files_store: HashMap<u32, Vec<Peer>>,
+    kv_store: HashMap<u32, Vec<StoredValue>>,
+    #[serde(default)]
+    mutable_values: HashMap<u32, Vec<MutableValue>>,
+    files_store: HashMap<u32, Vec<Announcement>>,
+    #[serde(default)]
+    banned_peers: Vec<u32>,
+    #[serde(default)]
+    mailboxes: HashMap<u32, Vec<StoredValue>>,
 }
 
 impl DistributedHashTable {
@@ -34,10 +115,47 @@ impl DistributedHashTable {
         Self {
             routing_table: RoutingTable::new(id),
             kv_store: HashMap::new(),
+            mutable_store: HashMap::new(),
             files_store: HashMap::new(),
+            mailboxes: HashMap::new(),
+            quota: StorageQuota::default(),
+            announce_ttl: DEFAULT_ANNOUNCE_TTL,
+            mail_ttl: DEFAULT_MAIL_TTL,
+            own_families: IpFamilies::ALL,
         }
     }
 
+    // Get the id of the owner of this dht.
+    pub fn id(&self) -> u32 {
+        self.routing_table.id()
+    }
+
+    // Set the limits applied on what other peers can store on us.
+    pub fn set_storage_quota(&mut self, quota: StorageQuota) {
+        self.quota = quota;
+    }
+
+    // Set how long a file announcement is kept, unless announced again.
+    pub fn set_announce_ttl(&mut self, ttl: Duration) {
+        self.announce_ttl = ttl;
+    }
+
+    // Get how long a file announcement is kept, unless announced again.
+    pub fn announce_ttl(&self) -> Duration {
+        self.announce_ttl
+    }
+
+    // Set how long a mail is kept, unless its recipient fetches it.
+    pub fn set_mail_ttl(&mut self, ttl: Duration) {
+        self.mail_ttl = ttl;
+    }
+
+    // Set the addresses this node listens on. Peers reachable on their IP
+    // families are preferred when looking for the closest ones.
+    pub fn set_own_addrs(&mut self, addrs: impl IntoIterator<Item = SocketAddr>) {
+        self.own_families = IpFamilies::of(addrs);
+    }
+
     // Enable the recent peer cache. On small network, with non uniform id
     /// distribution, caching peers could be hard. The "recent" peers cache is
     /// used on top of the routing table, to help finding peers. On big network,
@@ -46,38 +164,64 @@ impl DistributedHashTable {
         self.routing_table.set_recent_peers_cache_enable(value);
     }
 
+    // Set what to do with peers whose id doesn't match their IP address.
+    pub fn set_ip_id_policy(&mut self, policy: IpIdPolicy) {
+        self.routing_table.set_ip_id_policy(policy);
+    }
+
     // Try to find a given node. Either return it, or return the closest known
-    // node. When trying to find a node, also add the sender inside the routing
-    // table.
-    pub async fn find_node(&mut self, sender: PeerNode, target: u32) -> impl Iterator<Item = PeerNode> {
+    // node, preferably reachable by the sender. When trying to find a node, also
+    // add the sender inside the routing table, and return what should be
+    // replicated on it.
+    pub async fn find_node(&mut self, sender: PeerNode, target: u32) -> (Vec<PeerNode>, Replication) {
         let res = self
-            .routing_table
-            .get_closest_peers_from(target, 4)
+            .find_closest_peers_towards(target, 4, sender.addr())
             .await
             .collect::<Vec<_>>();
-        self.routing_table.add_node(sender).await;
-        res.into_iter()
+        let replication = self.add_node(sender.id(), sender.addr()).await;
+        (res, replication)
     }
 
     // Search for the closest peer.
     pub async fn find_closest_peer(&self, target: u32) -> Option<PeerNode> {
-        let mut res = self.find_closest_peers(target, 1).await.collect::<Vec<_>>();
-        res.pop()
+        self.find_closest_peers(target, 1).await.next()
     }
 
-    // Search for the N closest peers.
+    // Search for the N closest peers, preferably reachable by this node.
     pub async fn find_closest_peers(&self, target: u32, nb: usize) -> impl Iterator<Item = PeerNode> {
-        let res = self
-            .routing_table
-            .get_closest_peers_from(target, nb)
+        self.routing_table
+            .get_closest_peers_in(target, nb, self.own_families)
             .await
-            .collect::<Vec<_>>();
-        res.into_iter()
     }
 
-    // Add a new node for ease of purpose in test files.
-    pub async fn add_node(&mut self, id: u32, addr: SocketAddr) {
-        self.add_peer_node(PeerNode::new(id, addr)).await;
+    // Search for the N closest peers, preferably reachable from a given
+    // address, to answer the peer at this address.
+    pub async fn find_closest_peers_towards(
+        &self,
+        target: u32,
+        nb: usize,
+        addr: SocketAddr,
+    ) -> impl Iterator<Item = PeerNode> {
+        self.routing_table
+            .get_closest_peers_in(target, nb, IpFamilies::of([addr]))
+            .await
+    }
+
+    // Add a new node into the routing table. If this node wasn't known, return
+    // all values and file announcements it should now hold.
+    pub async fn add_node(&mut self, id: u32, addr: SocketAddr) -> Replication {
+        if !self.routing_table.add_node(PeerNode::new(id, addr)).await {
+            return Replication::default();
+        }
+        self.replication_for(id).await
+    }
+
+    // Same as add_node, for a peer we relay at the given address.
+    pub async fn add_relayed_node(&mut self, id: u32, addr: SocketAddr) -> Replication {
+        if !self.routing_table.add_relayed_node(PeerNode::new(id, addr)).await {
+            return Replication::default();
+        }
+        self.replication_for(id).await
     }
 
     // Clean the whole dht.
@@ -86,12 +230,12 @@ impl DistributedHashTable {
     }
 
     // Get the number of known peers.
-    pub async fn known_peers(&mut self) -> impl Iterator<Item = PeerNode> + '_ {
+    pub async fn known_peers(&self) -> impl Iterator<Item = PeerNode> + '_ {
         self.routing_table.get_all_peers().await
     }
 
-    // Dump this dht into a file.
-    pub async fn dump_to_file(&self, path: &Path) -> AnyResult<()> {
+    // Serialize this dht, as it's dumped into a file.
+    pub async fn to_json(&self) -> AnyResult<Vec<u8>> {
         let config = Config {
             peers: self.routing_table.get_all_peers().await.collect(),
             peers_lru: self
@@ -100,19 +244,28 @@ impl DistributedHashTable {
                 .map(Clone::clone)
                 .collect(),
             kv_store: self.kv_store.clone(),
+            mutable_values: self.mutable_store.clone(),
             files_store: self
                 .files_store
                 .iter()
-                .map(|(key, peers)| (*key, peers.into_iter().map(Clone::clone).collect::<Vec<_>>()))
+                .map(|(key, peers)| (*key, peers.values().cloned().collect()))
                 .collect(),
+            banned_peers: self.routing_table.get_banned_peers().copied().collect(),
+            mailboxes: self.mailboxes.clone(),
         };
 
+        Ok(serde_json::to_vec(&config)?)
+    }
+
+    // Dump this dht into a file.
+    pub async fn dump_to_file(&self, path: &Path) -> AnyResult<()> {
         let file = OpenOptions::new()
             .truncate(true)
             .create(true)
             .write(true)
             .open(&path)?;
-        serde_json::to_writer(file, &config)?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&self.to_json().await?)?;
 
         Ok(())
     }
@@ -124,48 +277,310 @@ impl DistributedHashTable {
         let config: Config = serde_json::from_reader(reader)?;
 
         self.clean().await;
+        for peer in config.banned_peers {
+            self.routing_table.ban_peer(peer).await;
+        }
         for peer in config.peers.into_iter().chain(config.peers_lru.into_iter()) {
             self.add_peer_node(peer).await;
         }
         self.kv_store = config.kv_store;
+        self.mutable_store = config.mutable_values;
         self.files_store = config
             .files_store
             .into_iter()
-            .map(|(key, peers)| (key, peers.into_iter().collect::<HashSet<_>>()))
+            .map(|(key, peers)| {
+                let peers = peers
+                    .into_iter()
+                    .map(|announcement| (announcement.owner.clone(), announcement))
+                    .collect::<HashMap<_, _>>();
+                (key, peers)
+            })
             .collect();
+        self.expire_file_peers();
+        self.mailboxes = config.mailboxes;
+        self.expire_mails();
+
+        Ok(())
+    }
+
+    // Store a given value for a given key, alongside the other values of this
+    // key. Storing again the same value from the same publisher only refreshes
+    // its timestamp. When the key already holds too many values, the oldest one
+    // is dropped.
+    // Values published by other peers are subject to the storage quota, ours
+    // are always accepted.
+    pub fn store_value(&mut self, key: u32, stored_value: StoredValue) -> Result<(), QuotaExceeded> {
+        let values = self.kv_store.get(&key);
+        let already_stored = values.and_then(|values| {
+            values.iter().position(|stored| {
+                stored.publisher == stored_value.publisher && stored.value == stored_value.value
+            })
+        });
+        if let Some(idx) = already_stored {
+            if let Some(stored) = self.kv_store.get_mut(&key).and_then(|values| values.get_mut(idx)) {
+                stored.timestamp = stored.timestamp.max(stored_value.timestamp);
+            }
+            return Ok(());
+        }
+
+        let oldest = values
+            .filter(|values| values.len() >= self.quota.max_values_per_key)
+            .and_then(|values| {
+                values
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, stored)| stored.timestamp)
+                    .map(|(idx, stored)| (idx, stored.value.len()))
+            });
+
+        if stored_value.publisher != self.id() {
+            if stored_value.value.len() > self.quota.max_value_size {
+                return Err(QuotaExceeded::ValueTooLarge);
+            }
+            if self.nb_entries_of(stored_value.publisher) >= self.quota.max_entries_per_sender {
+                return Err(QuotaExceeded::TooManyEntriesForSender);
+            }
+            let released = oldest.map_or(0, |(_, size)| size);
+            self.make_room(Keyspace::Values, key, released, stored_value.value.len())?;
+        }
+
+        let values = self.kv_store.entry(key).or_insert_with(Vec::new);
+        if let Some((idx, _)) = oldest {
+            values.remove(idx);
+        }
+        values.push(stored_value);
+        Ok(())
+    }
+
+    // Get all the values stored for a given key.
+    pub fn get_values(&self, key: u32) -> Option<&[StoredValue]> {
+        self.kv_store.get(&key).map(Vec::as_slice)
+    }
+
+    // Store a signed mutable value at its key, replacing the previous version
+    // of the same hash. Values of other owners landing on the same key are kept
+    // aside. The value is refused if its signature is wrong, or if it's not
+    // more recent than the stored one: same sequence number is only accepted
+    // for the very same value. Values from other owners are subject to the
+    // storage quota, ours are always accepted.
+    pub fn store_mutable(&mut self, mutable: MutableValue, is_ours: bool) -> Result<(), MutableRejected> {
+        if !mutable.has_valid_signature() {
+            return Err(MutableRejected::InvalidSignature);
+        }
+
+        let hash = mutable.hash();
+        let key = mutable_key(&hash);
+        let nb_owners = self.mutable_store.get(&key).map_or(0, Vec::len);
+        let released = match self.get_mutable(&hash) {
+            Some(stored) if stored.seq > mutable.seq => return Err(MutableRejected::OutdatedSequence),
+            Some(stored) if stored.seq == mutable.seq => {
+                if stored.value != mutable.value {
+                    return Err(MutableRejected::OutdatedSequence);
+                }
+                return Ok(());
+            }
+            Some(stored) => stored.value.len(),
+            // The values already there are kept, a newcomer can't evict them.
+            None if !is_ours && nb_owners >= self.quota.max_values_per_key => {
+                return Err(MutableRejected::TooManyOwners)
+            }
+            None => 0,
+        };
 
+        if !is_ours {
+            if mutable.value.len() > self.quota.max_value_size {
+                return Err(QuotaExceeded::ValueTooLarge.into());
+            }
+            self.make_room(Keyspace::Mutable, key, released, mutable.value.len())?;
+        }
+
+        let values = self.mutable_store.entry(key).or_default();
+        values.retain(|stored| stored.hash() != hash);
+        values.push(mutable);
         Ok(())
     }
 
-    // Store a given value for a given key.
-    // Value will be overwritten.
-    pub fn store_value(&mut self, key: u32, message: String) {
-        self.kv_store.insert(key, message);
+    // Get the mutable value of a given hash.
+    pub fn get_mutable(&self, hash: &MutableHash) -> Option<&MutableValue> {
+        self.mutable_store
+            .get(&mutable_key(hash))?
+            .iter()
+            .find(|stored| stored.hash() == *hash)
     }
 
-    // Get a stored value from its key.
-    pub fn get_value(&self, key: u32) -> Option<&String> {
-        self.kv_store.get(&key)
+    // Store a given file owner for a given key. Value will be added to the
+    // list, announcing it again only refreshes its announcement. Announcements
+    // are counted against their owner, and subject to the storage quota unless
+    // they're ours.
+    pub fn store_file_peer(&mut self, key: u32, announcement: Announcement) -> Result<(), QuotaExceeded> {
+        self.store_file_peer_at(key, announcement, unix_timestamp())
     }
 
-    // Store a given peer file owner for a given key.
-    // Value will be added to the list.
-    pub fn store_file_peer(&mut self, key: u32, peer: Peer) {
+    // Store a file owner at a given time. An announcement already expired is
+    // ignored.
+    fn store_file_peer_at(
+        &mut self,
+        key: u32,
+        announcement: Announcement,
+        now: u32,
+    ) -> Result<(), QuotaExceeded> {
+        // Expired announcements must not take the room of the new ones.
+        self.expire_file_peers_at(now);
+        if is_expired(announcement.announced_at, now, self.announce_ttl) {
+            return Ok(());
+        }
+
+        if let Some(known) = self
+            .files_store
+            .get_mut(&key)
+            .and_then(|peers| peers.get_mut(&announcement.owner))
+        {
+            if announcement.announced_at > known.announced_at {
+                *known = announcement;
+            }
+            return Ok(());
+        }
+
+        let owner = announcement.owner.id;
+        if owner != self.id() {
+            let peers = self.files_store.get(&key);
+            if peers.map_or(0, HashMap::len) >= self.quota.max_peers_per_file {
+                return Err(QuotaExceeded::TooManyPeersForFile);
+            }
+            if self.nb_entries_of(owner) >= self.quota.max_entries_per_sender {
+                return Err(QuotaExceeded::TooManyEntriesForSender);
+            }
+            self.make_room(Keyspace::Files, key, 0, peer_size(&announcement.owner))?;
+        }
+
         self.files_store
             .entry(key)
-            .and_modify(|peers| {
-                peers.insert(peer.clone());
+            .or_insert_with(HashMap::new)
+            .insert(announcement.owner.clone(), announcement);
+        Ok(())
+    }
+
+    // Get a list of peers who own a given file. Expired announcements are
+    // skipped, even if they're not removed yet.
+    pub fn get_file_peers(&self, key: u32) -> Option<impl Iterator<Item = &Peer>> {
+        let now = unix_timestamp();
+        let ttl = self.announce_ttl;
+        self.files_store
+            .get(&key)
+            .filter(|peers| {
+                peers
+                    .values()
+                    .any(|announcement| !is_expired(announcement.announced_at, now, ttl))
             })
-            .or_insert_with(|| {
-                let mut set = HashSet::new();
-                set.insert(peer);
-                set
+            .map(move |peers| {
+                peers
+                    .values()
+                    .filter(move |announcement| !is_expired(announcement.announced_at, now, ttl))
+                    .map(|announcement| &announcement.owner)
+            })
+    }
+
+    // Forget the file announcements which haven't been refreshed for too long.
+    pub fn expire_file_peers(&mut self) {
+        self.expire_file_peers_at(unix_timestamp());
+    }
+
+    // Forget the file announcements expired at a given time.
+    fn expire_file_peers_at(&mut self, now: u32) {
+        let ttl = self.announce_ttl;
+        for peers in self.files_store.values_mut() {
+            peers.retain(|_, announcement| !is_expired(announcement.announced_at, now, ttl));
+        }
+        self.files_store.retain(|_, peers| !peers.is_empty());
+    }
+
+    // Store a mail for a recipient, until it fetches it. Storing again the same
+    // mail from the same sender only refreshes its timestamp. When the
+    // recipient already has too many mails, the oldest one is dropped. Mails
+    // are subject to the storage quota, like values.
+    pub fn store_mail(&mut self, recipient: u32, mail: StoredValue) -> Result<(), QuotaExceeded> {
+        self.store_mail_at(recipient, mail, unix_timestamp())
+    }
+
+    // Store a mail, expiring the ones too old at a given time.
+    fn store_mail_at(&mut self, recipient: u32, mail: StoredValue, now: u32) -> Result<(), QuotaExceeded> {
+        // Expired mails must not take the room of the new ones.
+        self.expire_mails_at(now);
+
+        if let Some(stored) = self.mailboxes.get_mut(&recipient).and_then(|mails| {
+            mails
+                .iter_mut()
+                .find(|stored| stored.publisher == mail.publisher && stored.value == mail.value)
+        }) {
+            stored.timestamp = stored.timestamp.max(mail.timestamp);
+            return Ok(());
+        }
+
+        if mail.value.len() > self.quota.max_value_size {
+            return Err(QuotaExceeded::ValueTooLarge);
+        }
+        if self.nb_entries_of(mail.publisher) >= self.quota.max_entries_per_sender {
+            return Err(QuotaExceeded::TooManyEntriesForSender);
+        }
+        let oldest = self
+            .mailboxes
+            .get(&recipient)
+            .filter(|mails| mails.len() >= self.quota.max_values_per_key)
+            .and_then(|mails| {
+                mails
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, stored)| stored.timestamp)
+                    .map(|(idx, stored)| (idx, stored.value.len()))
             });
+        let released = oldest.map_or(0, |(_, size)| size);
+        self.make_room(Keyspace::Mails, recipient, released, mail.value.len())?;
+
+        let mails = self.mailboxes.entry(recipient).or_insert_with(Vec::new);
+        if let Some((idx, _)) = oldest {
+            mails.remove(idx);
+        }
+        mails.push(mail);
+        Ok(())
     }
 
-    // Get a list of peers who own a given file.
-    pub fn get_file_peers(&self, key: u32) -> Option<impl Iterator<Item = &Peer>> {
-        self.files_store.get(&key).and_then(|peers| Some(peers.iter()))
+    // Get the mails of a recipient, the oldest first. Expired mails are
+    // dropped. The others are kept until the recipient acknowledges them.
+    pub fn get_mails(&mut self, recipient: u32) -> Vec<StoredValue> {
+        self.expire_mails();
+        let mut mails = self.mailboxes.get(&recipient).cloned().unwrap_or_default();
+        mails.sort_by_key(|mail| mail.timestamp);
+        mails
+    }
+
+    // Forget the mails a recipient acknowledged, by their id. Return how many
+    // were forgotten.
+    pub fn remove_mails(&mut self, recipient: u32, ids: &[u32]) -> usize {
+        let mails = match self.mailboxes.get_mut(&recipient) {
+            Some(mails) => mails,
+            None => return 0,
+        };
+        let nb_mails = mails.len();
+        mails.retain(|mail| !ids.contains(&mail.mail_id()));
+        let nb_removed = nb_mails - mails.len();
+        if mails.is_empty() {
+            self.mailboxes.remove(&recipient);
+        }
+        nb_removed
+    }
+
+    // Forget the mails which haven't been fetched for too long.
+    pub fn expire_mails(&mut self) {
+        self.expire_mails_at(unix_timestamp());
+    }
+
+    // Forget the mails expired at a given time.
+    fn expire_mails_at(&mut self, now: u32) {
+        let ttl = self.mail_ttl;
+        for mails in self.mailboxes.values_mut() {
+            mails.retain(|mail| !is_expired(mail.timestamp, now, ttl));
+        }
+        self.mailboxes.retain(|_, mails| !mails.is_empty());
     }
 
     // Flag that we requested a peer. A peer which is requested a lot, but never
@@ -178,6 +593,54 @@ impl DistributedHashTable {
     pub async fn peer_has_responded(&mut self, target: u32) {
         self.routing_table.peer_has_responded(target).await;
     }
+
+    // Flag that the peer sent forged data. It will be considered bad, and
+    // replaced by the next peer found.
+    pub async fn peer_has_lied(&mut self, target: u32) {
+        self.routing_table.peer_has_lied(target).await;
+    }
+
+    // Update the reputation of a peer from the outcome of a request.
+    pub async fn rate_peer(&mut self, target: u32, event: PeerEvent) {
+        self.routing_table.rate_peer(target, event).await;
+    }
+
+    // Record the round-trip time of a request made to a peer.
+    pub async fn peer_rtt_measured(&mut self, target: u32, rtt: Duration) {
+        self.routing_table.peer_rtt_measured(target, rtt).await;
+    }
+
+    // Get the smoothed round-trip time of all the peers measured, by id, in a
+    // single pass over the routing table.
+    pub async fn peer_srtts(&self) -> HashMap<u32, Duration> {
+        self.routing_table
+            .get_all_peers()
+            .await
+            .filter_map(|peer| Some((peer.id(), peer.srtt()?)))
+            .collect()
+    }
+
+    // Get the retransmission timeout of a peer, if known and measured.
+    pub async fn peer_rto(&self, target: u32) -> Option<Duration> {
+        self.routing_table
+            .get_peer(target)
+            .await
+            .and_then(|peer| peer.rto())
+    }
+
+    // Check if a peer has been banned for misbehaving.
+    pub fn is_banned(&self, target: u32) -> bool {
+        self.routing_table.is_banned(target)
+    }
+
+    // Get the id of the known peer reachable at a given address, if any.
+    pub async fn peer_id_at(&self, addr: SocketAddr) -> Option<u32> {
+        self.routing_table
+            .get_all_peers()
+            .await
+            .find(|peer| peer.has_addr(addr))
+            .map(|peer| peer.id())
+    }
 }
 
 // Private method.
@@ -186,6 +649,201 @@ impl DistributedHashTable {
     async fn add_peer_node(&mut self, peer: PeerNode) {
         self.routing_table.add_node(peer).await;
     }
+
+    // Get all values and signed file announcements which are closer to the
+    // given peer than to us, with what's needed to tell for which of them the
+    // peer is one of the closest known peers. Values keep their publisher, and
+    // announcements their owner.
+    async fn replication_for(&self, peer_id: u32) -> Replication {
+        let own_id = self.id();
+        let is_closer = |key: u32| distance(peer_id, key) < distance(own_id, key);
+        let values = self
+            .kv_store
+            .iter()
+            .filter(|(key, _)| is_closer(**key))
+            .flat_map(|(key, values)| values.iter().map(move |stored| (*key, stored.clone())))
+            .collect::<Vec<_>>();
+        let mutable_values = self
+            .mutable_store
+            .iter()
+            .filter(|(key, _)| is_closer(**key))
+            .flat_map(|(_, values)| values.iter().cloned())
+            .collect::<Vec<_>>();
+        let file_peers = self
+            .files_store
+            .iter()
+            .filter(|(crc, _)| is_closer(**crc))
+            .flat_map(|(crc, peers)| {
+                peers
+                    .values()
+                    .filter(|announcement| !announcement.signature.is_empty())
+                    .map(move |announcement| (*crc, announcement.clone()))
+            })
+            .collect::<Vec<_>>();
+
+        let mut replication = Replication {
+            values,
+            mutable_values,
+            file_peers,
+            peer_id,
+            known_peers: vec![],
+        };
+        if !replication.is_empty() {
+            replication.known_peers = self
+                .routing_table
+                .get_all_peers()
+                .await
+                .map(|peer| peer.id())
+                .collect();
+        }
+        replication
+    }
+
+    // Count how many values, file announcements and mails are owned by a
+    // sender.
+    fn nb_entries_of(&self, sender: u32) -> usize {
+        let nb_values = self
+            .kv_store
+            .values()
+            .map(|values| values.iter().filter(|stored| stored.publisher == sender).count())
+            .sum::<usize>();
+        let nb_files = self
+            .files_store
+            .values()
+            .map(|peers| peers.keys().filter(|peer| peer.id == sender).count())
+            .sum::<usize>();
+        let nb_mails = self
+            .mailboxes
+            .values()
+            .map(|mails| mails.iter().filter(|mail| mail.publisher == sender).count())
+            .sum::<usize>();
+        nb_values + nb_files + nb_mails
+    }
+
+    // Get the number of bytes used by all stored values, announcements and
+    // mails.
+    fn used_bytes(&self) -> usize {
+        let values_size = self
+            .kv_store
+            .values()
+            .flat_map(|values| values.iter())
+            .map(|stored| stored.value.len())
+            .sum::<usize>();
+        let mutable_size = self
+            .mutable_store
+            .values()
+            .flat_map(|values| values.iter())
+            .map(|mutable| mutable.value.len())
+            .sum::<usize>();
+        let files_size = self
+            .files_store
+            .values()
+            .flat_map(|peers| peers.keys())
+            .map(peer_size)
+            .sum::<usize>();
+        let mails_size = self
+            .mailboxes
+            .values()
+            .flat_map(|mails| mails.iter())
+            .map(|mail| mail.value.len())
+            .sum::<usize>();
+        values_size + mutable_size + files_size + mails_size
+    }
+
+    // Ensure there's enough room to store `needed` bytes for the given key,
+    // knowing `released` bytes will be freed by overwriting an old entry.
+    // Only entries of the same store are evicted: a key means something else
+    // from one store to another. The furthest from our id are evicted first,
+    // but only if they're further than the key we want to store. If it's not
+    // enough, nothing is evicted and the store must be refused.
+    fn make_room(
+        &mut self,
+        keyspace: Keyspace,
+        key: u32,
+        released: usize,
+        needed: usize,
+    ) -> Result<(), QuotaExceeded> {
+        let used = self.used_bytes() - released;
+        if used + needed <= self.quota.max_total_bytes {
+            return Ok(());
+        }
+
+        let own_id = self.id();
+        let key_distance = distance(key, own_id);
+        let mut candidates = self
+            .keyspace_sizes(keyspace)
+            .into_iter()
+            .filter(|(candidate, _)| distance(*candidate, own_id) > key_distance)
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|(candidate, _)| std::cmp::Reverse(distance(*candidate, own_id)));
+
+        let mut freed = 0;
+        let mut to_evict = Vec::new();
+        for (candidate, size) in candidates {
+            if used + needed - freed <= self.quota.max_total_bytes {
+                break;
+            }
+            freed += size;
+            to_evict.push(candidate);
+        }
+        if used + needed - freed > self.quota.max_total_bytes {
+            return Err(QuotaExceeded::NoRoomLeft);
+        }
+
+        for candidate in to_evict {
+            match keyspace {
+                Keyspace::Values => {
+                    self.kv_store.remove(&candidate);
+                }
+                Keyspace::Mutable => {
+                    self.mutable_store.remove(&candidate);
+                }
+                Keyspace::Files => {
+                    self.files_store.remove(&candidate);
+                }
+                Keyspace::Mails => {
+                    self.mailboxes.remove(&candidate);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Get the keys of a store, each one with the number of bytes it takes.
+    fn keyspace_sizes(&self, keyspace: Keyspace) -> Vec<(u32 /*key*/, usize /*size*/)> {
+        match keyspace {
+            Keyspace::Values => self
+                .kv_store
+                .iter()
+                .map(|(key, values)| (*key, values.iter().map(|stored| stored.value.len()).sum()))
+                .collect(),
+            Keyspace::Mutable => self
+                .mutable_store
+                .iter()
+                .map(|(key, values)| (*key, values.iter().map(|mutable| mutable.value.len()).sum()))
+                .collect(),
+            Keyspace::Files => self
+                .files_store
+                .iter()
+                .map(|(key, peers)| (*key, peers.keys().map(peer_size).sum()))
+                .collect(),
+            Keyspace::Mails => self
+                .mailboxes
+                .iter()
+                .map(|(recipient, mails)| (*recipient, mails.iter().map(|mail| mail.value.len()).sum()))
+                .collect(),
+        }
+    }
+}
+
+// Check if an announcement or a mail made at a given time is too old to be kept.
+fn is_expired(announced_at: u32, now: u32, ttl: Duration) -> bool {
+    u64::from(announced_at) + ttl.as_secs() <= u64::from(now)
+}
+
+// Approximate size taken by a file announcement, as sent on the network.
+fn peer_size(peer: &Peer) -> usize {
+    4 + 4 + peer.addr.to_string().len()
 }
 
 // Only exists for testing purpose.
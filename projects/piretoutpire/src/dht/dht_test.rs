@@ -1,5 +1,32 @@
 use super::*;
+use crate::dht::identity::generate_node_keypair;
+use ed25519_dalek::Keypair;
 use errors::AnyResult;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
+fn value(publisher: u32, timestamp: u32, value: &str) -> StoredValue {
+    StoredValue {
+        publisher,
+        timestamp,
+        value: value.to_owned(),
+        public_key: vec![],
+        signature: value.as_bytes().to_vec(),
+    }
+}
+
+fn announced(id: u32, announced_at: u32) -> Announcement {
+    let owner = Peer {
+        id,
+        addr: "127.0.0.1:4000".parse().expect("valid addr"),
+    };
+    Announcement::unsigned(owner, announced_at)
+}
+
+// Get the node keypair generated from a seed, the same for each seed.
+fn keypair(seed: u8) -> Keypair {
+    generate_node_keypair(&mut ChaCha8Rng::seed_from_u64(seed.into()), |_| true)
+}
 
 #[tokio::test]
 async fn test_add_node() -> AnyResult<()> {
@@ -14,6 +41,25 @@ async fn test_add_node() -> AnyResult<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_peer_srtts() -> AnyResult<()> {
+    let dummy_addr = "127.0.0.1:4000".parse()?;
+
+    let mut dht = DistributedHashTable::new(0);
+    for id in 1..=3 {
+        dht.add_peer_node(PeerNode::new(id, dummy_addr)).await;
+    }
+    dht.peer_rtt_measured(2, Duration::from_millis(30)).await;
+
+    // Only measured peers are given.
+    assert_eq!(
+        HashMap::from([(2, Duration::from_millis(30))]),
+        dht.peer_srtts().await
+    );
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_find_node() -> AnyResult<()> {
     let dummy_addr = "127.0.0.1:4000".parse()?;
@@ -26,7 +72,7 @@ async fn test_find_node() -> AnyResult<()> {
     // Try to find a non-existing entry. Entry will be not found...
     assert_eq!(
         Vec::<PeerNode>::new(),
-        dht.find_node(dummy_peer.clone(), 38).await.collect::<Vec<_>>()
+        dht.find_node(dummy_peer.clone(), 38).await.0
     );
     // ... but the sender will be added into the dht.
     assert_eq!(1, dht.len().await);
@@ -35,3 +81,416 @@ async fn test_find_node() -> AnyResult<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_find_node_towards_sender_family() -> AnyResult<()> {
+    let ipv4_addr = "127.0.0.1:4001".parse()?;
+    let ipv6_addr = "[::1]:4001".parse()?;
+
+    let mut dht = DistributedHashTable::new(0);
+    dht.add_node(1, ipv4_addr).await;
+    dht.add_node(1, ipv6_addr).await;
+    assert_eq!(Some(1), dht.peer_id_at(ipv6_addr).await);
+
+    // Each sender gets the address it can reach.
+    let ipv6_sender = PeerNode::new(2, "[::1]:4002".parse()?);
+    let (peers, _) = dht.find_node(ipv6_sender, 1).await;
+    assert_eq!(ipv6_addr, peers[0].addr());
+    let ipv4_sender = PeerNode::new(3, "127.0.0.1:4003".parse()?);
+    let (peers, _) = dht.find_node(ipv4_sender, 1).await;
+    assert_eq!(ipv4_addr, peers[0].addr());
+
+    // An IPv6 only node looks for peers on IPv6.
+    dht.set_own_addrs(["[::1]:4000".parse()?]);
+    let peer = dht.find_closest_peer(1).await.expect("should be there");
+    assert_eq!(ipv6_addr, peer.addr());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_store_value_quota() -> AnyResult<()> {
+    let mut dht = DistributedHashTable::new(0);
+    dht.set_storage_quota(StorageQuota {
+        max_total_bytes: 100,
+        max_value_size: 10,
+        max_entries_per_sender: 2,
+        max_peers_per_file: 2,
+        max_values_per_key: 2,
+    });
+
+    // Too large value are refused.
+    assert_eq!(
+        Err(QuotaExceeded::ValueTooLarge),
+        dht.store_value(1, value(42, 0, "0123456789A"))
+    );
+
+    // A sender can't own more than 2 entries...
+    dht.store_value(1, value(42, 0, "value"))?;
+    dht.store_value(2, value(42, 0, "value"))?;
+    assert_eq!(
+        Err(QuotaExceeded::TooManyEntriesForSender),
+        dht.store_value(3, value(42, 0, "value"))
+    );
+    // ... but can still refresh its own ones.
+    dht.store_value(2, value(42, 10, "value"))?;
+    assert_eq!(Some([value(42, 10, "value")].as_slice()), dht.get_values(2));
+
+    // Our own values are never limited.
+    dht.store_value(3, value(0, 0, "0123456789A"))?;
+    assert_eq!(Some([value(0, 0, "0123456789A")].as_slice()), dht.get_values(3));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_store_multiple_values() -> AnyResult<()> {
+    let mut dht = DistributedHashTable::new(0);
+    dht.set_storage_quota(StorageQuota {
+        max_values_per_key: 3,
+        ..StorageQuota::default()
+    });
+
+    // Values from many publishers are kept side by side.
+    dht.store_value(1, value(42, 10, "hello"))?;
+    dht.store_value(1, value(43, 20, "world"))?;
+    dht.store_value(1, value(42, 30, "again"))?;
+    assert_eq!(
+        Some(
+            [
+                value(42, 10, "hello"),
+                value(43, 20, "world"),
+                value(42, 30, "again")
+            ]
+            .as_slice()
+        ),
+        dht.get_values(1)
+    );
+
+    // The same value is only refreshed.
+    dht.store_value(1, value(43, 40, "world"))?;
+    assert_eq!(3, dht.get_values(1).map_or(0, <[_]>::len));
+
+    // When full, the oldest value is dropped.
+    dht.store_value(1, value(44, 50, "!"))?;
+    assert_eq!(
+        Some([value(43, 40, "world"), value(42, 30, "again"), value(44, 50, "!")].as_slice()),
+        dht.get_values(1)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_store_file_peer_quota() -> AnyResult<()> {
+    let mut dht = DistributedHashTable::new(0);
+    dht.set_storage_quota(StorageQuota {
+        max_total_bytes: 1000,
+        max_value_size: 10,
+        max_entries_per_sender: 2,
+        max_peers_per_file: 2,
+        max_values_per_key: 2,
+    });
+
+    let now = unix_timestamp();
+    dht.store_file_peer(1, announced(1, now))?;
+    dht.store_file_peer(1, announced(2, now))?;
+    // Announcing twice is harmless.
+    dht.store_file_peer(1, announced(2, now))?;
+    assert_eq!(
+        Err(QuotaExceeded::TooManyPeersForFile),
+        dht.store_file_peer(1, announced(3, now))
+    );
+
+    // Values and announcements count for the same owner.
+    dht.store_value(5, value(3, 0, "value"))?;
+    dht.store_file_peer(2, announced(3, now))?;
+    assert_eq!(
+        Err(QuotaExceeded::TooManyEntriesForSender),
+        dht.store_file_peer(3, announced(3, now))
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_file_peer_expiration() -> AnyResult<()> {
+    let mut dht = DistributedHashTable::new(0);
+    dht.set_announce_ttl(Duration::from_secs(100));
+
+    let now = unix_timestamp();
+    dht.store_file_peer_at(1, announced(1, now - 90), now)?;
+    dht.store_file_peer_at(1, announced(2, now - 95), now)?;
+    // Announcing again refreshes the timestamp.
+    dht.store_file_peer_at(1, announced(1, now - 50), now)?;
+    // An announcement already expired is ignored.
+    dht.store_file_peer_at(1, announced(3, now - 100), now)?;
+
+    dht.expire_file_peers_at(now + 10);
+    assert_eq!(
+        vec![&announced(1, now).owner],
+        dht.get_file_peers(1).expect("not expired").collect::<Vec<_>>()
+    );
+
+    // Once every owner expired, the file is forgotten.
+    dht.expire_file_peers_at(now + 50);
+    assert!(dht.get_file_peers(1).is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_expired_file_peers_release_quota() -> AnyResult<()> {
+    let mut dht = DistributedHashTable::new(0);
+    dht.set_announce_ttl(Duration::from_secs(100));
+    dht.set_storage_quota(StorageQuota {
+        max_total_bytes: 1000,
+        max_value_size: 10,
+        max_entries_per_sender: 10,
+        max_peers_per_file: 1,
+        max_values_per_key: 10,
+    });
+
+    dht.store_file_peer_at(1, announced(1, 1000), 1000)?;
+    assert_eq!(
+        Err(QuotaExceeded::TooManyPeersForFile),
+        dht.store_file_peer_at(1, announced(2, 1050), 1050)
+    );
+    // The first announcement expired, it leaves room for the new one.
+    dht.store_file_peer_at(1, announced(2, 1100), 1100)?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_eviction() -> AnyResult<()> {
+    let mut dht = DistributedHashTable::new(0);
+    dht.set_storage_quota(StorageQuota {
+        max_total_bytes: 20,
+        max_value_size: 10,
+        max_entries_per_sender: 10,
+        max_peers_per_file: 10,
+        max_values_per_key: 10,
+    });
+
+    dht.store_value(0b1000, value(42, 0, "0123456789"))?;
+    dht.store_value(0b0100, value(42, 0, "0123456789"))?;
+
+    // Storage is full, and the key is further than any stored one.
+    assert_eq!(
+        Err(QuotaExceeded::NoRoomLeft),
+        dht.store_value(0b10000, value(42, 0, "0123456789"))
+    );
+    assert_eq!(None, dht.get_values(0b10000));
+
+    // The key is closer to us, so the furthest key is evicted.
+    dht.store_value(0b0010, value(42, 0, "0123456789"))?;
+    assert_eq!(None, dht.get_values(0b1000));
+    assert!(dht.get_values(0b0100).is_some());
+    assert!(dht.get_values(0b0010).is_some());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_eviction_per_store() -> AnyResult<()> {
+    let mut dht = DistributedHashTable::new(0);
+    dht.set_storage_quota(StorageQuota {
+        max_total_bytes: 20,
+        max_value_size: 10,
+        max_entries_per_sender: 10,
+        max_peers_per_file: 10,
+        max_values_per_key: 10,
+    });
+
+    dht.store_mail(0b1000, value(43, unix_timestamp(), "0123456789"))?;
+    dht.store_value(0b0001, value(42, 0, "0123456789"))?;
+
+    // A mailbox uses its own keyspace, it's never evicted for a value, even if
+    // further.
+    assert_eq!(
+        Err(QuotaExceeded::NoRoomLeft),
+        dht.store_value(0b0010, value(42, 0, "0123456789"))
+    );
+    assert_eq!(1, dht.get_mails(0b1000).len());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_replication_to_new_close_node() -> AnyResult<()> {
+    let dummy_addr = "127.0.0.1:4000".parse()?;
+    let signed = Announcement::new(&keypair(1), 0b0011, dummy_addr, unix_timestamp());
+
+    let mut dht = DistributedHashTable::new(0b1000);
+    dht.store_value(0b0001, value(42, 0, "value"))?;
+    dht.store_file_peer(0b0011, signed.clone())?;
+    dht.store_file_peer(0b0011, announced(42, unix_timestamp()))?;
+
+    // This node is further than us from all keys.
+    assert!(dht.add_node(0b1100, dummy_addr).await.is_empty());
+
+    // This one is closer, so it should get the value with its publisher, and
+    // the file announcement signed by its owner. An owner only known from a
+    // lookup can't be proven, so it's not announced further.
+    let mut replication = dht.add_node(0b0000, dummy_addr).await;
+    replication.retain_closest();
+    assert_eq!(vec![(0b0001, value(42, 0, "value"))], replication.values);
+    assert!(replication.mutable_values.is_empty());
+    assert_eq!(vec![(0b0011, signed.clone())], replication.file_peers);
+    assert!(signed.has_valid_signature(0b0011));
+    assert!(!signed.has_valid_signature(0b0010));
+
+    // Already known nodes are not replicated twice.
+    assert!(dht.add_node(0b0000, dummy_addr).await.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_signed_value() {
+    let keypair = keypair(1);
+    let signed = StoredValue::new(&keypair, 42, 1000, "hello".to_owned());
+    assert!(signed.has_valid_signature(42));
+
+    // The signature binds the value to its key, and to its publisher. The
+    // timestamp is only refreshed when stored again.
+    assert!(!signed.has_valid_signature(43));
+    let mut forged = signed.clone();
+    forged.publisher += 1;
+    assert!(!forged.has_valid_signature(42));
+    let mut forged = signed.clone();
+    forged.value = "world".to_owned();
+    assert!(!forged.has_valid_signature(42));
+    let mut refreshed = signed;
+    refreshed.timestamp = 2000;
+    assert!(refreshed.has_valid_signature(42));
+    assert!(!value(42, 1000, "hello").has_valid_signature(42));
+}
+
+#[tokio::test]
+async fn test_store_mutable() -> AnyResult<()> {
+    let keypair = keypair(1);
+    let mut dht = DistributedHashTable::new(0);
+
+    let first = MutableValue::new(&keypair, "salt".to_owned(), 1, "first".to_owned());
+    let hash = first.hash();
+    dht.store_mutable(first.clone(), false)?;
+    assert_eq!(Some(&first), dht.get_mutable(&hash));
+
+    // Storing the same version again is fine, but not a different value with
+    // the same sequence number.
+    dht.store_mutable(first.clone(), false)?;
+    let forked = MutableValue::new(&keypair, "salt".to_owned(), 1, "forked".to_owned());
+    assert_eq!(
+        Err(MutableRejected::OutdatedSequence),
+        dht.store_mutable(forked, false)
+    );
+
+    // A newer version replaces the old one, which can't come back.
+    let second = MutableValue::new(&keypair, "salt".to_owned(), 2, "second".to_owned());
+    dht.store_mutable(second.clone(), false)?;
+    assert_eq!(
+        Err(MutableRejected::OutdatedSequence),
+        dht.store_mutable(first, false)
+    );
+    assert_eq!(Some(&second), dht.get_mutable(&hash));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_store_mutable_other_owner() -> AnyResult<()> {
+    let mut dht = DistributedHashTable::new(0);
+
+    dht.set_storage_quota(StorageQuota {
+        max_total_bytes: 1000,
+        max_value_size: 10,
+        max_entries_per_sender: 10,
+        max_peers_per_file: 10,
+        max_values_per_key: 2,
+    });
+
+    // Another keypair got a value at the same key first, both are kept, and
+    // each one is only found from its own hash.
+    let ours = MutableValue::new(&keypair(1), "salt".to_owned(), 2, "ours".to_owned());
+    let other = MutableValue::new(&keypair(2), "salt".to_owned(), 1, "other".to_owned());
+    dht.mutable_store.insert(ours.key(), vec![other.clone()]);
+    dht.store_mutable(ours.clone(), false)?;
+    assert_eq!(Some(&ours), dht.get_mutable(&ours.hash()));
+    assert_eq!(vec![other.clone(), ours.clone()], dht.mutable_store[&ours.key()]);
+
+    // Values already there are never evicted by newcomers.
+    dht.mutable_store
+        .insert(ours.key(), vec![other.clone(), other.clone()]);
+    assert_eq!(
+        Err(MutableRejected::TooManyOwners),
+        dht.store_mutable(ours.clone(), false)
+    );
+    assert_eq!(None, dht.get_mutable(&ours.hash()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_store_mutable_invalid_signature() -> AnyResult<()> {
+    let mut dht = DistributedHashTable::new(0);
+
+    // Someone tampered the value.
+    let mut mutable = MutableValue::new(&keypair(1), "salt".to_owned(), 1, "value".to_owned());
+    mutable.value = "evil".to_owned();
+    assert_eq!(
+        Err(MutableRejected::InvalidSignature),
+        dht.store_mutable(mutable.clone(), false)
+    );
+
+    // Someone signed it with another key.
+    let mut mutable = MutableValue::new(&keypair(2), "salt".to_owned(), 1, "value".to_owned());
+    mutable.public_key = keypair(1).public.to_bytes().to_vec();
+    assert_eq!(
+        Err(MutableRejected::InvalidSignature),
+        dht.store_mutable(mutable.clone(), false)
+    );
+    assert_eq!(None, dht.get_mutable(&mutable.hash()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mailbox() -> AnyResult<()> {
+    let mut dht = DistributedHashTable::new(0);
+    dht.set_mail_ttl(Duration::from_secs(100));
+    dht.set_storage_quota(StorageQuota {
+        max_total_bytes: 1000,
+        max_value_size: 10,
+        max_entries_per_sender: 10,
+        max_peers_per_file: 10,
+        max_values_per_key: 2,
+    });
+
+    let now = unix_timestamp();
+    dht.store_mail(1, value(2, now - 120, "expired"))?;
+    dht.store_mail(1, value(2, now - 20, "hello"))?;
+    dht.store_mail(1, value(3, now - 10, "world"))?;
+    // Sending again the same mail only refreshes it.
+    dht.store_mail(1, value(2, now - 5, "hello"))?;
+    assert_eq!(
+        Err(QuotaExceeded::ValueTooLarge),
+        dht.store_mail(1, value(2, now, "too large mail"))
+    );
+
+    // Mails are given the oldest first, until acknowledged.
+    let mails = dht.get_mails(1);
+    assert_eq!(
+        vec![value(3, now - 10, "world"), value(2, now - 5, "hello")],
+        mails
+    );
+    assert_eq!(mails, dht.get_mails(1));
+    assert_eq!(0, dht.remove_mails(2, &[mails[0].mail_id()]));
+    assert_eq!(1, dht.remove_mails(1, &[mails[0].mail_id(), 42]));
+    assert_eq!(vec![value(2, now - 5, "hello")], dht.get_mails(1));
+    assert_eq!(1, dht.remove_mails(1, &[mails[1].mail_id()]));
+    assert!(dht.get_mails(1).is_empty());
+
+    Ok(())
+}
@@ -0,0 +1,137 @@
+use crate::network::protocol::Identity;
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, SECRET_KEY_LENGTH};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+
+// Number of leading zero bits the double sha256 of a public key must have, to
+// be the key of a node. A node keypair takes about 2^ID_PUZZLE_BITS tries to
+// generate, so getting a chosen id takes about 2^(32 + ID_PUZZLE_BITS) tries
+// instead of 2^32.
+pub const ID_PUZZLE_BITS: u32 = 8;
+
+// Get the id of a node from its public key, the beginning of its sha256.
+pub fn node_id(public_key: &PublicKey) -> u32 {
+    let hash = Sha256::digest(public_key.as_bytes());
+    u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]])
+}
+
+// Check a public key solves the id puzzle, so it can be the key of a node.
+pub fn is_node_key(public_key: &PublicKey) -> bool {
+    let hash = Sha256::digest(&Sha256::digest(public_key.as_bytes()));
+    u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]).leading_zeros() >= ID_PUZZLE_BITS
+}
+
+// Generate keypairs until one can be the key of a node, and matches a given
+// condition on its public key.
+pub fn generate_node_keypair(rng: &mut impl RngCore, is_wanted: impl Fn(&PublicKey) -> bool) -> Keypair {
+    let mut bytes = [0u8; SECRET_KEY_LENGTH];
+    loop {
+        rng.fill_bytes(&mut bytes);
+        let secret = match SecretKey::from_bytes(&bytes) {
+            Ok(secret) => secret,
+            Err(_) => continue,
+        };
+        let public = PublicKey::from(&secret);
+        if is_node_key(&public) && is_wanted(&public) {
+            return Keypair { secret, public };
+        }
+    }
+}
+
+// Number of public keys of other peers kept, the most recently used ones.
+pub const MAX_PEER_KEYS: usize = 256;
+
+// Public keys of other peers, checked against their id, so they can be given to
+// the ones sending them onions. Any peer proving its id gets its key kept, so
+// only the most recently used keys are: the others are asked again if needed.
+#[derive(Debug, Default)]
+pub struct PeerKeys {
+    // The most recently used first.
+    keys: VecDeque<(u32 /*peer id*/, PublicKey)>,
+}
+
+impl PeerKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Get the key of a peer, if known, and keep it as the most recently used.
+    pub fn get(&mut self, peer_id: u32) -> Option<PublicKey> {
+        let idx = self.keys.iter().position(|(id, _)| *id == peer_id)?;
+        let entry = self.keys.remove(idx)?;
+        self.keys.push_front(entry);
+        Some(entry.1)
+    }
+
+    // Keep the key of a peer, forgetting the least recently used one when
+    // there are too many.
+    pub fn insert(&mut self, peer_id: u32, public_key: PublicKey) {
+        self.keys.retain(|(id, _)| *id != peer_id);
+        self.keys.push_front((peer_id, public_key));
+        self.keys.truncate(MAX_PEER_KEYS);
+    }
+}
+
+// Maximum difference in seconds between the timestamp of a signed request and
+// the clock of the node receiving it, past which it's taken as a replay.
+pub const MAX_REQUEST_AGE: u32 = 120;
+
+// Payload signed along a request, to bind it to its recipient and time.
+fn signed_payload(recipient: Option<u32>, timestamp: u32, request: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(1 + 4 + 4 + request.len());
+    match recipient {
+        Some(recipient) => {
+            payload.push(1);
+            payload.extend(recipient.to_be_bytes());
+        }
+        None => payload.extend([0; 5]),
+    }
+    payload.extend(timestamp.to_be_bytes());
+    payload.extend(request);
+    payload
+}
+
+impl Identity {
+    // Sign a raw request on behalf of a node, for a given recipient (if known)
+    // at a given time.
+    pub fn sign(keypair: &Keypair, recipient: Option<u32>, timestamp: u32, request: &[u8]) -> Self {
+        Self {
+            public_key: keypair.public.to_bytes().to_vec(),
+            recipient,
+            timestamp,
+            signature: keypair
+                .sign(&signed_payload(recipient, timestamp, request))
+                .to_bytes()
+                .to_vec(),
+        }
+    }
+
+    // Check the request has been signed recently enough at the given time, to
+    // be accepted.
+    pub fn is_recent(&self, now: u32) -> bool {
+        self.timestamp.abs_diff(now) <= MAX_REQUEST_AGE
+    }
+
+    // Check the raw request has been signed by the owner of the public key, and
+    // return the id of this owner.
+    pub fn verify(&self, request: &[u8]) -> Option<u32> {
+        let public_key = match PublicKey::from_bytes(&self.public_key) {
+            Ok(public_key) => public_key,
+            Err(_) => return None,
+        };
+        let signature = match Signature::from_bytes(&self.signature) {
+            Ok(signature) => signature,
+            Err(_) => return None,
+        };
+        let payload = signed_payload(self.recipient, self.timestamp, request);
+        match public_key.verify_strict(&payload, &signature) {
+            Ok(()) if is_node_key(&public_key) => Some(node_id(&public_key)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+#[path = "identity_test.rs"]
+mod identity_test;
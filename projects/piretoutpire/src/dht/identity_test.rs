@@ -0,0 +1,20 @@
+use super::*;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
+#[test]
+fn test_peer_keys_bounded() {
+    let public_key = generate_node_keypair(&mut ChaCha8Rng::seed_from_u64(1), |_| true).public;
+    let mut keys = PeerKeys::new();
+    for peer_id in 0..MAX_PEER_KEYS as u32 {
+        keys.insert(peer_id, public_key);
+    }
+    // Using the oldest key makes it the most recent one.
+    assert_eq!(Some(public_key), keys.get(0));
+
+    // So the next one is forgotten when a new key comes.
+    keys.insert(MAX_PEER_KEYS as u32, public_key);
+    assert_eq!(Some(public_key), keys.get(0));
+    assert_eq!(None, keys.get(1));
+    assert_eq!(Some(public_key), keys.get(MAX_PEER_KEYS as u32));
+}
@@ -0,0 +1,21 @@
+use sha2::{Digest, Sha256};
+
+// Hash of the content of an immutable value, its sha256. Anyone can then check
+// a value found on the dht really belongs to it.
+pub type ImmutableHash = [u8; 32];
+
+// Get the hash of an immutable value.
+pub fn immutable_hash(value: &str) -> ImmutableHash {
+    Sha256::digest(value.as_bytes()).into()
+}
+
+// Get the key where an immutable value is stored, the beginning of its hash.
+// The whole hash is still checked on retrieval.
+pub fn immutable_key(hash: &ImmutableHash) -> u32 {
+    u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]])
+}
+
+// Check a value really has the given immutable hash.
+pub fn is_immutable_value_of(hash: &ImmutableHash, value: &str) -> bool {
+    immutable_hash(value) == *hash
+}
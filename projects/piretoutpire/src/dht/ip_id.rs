@@ -0,0 +1,111 @@
+use super::identity::{generate_node_keypair, node_id};
+use ed25519_dalek::Keypair;
+use std::{fmt::Display, net::IpAddr, str::FromStr};
+
+// Number of bits of an id derived from the IP address of its owner.
+pub const IP_ID_PREFIX_BITS: u32 = 12;
+
+// Max number of routing table entries sharing the same IP address.
+pub const MAX_PEERS_PER_IP: usize = 3;
+
+// Parts of an IP address used to derive an id, as BEP42 does. Only a few bits
+// of the lowest bytes are kept, so one owning a whole subnet gets few ids.
+const IPV4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+const IPV6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+// What to do with peers whose id doesn't match their IP address.
+// As described here: https://www.bittorrent.org/beps/bep_0042.html
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum IpIdPolicy {
+    // Ids are not checked.
+    Disabled,
+    // Mismatching peers are only kept in the recent peers cache, and never take
+    // a place in the routing table buckets.
+    Deprioritise,
+    // Mismatching peers are refused.
+    Enforce,
+}
+
+impl Default for IpIdPolicy {
+    fn default() -> Self {
+        Self::Disabled
+    }
+}
+
+impl FromStr for IpIdPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "disabled" => Ok(Self::Disabled),
+            "deprioritise" => Ok(Self::Deprioritise),
+            "enforce" => Ok(Self::Enforce),
+            _ => Err(format!(
+                "unknown policy {}, expected disabled, deprioritise or enforce",
+                value
+            )),
+        }
+    }
+}
+
+impl Display for IpIdPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Disabled => write!(f, "disabled"),
+            Self::Deprioritise => write!(f, "deprioritise"),
+            Self::Enforce => write!(f, "enforce"),
+        }
+    }
+}
+
+// Get the expected highest bits of an id for a given IP address, and a random
+// part r (0 to 7), which are the lowest bits of the id.
+pub fn ip_id_prefix(ip: IpAddr, r: u8) -> u32 {
+    let mut bytes = match ip {
+        IpAddr::V4(ip) => ip
+            .octets()
+            .iter()
+            .zip(IPV4_MASK)
+            .map(|(byte, mask)| byte & mask)
+            .collect::<Vec<_>>(),
+        IpAddr::V6(ip) => ip
+            .octets()
+            .iter()
+            .zip(IPV6_MASK)
+            .map(|(byte, mask)| byte & mask)
+            .collect::<Vec<_>>(),
+    };
+    bytes[0] |= (r & 0x7) << 5;
+    crc32fast::hash(&bytes) >> (32 - IP_ID_PREFIX_BITS)
+}
+
+// Local addresses don't tell anything about where a peer is, ids are not
+// checked for them.
+pub fn is_exempt_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified(),
+        IpAddr::V6(ip) => {
+            let first_segment = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || (first_segment & 0xfe00) == 0xfc00 // Unique local
+                || (first_segment & 0xffc0) == 0xfe80 // Link local
+        }
+    }
+}
+
+// Check an id has been derived from the IP address of its owner.
+pub fn is_id_valid_for_ip(id: u32, ip: IpAddr) -> bool {
+    if is_exempt_ip(ip) {
+        return true;
+    }
+    id >> (32 - IP_ID_PREFIX_BITS) == ip_id_prefix(ip, (id & 0x7) as u8)
+}
+
+// Generate node keypairs until the id derived from one of them matches the IP
+// address. Takes about 2^(IP_ID_PREFIX_BITS + ID_PUZZLE_BITS) tries.
+pub fn generate_keypair_for_ip(ip: IpAddr) -> Keypair {
+    generate_node_keypair(&mut rand::thread_rng(), |public| {
+        is_id_valid_for_ip(node_id(public), ip)
+    })
+}
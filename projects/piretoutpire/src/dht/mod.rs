@@ -1,4 +1,13 @@
+pub mod announcement;
+pub mod bucket_array;
 pub mod bucket_tree;
 pub mod dht;
+pub mod identity;
+pub mod immutable;
+pub mod ip_id;
+pub mod mutable;
 pub mod peer_node;
+pub mod quota;
 pub mod routing_table;
+pub mod stored_value;
+pub mod token;
@@ -0,0 +1,107 @@
+use super::quota::QuotaExceeded;
+use crate::{
+    network::protocol::MutableValue,
+    utils::{string_to_u8_array, u32_to_u8_array},
+};
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer};
+use sha2::{Digest, Sha256};
+use std::fmt::Display;
+
+// Hash identifying a mutable value, the sha256 of its owner public key and its
+// salt. Many owners can land on the same key, but never on the same hash.
+pub type MutableHash = [u8; 32];
+
+// Get the hash of a mutable value, from its owner public key and its salt.
+pub fn mutable_hash(public_key: &[u8], salt: &str) -> MutableHash {
+    Sha256::new()
+        .chain(public_key)
+        .chain(salt.as_bytes())
+        .finalize()
+        .into()
+}
+
+// Get the key where a mutable value is stored, the beginning of its hash. The
+// whole hash is still checked, when storing and when fetching it.
+pub fn mutable_key(hash: &MutableHash) -> u32 {
+    u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]])
+}
+
+// What the owner of a mutable value signs: the salt, the sequence number and
+// the value itself.
+fn signed_payload(salt: &str, seq: u32, value: &str) -> Vec<u8> {
+    let mut res = string_to_u8_array(salt.to_owned());
+    res.extend(u32_to_u8_array(seq));
+    res.extend(string_to_u8_array(value.to_owned()));
+    res
+}
+
+impl MutableValue {
+    // Create a new version of a mutable value, signed by its owner.
+    pub fn new(keypair: &Keypair, salt: String, seq: u32, value: String) -> Self {
+        let signature = keypair.sign(&signed_payload(&salt, seq, &value));
+        Self {
+            public_key: keypair.public.to_bytes().to_vec(),
+            salt,
+            seq,
+            value,
+            signature: signature.to_bytes().to_vec(),
+        }
+    }
+
+    // Get the hash identifying this value.
+    pub fn hash(&self) -> MutableHash {
+        mutable_hash(&self.public_key, &self.salt)
+    }
+
+    // Get the key where this value is stored.
+    pub fn key(&self) -> u32 {
+        mutable_key(&self.hash())
+    }
+
+    // Check the value has been signed by the owner of the public key.
+    pub fn has_valid_signature(&self) -> bool {
+        let public_key = match PublicKey::from_bytes(&self.public_key) {
+            Ok(public_key) => public_key,
+            Err(_) => return false,
+        };
+        let signature = match Signature::from_bytes(&self.signature) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        public_key
+            .verify_strict(&signed_payload(&self.salt, self.seq, &self.value), &signature)
+            .is_ok()
+    }
+}
+
+// Reason why a mutable value has been refused.
+#[derive(Debug, Eq, PartialEq)]
+pub enum MutableRejected {
+    // The signature doesn't match the public key.
+    InvalidSignature,
+    // The key already holds too many values of other owners.
+    TooManyOwners,
+    // A more recent version is already stored.
+    OutdatedSequence,
+    // The storage quota doesn't allow it.
+    Quota(QuotaExceeded),
+}
+
+impl Display for MutableRejected {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MutableRejected::InvalidSignature => write!(fmt, "signature is invalid"),
+            MutableRejected::TooManyOwners => write!(fmt, "the key holds too many values of other owners"),
+            MutableRejected::OutdatedSequence => write!(fmt, "a more recent version is stored"),
+            MutableRejected::Quota(quota) => write!(fmt, "{}", quota),
+        }
+    }
+}
+
+impl std::error::Error for MutableRejected {}
+
+impl From<QuotaExceeded> for MutableRejected {
+    fn from(value: QuotaExceeded) -> Self {
+        MutableRejected::Quota(value)
+    }
+}
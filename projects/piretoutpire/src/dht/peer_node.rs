@@ -11,6 +11,9 @@ pub struct PeerNode {
     id: u32,
     // Network address of the peer
     addr: SocketAddr,
+    // Addresses of the peer on the other IP families, when it's dual-stack
+    #[serde(default)]
+    other_addrs: Vec<SocketAddr>,
     // Last time a request was sent
     #[serde(skip)]
     last_request: Option<Instant>,
@@ -20,6 +23,107 @@ pub struct PeerNode {
     // Number of queries made in a row
     #[serde(skip)]
     nb_successive_try: usize,
+    // Whether the peer already sent us forged data
+    #[serde(skip)]
+    has_lied: bool,
+    // How well the peer behaved so far, see PeerEvent
+    #[serde(default)]
+    reputation: i32,
+    // Smoothed round-trip time of our requests, if any was measured
+    #[serde(default)]
+    srtt: Option<Duration>,
+    // Variation of the round-trip time
+    #[serde(default)]
+    rttvar: Duration,
+    // Number of timeouts in a row since the last round-trip time measured,
+    // each one doubling the retransmission timeout
+    #[serde(skip)]
+    rto_backoff: u32,
+}
+
+// IP families a node can reach, the ones it has an address on.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct IpFamilies {
+    ipv4: bool,
+    ipv6: bool,
+}
+
+impl Default for IpFamilies {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl IpFamilies {
+    // Both IPv4 and IPv6.
+    pub const ALL: Self = Self {
+        ipv4: true,
+        ipv6: true,
+    };
+
+    // Get the families of some addresses.
+    pub fn of(addrs: impl IntoIterator<Item = SocketAddr>) -> Self {
+        addrs.into_iter().fold(
+            Self {
+                ipv4: false,
+                ipv6: false,
+            },
+            |families, addr| Self {
+                ipv4: families.ipv4 || addr.is_ipv4(),
+                ipv6: families.ipv6 || addr.is_ipv6(),
+            },
+        )
+    }
+
+    // Check if an address is of one of these families.
+    pub fn contains(&self, addr: SocketAddr) -> bool {
+        match addr {
+            SocketAddr::V4(_) => self.ipv4,
+            SocketAddr::V6(_) => self.ipv6,
+        }
+    }
+}
+
+// Highest reputation a peer can get, so a peer behaving well for a long time
+// can still be banned quickly if it starts misbehaving.
+pub const MAX_REPUTATION: i32 = 20;
+
+// Under this reputation, a peer is avoided as much as possible.
+pub const LOW_REPUTATION: i32 = -10;
+
+// Under this reputation, a peer is banned.
+pub const BAN_REPUTATION: i32 = -40;
+
+// Shortest retransmission timeout, as recommended by RFC 6298, so a peer
+// answering fast once isn't given up on at its first hiccup.
+pub const MIN_RTO: Duration = Duration::from_secs(1);
+
+// Max number of times the retransmission timeout is doubled.
+const MAX_RTO_BACKOFF: u32 = 6;
+
+// Outcome of a request made to a peer, used to update its reputation.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PeerEvent {
+    // The peer answered correctly.
+    Success,
+    // The peer couldn't be reached, or didn't answer in time.
+    Timeout,
+    // The peer answered something malformed or unexpected.
+    ProtocolError,
+    // The peer sent data failing verification (forged value, bad signature).
+    IntegrityFailure,
+}
+
+impl PeerEvent {
+    // How much the reputation of a peer changes on this event.
+    pub fn score(&self) -> i32 {
+        match self {
+            PeerEvent::Success => 1,
+            PeerEvent::Timeout => -2,
+            PeerEvent::ProtocolError => -5,
+            PeerEvent::IntegrityFailure => -20,
+        }
+    }
 }
 
 // Status of a peer.
@@ -43,9 +147,15 @@ impl PeerNode {
         Self {
             id,
             addr,
+            other_addrs: Vec::new(),
             last_request: None,
             last_response: None,
             nb_successive_try: 0,
+            has_lied: false,
+            reputation: 0,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto_backoff: 0,
         }
     }
 
@@ -64,8 +174,46 @@ impl PeerNode {
         self.addr
     }
 
+    // Get all the addresses of the peer, the main one first.
+    pub fn addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        std::iter::once(self.addr).chain(self.other_addrs.iter().copied())
+    }
+
+    // Check if the peer can be reached at a given address.
+    pub fn has_addr(&self, addr: SocketAddr) -> bool {
+        self.addrs().any(|known| known == addr)
+    }
+
+    // Add an address of the peer, only if none is known yet on its family: the
+    // first one seen is kept. Return true if it has been added.
+    pub fn add_addr(&mut self, addr: SocketAddr) -> bool {
+        if self.addrs().any(|known| known.is_ipv4() == addr.is_ipv4()) {
+            return false;
+        }
+        self.other_addrs.push(addr);
+        true
+    }
+
+    // Get the first address of the peer on one of the given families, if any.
+    pub fn addr_in(&self, families: IpFamilies) -> Option<SocketAddr> {
+        self.addrs().find(|addr| families.contains(*addr))
+    }
+
+    // Make the first address on one of the given families the main one, so
+    // it's the one given to whoever asks for this peer.
+    pub fn prefer_addr_in(&mut self, families: IpFamilies) {
+        if let Some(addr) = self.addr_in(families).filter(|addr| *addr != self.addr) {
+            self.other_addrs.retain(|other| *other != addr);
+            self.other_addrs.insert(0, self.addr);
+            self.addr = addr;
+        }
+    }
+
     // Return the status of the node by looking on recent events.
     pub fn status(&self) -> PeerStatus {
+        if self.has_lied || self.has_low_reputation() {
+            return PeerStatus::Bad;
+        }
         let last_request = match self.last_request {
             Some(last_req) => last_req,
             None => return PeerStatus::Unknown,
@@ -85,6 +233,78 @@ impl PeerNode {
         self.nb_successive_try += 1;
     }
 
+    // Flag that the peer sent us forged data. It won't be trusted anymore.
+    pub fn flag_as_liar(&mut self) {
+        self.has_lied = true;
+    }
+
+    // Get the reputation of the peer.
+    pub fn reputation(&self) -> i32 {
+        self.reputation
+    }
+
+    // Update the reputation of the peer from the outcome of a request.
+    pub fn rate(&mut self, event: PeerEvent) {
+        if event == PeerEvent::Timeout {
+            self.rto_backoff = self.rto_backoff.saturating_add(1);
+        }
+        let reputation = (self.reputation + event.score()).min(MAX_REPUTATION);
+        self.reputation = match event {
+            // An honest peer can be offline for a while, so timeouts only make
+            // it avoided, they never get it banned.
+            PeerEvent::Timeout => reputation.max(self.reputation.min(LOW_REPUTATION)),
+            _ => reputation,
+        };
+    }
+
+    // Check if the peer should be avoided.
+    pub fn has_low_reputation(&self) -> bool {
+        self.reputation <= LOW_REPUTATION
+    }
+
+    // Get the smoothed round-trip time of our requests to the peer.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    // Get the variation of the round-trip time.
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+
+    // Get how long to wait for an answer of the peer before considering it
+    // lost, as TCP does (RFC 6298): the smoothed round-trip time, plus 4 times
+    // its variation, at least 1 sec, and doubled after each timeout in a row.
+    pub fn rto(&self) -> Option<Duration> {
+        self.srtt.map(|srtt| {
+            (srtt + self.rttvar * 4)
+                .max(MIN_RTO)
+                .saturating_mul(1 << self.rto_backoff.min(MAX_RTO_BACKOFF))
+        })
+    }
+
+    // Update the round-trip time with a new measure, as TCP does (RFC 6298).
+    // The peer answered, the retransmission timeout isn't backed off anymore.
+    pub fn update_rtt(&mut self, rtt: Duration) {
+        self.rto_backoff = 0;
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+    }
+
+    // Check if the peer misbehaved too much to be kept.
+    pub fn is_banned(&self) -> bool {
+        self.reputation <= BAN_REPUTATION
+    }
+
     // Update the last response made
     pub fn update_last_response(&mut self) {
         let now = Instant::now();
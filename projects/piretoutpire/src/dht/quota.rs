@@ -0,0 +1,62 @@
+use std::fmt::Display;
+
+pub const DEFAULT_MAX_STORAGE_BYTES: usize = 16 * 1024 * 1024; // 16 Mo
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 64 * 1024; // 64 Ko
+pub const DEFAULT_MAX_ENTRIES_PER_SENDER: usize = 256;
+pub const DEFAULT_MAX_PEERS_PER_FILE: usize = 64;
+pub const DEFAULT_MAX_VALUES_PER_KEY: usize = 16;
+
+// Limits applied on what other peers are allowed to store on us. Without them,
+// any peer could make our value and file stores grow indefinitely.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StorageQuota {
+    // Max size, in bytes, of all stored values, file announcements and mails.
+    pub max_total_bytes: usize,
+    // Max size, in bytes, of a single value or mail.
+    pub max_value_size: usize,
+    // Max number of entries (values + file announcements + mails) owned by a
+    // sender.
+    pub max_entries_per_sender: usize,
+    // Max number of peers kept for a given file crc.
+    pub max_peers_per_file: usize,
+    // Max number of values kept for a given key, the oldest are dropped.
+    pub max_values_per_key: usize,
+}
+
+impl Default for StorageQuota {
+    fn default() -> Self {
+        Self {
+            max_total_bytes: DEFAULT_MAX_STORAGE_BYTES,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+            max_entries_per_sender: DEFAULT_MAX_ENTRIES_PER_SENDER,
+            max_peers_per_file: DEFAULT_MAX_PEERS_PER_FILE,
+            max_values_per_key: DEFAULT_MAX_VALUES_PER_KEY,
+        }
+    }
+}
+
+// Reason why a value or a file announcement has been refused.
+#[derive(Debug, Eq, PartialEq)]
+pub enum QuotaExceeded {
+    // The value alone is bigger than the max value size.
+    ValueTooLarge,
+    // The sender already owns too many entries.
+    TooManyEntriesForSender,
+    // Too many peers already own this file.
+    TooManyPeersForFile,
+    // Nothing further from our id than this key can be evicted.
+    NoRoomLeft,
+}
+
+impl Display for QuotaExceeded {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuotaExceeded::ValueTooLarge => write!(fmt, "value is too large"),
+            QuotaExceeded::TooManyEntriesForSender => write!(fmt, "sender owns too many entries"),
+            QuotaExceeded::TooManyPeersForFile => write!(fmt, "file has too many peers"),
+            QuotaExceeded::NoRoomLeft => write!(fmt, "no room left"),
+        }
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
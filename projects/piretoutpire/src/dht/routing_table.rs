@@ -1,9 +1,14 @@
 use super::{
-    bucket_tree::{BucketTree, InsertResult},
-    peer_node::PeerNode,
+    bucket_array::BucketArray,
+    bucket_tree::InsertResult,
+    ip_id::{is_exempt_ip, is_id_valid_for_ip, IpIdPolicy, MAX_PEERS_PER_IP},
+    peer_node::{IpFamilies, PeerEvent, PeerNode},
+};
+use crate::utils::{distance, relevancy};
+use std::{
+    collections::{HashSet, VecDeque},
+    time::Duration,
 };
-use crate::utils::distance;
-use std::collections::VecDeque;
 
 // Holds information about other nodes.
 // This routing table represents part of the global distributed nodes. Only the
@@ -11,9 +16,11 @@ use std::collections::VecDeque;
 #[derive(Debug)]
 pub struct RoutingTable {
     id: u32,
-    bucket_tree: BucketTree,
+    buckets: BucketArray,
     recent_peers_cache_enabled: bool,
     latest_too_far_peers: VecDeque<PeerNode>,
+    ip_id_policy: IpIdPolicy,
+    banned_peers: HashSet<u32>,
 }
 
 impl RoutingTable {
@@ -22,12 +29,19 @@ impl RoutingTable {
     pub fn new(id: u32) -> Self {
         Self {
             id,
-            bucket_tree: BucketTree::new(),
+            buckets: BucketArray::new(),
             recent_peers_cache_enabled: true,
             latest_too_far_peers: VecDeque::new(),
+            ip_id_policy: IpIdPolicy::Disabled,
+            banned_peers: HashSet::new(),
         }
     }
 
+    // Get the id of the owner of this table.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
     // Enable the recent peer cache. On small network, with non uniform id
     /// distribution, caching peers could be hard. The "recent" peers cache is
     /// used on top of the routing table, to help finding peers. On big network,
@@ -36,6 +50,12 @@ impl RoutingTable {
         self.recent_peers_cache_enabled = value;
     }
 
+    // Set what to do with peers whose id doesn't match their IP address. When
+    // enabled, a single IP address also gets a limited number of entries.
+    pub fn set_ip_id_policy(&mut self, policy: IpIdPolicy) {
+        self.ip_id_policy = policy;
+    }
+
     // Get the peers lru cache
     pub fn get_recent_peers_cache(&self) -> impl Iterator<Item = &PeerNode> {
         self.latest_too_far_peers.iter()
@@ -43,37 +63,68 @@ impl RoutingTable {
 
     // Clear the routing table.
     pub async fn clear(&mut self) {
-        self.bucket_tree = BucketTree::new();
-    }
-
-    // Add a new node inside the routing table, store as a distance.
-    pub async fn add_node(&mut self, mut peer: PeerNode) {
-        peer.set_id(distance(peer.id(), self.id));
-        if let InsertResult::NoRoom = self.bucket_tree.add_peer_node(peer.clone()).await {
-            if self.recent_peers_cache_enabled {
-                // Push an existing node to the front, or add it.
-                if let Some(idx) = self
-                    .latest_too_far_peers
-                    .iter()
-                    .position(|lru| lru.id() == peer.id())
-                {
-                    self.latest_too_far_peers.remove(idx);
-                }
-                self.latest_too_far_peers.push_front(peer);
+        self.buckets = BucketArray::new();
+    }
 
-                // Prevent lru to grow too much.
-                if self.latest_too_far_peers.len() > 100 {
-                    self.latest_too_far_peers.pop_back();
+    // Add a new node inside the routing table, store as a distance. Banned
+    // peers are refused. A known node seen on another IP family gets this new
+    // address too.
+    // Return true if the node wasn't known before.
+    pub async fn add_node(&mut self, peer: PeerNode) -> bool {
+        self.insert_node(peer, true)
+    }
+
+    // Same as add_node, for a peer we relay: it's seen at our own IP address,
+    // which isn't checked. We know who it is, as it asked to be relayed with a
+    // signed request.
+    pub async fn add_relayed_node(&mut self, peer: PeerNode) -> bool {
+        self.insert_node(peer, false)
+    }
+
+    fn insert_node(&mut self, peer: PeerNode, check_ip: bool) -> bool {
+        if peer.is_banned() {
+            self.banned_peers.insert(peer.id());
+        }
+        if self.banned_peers.contains(&peer.id()) {
+            return false;
+        }
+
+        // An id can only be derived from one IP address, so a dual-stack peer
+        // is checked on the address it's first seen at, and its address on the
+        // other family is taken as is.
+        let bucket_id = distance(peer.id(), self.id);
+        if self.buckets.contains(bucket_id) {
+            self.buckets.peer_addr_found(bucket_id, peer.addr());
+            return false;
+        }
+
+        let ip = peer.addr().ip();
+        if check_ip && self.ip_id_policy != IpIdPolicy::Disabled && !is_exempt_ip(ip) {
+            if self.count_other_peers_with_ip(&peer) >= MAX_PEERS_PER_IP {
+                return false;
+            }
+            match self.ip_id_policy {
+                IpIdPolicy::Enforce if !is_id_valid_for_ip(peer.id(), ip) => return false,
+                IpIdPolicy::Deprioritise if !is_id_valid_for_ip(peer.id(), ip) => {
+                    return self.add_recent_peer(peer)
                 }
+                _ => {}
             }
         }
+
+        let mut bucket_peer = peer.clone();
+        bucket_peer.set_id(bucket_id);
+        match self.buckets.add_peer_node(bucket_peer) {
+            InsertResult::Succeed => true,
+            InsertResult::AlreadyExists => false,
+            InsertResult::NoRoom => self.add_recent_peer(peer),
+        }
     }
 
     // Get all peers in this routing table.
     pub async fn get_all_peers(&self) -> impl Iterator<Item = PeerNode> + '_ {
-        self.bucket_tree
+        self.buckets
             .get_all_peers()
-            .await
             .map(|mut peer| {
                 peer.set_id(distance(peer.id(), self.id));
                 peer
@@ -81,22 +132,161 @@ impl RoutingTable {
             .chain(self.latest_too_far_peers.iter().map(Clone::clone))
     }
 
-    // Get the closest peers from a given target.
+    // Get the closest peers from a given target. Peers with a low reputation
+    // come last, they're only used when there's nobody else. Among peers at a
+    // similar distance, the fastest to answer come first.
     pub async fn get_closest_peers_from(&self, target: u32, nb: usize) -> impl Iterator<Item = PeerNode> {
-        let mut peers = self.get_all_peers().await.collect::<Vec<_>>();
-        peers.sort_by_key(|peer| distance(peer.id(), target));
-        peers.into_iter().take(nb)
+        self.get_closest_peers_in(target, nb, IpFamilies::ALL).await
+    }
+
+    // Same as get_closest_peers_from, but peers without any address on the
+    // given IP families come last too, just before the ones with a low
+    // reputation. The main address of each peer is one of these families, when
+    // it has one.
+    pub async fn get_closest_peers_in(
+        &self,
+        target: u32,
+        nb: usize,
+        families: IpFamilies,
+    ) -> impl Iterator<Item = PeerNode> {
+        let mut peers = self
+            .buckets
+            .search_closest_peers_to(distance(target, self.id), nb, families)
+            .into_iter()
+            .map(|mut peer| {
+                peer.set_id(distance(peer.id(), self.id));
+                peer
+            })
+            .chain(self.latest_too_far_peers.iter().map(Clone::clone))
+            .collect::<Vec<_>>();
+        peers.sort_by_key(|peer| {
+            (
+                peer.has_low_reputation(),
+                peer.addr_in(families).is_none(),
+                relevancy(peer.id(), peer.srtt(), target),
+            )
+        });
+        peers.into_iter().take(nb).map(move |mut peer| {
+            peer.prefer_addr_in(families);
+            peer
+        })
+    }
+
+    // Get a known peer.
+    pub async fn get_peer(&self, target: u32) -> Option<PeerNode> {
+        self.get_all_peers().await.find(|peer| peer.id() == target)
+    }
+
+    // Get the peers banned for misbehaving.
+    pub fn get_banned_peers(&self) -> impl Iterator<Item = &u32> {
+        self.banned_peers.iter()
+    }
+
+    // Check if a peer has been banned for misbehaving.
+    pub fn is_banned(&self, target: u32) -> bool {
+        self.banned_peers.contains(&target)
+    }
+
+    // Ban a peer, it's forgotten and won't be added anymore.
+    pub async fn ban_peer(&mut self, target: u32) {
+        self.buckets.remove_peer(distance(target, self.id));
+        self.latest_too_far_peers.retain(|peer| peer.id() != target);
+        self.banned_peers.insert(target);
     }
 
     // Flag that we requested a peer. A peer which is requested a lot, but never
-    // answer will be considred bad.
+    // answer will be considred bad. Peers are stored as a distance.
     pub async fn peer_was_requested(&mut self, target: u32) {
-        self.bucket_tree.peer_was_requested(target).await;
+        self.buckets.peer_was_requested(distance(target, self.id));
+        self.modify_recent_peer(target, PeerNode::update_last_request);
     }
 
     // Flag that the peer correctly responded, hence is alive.
     pub async fn peer_has_responded(&mut self, target: u32) {
-        self.bucket_tree.peer_has_responded(target).await;
+        self.buckets.peer_has_responded(distance(target, self.id));
+        self.modify_recent_peer(target, PeerNode::update_last_response);
+    }
+
+    // Flag that the peer sent forged data.
+    pub async fn peer_has_lied(&mut self, target: u32) {
+        self.buckets.peer_has_lied(distance(target, self.id));
+        self.modify_recent_peer(target, PeerNode::flag_as_liar);
+        self.rate_peer(target, PeerEvent::IntegrityFailure).await;
+    }
+
+    // Record the round-trip time of a request made to a peer.
+    pub async fn peer_rtt_measured(&mut self, target: u32, rtt: Duration) {
+        self.buckets.peer_rtt_measured(distance(target, self.id), rtt);
+        self.modify_recent_peer(target, |peer| peer.update_rtt(rtt));
+    }
+
+    // Update the reputation of a peer from the outcome of a request. A peer
+    // misbehaving too much is banned.
+    pub async fn rate_peer(&mut self, target: u32, event: PeerEvent) {
+        self.buckets.rate_peer(distance(target, self.id), event);
+        self.modify_recent_peer(target, |peer| peer.rate(event));
+
+        let is_banned = self.get_peer(target).await.map_or(false, |peer| peer.is_banned());
+        if is_banned {
+            self.ban_peer(target).await;
+        }
+    }
+}
+
+// Private method.
+impl RoutingTable {
+    // Add a peer into the recent peers cache, if enabled. Return true if the
+    // node wasn't known before.
+    fn add_recent_peer(&mut self, peer: PeerNode) -> bool {
+        if !self.recent_peers_cache_enabled {
+            return false;
+        }
+
+        // Push an existing node to the front, keeping what we know about it, or
+        // add it.
+        let (peer, already_known) = match self
+            .latest_too_far_peers
+            .iter()
+            .position(|lru| lru.id() == peer.id())
+        {
+            Some(idx) => match self.latest_too_far_peers.remove(idx) {
+                Some(mut known) => {
+                    known.add_addr(peer.addr());
+                    (known, true)
+                }
+                None => (peer, false),
+            },
+            None => (peer, false),
+        };
+        self.latest_too_far_peers.push_front(peer);
+
+        // Prevent lru to grow too much.
+        if self.latest_too_far_peers.len() > 100 {
+            self.latest_too_far_peers.pop_back();
+        }
+        !already_known
+    }
+
+    // Apply a patch on a peer of the recent peers cache, if it's there.
+    fn modify_recent_peer(&mut self, target: u32, patch: impl Fn(&mut PeerNode)) {
+        if let Some(peer) = self
+            .latest_too_far_peers
+            .iter_mut()
+            .find(|peer| peer.id() == target)
+        {
+            patch(peer);
+        }
+    }
+
+    // Count the routing table entries sharing the IP address of a peer,
+    // without counting the peer itself.
+    fn count_other_peers_with_ip(&self, peer: &PeerNode) -> usize {
+        self.buckets
+            .get_all_peers()
+            .filter(|other| {
+                distance(other.id(), self.id) != peer.id() && other.addr().ip() == peer.addr().ip()
+            })
+            .count()
     }
 }
 
@@ -1,5 +1,10 @@
 use super::*;
+use crate::dht::{
+    ip_id::{ip_id_prefix, IP_ID_PREFIX_BITS},
+    peer_node::{IpFamilies, PeerStatus, MIN_RTO},
+};
 use errors::AnyResult;
+use std::time::Duration;
 
 #[tokio::test]
 async fn test_init_table() -> AnyResult<()> {
@@ -37,3 +42,251 @@ async fn test_init_table() -> AnyResult<()> {
 
     Ok(())
 }
+
+// Get an id matching an IP address, with r as random part.
+fn id_for_ip(ip: &str, r: u8) -> u32 {
+    let ip = ip.parse().expect("invalid ip");
+    (ip_id_prefix(ip, r) << (32 - IP_ID_PREFIX_BITS)) | r as u32
+}
+
+#[tokio::test]
+async fn test_ip_id_policy() -> AnyResult<()> {
+    let valid_id = id_for_ip("1.2.3.4", 5);
+    let invalid_id = valid_id ^ 0x8000_0000;
+    assert!(is_id_valid_for_ip(valid_id, "1.2.3.4".parse()?));
+    assert!(!is_id_valid_for_ip(invalid_id, "1.2.3.4".parse()?));
+    // Only the masked bits of the address matter.
+    assert!(is_id_valid_for_ip(valid_id, "65.2.3.4".parse()?));
+    // Local addresses are exempted.
+    assert!(is_id_valid_for_ip(invalid_id, "127.0.0.1".parse()?));
+    assert!(is_id_valid_for_ip(invalid_id, "192.168.1.1".parse()?));
+
+    // Not checked by default.
+    let mut rt = RoutingTable::new(0);
+    assert!(
+        rt.add_node(PeerNode::new(invalid_id, "1.2.3.4:4000".parse()?))
+            .await
+    );
+
+    // Refused.
+    let mut rt = RoutingTable::new(0);
+    rt.set_ip_id_policy(IpIdPolicy::Enforce);
+    assert!(
+        !rt.add_node(PeerNode::new(invalid_id, "1.2.3.4:4000".parse()?))
+            .await
+    );
+    assert!(
+        rt.add_node(PeerNode::new(valid_id, "1.2.3.4:4000".parse()?))
+            .await
+    );
+    assert!(
+        rt.add_node(PeerNode::new(invalid_id, "127.0.0.1:4000".parse()?))
+            .await
+    );
+    assert_eq!(2, rt.get_all_peers().await.count());
+
+    // Only kept in the recent peers cache.
+    let mut rt = RoutingTable::new(0);
+    rt.set_ip_id_policy(IpIdPolicy::Deprioritise);
+    assert!(
+        rt.add_node(PeerNode::new(invalid_id, "1.2.3.4:4000".parse()?))
+            .await
+    );
+    assert_eq!(1, rt.get_recent_peers_cache().count());
+    assert_eq!(1, rt.get_all_peers().await.count());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_max_peers_per_ip() -> AnyResult<()> {
+    let mut rt = RoutingTable::new(0);
+    rt.set_ip_id_policy(IpIdPolicy::Enforce);
+    for r in 0..MAX_PEERS_PER_IP as u8 {
+        assert!(
+            rt.add_node(PeerNode::new(id_for_ip("1.2.3.4", r), "1.2.3.4:4000".parse()?))
+                .await
+        );
+    }
+    // Already known peers are not counted twice.
+    assert!(
+        !rt.add_node(PeerNode::new(id_for_ip("1.2.3.4", 0), "1.2.3.4:4000".parse()?))
+            .await
+    );
+    // But a new one is refused.
+    let id = id_for_ip("1.2.3.4", MAX_PEERS_PER_IP as u8);
+    assert!(!rt.add_node(PeerNode::new(id, "1.2.3.4:4001".parse()?)).await);
+    assert_eq!(MAX_PEERS_PER_IP, rt.get_all_peers().await.count());
+
+    // Unless it's relayed by us, whatever its id.
+    assert!(
+        rt.add_relayed_node(PeerNode::new(id ^ 0x8000_0000, "1.2.3.4:4001".parse()?))
+            .await
+    );
+    assert_eq!(MAX_PEERS_PER_IP + 1, rt.get_all_peers().await.count());
+
+    // Local addresses are not capped.
+    for id in 0..=10 {
+        rt.add_node(PeerNode::new(id, "127.0.0.1:4000".parse()?)).await;
+    }
+    assert_eq!(MAX_PEERS_PER_IP + 12, rt.get_all_peers().await.count());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_peer_reputation() -> AnyResult<()> {
+    let dummy_addr = "127.0.0.1:4000".parse()?;
+
+    let mut rt = RoutingTable::new(8);
+    for id in 1..=3 {
+        rt.add_node(PeerNode::new(id, dummy_addr)).await;
+    }
+
+    // Peers are updated from their real id, not their distance.
+    rt.peer_has_responded(1).await;
+    rt.rate_peer(1, PeerEvent::Success).await;
+    let peer = rt.get_peer(1).await.expect("should be there");
+    assert!(peer.status() == PeerStatus::Good);
+    assert_eq!(1, peer.reputation());
+
+    // An unreachable peer is avoided, but not banned.
+    for _ in 0..30 {
+        rt.rate_peer(2, PeerEvent::Timeout).await;
+    }
+    let peer = rt.get_peer(2).await.expect("should be there");
+    assert!(peer.has_low_reputation());
+    assert!(!rt.is_banned(2));
+    rt.rate_peer(2, PeerEvent::Success).await;
+    assert!(!rt
+        .get_peer(2)
+        .await
+        .expect("should be there")
+        .has_low_reputation());
+
+    // A misbehaving peer comes last, even if it's the closest.
+    for _ in 0..3 {
+        rt.rate_peer(1, PeerEvent::ProtocolError).await;
+    }
+    assert_eq!(
+        vec![3, 2, 1],
+        rt.get_closest_peers_from(1, 3)
+            .await
+            .map(|peer| peer.id())
+            .collect::<Vec<_>>()
+    );
+
+    // Then it's banned, and can't come back.
+    rt.peer_has_lied(1).await;
+    assert!(!rt.is_banned(1));
+    rt.peer_has_lied(1).await;
+    assert!(rt.is_banned(1));
+    assert!(rt.get_peer(1).await.is_none());
+    assert!(!rt.add_node(PeerNode::new(1, dummy_addr)).await);
+    assert_eq!(2, rt.get_all_peers().await.count());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_peer_rtt() -> AnyResult<()> {
+    let dummy_addr = "127.0.0.1:4000".parse()?;
+
+    let mut rt = RoutingTable::new(8);
+    for id in 4..=5 {
+        rt.add_node(PeerNode::new(id, dummy_addr)).await;
+    }
+
+    // The first measure is taken as is, then smoothed.
+    rt.peer_rtt_measured(4, Duration::from_millis(800)).await;
+    rt.peer_rtt_measured(4, Duration::from_millis(1600)).await;
+    let peer = rt.get_peer(4).await.expect("should be there");
+    assert_eq!(Some(Duration::from_millis(900)), peer.srtt());
+    assert_eq!(Duration::from_millis(500), peer.rttvar());
+    assert_eq!(Some(Duration::from_millis(2900)), peer.rto());
+
+    // Each timeout doubles the retransmission timeout, until a new measure.
+    rt.rate_peer(4, PeerEvent::Timeout).await;
+    rt.rate_peer(4, PeerEvent::Timeout).await;
+    let peer = rt.get_peer(4).await.expect("should be there");
+    assert_eq!(Some(Duration::from_millis(11600)), peer.rto());
+    rt.peer_rtt_measured(4, Duration::from_millis(900)).await;
+    let peer = rt.get_peer(4).await.expect("should be there");
+    assert_eq!(Some(Duration::from_millis(2400)), peer.rto());
+
+    // Both peers are at a similar distance, the fastest comes first. A fast
+    // peer still gets at least 1 sec to answer.
+    rt.peer_rtt_measured(5, Duration::from_millis(10)).await;
+    let peer = rt.get_peer(5).await.expect("should be there");
+    assert_eq!(Some(MIN_RTO), peer.rto());
+    assert_eq!(
+        vec![5, 4],
+        rt.get_closest_peers_from(0, 2)
+            .await
+            .map(|peer| peer.id())
+            .collect::<Vec<_>>()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dual_stack_peer() -> AnyResult<()> {
+    let ipv4_addr = "127.0.0.1:4000".parse()?;
+    let ipv6_addr = "[::1]:4000".parse()?;
+
+    let mut rt = RoutingTable::new(8);
+    rt.add_node(PeerNode::new(4, ipv4_addr)).await;
+
+    // The same peer seen on another family gets this address too, but not a
+    // second address on a family already known.
+    assert!(!rt.add_node(PeerNode::new(4, ipv6_addr)).await);
+    assert!(!rt.add_node(PeerNode::new(4, "127.0.0.2:4000".parse()?)).await);
+    let peer = rt.get_peer(4).await.expect("should be there");
+    assert_eq!(vec![ipv4_addr, ipv6_addr], peer.addrs().collect::<Vec<_>>());
+
+    // Given on the asked family.
+    let ipv6_only = IpFamilies::of([ipv6_addr]);
+    let peers = rt.get_closest_peers_in(0, 1, ipv6_only).await.collect::<Vec<_>>();
+    assert_eq!(ipv6_addr, peers[0].addr());
+
+    // An id only matches one IP address: a dual-stack peer is checked on the
+    // first one seen.
+    let mut rt = RoutingTable::new(0);
+    rt.set_ip_id_policy(IpIdPolicy::Enforce);
+    let id = id_for_ip("1.2.3.4", 0);
+    let ipv6_addr = "[2001:db8::1]:4000".parse()?;
+    assert!(!rt.add_node(PeerNode::new(id, ipv6_addr)).await);
+    assert!(rt.add_node(PeerNode::new(id, "1.2.3.4:4000".parse()?)).await);
+    assert!(!rt.add_node(PeerNode::new(id, ipv6_addr)).await);
+    let peer = rt.get_peer(id).await.expect("should be there");
+    assert!(peer.has_addr(ipv6_addr));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_prefer_reachable_family() -> AnyResult<()> {
+    let mut rt = RoutingTable::new(8);
+    rt.add_node(PeerNode::new(4, "[::1]:4000".parse()?)).await;
+    rt.add_node(PeerNode::new(5, "127.0.0.1:4000".parse()?)).await;
+
+    // The IPv4 peer is further, but the only one reachable.
+    let ipv4_only = IpFamilies::of(["127.0.0.1:5000".parse()?]);
+    assert_eq!(
+        vec![5, 4],
+        rt.get_closest_peers_in(4, 2, ipv4_only)
+            .await
+            .map(|peer| peer.id())
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        vec![4, 5],
+        rt.get_closest_peers_in(4, 2, IpFamilies::ALL)
+            .await
+            .map(|peer| peer.id())
+            .collect::<Vec<_>>()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use super::identity::{is_node_key, node_id};
+use crate::{
+    network::protocol::StoredValue,
+    utils::{string_to_u8_array, u32_to_u8_array},
+};
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer};
+
+// What the publisher of a value signs: the key where it's stored, and the value
+// itself. The timestamp is left out, as storing a value again refreshes it.
+fn signed_payload(key: u32, value: &str) -> Vec<u8> {
+    let mut res = u32_to_u8_array(key).to_vec();
+    res.extend(string_to_u8_array(value.to_owned()));
+    res
+}
+
+impl StoredValue {
+    // Create a value to store at a given key, signed by its publisher.
+    pub fn new(keypair: &Keypair, key: u32, timestamp: u32, value: String) -> Self {
+        let signature = keypair.sign(&signed_payload(key, &value));
+        Self {
+            publisher: node_id(&keypair.public),
+            timestamp,
+            value,
+            public_key: keypair.public.to_bytes().to_vec(),
+            signature: signature.to_bytes().to_vec(),
+        }
+    }
+
+    // Get the id a mail is acknowledged with. Signatures are deterministic, so
+    // a mail sent again, which only refreshes it, keeps its id.
+    pub fn mail_id(&self) -> u32 {
+        crc32fast::hash(&self.signature)
+    }
+
+    // Check the value has been signed for the given key by its publisher, the
+    // node owning the public key.
+    pub fn has_valid_signature(&self, key: u32) -> bool {
+        let public_key = match PublicKey::from_bytes(&self.public_key) {
+            Ok(public_key) => public_key,
+            Err(_) => return false,
+        };
+        let signature = match Signature::from_bytes(&self.signature) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        is_node_key(&public_key)
+            && node_id(&public_key) == self.publisher
+            && public_key
+                .verify_strict(&signed_payload(key, &self.value), &signature)
+                .is_ok()
+    }
+}
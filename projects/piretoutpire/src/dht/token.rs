@@ -0,0 +1,223 @@
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+// Period after which a new secret is used to hand out tokens. A token stays
+// valid until the secret which made it is rotated twice.
+pub const TOKEN_ROTATION_PERIOD: Duration = Duration::from_secs(5 * 60);
+
+const SECRET_SIZE: usize = 32;
+
+// Period after which a new secret is used to hand out mail challenges. A
+// challenge stays valid until the secret which made it is rotated twice.
+pub const MAIL_CHALLENGE_ROTATION_PERIOD: Duration = Duration::from_secs(60);
+
+// Opaque write tokens, as described here:
+// https://www.bittorrent.org/beps/bep_0005.html
+// We hand them out in find node and get peers responses, and require them to
+// store a value or to announce a file. A token is bound to the IP address it
+// has been sent to, so one can't register as a file owner on behalf of another
+// address.
+// The tokens other peers gave us are also kept, to be sent back when writing
+// on them.
+pub struct WriteTokens {
+    secret: RotatingSecret,
+
+    // Tokens received from other peers, by peer id.
+    received: HashMap<u32, (u32 /*token*/, Instant /*received_at*/)>,
+}
+
+impl Default for WriteTokens {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WriteTokens {
+    // Create new tokens, from a random secret.
+    pub fn new() -> Self {
+        Self {
+            secret: RotatingSecret::new(TOKEN_ROTATION_PERIOD),
+            received: HashMap::new(),
+        }
+    }
+
+    // Replace the secret by a new one. Tokens made from the previous secret are
+    // still valid, the older ones are not anymore.
+    pub fn rotate(&mut self) {
+        self.secret.rotate();
+    }
+
+    // Get the token to hand out to a given IP address.
+    pub fn issue(&mut self, ip: IpAddr) -> u32 {
+        make_token(self.secret.current(), ip)
+    }
+
+    // Check a token has been handed out by us, to this IP address, not too
+    // long ago.
+    pub fn is_valid(&mut self, token: u32, ip: IpAddr) -> bool {
+        self.secret
+            .valid_ones()
+            .iter()
+            .any(|secret| token == make_token(secret, ip))
+    }
+
+    // Keep the token a peer gave us. Tokens too old to still be valid are
+    // dropped on the way.
+    pub fn token_received(&mut self, peer_id: u32, token: u32) {
+        self.received
+            .retain(|_, (_, received_at)| received_at.elapsed() < TOKEN_ROTATION_PERIOD);
+        self.received.insert(peer_id, (token, Instant::now()));
+    }
+
+    // Get the token a peer gave us, if it's still valid.
+    pub fn received_token(&self, peer_id: u32) -> Option<u32> {
+        self.received
+            .get(&peer_id)
+            .filter(|(_, received_at)| received_at.elapsed() < TOKEN_ROTATION_PERIOD)
+            .map(|(token, _)| *token)
+    }
+
+    // Forget the token a peer gave us, once it has refused it.
+    pub fn forget_received_token(&mut self, peer_id: u32) {
+        self.received.remove(&peer_id);
+    }
+}
+
+// Challenges handed out to the recipients of mails. Fetching mails requires a
+// challenge we issued to this recipient, at this IP address, not too long ago.
+// Like write tokens, nothing is kept per challenge: one asking for challenges
+// on behalf of others can't fill our memory, nor make the ones of a recipient
+// expire. A captured signed fetch can't be replayed from another address to
+// read the mails of someone else.
+pub struct MailChallenges {
+    secret: RotatingSecret,
+}
+
+impl Default for MailChallenges {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MailChallenges {
+    // Create new challenges, from a random secret.
+    pub fn new() -> Self {
+        Self {
+            secret: RotatingSecret::new(MAIL_CHALLENGE_ROTATION_PERIOD),
+        }
+    }
+
+    // Replace the secret by a new one. Challenges made from the previous secret
+    // are still valid, the older ones are not anymore.
+    pub fn rotate(&mut self) {
+        self.secret.rotate();
+    }
+
+    // Get the challenge to hand out to a recipient, at a given IP address.
+    pub fn issue(&mut self, recipient: u32, ip: IpAddr) -> u32 {
+        make_challenge(self.secret.current(), recipient, ip)
+    }
+
+    // Check a challenge has been handed out by us, to this recipient at this
+    // IP address, not too long ago.
+    pub fn is_valid(&mut self, challenge: u32, recipient: u32, ip: IpAddr) -> bool {
+        self.secret
+            .valid_ones()
+            .iter()
+            .any(|secret| challenge == make_challenge(secret, recipient, ip))
+    }
+}
+
+// A secret replaced by a new one after a given period. What's been made from
+// the previous secret is still accepted, so nothing expires right after it has
+// been handed out.
+struct RotatingSecret {
+    secret: [u8; SECRET_SIZE],
+    previous_secret: [u8; SECRET_SIZE],
+    rotated_at: Instant,
+    period: Duration,
+}
+
+impl RotatingSecret {
+    fn new(period: Duration) -> Self {
+        let secret = random_secret();
+        Self {
+            secret,
+            previous_secret: secret,
+            rotated_at: Instant::now(),
+            period,
+        }
+    }
+
+    fn rotate(&mut self) {
+        self.previous_secret = self.secret;
+        self.secret = random_secret();
+        self.rotated_at = Instant::now();
+    }
+
+    // Rotate the secret when it has been used long enough.
+    fn rotate_if_needed(&mut self) {
+        if self.rotated_at.elapsed() >= self.period {
+            self.rotate();
+        }
+    }
+
+    // Get the secret to make new things from.
+    fn current(&mut self) -> &[u8; SECRET_SIZE] {
+        self.rotate_if_needed();
+        &self.secret
+    }
+
+    // Get the secrets whose things are still accepted.
+    fn valid_ones(&mut self) -> [[u8; SECRET_SIZE]; 2] {
+        self.rotate_if_needed();
+        [self.secret, self.previous_secret]
+    }
+}
+
+fn random_secret() -> [u8; SECRET_SIZE] {
+    let mut secret = [0u8; SECRET_SIZE];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+// A token is the beginning of the HMAC-SHA256 of the IP address, keyed with
+// the secret. Unlike a crc, the secret can't be recovered from the tokens one
+// gets for a few addresses, to forge the ones of other addresses.
+fn make_token(secret: &[u8; SECRET_SIZE], ip: IpAddr) -> u32 {
+    tag(ip_mac(secret, ip))
+}
+
+// A mail challenge is made the same way, from the IP address and the
+// recipient.
+fn make_challenge(secret: &[u8; SECRET_SIZE], recipient: u32, ip: IpAddr) -> u32 {
+    let mut mac = ip_mac(secret, ip);
+    mac.update(&recipient.to_be_bytes());
+    tag(mac)
+}
+
+// Start an HMAC-SHA256 keyed with the secret, of an IP address.
+fn ip_mac(secret: &[u8; SECRET_SIZE], ip: IpAddr) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    match ip {
+        IpAddr::V4(ip) => mac.update(&ip.octets()),
+        IpAddr::V6(ip) => mac.update(&ip.octets()),
+    }
+    mac
+}
+
+// Get the beginning of an HMAC.
+fn tag(mac: Hmac<Sha256>) -> u32 {
+    let tag = mac.finalize().into_bytes();
+    u32::from_be_bytes([tag[0], tag[1], tag[2], tag[3]])
+}
+
+#[cfg(test)]
+#[path = "token_test.rs"]
+mod token_test;
@@ -0,0 +1,68 @@
+use super::*;
+
+#[test]
+fn test_token_bound_to_ip() {
+    let mut tokens = WriteTokens::new();
+    let ip: IpAddr = "10.0.0.1".parse().expect("valid ip");
+    let other_ip: IpAddr = "10.0.0.2".parse().expect("valid ip");
+
+    let token = tokens.issue(ip);
+    assert!(tokens.is_valid(token, ip));
+    assert!(!tokens.is_valid(token, other_ip));
+    assert!(!tokens.is_valid(token.wrapping_add(1), ip));
+}
+
+#[test]
+fn test_token_rotation() {
+    let mut tokens = WriteTokens::new();
+    let ip: IpAddr = "10.0.0.1".parse().expect("valid ip");
+
+    let token = tokens.issue(ip);
+    // Still valid right after a rotation.
+    tokens.rotate();
+    assert!(tokens.is_valid(token, ip));
+    assert_ne!(token, tokens.issue(ip));
+
+    // But not after the next one.
+    tokens.rotate();
+    assert!(!tokens.is_valid(token, ip));
+}
+
+#[test]
+fn test_received_tokens() {
+    let mut tokens = WriteTokens::new();
+    assert_eq!(None, tokens.received_token(42));
+
+    tokens.token_received(42, 1234);
+    assert_eq!(Some(1234), tokens.received_token(42));
+    tokens.token_received(42, 5678);
+    assert_eq!(Some(5678), tokens.received_token(42));
+
+    tokens.forget_received_token(42);
+    assert_eq!(None, tokens.received_token(42));
+}
+
+#[test]
+fn test_mail_challenges() {
+    let mut challenges = MailChallenges::new();
+    let ip: IpAddr = "10.0.0.1".parse().expect("valid ip");
+    let other_ip: IpAddr = "10.0.0.2".parse().expect("valid ip");
+    let challenge = challenges.issue(42, ip);
+
+    // Bound to the recipient and to its address.
+    assert!(challenges.is_valid(challenge, 42, ip));
+    assert!(!challenges.is_valid(challenge, 43, ip));
+    assert!(!challenges.is_valid(challenge, 42, other_ip));
+
+    // Challenges asked for others don't make it expire.
+    for recipient in 0..100 {
+        challenges.issue(recipient, other_ip);
+    }
+    assert!(challenges.is_valid(challenge, 42, ip));
+
+    // Until the secret is rotated twice.
+    challenges.rotate();
+    assert!(challenges.is_valid(challenge, 42, ip));
+    challenges.rotate();
+    assert!(!challenges.is_valid(challenge, 42, ip));
+}
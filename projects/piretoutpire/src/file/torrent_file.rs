@@ -57,6 +57,7 @@ impl<P: AsRef<Path>> TorrentFile<P> {
                 file_to_preallocate.as_ref().display().to_string(),
                 file_info.file_size,
                 file_info.file_crc,
+                file_info.chunk_crcs.clone(),
             ),
         };
         torrent.dump()?;
@@ -74,7 +75,7 @@ impl<P: AsRef<Path>> TorrentFile<P> {
 
     // Load an existing torrent metadata from an existing file.
     pub fn load(torrent_file: P) -> AnyResult<Self> {
-        let metadata = Metadata::load(&torrent_file)?;
+        let mut metadata = Metadata::load(&torrent_file)?;
         let mut reader = BufReader::new(OpenOptions::new().read(true).open(&metadata.original_file)?);
         let mut whole_file_hasher = Hasher::new();
 
@@ -97,6 +98,11 @@ impl<P: AsRef<Path>> TorrentFile<P> {
                 metadata.file_crc
             );
         }
+        // Torrents saved before chunk crcs were kept get them from the chunks
+        // they completed, which is the whole file.
+        if metadata.chunk_crcs.is_empty() {
+            metadata.chunk_crcs = metadata.completed_chunks.iter().flatten().copied().collect();
+        }
 
         Ok(Self {
             metadata,
@@ -111,6 +117,7 @@ impl<P: AsRef<Path>> TorrentFile<P> {
         file_size: u32,
         file_crc: u32,
         chunk_size: u32,
+        chunk_crcs: Vec<u32>,
     ) -> Self {
         Self {
             metadata: Metadata {
@@ -123,6 +130,7 @@ impl<P: AsRef<Path>> TorrentFile<P> {
                 file_crc,
                 chunk_size,
                 completed_chunks: vec![None; div_ceil(file_size, chunk_size) as usize],
+                chunk_crcs,
                 original_filename,
             },
             torrent_file,
@@ -147,6 +155,9 @@ pub struct Metadata {
     // contains none for incomplete chunks
     // contains the crc of already got chunks
     pub completed_chunks: Vec<Option<u32>>,
+    // Expected crc of each chunk
+    #[serde(default)]
+    pub chunk_crcs: Vec<u32>,
 }
 
 impl Metadata {
@@ -155,6 +166,7 @@ impl Metadata {
         original_file: String,
         file_size: u32,
         file_crc: u32,
+        chunk_crcs: Vec<u32>,
     ) -> Self {
         Self {
             original_filename,
@@ -163,6 +175,7 @@ impl Metadata {
             file_crc,
             chunk_size: CHUNK_SIZE,
             completed_chunks: vec![None; div_ceil(file_size, CHUNK_SIZE) as usize],
+            chunk_crcs,
         }
     }
 
@@ -186,6 +199,7 @@ impl Metadata {
             file_size: chunks.file_size(),
             file_crc: whole_file_hasher.finalize(),
             chunk_size: CHUNK_SIZE,
+            chunk_crcs: completed_chunks.iter().flatten().copied().collect(),
             completed_chunks,
         })
     }
@@ -1,70 +1,171 @@
 use super::context::Context;
 use crate::{
+    dht::{identity::node_id, mutable::MutableHash, peer_node::PeerEvent},
     file::{file_chunk::FileChunk, torrent_file::TorrentFile},
     network::{
-        api::{announce, file_chunk, file_info, find_node, find_value, get_peers, ping, send_message, store},
-        protocol::{Command, ErrorCode, FileInfo, Peer},
+        api::{
+            ack_mail, announce, connect, file_chunk, file_info, find_node, find_value, get_mail, get_mutable,
+            get_peers, mail_challenge, peer_key, ping, public_key, put_mail, put_mutable, relay,
+            send_message, send_onion, store,
+        },
+        protocol::{
+            Announcement, Command, ErrorCode, FileInfo, FoundMutable, FoundPeers, FoundValue, MutableValue,
+            Peer, StoredValue,
+        },
+        retry::is_network_error,
     },
 };
+use ed25519_dalek::PublicKey;
 use errors::{bail, AnyResult};
-use std::{net::SocketAddr, ops::DerefMut, sync::Arc};
+use std::{future::Future, net::SocketAddr, ops::DerefMut, sync::Arc};
 use tokio::{net::TcpStream, sync::Mutex};
 
 // Helpers ---------------------------------------------------------------------
 
 // Mark that we're trying to contact a given peer.
-async fn peer_was_requested(ctx: Arc<Mutex<Context>>, target: u32) {
-    let mut guard = ctx.lock().await;
-    let ctx = guard.deref_mut();
-    ctx.dht.peer_was_requested(target).await;
+async fn peer_was_requested(ctx: Arc<Context>, target: u32) {
+    let mut dht = ctx.dht.lock().await;
+    dht.peer_was_requested(target).await;
 }
 
 // Mark that we succeed to contact a given peer.
-async fn peer_has_responded(ctx: Arc<Mutex<Context>>, target: u32) {
-    let mut guard = ctx.lock().await;
-    let ctx = guard.deref_mut();
-    ctx.dht.peer_has_responded(target).await;
+async fn peer_has_responded(ctx: Arc<Context>, target: u32) {
+    let mut dht = ctx.dht.lock().await;
+    dht.peer_has_responded(target).await;
+}
+
+// Update the reputation of a peer from the outcome of a request.
+pub async fn rate_peer(ctx: Arc<Context>, target: u32, event: PeerEvent) {
+    let mut dht = ctx.dht.lock().await;
+    dht.rate_peer(target, event).await;
+}
+
+// Flag that a peer sent data failing verification.
+async fn peer_has_lied(ctx: Arc<Context>, target: u32) {
+    let mut dht = ctx.dht.lock().await;
+    dht.peer_has_lied(target).await;
+}
+
+// Get the token a peer requires to write on it. The one it gave us during a
+// previous lookup is used, otherwise it's asked with a find node.
+async fn write_token(
+    ctx: Arc<Context>,
+    stream: Arc<Mutex<TcpStream>>,
+    peer_id: u32,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    key: u32,
+) -> AnyResult<u32> {
+    if let Some(token) = ctx.tokens.lock().await.received_token(peer_id) {
+        return Ok(token);
+    }
+    handle_find_node(Arc::clone(&ctx), stream, peer_id, sender_addr, sender_id, key).await?;
+    match ctx.tokens.lock().await.received_token(peer_id) {
+        Some(token) => Ok(token),
+        None => bail!("peer {} didn't give any write token", peer_id),
+    }
+}
+
+// Connect to a peer, trying again as the retry policy allows. A peer which
+// can't be reached in time is timing out.
+pub async fn connect_to_peer(ctx: Arc<Context>, peer: &Peer) -> AnyResult<TcpStream> {
+    match connect(Arc::clone(&ctx), peer.addr).await {
+        Ok(connection) => Ok(connection),
+        Err(error) => {
+            rate_peer(ctx, peer.id, PeerEvent::Timeout).await;
+            Err(error)
+        }
+    }
+}
+
+// Send a request to a peer, and rate it on how it went. A peer which doesn't
+// answer in time is timing out, and one whose answer can't be decoded, or isn't
+// the expected one, breaks the protocol.
+async fn request_peer(
+    ctx: Arc<Context>,
+    target: u32,
+    request: impl Future<Output = AnyResult<Command>>,
+    is_expected: impl Fn(&Command) -> bool,
+) -> AnyResult<Command> {
+    peer_was_requested(Arc::clone(&ctx), target).await;
+    match request.await {
+        Ok(command) => {
+            peer_has_responded(Arc::clone(&ctx), target).await;
+            let event = if is_expected(&command) {
+                PeerEvent::Success
+            } else {
+                PeerEvent::ProtocolError
+            };
+            rate_peer(ctx, target, event).await;
+            Ok(command)
+        }
+        Err(error) => {
+            let event = if is_network_error(&error) {
+                PeerEvent::Timeout
+            } else {
+                PeerEvent::ProtocolError
+            };
+            rate_peer(ctx, target, event).await;
+            Err(error)
+        }
+    }
 }
 
 // Client API ------------------------------------------------------------------
 
 // Get file info from its ID (crc), then put it into our local store.
 pub async fn handle_file_info(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     stream: Arc<Mutex<TcpStream>>,
+    peer_id: u32,
     crc: u32,
 ) -> AnyResult<Option<FileInfo>> {
-    let command = file_info(Arc::clone(&ctx), Arc::clone(&stream), crc).await?;
+    let command = request_peer(
+        Arc::clone(&ctx),
+        peer_id,
+        file_info(Arc::clone(&ctx), Arc::clone(&stream), crc),
+        |command| {
+            matches!(
+                command,
+                Command::FileInfoResponse(found) if found.file_crc == crc
+            ) || matches!(command, Command::ErrorOccured(_))
+        },
+    )
+    .await?;
 
     match command {
         Command::FileInfoResponse(file_info) => {
-            let mut guard = ctx.lock().await;
-            let ctx = guard.deref_mut();
-
-            match ctx.available_torrents.entry(file_info.file_crc) {
-                std::collections::hash_map::Entry::Occupied(entry) => {
-                    eprintln!("already got the asked torrent {}", entry.key());
-                }
-                std::collections::hash_map::Entry::Vacant(entry) => {
-                    let torrent = TorrentFile::preallocate(
-                        format!(
-                            "{}/{}.torrent",
-                            ctx.working_directory, file_info.original_filename
-                        ),
-                        file_info.original_filename.clone(),
-                        file_info.file_size,
-                        file_info.file_crc,
-                        file_info.chunk_size,
-                    );
-                    torrent.dump()?;
-                    let chunks = FileChunk::open_new(
-                        format!("{}/{}", ctx.working_directory, file_info.original_filename),
-                        file_info.file_size,
-                    )?;
-
-                    entry.insert((torrent, chunks));
-                }
+            if !file_info.has_valid_chunk_crcs() {
+                peer_has_lied(Arc::clone(&ctx), peer_id).await;
+                bail!("peer sent chunk crcs not matching {}", file_info.file_crc);
             }
+            if ctx.torrent(file_info.file_crc).await.is_some() {
+                eprintln!("already got the asked torrent {}", file_info.file_crc);
+                return Ok(Some(file_info));
+            }
+
+            // Allocate the file without holding the torrents registry, as it
+            // may take a while for a big one.
+            let working_directory = ctx.settings.read().await.working_directory.clone();
+            let torrent = TorrentFile::preallocate(
+                format!("{}/{}.torrent", working_directory, file_info.original_filename),
+                file_info.original_filename.clone(),
+                file_info.file_size,
+                file_info.file_crc,
+                file_info.chunk_size,
+                file_info.chunk_crcs.clone(),
+            );
+            torrent.dump()?;
+            let chunks = FileChunk::open_new(
+                format!("{}/{}", working_directory, file_info.original_filename),
+                file_info.file_size,
+            )?;
+
+            ctx.available_torrents
+                .lock()
+                .await
+                .entry(file_info.file_crc)
+                .or_insert_with(|| Arc::new(Mutex::new((torrent, chunks))));
             Ok(Some(file_info))
         }
         Command::ErrorOccured(error) if error == ErrorCode::FileNotFound => Ok(None),
@@ -75,29 +176,52 @@ pub async fn handle_file_info(
 
 // Ask for a given file chunk.
 // Start by sending a GetChunk request, and received either an error code
-// or the chunk as a raw buffer.
+// or the chunk as a raw buffer. A chunk of another file, or another chunk than
+// the one asked, breaks the protocol. A chunk not matching its expected crc
+// is forged.
 pub async fn handle_file_chunk(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     stream: Arc<Mutex<TcpStream>>,
+    peer_id: u32,
     crc: u32,
     chunk_id: u32,
 ) -> AnyResult<bool> {
-    let command = file_chunk(Arc::clone(&ctx), Arc::clone(&stream), crc, chunk_id).await?;
+    let command = request_peer(
+        Arc::clone(&ctx),
+        peer_id,
+        file_chunk(Arc::clone(&ctx), Arc::clone(&stream), crc, chunk_id),
+        |command| match command {
+            Command::ChunkResponse(found_crc, found_chunk_id, _) => {
+                *found_crc == crc && *found_chunk_id == chunk_id
+            }
+            Command::ErrorOccured(_) => true,
+            _ => false,
+        },
+    )
+    .await?;
 
     match command {
+        Command::ChunkResponse(found_crc, found_chunk_id, _)
+            if found_crc != crc || found_chunk_id != chunk_id =>
+        {
+            bail!("peer sent the chunk {} of {}", found_chunk_id, found_crc)
+        }
         Command::ChunkResponse(crc, chunk_id, raw_chunk) => {
             // eprintln!("received buf {:?}", &raw_chunk);
-            let mut guard = ctx.lock().await;
-            let ctx = guard.deref_mut();
-
-            match ctx.available_torrents.get_mut(&crc) {
-                Some((torrent, chunks)) => {
+            match ctx.torrent(crc).await {
+                Some(torrent) => {
+                    let mut guard = torrent.lock().await;
+                    let (torrent, chunks) = guard.deref_mut();
+                    let chunk_crc = crc32fast::hash(&raw_chunk);
                     if chunk_id as usize >= torrent.metadata.completed_chunks.len() {
                         eprintln!("Invalid chunk_id {} for {}", chunk_id, crc);
+                    } else if torrent.metadata.chunk_crcs.get(chunk_id as usize) != Some(&chunk_crc) {
+                        drop(guard);
+                        peer_has_lied(Arc::clone(&ctx), peer_id).await;
+                        bail!("peer sent a forged chunk {} of {}", chunk_id, crc);
                     } else {
                         // Update metadata and write local chunk.
-                        torrent.metadata.completed_chunks[chunk_id as usize] =
-                            Some(crc32fast::hash(&raw_chunk));
+                        torrent.metadata.completed_chunks[chunk_id as usize] = Some(chunk_crc);
                         torrent.dump()?;
                         chunks.write_chunk(chunk_id, raw_chunk.as_slice())?;
                     }
@@ -114,87 +238,222 @@ pub async fn handle_file_chunk(
 
 // Ask for a node in the DHT.
 pub async fn handle_find_node(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     stream: Arc<Mutex<TcpStream>>,
+    peer_id: u32,
     sender_addr: SocketAddr,
     sender_id: u32,
     target: u32,
 ) -> AnyResult<Vec<Peer>> {
-    peer_was_requested(Arc::clone(&ctx), sender_id).await;
-    let command = find_node(
+    let command = request_peer(
         Arc::clone(&ctx),
-        Arc::clone(&stream),
-        sender_addr,
-        sender_id,
-        target,
+        peer_id,
+        find_node(
+            Arc::clone(&ctx),
+            Arc::clone(&stream),
+            peer_id,
+            Peer {
+                id: sender_id,
+                addr: sender_addr,
+            },
+            target,
+        ),
+        |command| {
+            matches!(
+                command,
+                Command::FindNodeResponse(_, _) | Command::ErrorOccured(_)
+            )
+        },
     )
     .await?;
-    peer_has_responded(Arc::clone(&ctx), sender_id).await;
 
     match command {
-        Command::FindNodeResponse(peers_found) => Ok(peers_found),
+        Command::FindNodeResponse(token, peers_found) => {
+            ctx.tokens.lock().await.token_received(peer_id, token);
+            Ok(peers_found)
+        }
         Command::ErrorOccured(error) => bail!("peer return error: {}", error),
         _ => bail!("Wrong command received: {:?}", command),
     }
 }
 
-// Ask a peer for it's id, and check if he's alive.
+// Ask a peer for it's id, and check if he's alive. Its id may only be known
+// from its answer, so only a successful ping is rated.
 pub async fn handle_ping(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     stream: Arc<Mutex<TcpStream>>,
+    peer_id: Option<u32>,
     sender_addr: SocketAddr,
     sender_id: u32,
 ) -> AnyResult<u32> {
-    peer_was_requested(Arc::clone(&ctx), sender_id).await;
-    let command = ping(Arc::clone(&ctx), Arc::clone(&stream), sender_addr, sender_id).await?;
-    peer_has_responded(Arc::clone(&ctx), sender_id).await;
+    let sender = Peer {
+        id: sender_id,
+        addr: sender_addr,
+    };
+    let command = ping(Arc::clone(&ctx), Arc::clone(&stream), peer_id, sender).await?;
 
     match command {
-        Command::PingResponse(target) => Ok(target),
+        Command::PingResponse(target, observed) => {
+            if let Ok(voter) = stream.lock().await.peer_addr() {
+                ctx.public_addr.lock().await.vote(voter.ip(), observed);
+            }
+            peer_has_responded(Arc::clone(&ctx), target).await;
+            rate_peer(Arc::clone(&ctx), target, PeerEvent::Success).await;
+            Ok(target)
+        }
         Command::ErrorOccured(error) => bail!("peer return error: {}", error),
         _ => bail!("Wrong command received: {:?}", command),
     }
 }
 
-// Ask a peer to store a value ina given key.
+// Ask a peer to store a value ina given key. A token is asked first if the peer
+// didn't give us one yet.
 pub async fn handle_store(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     stream: Arc<Mutex<TcpStream>>,
+    peer_id: u32,
     sender_addr: SocketAddr,
     sender_id: u32,
     key: u32,
-    value: String,
+    value: StoredValue,
 ) -> AnyResult<()> {
-    let command = store(
+    let token = write_token(
         Arc::clone(&ctx),
         Arc::clone(&stream),
+        peer_id,
         sender_addr,
         sender_id,
         key,
-        value,
+    )
+    .await?;
+    let command = request_peer(
+        Arc::clone(&ctx),
+        peer_id,
+        store(
+            Arc::clone(&ctx),
+            Arc::clone(&stream),
+            peer_id,
+            Peer {
+                id: sender_id,
+                addr: sender_addr,
+            },
+            token,
+            key,
+            value,
+        ),
+        |command| matches!(command, Command::StoreResponse() | Command::ErrorOccured(_)),
     )
     .await?;
 
     match command {
         Command::StoreResponse() => Ok(()),
+        Command::ErrorOccured(ErrorCode::InvalidToken) => {
+            ctx.tokens.lock().await.forget_received_token(peer_id);
+            bail!("peer refused our write token")
+        }
         Command::ErrorOccured(error) => bail!("peer return error: {}", error),
         _ => bail!("Wrong command received: {:?}", command),
     }
 }
 
-// Ask a peer for a store value in its kv_store, for a given key.
+// Ask a peer for a page of the values in its kv_store, for a given key. If the
+// peer doesn't have any, it will send back the closest peers it knows instead.
 pub async fn handle_find_value(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     stream: Arc<Mutex<TcpStream>>,
+    peer_id: u32,
     sender_addr: SocketAddr,
     sender_id: u32,
     key: u32,
-) -> AnyResult<Option<String>> {
-    let command = find_value(Arc::clone(&ctx), Arc::clone(&stream), sender_addr, sender_id, key).await?;
+    page: u32,
+) -> AnyResult<FoundValue> {
+    let command = request_peer(
+        Arc::clone(&ctx),
+        peer_id,
+        find_value(
+            Arc::clone(&ctx),
+            Arc::clone(&stream),
+            peer_id,
+            Peer {
+                id: sender_id,
+                addr: sender_addr,
+            },
+            key,
+            page,
+        ),
+        |command| matches!(command, Command::FindValueResponse(_) | Command::ErrorOccured(_)),
+    )
+    .await?;
 
     match command {
-        Command::FindValueResponse(message) => Ok(Some(message)),
-        Command::ErrorOccured(ErrorCode::KeyNotFound) => Ok(None),
+        Command::FindValueResponse(found) => Ok(found),
+        Command::ErrorOccured(error) => bail!("peer return error: {}", error),
+        _ => bail!("Wrong command received: {:?}", command),
+    }
+}
+
+// Put a signed mutable value on a peer.
+pub async fn handle_put_mutable(
+    ctx: Arc<Context>,
+    stream: Arc<Mutex<TcpStream>>,
+    peer_id: u32,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    value: MutableValue,
+) -> AnyResult<()> {
+    let command = request_peer(
+        Arc::clone(&ctx),
+        peer_id,
+        put_mutable(
+            Arc::clone(&ctx),
+            Arc::clone(&stream),
+            peer_id,
+            Peer {
+                id: sender_id,
+                addr: sender_addr,
+            },
+            value,
+        ),
+        |command| matches!(command, Command::PutMutableResponse() | Command::ErrorOccured(_)),
+    )
+    .await?;
+
+    match command {
+        Command::PutMutableResponse() => Ok(()),
+        Command::ErrorOccured(error) => bail!("peer return error: {}", error),
+        _ => bail!("Wrong command received: {:?}", command),
+    }
+}
+
+// Ask a peer for the mutable value of a hash. If the peer doesn't have it, it
+// will send back the closest peers it knows instead.
+pub async fn handle_get_mutable(
+    ctx: Arc<Context>,
+    stream: Arc<Mutex<TcpStream>>,
+    peer_id: u32,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    hash: MutableHash,
+) -> AnyResult<FoundMutable> {
+    let command = request_peer(
+        Arc::clone(&ctx),
+        peer_id,
+        get_mutable(
+            Arc::clone(&ctx),
+            Arc::clone(&stream),
+            peer_id,
+            Peer {
+                id: sender_id,
+                addr: sender_addr,
+            },
+            hash,
+        ),
+        |command| matches!(command, Command::GetMutableResponse(_) | Command::ErrorOccured(_)),
+    )
+    .await?;
+
+    match command {
+        Command::GetMutableResponse(found) => Ok(found),
         Command::ErrorOccured(error) => bail!("peer return error: {}", error),
         _ => bail!("Wrong command received: {:?}", command),
     }
@@ -202,11 +461,18 @@ pub async fn handle_find_value(
 
 // Send a message to a peer.
 pub async fn handle_message(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     stream: Arc<Mutex<TcpStream>>,
+    peer_id: u32,
     message: String,
 ) -> AnyResult<()> {
-    let command = send_message(Arc::clone(&ctx), Arc::clone(&stream), message).await?;
+    let command = request_peer(
+        Arc::clone(&ctx),
+        peer_id,
+        send_message(Arc::clone(&ctx), Arc::clone(&stream), message),
+        |command| matches!(command, Command::MessageResponse() | Command::ErrorOccured(_)),
+    )
+    .await?;
 
     match command {
         Command::MessageResponse() => Ok(()),
@@ -215,36 +481,299 @@ pub async fn handle_message(
     }
 }
 
-// Send to a peer that a given peer own a file (by its crc).
+// Send to a peer that a given peer own a file (by its crc), as signed by this
+// owner. A token is asked first if the peer didn't give us one yet.
 pub async fn handle_announce(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     stream: Arc<Mutex<TcpStream>>,
+    peer_id: u32,
     sender_addr: SocketAddr,
     sender_id: u32,
     crc: u32,
+    announcement: Announcement,
 ) -> AnyResult<()> {
-    peer_was_requested(Arc::clone(&ctx), sender_id).await;
-    let command = announce(Arc::clone(&ctx), Arc::clone(&stream), sender_addr, sender_id, crc).await?;
-    peer_was_requested(Arc::clone(&ctx), sender_id).await;
+    let token = write_token(
+        Arc::clone(&ctx),
+        Arc::clone(&stream),
+        peer_id,
+        sender_addr,
+        sender_id,
+        crc,
+    )
+    .await?;
+    let command = request_peer(
+        Arc::clone(&ctx),
+        peer_id,
+        announce(
+            Arc::clone(&ctx),
+            Arc::clone(&stream),
+            peer_id,
+            Peer {
+                id: sender_id,
+                addr: sender_addr,
+            },
+            token,
+            crc,
+            announcement,
+        ),
+        |command| matches!(command, Command::AnnounceResponse() | Command::ErrorOccured(_)),
+    )
+    .await?;
 
     match command {
         Command::AnnounceResponse() => Ok(()),
+        Command::ErrorOccured(ErrorCode::InvalidToken) => {
+            ctx.tokens.lock().await.forget_received_token(peer_id);
+            bail!("peer refused our write token")
+        }
         Command::ErrorOccured(error) => bail!("peer return error: {}", error),
         _ => bail!("Wrong command received: {:?}", command),
     }
 }
 
-// Get the list of peers who own a given file (by its crc).
+// Get the list of peers who own a given file (by its crc), along with the
+// closest peers it knows from it.
 pub async fn handle_get_peers(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     stream: Arc<Mutex<TcpStream>>,
+    peer_id: u32,
     crc: u32,
-) -> AnyResult<Option<Vec<Peer>>> {
-    let command = get_peers(Arc::clone(&ctx), Arc::clone(&stream), crc).await?;
+) -> AnyResult<FoundPeers> {
+    let command = request_peer(
+        Arc::clone(&ctx),
+        peer_id,
+        get_peers(Arc::clone(&ctx), Arc::clone(&stream), crc),
+        |command| {
+            matches!(
+                command,
+                Command::GetPeersResponse(_, _) | Command::ErrorOccured(_)
+            )
+        },
+    )
+    .await?;
 
     match command {
-        Command::GetPeersResponse(found_peers) => Ok(Some(found_peers)),
-        Command::ErrorOccured(error) if error == ErrorCode::FileNotFound => Ok(None),
+        Command::GetPeersResponse(token, found) => {
+            ctx.tokens.lock().await.token_received(peer_id, token);
+            Ok(found)
+        }
+        Command::ErrorOccured(error) => bail!("peer return error: {}", error),
+        _ => bail!("Wrong command received: {:?}", command),
+    }
+}
+
+// Ask a peer to relay the requests sent to us. Like an entry point, the relay
+// is only known by its address, so its public key is asked first to get its id
+// and sign the request for it. Return the address it listens on for us.
+pub async fn handle_relay(
+    ctx: Arc<Context>,
+    stream: Arc<Mutex<TcpStream>>,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+) -> AnyResult<SocketAddr> {
+    let relay_id = match public_key(Arc::clone(&ctx), Arc::clone(&stream)).await? {
+        Command::PublicKeyResponse(raw_key) => node_id(&PublicKey::from_bytes(&raw_key)?),
+        Command::ErrorOccured(error) => bail!("peer return error: {}", error),
+        command => bail!("Wrong command received: {:?}", command),
+    };
+    let sender = Peer {
+        id: sender_id,
+        addr: sender_addr,
+    };
+    let command = relay(Arc::clone(&ctx), stream, relay_id, sender).await?;
+
+    match command {
+        Command::RelayResponse(relay_addr) => Ok(relay_addr),
+        Command::ErrorOccured(error) => bail!("peer return error: {}", error),
+        _ => bail!("Wrong command received: {:?}", command),
+    }
+}
+
+// Send an onion to its next hop, known only by its address. Succeed once this
+// hop took it, it then forwards it on its own.
+pub async fn handle_onion(ctx: Arc<Context>, stream: Arc<Mutex<TcpStream>>, onion: Vec<u8>) -> AnyResult<()> {
+    let command = send_onion(ctx, stream, onion).await?;
+
+    match command {
+        Command::OnionResponse() => Ok(()),
+        Command::ErrorOccured(error) => bail!("peer return error: {}", error),
+        _ => bail!("Wrong command received: {:?}", command),
+    }
+}
+
+// Get the public key of a peer, asking for it the first time, either to the
+// peer itself or to another one which knows it. A key which isn't the one the
+// peer id is derived from is a lie.
+pub async fn handle_public_key(ctx: Arc<Context>, peer: &Peer, owner: u32) -> AnyResult<PublicKey> {
+    if let Some(public_key) = ctx.public_keys.lock().await.get(owner) {
+        return Ok(public_key);
+    }
+
+    let stream = Arc::new(Mutex::new(connect_to_peer(Arc::clone(&ctx), peer).await?));
+    let is_answer =
+        |command: &Command| matches!(command, Command::PublicKeyResponse(_) | Command::ErrorOccured(_));
+    let command = if peer.id == owner {
+        request_peer(
+            Arc::clone(&ctx),
+            peer.id,
+            public_key(Arc::clone(&ctx), stream),
+            is_answer,
+        )
+        .await?
+    } else {
+        request_peer(
+            Arc::clone(&ctx),
+            peer.id,
+            peer_key(Arc::clone(&ctx), stream, owner),
+            is_answer,
+        )
+        .await?
+    };
+
+    match command {
+        Command::PublicKeyResponse(raw_key) => {
+            let public_key = PublicKey::from_bytes(&raw_key)?;
+            if node_id(&public_key) != owner {
+                peer_has_lied(Arc::clone(&ctx), peer.id).await;
+                bail!("peer sent a public key of another id");
+            }
+            ctx.public_keys.lock().await.insert(owner, public_key);
+            Ok(public_key)
+        }
+        Command::ErrorOccured(error) => bail!("peer return error: {}", error),
+        _ => bail!("Wrong command received: {:?}", command),
+    }
+}
+
+// Ask a peer to keep a mail until its recipient fetches it. A token is asked
+// first if the peer didn't give us one yet.
+pub async fn handle_put_mail(
+    ctx: Arc<Context>,
+    stream: Arc<Mutex<TcpStream>>,
+    peer_id: u32,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    recipient: u32,
+    mail: StoredValue,
+) -> AnyResult<()> {
+    let token = write_token(
+        Arc::clone(&ctx),
+        Arc::clone(&stream),
+        peer_id,
+        sender_addr,
+        sender_id,
+        recipient,
+    )
+    .await?;
+    let command = request_peer(
+        Arc::clone(&ctx),
+        peer_id,
+        put_mail(
+            Arc::clone(&ctx),
+            Arc::clone(&stream),
+            peer_id,
+            Peer {
+                id: sender_id,
+                addr: sender_addr,
+            },
+            token,
+            recipient,
+            mail,
+        ),
+        |command| matches!(command, Command::PutMailResponse() | Command::ErrorOccured(_)),
+    )
+    .await?;
+
+    match command {
+        Command::PutMailResponse() => Ok(()),
+        Command::ErrorOccured(ErrorCode::InvalidToken) => {
+            ctx.tokens.lock().await.forget_received_token(peer_id);
+            bail!("peer refused our write token")
+        }
+        Command::ErrorOccured(error) => bail!("peer return error: {}", error),
+        _ => bail!("Wrong command received: {:?}", command),
+    }
+}
+
+// Fetch the mails a peer keeps for us, with a challenge it gives first. The
+// peer keeps them until we acknowledge them.
+pub async fn handle_get_mail(
+    ctx: Arc<Context>,
+    stream: Arc<Mutex<TcpStream>>,
+    peer_id: u32,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+) -> AnyResult<Vec<StoredValue>> {
+    let command = request_peer(
+        Arc::clone(&ctx),
+        peer_id,
+        mail_challenge(Arc::clone(&ctx), Arc::clone(&stream), sender_id),
+        |command| {
+            matches!(
+                command,
+                Command::MailChallengeResponse(_) | Command::ErrorOccured(_)
+            )
+        },
+    )
+    .await?;
+    let challenge = match command {
+        Command::MailChallengeResponse(challenge) => challenge,
+        Command::ErrorOccured(error) => bail!("peer return error: {}", error),
+        _ => bail!("Wrong command received: {:?}", command),
+    };
+
+    let command = request_peer(
+        Arc::clone(&ctx),
+        peer_id,
+        get_mail(
+            Arc::clone(&ctx),
+            stream,
+            peer_id,
+            Peer {
+                id: sender_id,
+                addr: sender_addr,
+            },
+            challenge,
+        ),
+        |command| matches!(command, Command::GetMailResponse(_) | Command::ErrorOccured(_)),
+    )
+    .await?;
+
+    match command {
+        Command::GetMailResponse(mails) => Ok(mails),
+        Command::ErrorOccured(error) => bail!("peer return error: {}", error),
+        _ => bail!("Wrong command received: {:?}", command),
+    }
+}
+
+// Tell a peer which of its mails we received, so it forgets them.
+pub async fn handle_ack_mail(
+    ctx: Arc<Context>,
+    stream: Arc<Mutex<TcpStream>>,
+    peer_id: u32,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    mail_ids: Vec<u32>,
+) -> AnyResult<()> {
+    let command = request_peer(
+        Arc::clone(&ctx),
+        peer_id,
+        ack_mail(
+            Arc::clone(&ctx),
+            stream,
+            peer_id,
+            Peer {
+                id: sender_id,
+                addr: sender_addr,
+            },
+            mail_ids,
+        ),
+        |command| matches!(command, Command::AckMailResponse() | Command::ErrorOccured(_)),
+    )
+    .await?;
+
+    match command {
+        Command::AckMailResponse() => Ok(()),
         Command::ErrorOccured(error) => bail!("peer return error: {}", error),
         _ => bail!("Wrong command received: {:?}", command),
     }
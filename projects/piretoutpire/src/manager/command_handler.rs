@@ -1,37 +1,134 @@
-use super::context::Context;
+use super::{
+    context::{Context, RpcClass},
+    relay::run_relay,
+};
 use crate::{
     manager::server::{
-        serve_announce, serve_file_chunk, serve_file_info, serve_find_node, serve_find_value,
-        serve_get_peers, serve_message, serve_ping, serve_store,
+        serve_ack_mail, serve_announce, serve_file_chunk, serve_file_info, serve_find_node, serve_find_value,
+        serve_get_mail, serve_get_mutable, serve_get_peers, serve_mail_challenge, serve_message, serve_onion,
+        serve_peer_key, serve_ping, serve_public_key, serve_put_mail, serve_put_mutable, serve_relay,
+        serve_store,
     },
-    network::protocol::Command,
+    network::protocol::{Command, Identity, Peer},
     read_all,
+    utils::unix_timestamp,
 };
+use ed25519_dalek::PublicKey;
 use errors::AnyResult;
-use std::{
-    net::SocketAddr,
-    ops::{Deref, DerefMut},
-    sync::Arc,
-};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, BufWriter},
-    net::{tcp::WriteHalf, TcpStream},
-    sync::Mutex,
+    net::{tcp::WriteHalf, TcpListener, TcpStream},
     time::{sleep, timeout},
 };
 
 // Command handler -------------------------------------------------------------
 
+// Get the id of the sender of a signed request, if it's proven. Only trust the
+// sender id of a request signed by the owner of this id, for us and recently,
+// so a request can't be replayed to another peer or later on. A ping can be
+// signed for anyone, as it's how a peer learns our id: it's then only trusted
+// from the IP address it was signed for, so the peer it was sent to can't
+// replay it from its own address.
+fn verify_sender(
+    identity: &Identity,
+    raw_request: &[u8],
+    request: &Command,
+    own_id: u32,
+    incoming_addr: SocketAddr,
+    now: u32,
+) -> Option<u32> {
+    let is_for_us = match identity.recipient {
+        Some(recipient) => recipient == own_id,
+        None => matches!(
+            request,
+            Command::PingRequest(sender) if sender.addr.ip() == incoming_addr.ip()
+        ),
+    };
+    if is_for_us && identity.is_recent(now) {
+        identity.verify(raw_request)
+    } else {
+        None
+    }
+}
+
 // Interpret a command and act accordingly. This is where request/response are
-// handled.
+// handled. When a peer is accepted to be relayed, its id and the listener of
+// its relay are given back, so its connection is kept to forward requests.
+// Requests forwarded by our relay all come from its address.
 async fn dispatch(
-    main_ctx: Arc<Mutex<Context>>,
+    main_ctx: Arc<Context>,
     incoming_addr: SocketAddr,
+    is_relayed: bool,
     writer: &mut BufWriter<WriteHalf<'_>>,
     request: Command,
     own_id: u32,
-) -> AnyResult<()> {
+) -> AnyResult<Option<(u32 /*relayed peer*/, TcpListener)>> {
+    let (request, verified_id) = match request {
+        Command::SignedRequest(identity, raw_request) => match Command::try_from(raw_request.as_slice()) {
+            Ok(Command::SignedRequest(_, _)) | Err(_) => {
+                eprintln!("Invalid signed command received!");
+                return Ok(None);
+            }
+            Ok(request) => {
+                let verified_id = verify_sender(
+                    &identity,
+                    &raw_request,
+                    &request,
+                    own_id,
+                    incoming_addr,
+                    unix_timestamp(),
+                );
+                // Keep the key of a peer proven to own its id, to give it to the
+                // ones sending it onions.
+                if let (Some(id), Ok(public_key)) = (verified_id, PublicKey::from_bytes(&identity.public_key))
+                {
+                    main_ctx.public_keys.lock().await.insert(id, public_key);
+                }
+                (request, verified_id)
+            }
+        },
+        request => (request, None),
+    };
+    let verified = request
+        .sender()
+        .map_or(false, |sender| Some(sender.id) == verified_id);
+
+    // Writing on us is only allowed with a token we gave to this address.
+    let valid_token = match &request {
+        Command::StoreRequest(_, token, _, _)
+        | Command::AnnounceRequest(_, token, _, _)
+        | Command::PutMailRequest(_, token, _, _) => {
+            main_ctx.tokens.lock().await.is_valid(*token, incoming_addr.ip())
+        }
+        _ => false,
+    };
+
+    // Fetching mails is only allowed with a challenge we gave to this recipient,
+    // at this address.
+    let valid_challenge =
+        match &request {
+            Command::GetMailRequest(peer_sender, challenge) => main_ctx
+                .mail_challenges
+                .lock()
+                .await
+                .is_valid(*challenge, peer_sender.id, incoming_addr.ip()),
+            _ => false,
+        };
+
+    // Only the port is taken from a request, the sender is registered at the IP
+    // address it's seen from. Through our relay, it can't be seen, the address
+    // given by the sender is kept.
+    let seen_at = |peer_sender: &Peer| {
+        if is_relayed {
+            peer_sender.addr
+        } else {
+            SocketAddr::new(incoming_addr.ip(), peer_sender.addr.port())
+        }
+    };
+
     let ctx = Arc::clone(&main_ctx);
+    let mut relay = None;
     let (sender, res_command) = match request {
         // Server message handling
         Command::FileInfoRequest(crc) => (None, serve_file_info(ctx, incoming_addr, crc).await),
@@ -40,97 +137,233 @@ async fn dispatch(
         }
         Command::FindNodeRequest(peer_sender, target) => (
             Some(peer_sender.id),
-            serve_find_node(ctx, peer_sender.addr, peer_sender.id, target).await,
+            serve_find_node(
+                ctx,
+                incoming_addr,
+                seen_at(&peer_sender),
+                peer_sender.id,
+                verified,
+                target,
+            )
+            .await,
         ),
         Command::PingRequest(peer_sender) => (
             Some(peer_sender.id),
-            serve_ping(ctx, peer_sender.addr, peer_sender.id, own_id).await,
+            serve_ping(
+                ctx,
+                incoming_addr,
+                seen_at(&peer_sender),
+                peer_sender.id,
+                verified,
+                own_id,
+            )
+            .await,
+        ),
+        Command::StoreRequest(peer_sender, _, key, stored_value) => (
+            None,
+            serve_store(
+                ctx,
+                seen_at(&peer_sender),
+                peer_sender.id,
+                verified,
+                valid_token,
+                key,
+                stored_value,
+            )
+            .await,
         ),
-        Command::StoreRequest(peer_sender, key, message) => (
+        Command::FindValueRequest(peer_sender, key, page) => (
             None,
-            serve_store(ctx, peer_sender.addr, peer_sender.id, key, message).await,
+            serve_find_value(ctx, seen_at(&peer_sender), peer_sender.id, verified, key, page).await,
         ),
-        Command::FindValueRequest(peer_sender, key) => (
+        Command::PutMutableRequest(peer_sender, mutable_value) => (
             None,
-            serve_find_value(ctx, peer_sender.addr, peer_sender.id, key).await,
+            serve_put_mutable(
+                ctx,
+                seen_at(&peer_sender),
+                peer_sender.id,
+                verified,
+                mutable_value,
+            )
+            .await,
+        ),
+        Command::GetMutableRequest(peer_sender, hash) => (
+            None,
+            serve_get_mutable(ctx, seen_at(&peer_sender), peer_sender.id, verified, hash).await,
         ),
         Command::MessageRequest(message) => (None, serve_message(ctx, incoming_addr, message).await),
-        Command::AnnounceRequest(peer_sender, crc) => (
+        Command::AnnounceRequest(peer_sender, _, crc, announcement) => (
             Some(peer_sender.id),
-            serve_announce(ctx, peer_sender.addr, peer_sender.id, crc).await,
+            serve_announce(
+                ctx,
+                seen_at(&peer_sender),
+                peer_sender.id,
+                verified,
+                valid_token,
+                crc,
+                announcement,
+            )
+            .await,
         ),
         Command::GetPeersRequest(crc) => (None, serve_get_peers(ctx, incoming_addr, crc).await),
+        Command::OnionRequest(onion) => (None, serve_onion(ctx, incoming_addr, onion).await),
+        Command::PublicKeyRequest() => (None, serve_public_key(ctx, incoming_addr).await),
+        Command::PeerKeyRequest(peer_id) => (None, serve_peer_key(ctx, incoming_addr, own_id, peer_id).await),
+        Command::PutMailRequest(peer_sender, _, recipient, mail) => (
+            Some(peer_sender.id),
+            serve_put_mail(
+                ctx,
+                seen_at(&peer_sender),
+                peer_sender.id,
+                verified,
+                valid_token,
+                recipient,
+                mail,
+            )
+            .await,
+        ),
+        Command::MailChallengeRequest(recipient) => {
+            (None, serve_mail_challenge(ctx, incoming_addr, recipient).await)
+        }
+        Command::GetMailRequest(peer_sender, _) => (
+            Some(peer_sender.id),
+            serve_get_mail(
+                ctx,
+                seen_at(&peer_sender),
+                peer_sender.id,
+                verified,
+                valid_challenge,
+            )
+            .await,
+        ),
+        Command::AckMailRequest(peer_sender, mail_ids) => (
+            Some(peer_sender.id),
+            serve_ack_mail(ctx, seen_at(&peer_sender), peer_sender.id, verified, mail_ids).await,
+        ),
+        Command::RelayRequest(peer_sender) => {
+            let (res_command, listener) = serve_relay(ctx, incoming_addr, peer_sender.id, verified).await;
+            relay = listener.map(|listener| (peer_sender.id, listener));
+            (Some(peer_sender.id), res_command)
+        }
 
         // Client message handling, shouldn't be reach.
         Command::ChunkResponse(_, _, _)
         | Command::FileInfoResponse(_)
-        | Command::FindNodeResponse(_)
-        | Command::PingResponse(_)
+        | Command::FindNodeResponse(_, _)
+        | Command::PingResponse(_, _)
         | Command::ErrorOccured(_)
         | Command::StoreResponse()
         | Command::FindValueResponse(_)
+        | Command::PutMutableResponse()
+        | Command::GetMutableResponse(_)
         | Command::AnnounceResponse()
-        | Command::GetPeersResponse(_)
-        | Command::MessageResponse() => unreachable!(),
+        | Command::GetPeersResponse(_, _)
+        | Command::MessageResponse()
+        | Command::RelayResponse(_)
+        | Command::OnionResponse()
+        | Command::PublicKeyResponse(_)
+        | Command::PutMailResponse()
+        | Command::GetMailResponse(_)
+        | Command::MailChallengeResponse(_)
+        | Command::AckMailResponse() => unreachable!(),
+
+        // Already unwrapped above.
+        Command::SignedRequest(_, _) => unreachable!(),
     };
 
+    // File chunks are much bigger than the other answers, they are given more
+    // time to be sent.
+    let class = match res_command {
+        Command::ChunkResponse(_, _, _) => RpcClass::Bulk,
+        _ => RpcClass::Control,
+    };
     let response: Vec<u8> = res_command.into();
 
-    let write_timeout = {
-        let mut guard = main_ctx.lock().await;
-        let ctx = guard.deref_mut();
-
-        // Mark the peer who contact us, as alive.
-        if let Some(sender) = sender {
-            ctx.dht.peer_has_responded(sender).await;
-        }
-
-        // Check if we need to simulate a slowness.
-        if let Some(wait_time) = ctx.slowness {
-            sleep(wait_time).await;
-        }
+    // Mark the peer who contact us, as alive.
+    if let Some(sender) = sender.filter(|_| verified) {
+        main_ctx.dht.lock().await.peer_has_responded(sender).await;
+    }
 
-        ctx.write_timeout
+    let (slowness, (write_timeout, _)) = {
+        let settings = main_ctx.settings.read().await;
+        (settings.slowness, settings.timeouts(class))
     };
 
+    // Check if we need to simulate a slowness.
+    if let Some(wait_time) = slowness {
+        sleep(wait_time).await;
+    }
+
     // eprintln!("sending buf {:?}", &response);
     timeout(write_timeout, writer.write_all(response.as_slice())).await??;
     timeout(write_timeout, writer.flush()).await??;
-    Ok(())
+    Ok(relay)
 }
 
 // Main handler ----------------------------------------------------------------
 
 // Start to listen to command. One instance will be spawn for each peer.
-pub async fn listen_to_command(
-    ctx: Arc<Mutex<Context>>,
+pub async fn listen_to_command(ctx: Arc<Context>, stream: TcpStream, own_id: u32) -> AnyResult<()> {
+    let read_timeout = ctx.settings.read().await.read_timeout;
+    serve_connection(ctx, stream, own_id, false, read_timeout).await
+}
+
+// Listen to the commands a relay forwards to us. The connection is kept open
+// even when no command comes for a while, as it's the only way to reach us.
+pub async fn listen_to_relay(ctx: Arc<Context>, stream: TcpStream, own_id: u32) -> AnyResult<()> {
+    serve_connection(ctx, stream, own_id, true, Duration::MAX).await
+}
+
+// Answer the commands of a connection, until it's closed or idle for too long.
+// If the peer at the other end asks to be relayed, the connection is then used
+// to forward it the requests sent to its relay.
+async fn serve_connection(
+    ctx: Arc<Context>,
     mut stream: TcpStream,
     own_id: u32,
+    is_relayed: bool,
+    idle_timeout: Duration,
 ) -> AnyResult<()> {
-    let read_timeout = {
-        let guard = ctx.lock().await;
-        let ctx = guard.deref();
-        ctx.read_timeout
-    };
-
     let peer_addr = stream.peer_addr()?;
     // eprintln!("{} is connected", peer_addr);
-    let (reader, writer) = stream.split();
-    let mut reader = tokio::io::BufReader::new(reader);
-    let mut writer = tokio::io::BufWriter::new(writer);
-
-    loop {
-        let raw_order = read_all!(reader, read_timeout);
-        let len = raw_order.len();
-        if len == 0 {
-            break;
-        }
+    let relay = {
+        let (reader, writer) = stream.split();
+        let mut reader = tokio::io::BufReader::new(reader);
+        let mut writer = tokio::io::BufWriter::new(writer);
+
+        loop {
+            let raw_order = read_all!(reader, idle_timeout);
+            let len = raw_order.len();
+            if len == 0 {
+                break None;
+            }
 
-        match raw_order.as_slice().try_into() {
-            Ok(command) => dispatch(Arc::clone(&ctx), peer_addr, &mut writer, command, own_id).await?,
-            Err(err) => eprintln!("Unknown command received! {}", err),
+            match raw_order.as_slice().try_into() {
+                Ok(command) => {
+                    if let Some(relay) = dispatch(
+                        Arc::clone(&ctx),
+                        peer_addr,
+                        is_relayed,
+                        &mut writer,
+                        command,
+                        own_id,
+                    )
+                    .await?
+                    {
+                        break Some(relay);
+                    }
+                }
+                Err(err) => eprintln!("Unknown command received! {}", err),
+            }
         }
-    }
+    };
 
+    if let Some((peer_id, listener)) = relay {
+        run_relay(ctx, peer_id, listener, stream).await?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+#[path = "command_handler_test.rs"]
+mod command_handler_test;
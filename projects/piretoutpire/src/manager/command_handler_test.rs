@@ -0,0 +1,48 @@
+use super::*;
+use crate::dht::identity::{generate_node_keypair, node_id};
+use errors::AnyResult;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
+#[test]
+fn test_unaddressed_ping_replayed_from_another_address() -> AnyResult<()> {
+    let keypair = generate_node_keypair(&mut ChaCha8Rng::seed_from_u64(1), |_| true);
+    let id = node_id(&keypair.public);
+    let now = unix_timestamp();
+
+    // A ping to an address whose id is unknown yet.
+    let sender = Peer {
+        id,
+        addr: "10.0.0.1:4000".parse()?,
+    };
+    let raw_request: Vec<u8> = Command::PingRequest(sender.clone()).into();
+    let request = Command::PingRequest(sender);
+    let identity = Identity::sign(&keypair, None, now, &raw_request);
+
+    // Trusted when coming from the address it was signed for.
+    let incoming_addr = "10.0.0.1:51000".parse()?;
+    assert_eq!(
+        Some(id),
+        verify_sender(&identity, &raw_request, &request, 42, incoming_addr, now)
+    );
+
+    // Not when replayed by the pinged peer, from its own address.
+    let replayed_from = "10.0.0.2:51000".parse()?;
+    assert_eq!(
+        None,
+        verify_sender(&identity, &raw_request, &request, 43, replayed_from, now)
+    );
+
+    // A ping signed for its recipient can't be replayed to another one.
+    let identity = Identity::sign(&keypair, Some(42), now, &raw_request);
+    assert_eq!(
+        Some(id),
+        verify_sender(&identity, &raw_request, &request, 42, incoming_addr, now)
+    );
+    assert_eq!(
+        None,
+        verify_sender(&identity, &raw_request, &request, 43, incoming_addr, now)
+    );
+
+    Ok(())
+}
@@ -1,22 +1,89 @@
+use super::{deadline::CancelToken, public_addr::PublicAddr};
 use crate::{
-    dht::dht::DistributedHashTable,
+    dht::{
+        dht::DistributedHashTable,
+        identity::{node_id, PeerKeys},
+        peer_node::IpFamilies,
+        token::{MailChallenges, WriteTokens},
+    },
     file::{file_chunk::FileChunk, torrent_file::TorrentFile},
+    network::retry::RetryPolicy,
 };
-use std::{collections::HashMap, time::Duration};
+use ed25519_dalek::Keypair;
+use errors::AnyResult;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::sync::{Mutex, RwLock};
 
 pub const DEFAULT_READ_TIMEOUT_MS: u64 = 200;
 pub const DEFAULT_WRITE_TIMEOUT_MS: u64 = 200;
+pub const DEFAULT_BULK_READ_TIMEOUT_MS: u64 = 2000; // 2 sec
+pub const DEFAULT_BULK_WRITE_TIMEOUT_MS: u64 = 2000; // 2 sec
 pub const DEFAULT_CONNECTION_TIMEOUT_MS: u64 = 200;
 pub const DEFAULT_DHT_DUMP_FREQUENCY_MS: u64 = 30 * 1000; // 30 sec
 
-// Context handle everything about shared context
+// Class of a request, each one has its own timeouts: a file chunk takes much
+// longer to transfer than a ping.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RpcClass {
+    // Small requests and answers: ping, find node, store...
+    Control,
+    // File chunks transfer.
+    Bulk,
+}
+
+// A file currently owned, or currently downloading: its metadata and the file
+// itself.
+pub type Torrent = (TorrentFile<String> /*metadata*/, FileChunk /*file*/);
+
+// Context handle everything about shared context. Each part has its own lock,
+// so a slow disk access on a torrent never blocks the dht, and the other way
+// around.
 pub struct Context {
-    // Contains a trackerless list of local peers
-    pub dht: DistributedHashTable,
+    // Address on which this peer can be reached
+    pub addr: SocketAddr,
+
+    // Address on the other IP family, when this peer is dual-stack
+    pub alt_addr: Option<SocketAddr>,
+
+    // Address told to other peers, when it's not the one we listen on
+    pub public_addr: Mutex<PublicAddr>,
+
+    // Keypair of this peer, its id is derived from the public key
+    pub keypair: Keypair,
+
+    // Contains a trackerless list of local peers, and the values stored on us.
+    // Both share the same lock, as adding a peer tells which values should be
+    // replicated on it.
+    pub dht: Mutex<DistributedHashTable>,
 
-    // List of all available torrents currently owned, or currently downloading
-    pub available_torrents: HashMap<u32 /*crc*/, (TorrentFile<String> /*metadata*/, FileChunk /*file*/)>,
+    // List of all available torrents currently owned, or currently downloading.
+    // Each one has its own lock, held while reading or writing its file.
+    pub available_torrents: Mutex<HashMap<u32 /*crc*/, Arc<Mutex<Torrent>>>>,
+
+    // Tokens handed out to other peers, so they can write on us, and the ones
+    // they gave us to write on them.
+    pub tokens: Mutex<WriteTokens>,
+
+    // Challenges handed out to the peers fetching their mails
+    pub mail_challenges: Mutex<MailChallenges>,
+
+    // Peers we relay requests to, each one with the token stopping its relay
+    pub relays: Mutex<HashMap<u32 /*peer id*/, CancelToken>>,
+
+    // Public keys of the peers which proved their id or which onions were sent
+    // through, the most recently used ones only
+    pub public_keys: Mutex<PeerKeys>,
+
+    // Everything which can be tuned
+    pub settings: RwLock<Settings>,
+
+    // Only one dump of the dht is written at a time
+    dht_dump: Mutex<()>,
+}
 
+// Settings of a peer, read by most requests.
+#[derive(Debug, Clone)]
+pub struct Settings {
     // Where all torrents and their metadata are
     pub working_directory: String,
 
@@ -26,33 +93,158 @@ pub struct Context {
     /// Max wait time for initiating a connection.
     pub connection_timeout: Duration,
 
-    /// Max wait time for sending a query.
+    /// Max wait time for sending a control query.
     pub write_timeout: Duration,
 
-    /// Max wait time for receiving a query.
+    /// Max wait time for receiving a control query.
     pub read_timeout: Duration,
 
+    /// Max wait time for sending a file chunk query.
+    pub bulk_write_timeout: Duration,
+
+    /// Max wait time for receiving a file chunk query.
+    pub bulk_read_timeout: Duration,
+
+    /// Adapt the wait time of control queries to the peer round-trip time.
+    pub adaptive_timeouts: bool,
+
+    /// Relay the requests sent to peers which can't be reached directly.
+    pub accept_relays: bool,
+
     /// Frequency at which the dht is dump on the disk.
     pub dht_dump_frequency: Duration,
 
+    /// How failed requests are tried again.
+    pub retry_policy: RetryPolicy,
+
     /// Where to save the dht
     pub dht_config_filename: String,
 }
 
-impl Context {
-    // Create a new context from a working directory
-    pub fn new(dht_config_filename: String, working_directory: String, self_id: u32) -> Self {
+impl Settings {
+    // Create the default settings.
+    fn new(dht_config_filename: String, working_directory: String) -> Self {
         Self {
-            dht: DistributedHashTable::new(self_id),
-            available_torrents: HashMap::new(),
-            dht_config_filename,
             working_directory,
             slowness: None,
             connection_timeout: Duration::from_millis(DEFAULT_CONNECTION_TIMEOUT_MS),
             write_timeout: Duration::from_millis(DEFAULT_WRITE_TIMEOUT_MS),
             read_timeout: Duration::from_millis(DEFAULT_READ_TIMEOUT_MS),
+            bulk_write_timeout: Duration::from_millis(DEFAULT_BULK_WRITE_TIMEOUT_MS),
+            bulk_read_timeout: Duration::from_millis(DEFAULT_BULK_READ_TIMEOUT_MS),
+            adaptive_timeouts: false,
+            accept_relays: false,
             dht_dump_frequency: Duration::from_millis(DEFAULT_DHT_DUMP_FREQUENCY_MS),
+            retry_policy: RetryPolicy::default(),
+            dht_config_filename,
+        }
+    }
+
+    // Get the write and read timeouts of a class of requests.
+    pub fn timeouts(&self, class: RpcClass) -> (Duration /*write*/, Duration /*read*/) {
+        match class {
+            RpcClass::Control => (self.write_timeout, self.read_timeout),
+            RpcClass::Bulk => (self.bulk_write_timeout, self.bulk_read_timeout),
+        }
+    }
+}
+
+impl Context {
+    // Create a new context from a working directory
+    pub fn new(
+        dht_config_filename: String,
+        working_directory: String,
+        keypair: Keypair,
+        self_addr: SocketAddr,
+        alt_addr: Option<SocketAddr>,
+    ) -> Self {
+        let mut dht = DistributedHashTable::new(node_id(&keypair.public));
+        dht.set_own_addrs(std::iter::once(self_addr).chain(alt_addr));
+        Self {
+            addr: self_addr,
+            alt_addr,
+            public_addr: Mutex::new(PublicAddr::new()),
+            dht: Mutex::new(dht),
+            keypair,
+            available_torrents: Mutex::new(HashMap::new()),
+            tokens: Mutex::new(WriteTokens::new()),
+            mail_challenges: Mutex::new(MailChallenges::new()),
+            relays: Mutex::new(HashMap::new()),
+            public_keys: Mutex::new(PeerKeys::new()),
+            settings: RwLock::new(Settings::new(dht_config_filename, working_directory)),
+            dht_dump: Mutex::new(()),
+        }
+    }
+
+    // Get all the addresses this peer can be reached on, the main one first.
+    pub fn addrs(&self) -> impl Iterator<Item = SocketAddr> {
+        std::iter::once(self.addr).chain(self.alt_addr)
+    }
+
+    // Get the IP families this peer can reach.
+    pub fn families(&self) -> IpFamilies {
+        IpFamilies::of(self.addrs())
+    }
+
+    // Get the address this peer should tell when contacting another one: a
+    // dual-stack peer tells its address on the family of the other one, so
+    // it's added there with an address it can be reached back on. Then, its
+    // public address is told rather than the one it listens on. Addresses
+    // which are not ours are kept as they are.
+    pub async fn addr_towards(&self, peer_addr: SocketAddr, addr: SocketAddr) -> SocketAddr {
+        let listen_addr = match self.alt_addr {
+            Some(alt_addr) if addr == self.addr && alt_addr.is_ipv4() == peer_addr.is_ipv4() => alt_addr,
+            _ if self.addrs().any(|own_addr| own_addr == addr) => addr,
+            _ => return addr,
+        };
+        self.public_addr.lock().await.resolve(listen_addr)
+    }
+
+    // Get a copy of the current settings.
+    pub async fn settings(&self) -> Settings {
+        self.settings.read().await.clone()
+    }
+
+    // Get the write and read timeouts of a request sent to a peer. With adaptive
+    // timeouts, the answer of a control request is only awaited as long as the
+    // retransmission timeout of the peer, as TCP does: never less than 1 sec,
+    // doubled after each timeout, but never longer than the configured timeout.
+    // Peers never measured get the configured one.
+    pub async fn request_timeouts(&self, class: RpcClass, addr: SocketAddr) -> (Duration, Duration) {
+        let (write_timeout, read_timeout, adaptive) = {
+            let settings = self.settings.read().await;
+            let (write_timeout, read_timeout) = settings.timeouts(class);
+            (write_timeout, read_timeout, settings.adaptive_timeouts)
+        };
+        if !adaptive || class != RpcClass::Control {
+            return (write_timeout, read_timeout);
         }
+
+        let rto = {
+            let dht = self.dht.lock().await;
+            match dht.peer_id_at(addr).await {
+                Some(peer_id) => dht.peer_rto(peer_id).await,
+                None => None,
+            }
+        };
+        let read_timeout = rto.map_or(read_timeout, |rto| rto.min(read_timeout));
+        (write_timeout, read_timeout)
+    }
+
+    // Get a torrent currently owned or downloading, given its crc.
+    pub async fn torrent(&self, crc: u32) -> Option<Arc<Mutex<Torrent>>> {
+        self.available_torrents.lock().await.get(&crc).map(Arc::clone)
+    }
+
+    // Dump the dht into its file. The dht is only locked while being
+    // serialized, not while it's written. A dump is serialized once the
+    // previous one is written, so an older snapshot never overwrites a newer.
+    pub async fn dump_dht(&self) -> AnyResult<()> {
+        let _guard = self.dht_dump.lock().await;
+        let json = self.dht.lock().await.to_json().await?;
+        let path = self.settings.read().await.dht_config_filename.clone();
+        tokio::fs::write(path, json).await?;
+        Ok(())
     }
 }
 
@@ -63,16 +255,25 @@ impl Context {
     pub fn new_test(self_id: u32, enable_lru: bool) -> Self {
         let mut dht = DistributedHashTable::new(self_id);
         dht.set_recent_peers_cache_enable(enable_lru);
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[0; 32]).expect("valid secret key");
+        let public = (&secret).into();
         Self {
-            dht,
-            available_torrents: HashMap::new(),
-            dht_config_filename: "".to_owned(),
-            working_directory: "".to_owned(),
-            slowness: None,
-            connection_timeout: Duration::from_millis(DEFAULT_CONNECTION_TIMEOUT_MS),
-            write_timeout: Duration::from_millis(DEFAULT_WRITE_TIMEOUT_MS),
-            read_timeout: Duration::from_millis(DEFAULT_READ_TIMEOUT_MS),
-            dht_dump_frequency: Duration::from_millis(DEFAULT_DHT_DUMP_FREQUENCY_MS),
+            addr: "127.0.0.1:4000".parse().expect("valid address"),
+            alt_addr: None,
+            public_addr: Mutex::new(PublicAddr::new()),
+            keypair: Keypair { secret, public },
+            dht: Mutex::new(dht),
+            available_torrents: Mutex::new(HashMap::new()),
+            tokens: Mutex::new(WriteTokens::new()),
+            mail_challenges: Mutex::new(MailChallenges::new()),
+            relays: Mutex::new(HashMap::new()),
+            public_keys: Mutex::new(PeerKeys::new()),
+            settings: RwLock::new(Settings::new("".to_owned(), "".to_owned())),
+            dht_dump: Mutex::new(()),
         }
     }
 }
+
+#[cfg(test)]
+#[path = "context_test.rs"]
+mod context_test;
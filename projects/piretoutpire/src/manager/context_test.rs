@@ -0,0 +1,87 @@
+use super::*;
+use crate::dht::peer_node::PeerEvent;
+use errors::AnyResult;
+
+#[tokio::test]
+async fn test_request_timeouts() -> AnyResult<()> {
+    let ctx = Context::new_test(0, false);
+    let peer_addr: SocketAddr = "127.0.0.1:4001".parse()?;
+    {
+        let mut dht = ctx.dht.lock().await;
+        dht.add_node(1, peer_addr).await;
+        dht.peer_rtt_measured(1, Duration::from_millis(10)).await;
+    }
+
+    // Each class has its own timeouts.
+    let control = (
+        Duration::from_millis(DEFAULT_WRITE_TIMEOUT_MS),
+        Duration::from_millis(DEFAULT_READ_TIMEOUT_MS),
+    );
+    let bulk = (
+        Duration::from_millis(DEFAULT_BULK_WRITE_TIMEOUT_MS),
+        Duration::from_millis(DEFAULT_BULK_READ_TIMEOUT_MS),
+    );
+    assert_eq!(control, ctx.request_timeouts(RpcClass::Control, peer_addr).await);
+    assert_eq!(bulk, ctx.request_timeouts(RpcClass::Bulk, peer_addr).await);
+
+    // Once adaptive, the read timeout of control requests follows the peer
+    // retransmission timeout, at least 1 sec, doubled after a timeout.
+    let control = (control.0, Duration::from_secs(5));
+    {
+        let mut settings = ctx.settings.write().await;
+        settings.adaptive_timeouts = true;
+        settings.read_timeout = control.1;
+    }
+    assert_eq!(
+        (control.0, Duration::from_secs(1)),
+        ctx.request_timeouts(RpcClass::Control, peer_addr).await
+    );
+    assert_eq!(bulk, ctx.request_timeouts(RpcClass::Bulk, peer_addr).await);
+    ctx.dht.lock().await.rate_peer(1, PeerEvent::Timeout).await;
+    assert_eq!(
+        (control.0, Duration::from_secs(2)),
+        ctx.request_timeouts(RpcClass::Control, peer_addr).await
+    );
+
+    // But stays within the configured bounds.
+    ctx.dht
+        .lock()
+        .await
+        .peer_rtt_measured(1, Duration::from_millis(20000))
+        .await;
+    assert_eq!(control, ctx.request_timeouts(RpcClass::Control, peer_addr).await);
+
+    // Unknown peers get the configured timeouts.
+    let unknown_addr = "127.0.0.1:4002".parse()?;
+    assert_eq!(
+        control,
+        ctx.request_timeouts(RpcClass::Control, unknown_addr).await
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_addr_towards() -> AnyResult<()> {
+    let mut ctx = Context::new_test(0, false);
+    let ipv4_peer: SocketAddr = "127.0.0.1:4001".parse()?;
+    let ipv6_peer: SocketAddr = "[::1]:4001".parse()?;
+    let other_addr: SocketAddr = "127.0.0.1:5000".parse()?;
+
+    // A single address is always told.
+    assert_eq!(ctx.addr, ctx.addr_towards(ipv6_peer, ctx.addr).await);
+
+    // A dual-stack peer tells the address on the family of the other one.
+    let alt_addr = "[::1]:4000".parse()?;
+    ctx.alt_addr = Some(alt_addr);
+    assert_eq!(ctx.addr, ctx.addr_towards(ipv4_peer, ctx.addr).await);
+    assert_eq!(alt_addr, ctx.addr_towards(ipv6_peer, ctx.addr).await);
+    assert_eq!(other_addr, ctx.addr_towards(ipv6_peer, other_addr).await);
+
+    // The public address is told rather than the listening one.
+    let public_addr = "1.2.3.4:4000".parse()?;
+    ctx.public_addr.lock().await.set_advertised(vec![public_addr]);
+    assert_eq!(public_addr, ctx.addr_towards(ipv4_peer, ctx.addr).await);
+
+    Ok(())
+}
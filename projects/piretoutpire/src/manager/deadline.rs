@@ -0,0 +1,146 @@
+use errors::AnyResult;
+use std::{
+    fmt::Display,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    sync::watch,
+    task::{JoinError, JoinHandle},
+    time::sleep,
+};
+
+// Reason why an operation has been stopped before its end.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OperationAborted {
+    // The operation took longer than its deadline.
+    DeadlineExceeded,
+    // The operation has been cancelled from the outside.
+    Cancelled,
+}
+
+impl Display for OperationAborted {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OperationAborted::DeadlineExceeded => write!(fmt, "operation deadline exceeded"),
+            OperationAborted::Cancelled => write!(fmt, "operation cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for OperationAborted {}
+
+// Allow to cancel operations from the outside. All clones share the same state:
+// cancelling one of them cancels the operations watching any other. Once
+// cancelled, a token stays cancelled.
+#[derive(Debug, Clone)]
+pub struct CancelToken {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CancelToken {
+    // Create a new token, not cancelled yet.
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        Self {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+
+    // Cancel all the operations watching this token.
+    pub fn cancel(&self) {
+        let _ = self.sender.send(true);
+    }
+
+    // Check if the token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    // Wait until the token is cancelled.
+    pub async fn cancelled(&self) {
+        let mut receiver = self.receiver.clone();
+        while !*receiver.borrow() {
+            // The sender lives as long as the token, it can't be dropped.
+            if receiver.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+// Limits applied to a whole operation, whatever the number of requests it
+// makes.
+#[derive(Debug, Clone, Default)]
+pub struct OperationLimits {
+    // Max duration of the operation, if any.
+    pub deadline: Option<Duration>,
+    // Token cancelling the operation.
+    pub cancel_token: CancelToken,
+}
+
+// Handle of a task spawned by an operation. The task is aborted as soon as its
+// handle is dropped, so the requests of an operation stopped by `bounded` don't
+// keep running in the background.
+pub struct ScopedTask<T>(JoinHandle<T>);
+
+impl<T> Future for ScopedTask<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+impl<T> Drop for ScopedTask<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// Spawn a task living as long as its handle, see ScopedTask.
+pub fn spawn_scoped<F>(future: F) -> ScopedTask<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    ScopedTask(tokio::spawn(future))
+}
+
+// Run an operation within the given limits. When the deadline is reached, or
+// the token is cancelled, the operation is dropped where it is: the requests it
+// waits for are abandoned, the tasks it spawned with `spawn_scoped` are aborted,
+// and the locks it holds are released.
+pub async fn bounded<T>(
+    limits: OperationLimits,
+    operation: impl Future<Output = AnyResult<T>>,
+) -> AnyResult<T> {
+    let deadline = async {
+        match limits.deadline {
+            Some(deadline) => sleep(deadline).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        biased;
+        _ = limits.cancel_token.cancelled() => Err(OperationAborted::Cancelled.into()),
+        _ = deadline => Err(OperationAborted::DeadlineExceeded.into()),
+        res = operation => res,
+    }
+}
+
+#[cfg(test)]
+#[path = "deadline_test.rs"]
+mod deadline_test;
@@ -0,0 +1,89 @@
+use super::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+fn aborted_with<T>(res: AnyResult<T>) -> Option<OperationAborted> {
+    res.err()
+        .and_then(|error| error.downcast_ref::<OperationAborted>().copied())
+}
+
+#[tokio::test]
+async fn test_bounded_operation_ends_in_time() -> AnyResult<()> {
+    let limits = OperationLimits {
+        deadline: Some(Duration::from_millis(200)),
+        ..OperationLimits::default()
+    };
+
+    let res = bounded(limits, async { Ok(42) }).await?;
+    assert_eq!(42, res);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bounded_operation_deadline() -> AnyResult<()> {
+    let limits = OperationLimits {
+        deadline: Some(Duration::from_millis(20)),
+        ..OperationLimits::default()
+    };
+
+    let res = bounded(limits, async {
+        sleep(Duration::from_secs(10)).await;
+        Ok(())
+    })
+    .await;
+    assert_eq!(Some(OperationAborted::DeadlineExceeded), aborted_with(res));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bounded_operation_cancelled() -> AnyResult<()> {
+    let limits = OperationLimits::default();
+    let token = limits.cancel_token.clone();
+    assert!(!token.is_cancelled());
+
+    tokio::spawn(async move {
+        sleep(Duration::from_millis(20)).await;
+        token.cancel();
+    });
+    let res = bounded(limits.clone(), async {
+        sleep(Duration::from_secs(10)).await;
+        Ok(())
+    })
+    .await;
+    assert_eq!(Some(OperationAborted::Cancelled), aborted_with(res));
+
+    // Once cancelled, the next operations are cancelled right away.
+    assert!(limits.cancel_token.is_cancelled());
+    let res = bounded(limits, async { Ok(()) }).await;
+    assert_eq!(Some(OperationAborted::Cancelled), aborted_with(res));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bounded_operation_aborts_its_tasks() -> AnyResult<()> {
+    let limits = OperationLimits {
+        deadline: Some(Duration::from_millis(20)),
+        ..OperationLimits::default()
+    };
+
+    let finished = Arc::new(AtomicBool::new(false));
+    let task_finished = Arc::clone(&finished);
+    let res = bounded(limits, async move {
+        spawn_scoped(async move {
+            sleep(Duration::from_millis(100)).await;
+            task_finished.store(true, Ordering::SeqCst);
+        })
+        .await?;
+        Ok(())
+    })
+    .await;
+    assert_eq!(Some(OperationAborted::DeadlineExceeded), aborted_with(res));
+
+    // The task spawned by the operation has been stopped with it.
+    sleep(Duration::from_millis(200)).await;
+    assert!(!finished.load(Ordering::SeqCst));
+
+    Ok(())
+}
@@ -0,0 +1,179 @@
+use super::{context::Context, deadline::spawn_scoped, find_node::sort_by_relevancy};
+use crate::{network::protocol::Peer, utils::distance};
+use errors::AnyResult;
+use std::{collections::HashSet, future::Future, net::SocketAddr, sync::Arc};
+use tokio::{self, sync::Mutex};
+
+// Number of disjoint paths used by a secure lookup.
+pub const NB_DISJOINT_PATHS: usize = 3;
+
+// Number of closest peers kept by each path.
+const PATH_RESULT_SIZE: usize = 4;
+
+// Search for the closest peers of a target through many disjoint paths, as
+// described by S/Kademlia. The initial peers are spread among the paths, and a
+// peer is never queried by two paths: a poisoned peer can only steer its own
+// path toward attacker-controlled nodes, while the others still reach the
+// honest closest peers.
+// Return the union of the closest peers found by each path, the closest first.
+pub async fn find_closest_nodes_disjoint<F, T>(
+    ctx: Arc<Context>,
+    mut initial_peers: Vec<Peer>,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    target: u32,
+    max_hop: Option<u32>,
+    query_func: F,
+) -> AnyResult<Vec<Peer>>
+where
+    F: FnMut(Arc<Context>, Peer, SocketAddr, u32, u32) -> T + Send + Copy + 'static,
+    T: Future<Output = AnyResult<Vec<Peer>>> + Send + 'static,
+{
+    initial_peers.sort_by_key(|peer| distance(peer.id, target));
+    initial_peers.dedup_by_key(|peer| peer.id);
+
+    // Spread the initial peers, so each path starts from a close one.
+    let mut paths = vec![Vec::new(); NB_DISJOINT_PATHS];
+    for (idx, peer) in initial_peers.into_iter().enumerate() {
+        paths[idx % NB_DISJOINT_PATHS].push(peer);
+    }
+
+    let visited = Arc::new(Mutex::new(HashSet::<u32>::new()));
+    visited.lock().await.insert(sender_id); // Let's avoid ourself.
+
+    let mut handles = Vec::new();
+    for path in paths.into_iter().filter(|path| !path.is_empty()) {
+        handles.push(spawn_scoped(lookup_path(
+            Arc::clone(&ctx),
+            path,
+            Arc::clone(&visited),
+            Peer {
+                id: sender_id,
+                addr: sender_addr,
+            },
+            target,
+            max_hop,
+            query_func,
+        )));
+    }
+
+    let mut res = Vec::new();
+    for handle in handles {
+        res.extend(handle.await??);
+    }
+    res.sort_by_key(|peer| distance(peer.id, target));
+    res.dedup_by_key(|peer| peer.id);
+
+    Ok(res)
+}
+
+// Run a single path of a disjoint lookup, hopping toward the target like a
+// classic find node. Peers already claimed by another path are skipped.
+// Return the closest peers which answered on this path.
+async fn lookup_path<F, T>(
+    ctx: Arc<Context>,
+    initial_peers: Vec<Peer>,
+    visited: Arc<Mutex<HashSet<u32>>>,
+    sender: Peer,
+    target: u32,
+    max_hop: Option<u32>,
+    mut query_func: F,
+) -> AnyResult<Vec<Peer>>
+where
+    F: FnMut(Arc<Context>, Peer, SocketAddr, u32, u32) -> T + Send + Copy + 'static,
+    T: Future<Output = AnyResult<Vec<Peer>>> + Send + 'static,
+{
+    let Peer {
+        id: sender_id,
+        addr: sender_addr,
+    } = sender;
+    let mut hop = 0;
+    let mut queue = initial_peers;
+    // Put the best at the end (easier for poping values).
+    sort_by_relevancy(Arc::clone(&ctx), &mut queue, target).await;
+    queue.reverse();
+    let mut best_distance = u32::MAX;
+    let mut answered = Vec::new();
+
+    loop {
+        hop += 1;
+
+        // Claim the 3 closest peers no path queried yet.
+        let mut to_query = Vec::new();
+        {
+            let mut visited = visited.lock().await;
+            while let Some(peer) = queue.pop() {
+                if !visited.insert(peer.id) {
+                    continue;
+                }
+                to_query.push(peer);
+                if to_query.len() >= 3 {
+                    break;
+                }
+            }
+        }
+        if to_query.is_empty() {
+            break;
+        }
+
+        let queries = to_query
+            .into_iter()
+            .map(|peer| {
+                let ctx = Arc::clone(&ctx);
+                spawn_scoped(async move {
+                    let res = query_func(ctx, peer.clone(), sender_addr, sender_id, target).await;
+                    (peer, res)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // Unreachable peers are just ignored.
+        let mut next_queue = Vec::new();
+        for handle in queries {
+            if let (peer, Ok(peers)) = handle.await? {
+                answered.push(peer);
+                next_queue.extend(peers);
+            }
+        }
+
+        // Keep only non-visited nodes, the closest first.
+        {
+            let visited = visited.lock().await;
+            next_queue.retain(|peer| !visited.contains(&peer.id));
+        }
+        next_queue.sort_by_key(|peer| distance(peer.id, target));
+        next_queue.dedup_by_key(|peer| peer.id);
+
+        // Let's check if the best peers is better than the previous hop.
+        let mut better_distance_found = false;
+        if let Some(peer) = next_queue.first() {
+            let distance = distance(peer.id, target);
+            if distance < best_distance {
+                best_distance = distance;
+                better_distance_found = true;
+            }
+        }
+
+        queue.extend(next_queue);
+        sort_by_relevancy(Arc::clone(&ctx), &mut queue, target).await;
+        queue.reverse();
+        queue.dedup_by_key(|peer| peer.id);
+
+        // Same strategies than find node.
+        if let Some(max_hop) = max_hop {
+            if hop >= max_hop || queue.is_empty() {
+                break;
+            }
+        } else if !better_distance_found {
+            break;
+        }
+    }
+
+    answered.sort_by_key(|peer| distance(peer.id, target));
+    answered.truncate(PATH_RESULT_SIZE);
+    Ok(answered)
+}
+
+#[cfg(test)]
+#[path = "disjoint_lookup_test.rs"]
+mod disjoint_lookup_test;
@@ -0,0 +1,125 @@
+use super::*;
+use std::collections::HashMap;
+
+// MOCKED FUNCTIONS ------------------------------------------------------------
+
+// Peers queried by the mocked network, to check the paths are disjoint.
+static QUERIED: std::sync::Mutex<Vec<u32>> = std::sync::Mutex::new(Vec::new());
+
+// Emulate the querying of another peer with find_node.
+async fn mock_find_node(
+    peers: HashMap<u32, Vec<u32>>,
+    ctx: Arc<Context>,
+    peer: Peer,
+    target: u32,
+) -> AnyResult<Vec<Peer>> {
+    QUERIED.lock().expect("poisoned").push(peer.id);
+
+    let mut nodes = peers.get(&peer.id).map_or(vec![], |vec| {
+        vec.iter()
+            .map(|peer_id| Peer {
+                id: *peer_id,
+                addr: "127.0.0.1:4000".parse().expect(""),
+            })
+            .collect()
+    });
+    nodes.sort_by_key(|peer| distance(peer.id, target));
+
+    // The peer just answered us, let's add him into our dht.
+    {
+        let mut dht = ctx.dht.lock().await;
+        dht.add_node(peer.id, peer.addr).await;
+    }
+
+    Ok(nodes.into_iter().take(4).collect())
+}
+
+// 1 is poisoned: it only knows attacker nodes (20 to 23), which look close to
+// 16 but only know each others. 2 and 3 are honest and lead to 16.
+// 1 -> 20 -> 21 <-> 22, 23
+// 2 -> 12 -> 17 -> 16
+// 3 -> 24 -> 17
+async fn mocked_query_find_node_poisoned(
+    ctx: Arc<Context>,
+    peer: Peer,
+    _sender_addr: SocketAddr,
+    _sender_id: u32,
+    target: u32,
+) -> AnyResult<Vec<Peer>> {
+    let mut peers = HashMap::<u32, Vec<u32>>::new();
+    peers.insert(1, vec![20, 21]);
+    peers.insert(20, vec![21, 22, 23]);
+    peers.insert(21, vec![20, 22, 23]);
+    peers.insert(22, vec![20, 21, 23]);
+    peers.insert(23, vec![20, 21, 22]);
+    peers.insert(2, vec![12]);
+    peers.insert(12, vec![2, 17]);
+    peers.insert(17, vec![12, 16, 24]);
+    peers.insert(16, vec![17]);
+    peers.insert(3, vec![24]);
+    peers.insert(24, vec![3, 17]);
+    mock_find_node(peers, ctx, peer, target).await
+}
+
+fn peer(id: u32) -> Peer {
+    Peer {
+        id,
+        addr: "127.0.0.1:4000".parse().expect(""),
+    }
+}
+
+// TESTS -----------------------------------------------------------------------
+
+#[tokio::test]
+async fn test_disjoint_lookup_poisoned() -> AnyResult<()> {
+    let sender_id = 0;
+    let sender_addr = "127.0.0.1:4000".parse()?;
+    let target = 16;
+
+    let ctx = Arc::new(Context::new_test(sender_id, false));
+    let res = find_closest_nodes_disjoint(
+        Arc::clone(&ctx),
+        vec![peer(1), peer(2), peer(3)],
+        sender_addr,
+        sender_id,
+        target,
+        None,
+        mocked_query_find_node_poisoned,
+    )
+    .await?;
+
+    // The poisoned path ends among the attacker nodes, but the honest ones
+    // still reach the target.
+    assert_eq!(Some(&peer(16)), res.first());
+    assert!(res.contains(&peer(17)));
+
+    // No peer has been queried twice.
+    let mut queried = QUERIED.lock().expect("poisoned").clone();
+    let nb_queried = queried.len();
+    queried.sort_unstable();
+    queried.dedup();
+    assert_eq!(nb_queried, queried.len());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_disjoint_lookup_no_peers() -> AnyResult<()> {
+    let sender_id = 0;
+    let sender_addr = "127.0.0.1:4000".parse()?;
+
+    let ctx = Arc::new(Context::new_test(sender_id, false));
+    let res = find_closest_nodes_disjoint(
+        ctx,
+        vec![],
+        sender_addr,
+        sender_id,
+        16,
+        None,
+        mocked_query_find_node_poisoned,
+    )
+    .await?;
+    assert!(res.is_empty());
+
+    Ok(())
+}
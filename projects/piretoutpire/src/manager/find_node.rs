@@ -1,19 +1,23 @@
-use super::{client::handle_find_node, context::Context};
-use crate::{network::protocol::Peer, utils::distance};
-use errors::{AnyError, AnyResult};
-use std::{collections::HashSet, future::Future, net::SocketAddr, ops::DerefMut, sync::Arc};
-use tokio::{
-    self,
-    net::TcpStream,
-    sync::Mutex,
-    time::{sleep, timeout},
+use super::{
+    client::{connect_to_peer, handle_find_node, handle_ping},
+    context::Context,
+    deadline::spawn_scoped,
+    lookup_trace::{LookupTrace, TraceHop, TracedQuery},
+    replication::replicate_to,
 };
+use crate::{
+    network::protocol::Peer,
+    utils::{distance, relevancy},
+};
+use errors::AnyResult;
+use std::{collections::HashSet, future::Future, net::SocketAddr, sync::Arc, time::Instant};
+use tokio::{self, net::TcpStream, sync::Mutex, time::sleep};
 
 // Search for a requested node until finding it. Will stop if the most closest
 // ones found in a row are not closer.
 // Return either the found peer or none.
 pub async fn find_closest_node<F, T>(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     initial_peer: Peer,
     sender_addr: SocketAddr,
     sender_id: u32,
@@ -22,9 +26,41 @@ pub async fn find_closest_node<F, T>(
     query_func: F,
 ) -> AnyResult<Option<Peer>>
 where
-    F: FnMut(Arc<Mutex<Context>>, Peer, SocketAddr, u32, u32) -> T + Send + Copy + 'static,
+    F: FnMut(Arc<Context>, Peer, SocketAddr, u32, u32) -> T + Send + Copy + 'static,
+    T: Future<Output = AnyResult<Vec<Peer>>> + Send + 'static,
+{
+    let (found_peer, _) = find_closest_node_traced(
+        ctx,
+        initial_peer,
+        sender_addr,
+        sender_id,
+        target,
+        max_hop,
+        query_func,
+    )
+    .await?;
+    Ok(found_peer)
+}
+
+// Same as find_closest_node, but also return the trace of the lookup: the
+// peers queried at each hop, what they answered, and the best distance found.
+pub async fn find_closest_node_traced<F, T>(
+    ctx: Arc<Context>,
+    initial_peer: Peer,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    target: u32,
+    max_hop: Option<u32>,
+    query_func: F,
+) -> AnyResult<(Option<Peer>, LookupTrace)>
+where
+    F: FnMut(Arc<Context>, Peer, SocketAddr, u32, u32) -> T + Send + Copy + 'static,
     T: Future<Output = AnyResult<Vec<Peer>>> + Send + 'static,
 {
+    let mut trace = LookupTrace {
+        target,
+        hops: Vec::new(),
+    };
     let mut hop = 0;
     let mut queue = vec![initial_peer];
     let mut visited = HashSet::<u32>::new();
@@ -39,7 +75,7 @@ where
         // non-visited peers in the queue. Will drain peer from the queue, until
         // the queue is empty or 3 non visited has been queried.
         // Will responsd with a queue containing from 0 up to 3*4 uniques nodes.
-        let next_queue = parallel_find_node(
+        let (next_queue, queries) = parallel_find_node(
             Arc::clone(&ctx),
             sender_addr,
             sender_id,
@@ -63,21 +99,27 @@ where
                 found_peer = Some(peer.clone());
             }
         }
+        trace.hops.push(TraceHop {
+            queries,
+            best_distance: Some(best_distance).filter(|distance| *distance != u32::MAX),
+        });
 
         queue.extend(next_queue);
         // Now let's sort the queue putting the best at the end (easier for
         // poping values).
-        queue.sort_by_key(|peer| distance(peer.id, target));
+        sort_by_relevancy(Arc::clone(&ctx), &mut queue, target).await;
         queue.reverse();
         queue.dedup_by_key(|peer| peer.id);
 
         // Handle strategy here.
         if let Some(max_hop) = max_hop {
-            // If we found the exact peer, put it in our dht, and stop searching.
+            // If we found the exact peer, put it in our dht once it confirms its
+            // id, and stop searching.
             if let Some(peer) = &found_peer {
-                let mut guard = ctx.lock().await;
-                let ctx = guard.deref_mut();
-                ctx.dht.add_node(peer.id, peer.addr).await;
+                if let Ok(connexion) = connect_to_peer(Arc::clone(&ctx), peer).await {
+                    let stream = Arc::new(Mutex::new(connexion));
+                    add_queried_peer(Arc::clone(&ctx), stream, peer.clone(), sender_addr, sender_id).await;
+                }
                 break;
             }
             // Hop strategy: stop after doing N hops, or if we found the target.
@@ -96,14 +138,29 @@ where
         }
     }
 
-    Ok(found_peer)
+    Ok((found_peer, trace))
+}
+
+// Sort peers by relevancy for a lookup toward a target, the best first. Among
+// peers at a similar distance, the ones we measured as the fastest come first.
+// Peers on an IP family we can't reach come last.
+pub async fn sort_by_relevancy(ctx: Arc<Context>, peers: &mut [Peer], target: u32) {
+    let families = ctx.families();
+    let srtts = ctx.dht.lock().await.peer_srtts().await;
+    peers.sort_by_key(|peer| {
+        (
+            !families.contains(peer.addr),
+            relevancy(peer.id, srtts.get(&peer.id).copied(), target),
+        )
+    });
 }
 
 // Will drain N values from the main task queues, launch them in parallel, then
 // return a flatten results of peers. Peers will be sorted by relevancy (closest
-// first).
+// first). What each peer answered is returned as well, unreachable peers are
+// only reported there.
 async fn parallel_find_node<F, T>(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     sender_addr: SocketAddr,
     sender_id: u32,
     target: u32,
@@ -111,12 +168,13 @@ async fn parallel_find_node<F, T>(
     visited: &mut HashSet<u32>,
     mut query_func: F,
     nb_parallel: usize,
-) -> AnyResult<Vec<Peer>>
+) -> AnyResult<(Vec<Peer>, Vec<TracedQuery>)>
 where
-    F: FnMut(Arc<Mutex<Context>>, Peer, SocketAddr, u32, u32) -> T + Send + Copy + 'static,
+    F: FnMut(Arc<Context>, Peer, SocketAddr, u32, u32) -> T + Send + Copy + 'static,
     T: Future<Output = AnyResult<Vec<Peer>>> + Send + 'static,
 {
     let mut next_queue = Vec::new();
+    let mut traced = Vec::new();
 
     // Just launch 3 concurrent tasks.
     let mut queries = Vec::new();
@@ -129,10 +187,10 @@ where
 
         nb_tasks += 1;
         let ctx = Arc::clone(&ctx);
-        let handle = tokio::spawn(async move {
-            let peer_id = peer.id;
-            let peers = query_func(ctx, peer, sender_addr, sender_id, target).await?;
-            Ok::<(u32, Vec<Peer>), AnyError>((peer_id, peers))
+        let handle = spawn_scoped(async move {
+            let started_at = Instant::now();
+            let res = query_func(ctx, peer.clone(), sender_addr, sender_id, target).await;
+            (peer, started_at.elapsed(), res)
         });
 
         queries.push(handle);
@@ -143,10 +201,22 @@ where
 
     // Now wait for all tasks to complete and put result in a queue.
     for handle in queries {
-        let (peer_id, peers) = handle.await??;
-        visited.insert(peer_id);
+        let (peer, latency, res) = handle.await?;
+        visited.insert(peer.id);
+        let (returned, error) = match res {
+            Ok(peers) => (peers, None),
+            // Unreachable peer, just ignore it.
+            Err(err) => (vec![], Some(err.to_string())),
+        };
         // Let's keep the 4 best nodes found.
-        next_queue.extend(peers.into_iter());
+        next_queue.extend(returned.iter().cloned());
+        traced.push(TracedQuery {
+            peer,
+            latency_ms: latency.as_millis() as u64,
+            returned,
+            has_value: false,
+            error,
+        });
     }
 
     // Keep only non-visited nodes.
@@ -156,46 +226,78 @@ where
     next_queue.sort_by_key(|peer| distance(peer.id, target));
     next_queue.dedup_by_key(|peer| peer.id);
 
-    Ok(next_queue)
+    Ok((next_queue, traced))
 }
 
-// Query the distant nodes and update the current context.
+// Query the distant nodes and update the current context. An unreachable peer
+// is an error, lookups just skip it.
 pub async fn query_find_node(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     peer: Peer,
     sender_addr: SocketAddr,
     sender_id: u32,
     target: u32,
 ) -> AnyResult<Vec<Peer>> {
-    let (slowness, connection_timeout) = {
-        let mut guard = ctx.lock().await;
-        let ctx = guard.deref_mut();
-        (ctx.slowness, ctx.connection_timeout)
-    };
+    let slowness = ctx.settings.read().await.slowness;
 
-    let connexion = timeout(connection_timeout, TcpStream::connect(peer.addr)).await;
-    match connexion {
-        Ok(Ok(connexion)) => {
-            let stream = Arc::new(Mutex::new(connexion));
-            if let Some(wait_time) = slowness {
-                sleep(wait_time).await;
-            }
-            let peers = handle_find_node(Arc::clone(&ctx), stream, sender_addr, sender_id, target).await?;
+    let connexion = connect_to_peer(Arc::clone(&ctx), &peer).await?;
+    let stream = Arc::new(Mutex::new(connexion));
+    if let Some(wait_time) = slowness {
+        sleep(wait_time).await;
+    }
+    let peers = handle_find_node(
+        Arc::clone(&ctx),
+        Arc::clone(&stream),
+        peer.id,
+        sender_addr,
+        sender_id,
+        target,
+    )
+    .await?;
 
-            // The peer just answered us, let's add him into our dht.
-            {
-                let mut guard = ctx.lock().await;
-                let ctx = guard.deref_mut();
-                ctx.dht.add_node(peer.id, peer.addr).await;
-            }
+    // The peer just answered us, let's add him into our dht.
+    add_queried_peer(Arc::clone(&ctx), stream, peer, sender_addr, sender_id).await;
 
-            Ok(peers)
-        }
-        _ => {
-            // Peer is not connected, or timeout. Just ignore it.
-            Ok(vec![])
+    Ok(peers)
+}
+
+// Add a peer which answered one of our queries into our dht. Its id is only
+// claimed by the peer which referred it to us: unless we already know it at
+// this address, it must first answer a ping signed for this id with this very
+// id. A lying referrer then can't fill our routing table with ids at addresses
+// which don't own them, the peers it gives are only lookup candidates.
+pub async fn add_queried_peer(
+    ctx: Arc<Context>,
+    stream: Arc<Mutex<TcpStream>>,
+    peer: Peer,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+) {
+    let is_known = ctx.dht.lock().await.peer_id_at(peer.addr).await == Some(peer.id);
+    if !is_known {
+        match handle_ping(Arc::clone(&ctx), stream, Some(peer.id), sender_addr, sender_id).await {
+            Ok(id) if id == peer.id => {}
+            _ => return,
         }
     }
+
+    let replication = ctx.dht.lock().await.add_node(peer.id, peer.addr).await;
+    replicate_to(ctx, peer, replication);
+}
+
+// Same as query_find_node, but the target itself is never asked, as if it
+// didn't answer: a lookup for a peer then doesn't tell it who's looking for it.
+pub async fn query_find_node_around(
+    ctx: Arc<Context>,
+    peer: Peer,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    target: u32,
+) -> AnyResult<Vec<Peer>> {
+    if peer.id == target {
+        return Ok(vec![]);
+    }
+    query_find_node(ctx, peer, sender_addr, sender_id, target).await
 }
 
 #[cfg(test)]
@@ -1,12 +1,17 @@
 use super::*;
+use crate::network::protocol::Command;
 use std::collections::HashMap;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
 
 // MOCKED FUNCTIONS ------------------------------------------------------------
 
 // Emulate the querying of another peer with find_node.
 async fn mock_find_node(
     peers: HashMap<u32, Vec<u32>>,
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     peer: Peer,
     target: u32,
 ) -> AnyResult<Vec<Peer>> {
@@ -22,9 +27,8 @@ async fn mock_find_node(
 
     // The peer just answered us, let's add him into our dht.
     {
-        let mut guard = ctx.lock().await;
-        let ctx = guard.deref_mut();
-        ctx.dht.add_node(peer.id, peer.addr).await;
+        let mut dht = ctx.dht.lock().await;
+        dht.add_node(peer.id, peer.addr).await;
     }
 
     Ok(nodes.into_iter().take(4).collect())
@@ -37,7 +41,7 @@ async fn mock_find_node(
 //     \      |
 // 9    6     8
 async fn mocked_query_find_node_small(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     peer: Peer,
     _sender_addr: SocketAddr,
     _sender_id: u32,
@@ -58,7 +62,7 @@ async fn mocked_query_find_node_small(
 
 // A big mock-up with many interconnected nodes.
 async fn mocked_query_find_node_big(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     peer: Peer,
     _sender_addr: SocketAddr,
     _sender_id: u32,
@@ -85,7 +89,7 @@ async fn mocked_query_find_node_big(
 
 // A big mock-up with some interconnected nodes.
 async fn mocked_query_find_node_partial(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     peer: Peer,
     _sender_addr: SocketAddr,
     _sender_id: u32,
@@ -110,6 +114,20 @@ async fn mocked_query_find_node_partial(
     mock_find_node(peers, ctx, peer, target).await
 }
 
+// Same network as the small one, but 2 is unreachable.
+async fn mocked_query_find_node_unreachable(
+    ctx: Arc<Context>,
+    peer: Peer,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    target: u32,
+) -> AnyResult<Vec<Peer>> {
+    if peer.id == 2 {
+        errors::bail!("peer 2 is unreachable");
+    }
+    mocked_query_find_node_small(ctx, peer, sender_addr, sender_id, target).await
+}
+
 // TESTS -----------------------------------------------------------------------
 
 #[tokio::test]
@@ -120,7 +138,7 @@ async fn test_find_node_itself() -> AnyResult<()> {
     let target = 0;
 
     {
-        let ctx = Arc::new(Mutex::new(Context::new_test(sender_id, false)));
+        let ctx = Arc::new(Context::new_test(sender_id, false));
         let res = find_closest_node(
             ctx,
             Peer {
@@ -138,7 +156,7 @@ async fn test_find_node_itself() -> AnyResult<()> {
     }
 
     {
-        let ctx = Arc::new(Mutex::new(Context::new_test(sender_id, false)));
+        let ctx = Arc::new(Context::new_test(sender_id, false));
         let res = find_closest_node(
             ctx,
             Peer {
@@ -166,7 +184,7 @@ async fn test_find_node_1_roundtrip() -> AnyResult<()> {
     let target = 2;
 
     {
-        let ctx = Arc::new(Mutex::new(Context::new_test(sender_id, false)));
+        let ctx = Arc::new(Context::new_test(sender_id, false));
         let res = find_closest_node(
             Arc::clone(&ctx),
             Peer {
@@ -182,13 +200,12 @@ async fn test_find_node_1_roundtrip() -> AnyResult<()> {
         .await?;
         assert_eq!(target, res.expect("should be there").id);
 
-        let mut guard = ctx.lock().await;
-        let ctx = guard.deref_mut();
-        assert_eq!(vec![1, 2, 3, 5], ctx.dht.peer_ids().await);
+        let dht = ctx.dht.lock().await;
+        assert_eq!(vec![1, 2, 3, 5], dht.peer_ids().await);
     }
 
     {
-        let ctx = Arc::new(Mutex::new(Context::new_test(sender_id, false)));
+        let ctx = Arc::new(Context::new_test(sender_id, false));
         let res = find_closest_node(
             Arc::clone(&ctx),
             Peer {
@@ -204,9 +221,10 @@ async fn test_find_node_1_roundtrip() -> AnyResult<()> {
         .await?;
         assert_eq!(target, res.expect("should be there").id);
 
-        let mut guard = ctx.lock().await;
-        let ctx = guard.deref_mut();
-        assert_eq!(vec![1, 2], ctx.dht.peer_ids().await);
+        // The target is only known from a referral, it's not added until it
+        // confirms its id, and nothing answers at its mocked address.
+        let dht = ctx.dht.lock().await;
+        assert_eq!(vec![1], dht.peer_ids().await);
     }
 
     Ok(())
@@ -220,7 +238,7 @@ async fn test_find_node_max_roundtrip() -> AnyResult<()> {
     let target = 1;
 
     {
-        let ctx = Arc::new(Mutex::new(Context::new_test(sender_id, false)));
+        let ctx = Arc::new(Context::new_test(sender_id, false));
         let res = find_closest_node(
             Arc::clone(&ctx),
             Peer {
@@ -238,7 +256,7 @@ async fn test_find_node_max_roundtrip() -> AnyResult<()> {
     }
 
     {
-        let ctx = Arc::new(Mutex::new(Context::new_test(sender_id, false)));
+        let ctx = Arc::new(Context::new_test(sender_id, false));
         let res = find_closest_node(
             Arc::clone(&ctx),
             Peer {
@@ -266,7 +284,7 @@ async fn test_find_node_unbalanced_roundtrip() -> AnyResult<()> {
     let target = 8;
 
     {
-        let ctx = Arc::new(Mutex::new(Context::new_test(sender_id, false)));
+        let ctx = Arc::new(Context::new_test(sender_id, false));
         let res = find_closest_node(
             Arc::clone(&ctx),
             Peer {
@@ -295,7 +313,7 @@ async fn test_find_node_unbalanced_roundtrip() -> AnyResult<()> {
     }
 
     {
-        let ctx = Arc::new(Mutex::new(Context::new_test(sender_id, false)));
+        let ctx = Arc::new(Context::new_test(sender_id, false));
         let res = find_closest_node(
             Arc::clone(&ctx),
             Peer {
@@ -326,7 +344,7 @@ async fn test_find_node_max_roundtrip_in_a_big_mock_not_found() -> AnyResult<()>
     let target = 47;
 
     {
-        let ctx = Arc::new(Mutex::new(Context::new_test(sender_id, false)));
+        let ctx = Arc::new(Context::new_test(sender_id, false));
         let res = find_closest_node(
             Arc::clone(&ctx),
             Peer {
@@ -343,15 +361,14 @@ async fn test_find_node_max_roundtrip_in_a_big_mock_not_found() -> AnyResult<()>
         // Will be not found, closest should be 43.
         assert!(res.is_none());
 
-        let mut guard = ctx.lock().await;
-        let ctx = guard.deref_mut();
+        let dht = ctx.dht.lock().await;
         // Node 1 (starting point) and its next 3 nodes (34, 43, 60) should be added
         // in the dht.
-        assert_eq!(vec![1, 34, 43, 60], ctx.dht.peer_ids().await);
+        assert_eq!(vec![1, 34, 43, 60], dht.peer_ids().await);
     }
 
     {
-        let ctx = Arc::new(Mutex::new(Context::new_test(sender_id, false)));
+        let ctx = Arc::new(Context::new_test(sender_id, false));
         let res = find_closest_node(
             Arc::clone(&ctx),
             Peer {
@@ -368,15 +385,14 @@ async fn test_find_node_max_roundtrip_in_a_big_mock_not_found() -> AnyResult<()>
         // Will be not found, closest should be 43.
         assert!(res.is_none());
 
-        let mut guard = ctx.lock().await;
-        let ctx = guard.deref_mut();
+        let dht = ctx.dht.lock().await;
         // Should be [1, 34, 43, 49, 60, 62], but the routing table is full so
         // 49 is not added.
-        assert_eq!(vec![1, 34, 43, 60, 62], ctx.dht.peer_ids().await);
+        assert_eq!(vec![1, 34, 43, 60, 62], dht.peer_ids().await);
     }
 
     {
-        let ctx = Arc::new(Mutex::new(Context::new_test(sender_id, true)));
+        let ctx = Arc::new(Context::new_test(sender_id, true));
         let res = find_closest_node(
             Arc::clone(&ctx),
             Peer {
@@ -393,10 +409,9 @@ async fn test_find_node_max_roundtrip_in_a_big_mock_not_found() -> AnyResult<()>
         // Will be not found, closest should be 43.
         assert!(res.is_none());
 
-        let mut guard = ctx.lock().await;
-        let ctx = guard.deref_mut();
+        let dht = ctx.dht.lock().await;
         // 49 is there thanks to the lru cache.
-        assert_eq!(vec![1, 34, 43, 49, 60, 62], ctx.dht.peer_ids().await);
+        assert_eq!(vec![1, 34, 43, 49, 60, 62], dht.peer_ids().await);
     }
 
     Ok(())
@@ -410,7 +425,7 @@ async fn test_find_node_max_roundtrip_in_a_big_mock_found() -> AnyResult<()> {
     let target = 43;
 
     {
-        let ctx = Arc::new(Mutex::new(Context::new_test(sender_id, false)));
+        let ctx = Arc::new(Context::new_test(sender_id, false));
         let res = find_closest_node(
             Arc::clone(&ctx),
             Peer {
@@ -427,15 +442,14 @@ async fn test_find_node_max_roundtrip_in_a_big_mock_found() -> AnyResult<()> {
         // 43 will be found!
         assert_eq!(target, res.expect("should be there").id);
 
-        let mut guard = ctx.lock().await;
-        let ctx = guard.deref_mut();
+        let dht = ctx.dht.lock().await;
         // Node 1 (starting point) and its next 3 nodes (34, 43, 60) should be added
         // in the dht.
-        assert_eq!(vec![1, 34, 43, 60], ctx.dht.peer_ids().await);
+        assert_eq!(vec![1, 34, 43, 60], dht.peer_ids().await);
     }
 
     {
-        let ctx = Arc::new(Mutex::new(Context::new_test(sender_id, false)));
+        let ctx = Arc::new(Context::new_test(sender_id, false));
         let res = find_closest_node(
             Arc::clone(&ctx),
             Peer {
@@ -452,10 +466,9 @@ async fn test_find_node_max_roundtrip_in_a_big_mock_found() -> AnyResult<()> {
         // 43 will be found!
         assert_eq!(target, res.expect("should be there").id);
 
-        let mut guard = ctx.lock().await;
-        let ctx = guard.deref_mut();
+        let dht = ctx.dht.lock().await;
         // Node 1 (starting point) and only the found node.
-        assert_eq!(vec![1, 43], ctx.dht.peer_ids().await);
+        assert_eq!(vec![1], dht.peer_ids().await);
     }
 
     Ok(())
@@ -469,7 +482,7 @@ async fn test_find_node_max_roundtrip_in_a_partial_mock_found() -> AnyResult<()>
     let target = 43;
 
     {
-        let ctx = Arc::new(Mutex::new(Context::new_test(sender_id, false)));
+        let ctx = Arc::new(Context::new_test(sender_id, false));
         let res = find_closest_node(
             Arc::clone(&ctx),
             Peer {
@@ -486,15 +499,14 @@ async fn test_find_node_max_roundtrip_in_a_partial_mock_found() -> AnyResult<()>
         // 43 will not be found, because the graph is too much partial.
         assert!(res.is_none());
 
-        let mut guard = ctx.lock().await;
-        let ctx = guard.deref_mut();
+        let dht = ctx.dht.lock().await;
         // Node 1 (starting point) and its next 3 nodes (34, 43, 60) should be added
         // in the dht.
-        assert_eq!(vec![1, 4, 5, 6, 12, 13, 15], ctx.dht.peer_ids().await);
+        assert_eq!(vec![1, 4, 5, 6, 12, 13, 15], dht.peer_ids().await);
     }
 
     {
-        let ctx = Arc::new(Mutex::new(Context::new_test(sender_id, false)));
+        let ctx = Arc::new(Context::new_test(sender_id, false));
         let res = find_closest_node(
             Arc::clone(&ctx),
             Peer {
@@ -511,10 +523,91 @@ async fn test_find_node_max_roundtrip_in_a_partial_mock_found() -> AnyResult<()>
         // 43 will be found!
         assert_eq!(target, res.expect("should be there").id);
 
-        let mut guard = ctx.lock().await;
-        let ctx = guard.deref_mut();
-        assert_eq!(vec![1, 4, 5, 6, 12, 13, 15, 18, 19, 43], ctx.dht.peer_ids().await);
+        let dht = ctx.dht.lock().await;
+        assert_eq!(vec![1, 4, 5, 6, 12, 13, 15, 18, 19], dht.peer_ids().await);
     }
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_find_node_trace() -> AnyResult<()> {
+    let sender_id = 0;
+    let sender_addr = "127.0.0.1:4000".parse()?;
+    let peer = |id| Peer {
+        id,
+        addr: "127.0.0.1:4000".parse().expect(""),
+    };
+    let target = 7;
+
+    let ctx = Arc::new(Context::new_test(sender_id, false));
+    let (res, trace) = find_closest_node_traced(
+        Arc::clone(&ctx),
+        peer(1),
+        sender_addr,
+        sender_id,
+        target,
+        None,
+        mocked_query_find_node_unreachable,
+    )
+    .await?;
+    // The unreachable peer doesn't stop the lookup.
+    assert_eq!(Some(peer(7)), res);
+    assert_eq!(target, trace.target);
+
+    // 1 is asked first, and answers with its peers, the closest first.
+    let first_hop = &trace.hops[0];
+    assert_eq!(1, first_hop.queries.len());
+    assert_eq!(peer(1), first_hop.queries[0].peer);
+    assert_eq!(vec![peer(5), peer(3), peer(2)], first_hop.queries[0].returned);
+    assert_eq!(Some(2), first_hop.best_distance);
+
+    // 2 is then asked alongside 5 and 3, and its error is reported.
+    let second_hop = &trace.hops[1];
+    let unreachable = second_hop
+        .queries
+        .iter()
+        .find(|query| query.peer.id == 2)
+        .expect("2 has been queried");
+    assert!(unreachable.returned.is_empty());
+    assert_eq!(Some("peer 2 is unreachable".to_owned()), unreachable.error);
+
+    assert_eq!(Some(0), trace.hops.last().and_then(|hop| hop.best_distance));
+
+    Ok(())
+}
+
+// Answer a single ping with the given id, as a peer listening on a local port.
+async fn fake_peer(id: u32) -> AnyResult<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        if let Ok((mut stream, observed)) = listener.accept().await {
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await;
+            let response: Vec<u8> = Command::PingResponse(id, observed).into();
+            let _ = stream.write_all(&response).await;
+        }
+    });
+    Ok(addr)
+}
+
+#[tokio::test]
+async fn test_referred_peer_added_once_verified() -> AnyResult<()> {
+    let sender_addr = "127.0.0.1:4000".parse()?;
+    let ctx = Arc::new(Context::new_test(0, false));
+
+    // A referrer claimed the id 2 for the peer at this address, but it's 3.
+    let addr = fake_peer(3).await?;
+    let stream = Arc::new(Mutex::new(TcpStream::connect(addr).await?));
+    add_queried_peer(Arc::clone(&ctx), stream, Peer { id: 2, addr }, sender_addr, 0).await;
+    assert!(ctx.dht.lock().await.peer_ids().await.is_empty());
+
+    // The peer confirms the id it's been referred with.
+    let addr = fake_peer(4).await?;
+    let stream = Arc::new(Mutex::new(TcpStream::connect(addr).await?));
+    add_queried_peer(Arc::clone(&ctx), stream, Peer { id: 4, addr }, sender_addr, 0).await;
+    assert_eq!(vec![4], ctx.dht.lock().await.peer_ids().await);
+
+    Ok(())
+}
@@ -0,0 +1,535 @@
+use super::{
+    client::{connect_to_peer, handle_find_value, handle_get_mutable},
+    context::Context,
+    deadline::spawn_scoped,
+    find_node::{add_queried_peer, sort_by_relevancy},
+    lookup_trace::{LookupTrace, TraceHop, TracedQuery},
+};
+use crate::{
+    dht::{
+        immutable::{immutable_key, is_immutable_value_of, ImmutableHash},
+        mutable::{mutable_key, MutableHash},
+    },
+    network::protocol::{FoundMutable, FoundValue, MutableValue, Peer, StoredValue},
+    utils::distance,
+};
+use errors::{bail, AnyResult};
+use std::{
+    collections::HashSet,
+    future::Future,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{self, sync::Mutex, time::sleep};
+
+// Max number of pages of values fetched from a single peer, so a peer can't
+// keep us busy forever.
+const MAX_VALUE_PAGES: u32 = 8;
+
+// Answer of a peer during a value lookup: either what it holds for the key, or
+// the closest peers it knows from the key.
+pub trait LookupAnswer {
+    type Value;
+
+    // Get the value held by the peer, or the peers to query next.
+    fn into_value(self) -> Result<Self::Value, Vec<Peer>>;
+
+    // Merge a value found on a peer with what has already been found.
+    fn merge(found: &mut Self::Value, value: Self::Value);
+}
+
+impl LookupAnswer for FoundValue {
+    type Value = Vec<StoredValue>;
+
+    fn into_value(self) -> Result<Vec<StoredValue>, Vec<Peer>> {
+        match self {
+            FoundValue::Values(values, _) if !values.is_empty() => Ok(values),
+            FoundValue::Values(_, _) => Err(vec![]),
+            FoundValue::ClosestPeers(peers) => Err(peers),
+        }
+    }
+
+    // The same value may be held by many peers, only the most recent timestamp
+    // is kept.
+    fn merge(found: &mut Vec<StoredValue>, values: Vec<StoredValue>) {
+        for value in values {
+            let known = found
+                .iter_mut()
+                .find(|known| known.publisher == value.publisher && known.value == value.value);
+            match known {
+                Some(known) => known.timestamp = known.timestamp.max(value.timestamp),
+                None => found.push(value),
+            }
+        }
+    }
+}
+
+impl LookupAnswer for FoundMutable {
+    type Value = MutableValue;
+
+    fn into_value(self) -> Result<MutableValue, Vec<Peer>> {
+        match self {
+            FoundMutable::Value(value) => Ok(value),
+            FoundMutable::ClosestPeers(peers) => Err(peers),
+        }
+    }
+
+    // Peers may hold different versions, only the most recent one is kept.
+    // Values are checked against their hash beforehand, so they all have the
+    // same owner.
+    fn merge(found: &mut MutableValue, value: MutableValue) {
+        if value.public_key == found.public_key && value.seq > found.seq {
+            *found = value;
+        }
+    }
+}
+
+// Outcome of a value lookup.
+#[derive(Debug, Eq, PartialEq)]
+pub struct ValueLookup<V> {
+    // What has been found for the key, if any peer had it.
+    pub value: Option<V>,
+    // The closest queried peer which didn't have the value. That's where the
+    // value should be cached, so the next lookups will end sooner.
+    pub closest_without_value: Option<Peer>,
+}
+
+impl<V> Default for ValueLookup<V> {
+    fn default() -> Self {
+        Self {
+            value: None,
+            closest_without_value: None,
+        }
+    }
+}
+
+// Search for the value of a key, hopping from peer to peer. Each peer either
+// answers with its value, which ends the search, or with the closest peers it
+// knows from the key, which are queried next. Will stop if the closest peers
+// found in a row are not closer.
+pub async fn find_value_iteratively<F, T, A>(
+    ctx: Arc<Context>,
+    initial_peers: Vec<Peer>,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    key: u32,
+    max_hop: Option<u32>,
+    query_func: F,
+) -> AnyResult<ValueLookup<A::Value>>
+where
+    F: FnMut(Arc<Context>, Peer, SocketAddr, u32, u32) -> T + Send + Copy + 'static,
+    T: Future<Output = AnyResult<A>> + Send + 'static,
+    A: LookupAnswer + Send + 'static,
+{
+    let (lookup, _) = find_value_iteratively_traced(
+        ctx,
+        initial_peers,
+        sender_addr,
+        sender_id,
+        key,
+        max_hop,
+        query_func,
+    )
+    .await?;
+    Ok(lookup)
+}
+
+// Same as find_value_iteratively, but also return the trace of the lookup: the
+// peers queried at each hop, what they answered, and the best distance found.
+pub async fn find_value_iteratively_traced<F, T, A>(
+    ctx: Arc<Context>,
+    initial_peers: Vec<Peer>,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    key: u32,
+    max_hop: Option<u32>,
+    mut query_func: F,
+) -> AnyResult<(ValueLookup<A::Value>, LookupTrace)>
+where
+    F: FnMut(Arc<Context>, Peer, SocketAddr, u32, u32) -> T + Send + Copy + 'static,
+    T: Future<Output = AnyResult<A>> + Send + 'static,
+    A: LookupAnswer + Send + 'static,
+{
+    let mut trace = LookupTrace {
+        target: key,
+        hops: Vec::new(),
+    };
+    let mut hop = 0;
+    let mut queue = initial_peers;
+    sort_by_relevancy(Arc::clone(&ctx), &mut queue, key).await;
+    queue.reverse();
+    let mut visited = HashSet::<u32>::new();
+    visited.insert(sender_id); // Let's avoid ourself.
+    let mut best_distance = u32::MAX;
+    let mut lookup = ValueLookup::default();
+
+    loop {
+        hop += 1;
+
+        // Query the 3 closest non-visited peers at the same time.
+        let mut queries = Vec::new();
+        while let Some(peer) = queue.pop() {
+            if !visited.insert(peer.id) {
+                continue;
+            }
+
+            let ctx = Arc::clone(&ctx);
+            queries.push(spawn_scoped(async move {
+                let started_at = Instant::now();
+                let res = query_func(ctx, peer.clone(), sender_addr, sender_id, key).await;
+                (peer, started_at.elapsed(), res)
+            }));
+            if queries.len() >= 3 {
+                break;
+            }
+        }
+        if queries.is_empty() {
+            break;
+        }
+
+        let mut next_queue = Vec::new();
+        let mut traced = Vec::new();
+        for handle in queries {
+            let (peer, latency, res) = handle.await?;
+            let query = record_answer(&mut lookup, peer, latency, res, key);
+            next_queue.extend(query.returned.iter().cloned());
+            traced.push(query);
+        }
+
+        // Keep only non-visited nodes, the closest first.
+        next_queue.retain(|peer| !visited.contains(&peer.id));
+        next_queue.sort_by_key(|peer| distance(peer.id, key));
+        next_queue.dedup_by_key(|peer| peer.id);
+
+        // Let's check if the best peers is better than the previous hop.
+        let mut better_distance_found = false;
+        if let Some(peer) = next_queue.first() {
+            let distance = distance(peer.id, key);
+            if distance < best_distance {
+                best_distance = distance;
+                better_distance_found = true;
+            }
+        }
+        trace.hops.push(TraceHop {
+            queries: traced,
+            best_distance: Some(best_distance).filter(|distance| *distance != u32::MAX),
+        });
+        if lookup.value.is_some() {
+            break;
+        }
+
+        queue.extend(next_queue);
+        // Now let's sort the queue putting the best at the end (easier for
+        // poping values).
+        sort_by_relevancy(Arc::clone(&ctx), &mut queue, key).await;
+        queue.reverse();
+        queue.dedup_by_key(|peer| peer.id);
+
+        // Handle strategy here.
+        if let Some(max_hop) = max_hop {
+            // Hop strategy: stop after doing N hops, or if all nodes have been
+            // visited.
+            if hop >= max_hop || queue.is_empty() {
+                break;
+            }
+        } else if !better_distance_found {
+            // Classic strategy: stop when the next route is not closer to the
+            // key than this one.
+            break;
+        }
+    }
+
+    Ok((lookup, trace))
+}
+
+// Ask a known set of peers for the value of a key, without hopping any further.
+// That's the last step of a disjoint lookup, once the closest peers of the key
+// have been found through independent paths. The queries are traced as a
+// single hop.
+pub async fn find_value_from_peers<F, T, A>(
+    ctx: Arc<Context>,
+    peers: Vec<Peer>,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    key: u32,
+    mut query_func: F,
+) -> AnyResult<(ValueLookup<A::Value>, LookupTrace)>
+where
+    F: FnMut(Arc<Context>, Peer, SocketAddr, u32, u32) -> T + Send + Copy + 'static,
+    T: Future<Output = AnyResult<A>> + Send + 'static,
+    A: LookupAnswer + Send + 'static,
+{
+    let queries = peers
+        .into_iter()
+        .filter(|peer| peer.id != sender_id)
+        .map(|peer| {
+            let ctx = Arc::clone(&ctx);
+            spawn_scoped(async move {
+                let started_at = Instant::now();
+                let res = query_func(ctx, peer.clone(), sender_addr, sender_id, key).await;
+                (peer, started_at.elapsed(), res)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut lookup = ValueLookup::default();
+    let mut traced = Vec::new();
+    for handle in queries {
+        let (peer, latency, res) = handle.await?;
+        traced.push(record_answer(&mut lookup, peer, latency, res, key));
+    }
+
+    let trace = LookupTrace {
+        target: key,
+        hops: vec![TraceHop {
+            best_distance: traced.iter().map(|query| distance(query.peer.id, key)).min(),
+            queries: traced,
+        }],
+    };
+    Ok((lookup, trace))
+}
+
+// Merge what a peer answered into a lookup, and get it as a traced query. The
+// peers it gave instead of the value are the returned ones.
+fn record_answer<A: LookupAnswer>(
+    lookup: &mut ValueLookup<A::Value>,
+    peer: Peer,
+    latency: Duration,
+    res: AnyResult<A>,
+    key: u32,
+) -> TracedQuery {
+    let (returned, has_value, error) = match res.map(A::into_value) {
+        Ok(Ok(value)) => {
+            match lookup.value.as_mut() {
+                Some(found) => A::merge(found, value),
+                None => lookup.value = Some(value),
+            }
+            (vec![], true, None)
+        }
+        Ok(Err(peers)) => {
+            let is_closer = lookup
+                .closest_without_value
+                .as_ref()
+                .map_or(true, |best| distance(peer.id, key) < distance(best.id, key));
+            if is_closer {
+                lookup.closest_without_value = Some(peer.clone());
+            }
+            (peers, false, None)
+        }
+        // Unreachable peer, just ignore it.
+        Err(err) => (vec![], false, Some(err.to_string())),
+    };
+
+    TracedQuery {
+        peer,
+        latency_ms: latency.as_millis() as u64,
+        returned,
+        has_value,
+        error,
+    }
+}
+
+// Query the distant nodes and update the current context. Unlike find node, an
+// unreachable peer is an error, so it won't be mistaken for a peer which just
+// doesn't have the value.
+pub async fn query_find_value(
+    ctx: Arc<Context>,
+    peer: Peer,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    key: u32,
+) -> AnyResult<FoundValue> {
+    let slowness = ctx.settings.read().await.slowness;
+
+    let connexion = connect_to_peer(Arc::clone(&ctx), &peer).await?;
+    let stream = Arc::new(Mutex::new(connexion));
+    if let Some(wait_time) = slowness {
+        sleep(wait_time).await;
+    }
+    let mut found = handle_find_value(
+        Arc::clone(&ctx),
+        Arc::clone(&stream),
+        peer.id,
+        sender_addr,
+        sender_id,
+        key,
+        0,
+    )
+    .await?;
+
+    // Get the remaining pages of values, if any. When a page can't be fetched,
+    // the values of the previous ones are still kept.
+    if let FoundValue::Values(values, nb_pages) = &mut found {
+        for page in 1..(*nb_pages).min(MAX_VALUE_PAGES) {
+            match handle_find_value(
+                Arc::clone(&ctx),
+                Arc::clone(&stream),
+                peer.id,
+                sender_addr,
+                sender_id,
+                key,
+                page,
+            )
+            .await
+            {
+                Ok(FoundValue::Values(next_values, _)) => values.extend(next_values),
+                Ok(FoundValue::ClosestPeers(_)) | Err(_) => break,
+            }
+        }
+    }
+    let found = check_signed_values(Arc::clone(&ctx), peer.clone(), key, found).await?;
+
+    // The peer just answered us, let's add him into our dht.
+    add_queried_peer(Arc::clone(&ctx), stream, peer, sender_addr, sender_id).await;
+
+    Ok(found)
+}
+
+// Query the values of an immutable hash on a distant node, at its key. Values
+// which don't match the hash are dropped, and the peer sending them is flagged
+// as a liar. If nothing valid remains, its answer is an error, so the lookup
+// goes on.
+pub async fn query_find_immutable(
+    ctx: Arc<Context>,
+    peer: Peer,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    hash: ImmutableHash,
+) -> AnyResult<FoundValue> {
+    let key = immutable_key(&hash);
+    let found = query_find_value(Arc::clone(&ctx), peer.clone(), sender_addr, sender_id, key).await?;
+    check_immutable_values(ctx, peer, hash, found).await
+}
+
+// Keep only the values signed by their publisher, and penalise the peer who
+// sent the others.
+async fn check_signed_values(
+    ctx: Arc<Context>,
+    peer: Peer,
+    key: u32,
+    found: FoundValue,
+) -> AnyResult<FoundValue> {
+    let (values, nb_pages) = match found {
+        FoundValue::Values(values, nb_pages) => (values, nb_pages),
+        FoundValue::ClosestPeers(peers) => return Ok(FoundValue::ClosestPeers(peers)),
+    };
+
+    let nb_values = values.len();
+    let values = values
+        .into_iter()
+        .filter(|stored| stored.has_valid_signature(key))
+        .collect::<Vec<_>>();
+    if values.len() < nb_values {
+        {
+            let mut dht = ctx.dht.lock().await;
+            dht.peer_has_lied(peer.id).await;
+        }
+        if values.is_empty() {
+            bail!("peer {} sent values not signed by their publisher", peer.id);
+        }
+    }
+
+    Ok(FoundValue::Values(values, nb_pages))
+}
+
+// Keep only the values matching their immutable hash, and penalise the peer
+// who sent the others.
+async fn check_immutable_values(
+    ctx: Arc<Context>,
+    peer: Peer,
+    hash: ImmutableHash,
+    found: FoundValue,
+) -> AnyResult<FoundValue> {
+    let (values, nb_pages) = match found {
+        FoundValue::Values(values, nb_pages) => (values, nb_pages),
+        FoundValue::ClosestPeers(peers) => return Ok(FoundValue::ClosestPeers(peers)),
+    };
+
+    let nb_values = values.len();
+    let values = values
+        .into_iter()
+        .filter(|stored| is_immutable_value_of(&hash, &stored.value))
+        .collect::<Vec<_>>();
+    if values.len() < nb_values {
+        {
+            let mut dht = ctx.dht.lock().await;
+            dht.peer_has_lied(peer.id).await;
+        }
+        if values.is_empty() {
+            bail!(
+                "peer {} sent values not matching the key {}",
+                peer.id,
+                immutable_key(&hash)
+            );
+        }
+    }
+
+    Ok(FoundValue::Values(values, nb_pages))
+}
+
+// Query the mutable value of a hash on a distant node, and update the current
+// context. Like find value, an unreachable peer, or a peer sending a forged
+// value, is an error.
+pub async fn query_get_mutable(
+    ctx: Arc<Context>,
+    peer: Peer,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    hash: MutableHash,
+) -> AnyResult<FoundMutable> {
+    let slowness = ctx.settings.read().await.slowness;
+
+    let connexion = connect_to_peer(Arc::clone(&ctx), &peer).await?;
+    let stream = Arc::new(Mutex::new(connexion));
+    if let Some(wait_time) = slowness {
+        sleep(wait_time).await;
+    }
+    let found = handle_get_mutable(
+        Arc::clone(&ctx),
+        Arc::clone(&stream),
+        peer.id,
+        sender_addr,
+        sender_id,
+        hash,
+    )
+    .await?;
+
+    // The peer just answered us, let's add him into our dht.
+    add_queried_peer(Arc::clone(&ctx), stream, peer.clone(), sender_addr, sender_id).await;
+
+    check_mutable_value(ctx, peer, hash, found).await
+}
+
+// Check a mutable value really has the requested hash, meaning the owner and
+// salt we asked for, and is signed by this owner. Otherwise, the peer who sent
+// it is penalised, and its answer is an error, so the lookup goes on.
+async fn check_mutable_value(
+    ctx: Arc<Context>,
+    peer: Peer,
+    hash: MutableHash,
+    found: FoundMutable,
+) -> AnyResult<FoundMutable> {
+    let value = match found {
+        FoundMutable::Value(value) => value,
+        FoundMutable::ClosestPeers(peers) => return Ok(FoundMutable::ClosestPeers(peers)),
+    };
+
+    if value.hash() != hash || !value.has_valid_signature() {
+        {
+            let mut dht = ctx.dht.lock().await;
+            dht.peer_has_lied(peer.id).await;
+        }
+        bail!(
+            "peer {} sent a forged value for the key {}",
+            peer.id,
+            mutable_key(&hash)
+        );
+    }
+
+    Ok(FoundMutable::Value(value))
+}
+
+#[cfg(test)]
+#[path = "find_value_test.rs"]
+mod find_value_test;
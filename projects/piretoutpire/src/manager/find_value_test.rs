@@ -0,0 +1,568 @@
+use super::*;
+use crate::dht::{
+    immutable::{immutable_hash, immutable_key},
+    peer_node::PeerStatus,
+};
+use ed25519_dalek::{Keypair, SecretKey};
+use errors::bail;
+use std::collections::HashMap;
+
+// MOCKED FUNCTIONS ------------------------------------------------------------
+
+// Emulate the querying of another peer with find_value.
+async fn mock_find_value(
+    peers: HashMap<u32, Vec<u32>>,
+    values: HashMap<u32, Vec<StoredValue>>,
+    ctx: Arc<Context>,
+    peer: Peer,
+    key: u32,
+) -> AnyResult<FoundValue> {
+    if !peers.contains_key(&peer.id) {
+        bail!("peer {} is unreachable", peer.id);
+    }
+
+    // The peer just answered us, let's add him into our dht.
+    {
+        let mut dht = ctx.dht.lock().await;
+        dht.add_node(peer.id, peer.addr).await;
+    }
+
+    if let Some(values) = values.get(&peer.id) {
+        return Ok(FoundValue::Values(values.clone(), 1));
+    }
+
+    let mut nodes = peers.get(&peer.id).map_or(vec![], |vec| {
+        vec.into_iter()
+            .map(|peer_id| Peer {
+                id: *peer_id,
+                addr: "127.0.0.1:4000".parse().expect(""),
+            })
+            .collect()
+    });
+    nodes.sort_by_key(|peer| distance(peer.id, key));
+    Ok(FoundValue::ClosestPeers(nodes.into_iter().take(4).collect()))
+}
+
+// This function own a predefined set of nodes, emulating a dht network.
+// 1 -- 2
+// | \ /
+// 5  3 - 4 - 7
+//     \      |
+//      6     8
+fn small_network() -> HashMap<u32, Vec<u32>> {
+    let mut peers = HashMap::<u32, Vec<u32>>::new();
+    peers.insert(1, vec![2, 3, 5]);
+    peers.insert(2, vec![1, 3]);
+    peers.insert(3, vec![1, 2, 4, 6]);
+    peers.insert(4, vec![3, 7]);
+    peers.insert(5, vec![1]);
+    peers.insert(6, vec![3]);
+    peers.insert(7, vec![4, 8]);
+    peers.insert(8, vec![7]);
+    peers
+}
+
+// Only 7 has a value.
+async fn mocked_query_find_value(
+    ctx: Arc<Context>,
+    peer: Peer,
+    _sender_addr: SocketAddr,
+    _sender_id: u32,
+    key: u32,
+) -> AnyResult<FoundValue> {
+    let mut values = HashMap::<u32, Vec<StoredValue>>::new();
+    values.insert(7, vec![value(42, 1000, "hello")]);
+    mock_find_value(small_network(), values, ctx, peer, key).await
+}
+
+// Nobody has a value.
+async fn mocked_query_find_value_missing(
+    ctx: Arc<Context>,
+    peer: Peer,
+    _sender_addr: SocketAddr,
+    _sender_id: u32,
+    key: u32,
+) -> AnyResult<FoundValue> {
+    mock_find_value(small_network(), HashMap::new(), ctx, peer, key).await
+}
+
+// Only 7 has a value, and 6 is down.
+async fn mocked_query_find_value_unreachable(
+    ctx: Arc<Context>,
+    peer: Peer,
+    _sender_addr: SocketAddr,
+    _sender_id: u32,
+    key: u32,
+) -> AnyResult<FoundValue> {
+    let mut peers = small_network();
+    peers.remove(&6);
+    let mut values = HashMap::<u32, Vec<StoredValue>>::new();
+    values.insert(7, vec![value(42, 1000, "hello")]);
+    mock_find_value(peers, values, ctx, peer, key).await
+}
+
+// 4 and 6 hold different values, but share one.
+async fn mocked_query_find_value_spread(
+    ctx: Arc<Context>,
+    peer: Peer,
+    _sender_addr: SocketAddr,
+    _sender_id: u32,
+    key: u32,
+) -> AnyResult<FoundValue> {
+    let mut values = HashMap::<u32, Vec<StoredValue>>::new();
+    values.insert(4, vec![value(42, 1000, "hello"), value(43, 1000, "world")]);
+    values.insert(6, vec![value(42, 1010, "hello"), value(44, 1000, "!")]);
+    mock_find_value(small_network(), values, ctx, peer, key).await
+}
+
+// 4 holds the immutable value, but 6 holds a forged one.
+async fn mocked_query_find_immutable(
+    ctx: Arc<Context>,
+    peer: Peer,
+    _sender_addr: SocketAddr,
+    _sender_id: u32,
+    key: u32,
+) -> AnyResult<FoundValue> {
+    let mut values = HashMap::<u32, Vec<StoredValue>>::new();
+    values.insert(4, vec![value(42, 1000, "hello")]);
+    values.insert(6, vec![value(43, 1000, "forged")]);
+    let found = mock_find_value(small_network(), values, Arc::clone(&ctx), peer.clone(), key).await?;
+    check_immutable_values(ctx, peer, immutable_hash("hello"), found).await
+}
+
+// 4 holds an old version of a mutable value, 6 the newest one.
+async fn mocked_query_get_mutable(
+    ctx: Arc<Context>,
+    peer: Peer,
+    _sender_addr: SocketAddr,
+    _sender_id: u32,
+    key: u32,
+) -> AnyResult<FoundMutable> {
+    let mut values = HashMap::<u32, Vec<StoredValue>>::new();
+    values.insert(4, vec![value(0, 1, "old")]);
+    values.insert(6, vec![value(0, 2, "new")]);
+    let found = match mock_find_value(small_network(), values, ctx, peer, key).await? {
+        FoundValue::Values(values, _) => FoundMutable::Value(mutable(values[0].timestamp, &values[0].value)),
+        FoundValue::ClosestPeers(peers) => FoundMutable::ClosestPeers(peers),
+    };
+    Ok(found)
+}
+
+// 4 holds the mutable value, but 6 holds a newer one of another owner.
+async fn mocked_query_get_mutable_forged(
+    ctx: Arc<Context>,
+    peer: Peer,
+    _sender_addr: SocketAddr,
+    _sender_id: u32,
+    key: u32,
+) -> AnyResult<FoundMutable> {
+    let mut values = HashMap::<u32, Vec<StoredValue>>::new();
+    values.insert(4, vec![value(0, 1, "real")]);
+    values.insert(6, vec![value(0, 5, "forged")]);
+    let found = match mock_find_value(small_network(), values, Arc::clone(&ctx), peer.clone(), key).await? {
+        FoundValue::Values(values, _) if values[0].value == "forged" => {
+            FoundMutable::Value(owned_mutable(2, values[0].timestamp, &values[0].value))
+        }
+        FoundValue::Values(values, _) => FoundMutable::Value(mutable(values[0].timestamp, &values[0].value)),
+        FoundValue::ClosestPeers(peers) => FoundMutable::ClosestPeers(peers),
+    };
+    check_mutable_value(ctx, peer, mutable(0, "").hash(), found).await
+}
+
+fn mutable(seq: u32, value: &str) -> MutableValue {
+    owned_mutable(1, seq, value)
+}
+
+fn owned_mutable(owner: u8, seq: u32, value: &str) -> MutableValue {
+    let secret = SecretKey::from_bytes(&[owner; 32]).expect("valid secret key");
+    let public = (&secret).into();
+    MutableValue::new(&Keypair { secret, public }, "".to_owned(), seq, value.to_owned())
+}
+
+fn value(publisher: u32, timestamp: u32, value: &str) -> StoredValue {
+    StoredValue {
+        publisher,
+        timestamp,
+        value: value.to_owned(),
+        public_key: vec![],
+        signature: vec![],
+    }
+}
+
+fn peer(id: u32) -> Peer {
+    Peer {
+        id,
+        addr: "127.0.0.1:4000".parse().expect(""),
+    }
+}
+
+// TESTS -----------------------------------------------------------------------
+
+#[tokio::test]
+async fn test_find_value_found() -> AnyResult<()> {
+    let sender_id = 0;
+    let sender_addr = "127.0.0.1:4000".parse()?;
+    let key = 7;
+
+    for max_hop in [None, Some(u32::MAX)] {
+        let ctx = Arc::new(Context::new_test(sender_id, false));
+        let res = find_value_iteratively(
+            Arc::clone(&ctx),
+            vec![peer(1)],
+            sender_addr,
+            sender_id,
+            key,
+            max_hop,
+            mocked_query_find_value,
+        )
+        .await?;
+
+        // 1 -> (5, 3, 2) -> (6, 4) -> 7, and 6 is the closest without the
+        // value.
+        assert_eq!(
+            ValueLookup {
+                value: Some(vec![value(42, 1000, "hello")]),
+                closest_without_value: Some(peer(6)),
+            },
+            res
+        );
+
+        let dht = ctx.dht.lock().await;
+        assert_eq!(vec![1, 2, 3, 4, 5, 6, 7], dht.peer_ids().await);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_find_value_from_the_owner() -> AnyResult<()> {
+    let sender_id = 0;
+    let sender_addr = "127.0.0.1:4000".parse()?;
+    let key = 7;
+
+    let ctx = Arc::new(Context::new_test(sender_id, false));
+    let res = find_value_iteratively(
+        ctx,
+        vec![peer(7), peer(1)],
+        sender_addr,
+        sender_id,
+        key,
+        None,
+        mocked_query_find_value,
+    )
+    .await?;
+
+    // The value is found at the first hop, 1 didn't have it.
+    assert_eq!(
+        ValueLookup {
+            value: Some(vec![value(42, 1000, "hello")]),
+            closest_without_value: Some(peer(1)),
+        },
+        res
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_find_value_missing() -> AnyResult<()> {
+    let sender_id = 0;
+    let sender_addr = "127.0.0.1:4000".parse()?;
+    let key = 7;
+
+    for max_hop in [None, Some(u32::MAX)] {
+        let ctx = Arc::new(Context::new_test(sender_id, false));
+        let res = find_value_iteratively(
+            ctx,
+            vec![peer(1)],
+            sender_addr,
+            sender_id,
+            key,
+            max_hop,
+            mocked_query_find_value_missing,
+        )
+        .await?;
+
+        assert_eq!(
+            ValueLookup {
+                value: None,
+                closest_without_value: Some(peer(7)),
+            },
+            res
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_find_value_skip_unreachable() -> AnyResult<()> {
+    let sender_id = 0;
+    let sender_addr = "127.0.0.1:4000".parse()?;
+    let key = 7;
+
+    let ctx = Arc::new(Context::new_test(sender_id, false));
+    let res = find_value_iteratively(
+        Arc::clone(&ctx),
+        vec![peer(1)],
+        sender_addr,
+        sender_id,
+        key,
+        None,
+        mocked_query_find_value_unreachable,
+    )
+    .await?;
+
+    // 6 is down, so it can't be the one caching the value.
+    assert_eq!(
+        ValueLookup {
+            value: Some(vec![value(42, 1000, "hello")]),
+            closest_without_value: Some(peer(5)),
+        },
+        res
+    );
+
+    let dht = ctx.dht.lock().await;
+    assert_eq!(vec![1, 2, 3, 4, 5, 7], dht.peer_ids().await);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_find_value_merged() -> AnyResult<()> {
+    let sender_id = 0;
+    let sender_addr = "127.0.0.1:4000".parse()?;
+    let key = 7;
+
+    let ctx = Arc::new(Context::new_test(sender_id, false));
+    let res = find_value_iteratively(
+        ctx,
+        vec![peer(1)],
+        sender_addr,
+        sender_id,
+        key,
+        None,
+        mocked_query_find_value_spread,
+    )
+    .await?;
+
+    // 6 and 4 are queried during the same hop, their values are merged.
+    let mut values = res.value.unwrap_or_default();
+    values.sort_by_key(|stored| stored.publisher);
+    assert_eq!(
+        vec![
+            value(42, 1010, "hello"),
+            value(43, 1000, "world"),
+            value(44, 1000, "!")
+        ],
+        values
+    );
+    assert_eq!(Some(peer(5)), res.closest_without_value);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_find_value_no_peers() -> AnyResult<()> {
+    let sender_id = 0;
+    let sender_addr = "127.0.0.1:4000".parse()?;
+
+    let ctx = Arc::new(Context::new_test(sender_id, false));
+    let res = find_value_iteratively(
+        ctx,
+        vec![],
+        sender_addr,
+        sender_id,
+        7,
+        None,
+        mocked_query_find_value,
+    )
+    .await?;
+    assert_eq!(ValueLookup::<Vec<StoredValue>>::default(), res);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_mutable_most_recent() -> AnyResult<()> {
+    let sender_id = 0;
+    let sender_addr = "127.0.0.1:4000".parse()?;
+    let key = 7;
+
+    let ctx = Arc::new(Context::new_test(sender_id, false));
+    let res = find_value_iteratively(
+        ctx,
+        vec![peer(1)],
+        sender_addr,
+        sender_id,
+        key,
+        None,
+        mocked_query_get_mutable,
+    )
+    .await?;
+
+    // 6 and 4 are queried during the same hop, only the newest version is kept.
+    assert_eq!(
+        ValueLookup {
+            value: Some(mutable(2, "new")),
+            closest_without_value: Some(peer(5)),
+        },
+        res
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_find_immutable_drop_forged() -> AnyResult<()> {
+    let sender_id = 0;
+    let sender_addr = "127.0.0.1:4000".parse()?;
+    let key = immutable_key(&immutable_hash("hello"));
+
+    let ctx = Arc::new(Context::new_test(sender_id, false));
+    let res = find_value_iteratively(
+        Arc::clone(&ctx),
+        vec![peer(1)],
+        sender_addr,
+        sender_id,
+        key,
+        Some(u32::MAX),
+        mocked_query_find_immutable,
+    )
+    .await?;
+
+    // 6 and 4 are queried during the same hop, but only the value from 4 is
+    // kept, and 6 can't be used to cache it.
+    assert_eq!(Some(vec![value(42, 1000, "hello")]), res.value);
+    assert_ne!(Some(peer(6)), res.closest_without_value);
+
+    // 6 is now considered as a bad peer.
+    let dht = ctx.dht.lock().await;
+    let liar = dht.known_peers().await.find(|known| known.id() == 6);
+    assert!(liar.map_or(false, |liar| liar.status() == PeerStatus::Bad));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_mutable_drop_forged() -> AnyResult<()> {
+    let sender_id = 0;
+    let sender_addr = "127.0.0.1:4000".parse()?;
+
+    let ctx = Arc::new(Context::new_test(sender_id, false));
+    let res = find_value_iteratively(
+        Arc::clone(&ctx),
+        vec![peer(1)],
+        sender_addr,
+        sender_id,
+        7,
+        Some(u32::MAX),
+        mocked_query_get_mutable_forged,
+    )
+    .await?;
+
+    // The value of 6 doesn't match the hash asked, even if it's newer and
+    // correctly signed by its own owner.
+    assert_eq!(Some(mutable(1, "real")), res.value);
+
+    // 6 is now considered as a bad peer.
+    let dht = ctx.dht.lock().await;
+    let liar = dht.known_peers().await.find(|known| known.id() == 6);
+    assert!(liar.map_or(false, |liar| liar.status() == PeerStatus::Bad));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_find_value_from_peers() -> AnyResult<()> {
+    let sender_id = 0;
+    let sender_addr = "127.0.0.1:4000".parse()?;
+    let key = 5;
+
+    // Only the given peers are asked, there is no hop: 1 has no value, 4 and 6
+    // values are merged, and 9 is unreachable.
+    let ctx = Arc::new(Context::new_test(sender_id, false));
+    let (res, trace) = find_value_from_peers(
+        Arc::clone(&ctx),
+        vec![peer(1), peer(4), peer(6), peer(9)],
+        sender_addr,
+        sender_id,
+        key,
+        mocked_query_find_value_spread,
+    )
+    .await?;
+    assert_eq!(
+        ValueLookup {
+            value: Some(vec![
+                value(42, 1010, "hello"),
+                value(43, 1000, "world"),
+                value(44, 1000, "!"),
+            ]),
+            closest_without_value: Some(peer(1)),
+        },
+        res
+    );
+
+    // The queries are traced as a single hop.
+    assert_eq!(1, trace.hops.len());
+    let with_value = trace.hops[0]
+        .queries
+        .iter()
+        .filter(|query| query.has_value)
+        .map(|query| query.peer.id)
+        .collect::<Vec<_>>();
+    assert_eq!(vec![4, 6], with_value);
+
+    let dht = ctx.dht.lock().await;
+    assert_eq!(vec![1, 4, 6], dht.peer_ids().await);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_find_value_trace() -> AnyResult<()> {
+    let sender_id = 0;
+    let sender_addr = "127.0.0.1:4000".parse()?;
+    let key = 7;
+
+    let ctx = Arc::new(Context::new_test(sender_id, false));
+    let (res, trace) = find_value_iteratively_traced(
+        Arc::clone(&ctx),
+        vec![peer(1)],
+        sender_addr,
+        sender_id,
+        key,
+        None,
+        mocked_query_find_value_unreachable,
+    )
+    .await?;
+    // The unreachable peer doesn't stop the lookup.
+    assert_eq!(Some(vec![value(42, 1000, "hello")]), res.value);
+    assert_eq!(key, trace.target);
+
+    // 1 is asked first, and answers with its peers, the closest first.
+    let first_hop = &trace.hops[0];
+    assert_eq!(1, first_hop.queries.len());
+    assert_eq!(peer(1), first_hop.queries[0].peer);
+    assert_eq!(vec![peer(5), peer(3), peer(2)], first_hop.queries[0].returned);
+    assert_eq!(Some(2), first_hop.best_distance);
+
+    // 6 is then asked alongside 4, and its error is reported.
+    let unreachable = trace
+        .hops
+        .iter()
+        .flat_map(|hop| hop.queries.iter())
+        .find(|query| query.peer.id == 6)
+        .expect("6 has been queried");
+    assert!(unreachable.returned.is_empty());
+    assert_eq!(Some("peer 6 is unreachable".to_owned()), unreachable.error);
+
+    // The last hop got the value from 7.
+    let last_hop = trace.hops.last().expect("a hop");
+    assert!(last_hop
+        .queries
+        .iter()
+        .any(|query| query.peer.id == 7 && query.has_value));
+
+    Ok(())
+}
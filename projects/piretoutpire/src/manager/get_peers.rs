@@ -0,0 +1,188 @@
+use super::{
+    client::{connect_to_peer, handle_get_peers},
+    context::Context,
+    deadline::spawn_scoped,
+    find_node::{add_queried_peer, sort_by_relevancy},
+};
+use crate::{
+    network::protocol::{FoundPeers, Peer},
+    utils::distance,
+};
+use errors::AnyResult;
+use std::{collections::HashSet, future::Future, net::SocketAddr, sync::Arc};
+use tokio::{self, sync::Mutex, time::sleep};
+
+// Number of closest peers of a file which must have been asked before stopping
+// a lookup.
+const NB_CLOSEST_PEERS: usize = 4;
+
+// Peers owning a file, each one with the peer which reported it.
+pub type FilePeers = Vec<(Peer /*owner*/, Peer /*responder*/)>;
+
+// Add the owners reported by a peer to what has already been found. An owner
+// reported many times is kept once, with the first peer which reported it.
+fn merge_owners(found: &mut FilePeers, owners: Vec<Peer>, responder: &Peer) {
+    for owner in owners {
+        if !found.iter().any(|(known, _)| known.id == owner.id) {
+            found.push((owner, responder.clone()));
+        }
+    }
+}
+
+// Search the peers owning a file, hopping from peer to peer. Unlike a value
+// lookup, finding owners doesn't end the search: each peer answers with the
+// owners it knows, and with the closest peers it knows from the file crc, which
+// are queried next. Will stop once the closest peers which answered are
+// closer than any peer left to query, so the owners of all of them are
+// gathered.
+pub async fn get_peers_iteratively<F, T>(
+    ctx: Arc<Context>,
+    initial_peers: Vec<Peer>,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    crc: u32,
+    max_hop: Option<u32>,
+    mut query_func: F,
+) -> AnyResult<FilePeers>
+where
+    F: FnMut(Arc<Context>, Peer, SocketAddr, u32, u32) -> T + Send + Copy + 'static,
+    T: Future<Output = AnyResult<FoundPeers>> + Send + 'static,
+{
+    let mut hop = 0;
+    let mut queue = initial_peers;
+    sort_by_relevancy(Arc::clone(&ctx), &mut queue, crc).await;
+    queue.reverse();
+    let mut visited = HashSet::<u32>::new();
+    visited.insert(sender_id); // Let's avoid ourself.
+    let mut responders = Vec::<Peer>::new();
+    let mut found = FilePeers::new();
+
+    loop {
+        hop += 1;
+
+        // Query the 3 closest non-visited peers at the same time.
+        let mut queries = Vec::new();
+        while let Some(peer) = queue.pop() {
+            if !visited.insert(peer.id) {
+                continue;
+            }
+
+            let ctx = Arc::clone(&ctx);
+            queries.push(spawn_scoped(async move {
+                let res = query_func(ctx, peer.clone(), sender_addr, sender_id, crc).await;
+                (peer, res)
+            }));
+            if queries.len() >= 3 {
+                break;
+            }
+        }
+        if queries.is_empty() {
+            break;
+        }
+
+        for handle in queries {
+            let (peer, res) = handle.await?;
+            // An unreachable peer is just ignored.
+            if let Ok(FoundPeers {
+                owners,
+                closest_peers,
+            }) = res
+            {
+                merge_owners(&mut found, owners, &peer);
+                queue.extend(
+                    closest_peers
+                        .into_iter()
+                        .filter(|peer| !visited.contains(&peer.id)),
+                );
+                responders.push(peer);
+            }
+        }
+        responders.sort_by_key(|peer| distance(peer.id, crc));
+
+        // Now let's sort the queue putting the best at the end (easier for
+        // poping values).
+        sort_by_relevancy(Arc::clone(&ctx), &mut queue, crc).await;
+        queue.reverse();
+        queue.dedup_by_key(|peer| peer.id);
+
+        // Hop strategy: stop after doing N hops.
+        if max_hop.map_or(false, |max_hop| hop >= max_hop) {
+            break;
+        }
+        // Stop when nobody left in the queue is closer than the closest peers
+        // which already answered.
+        if let Some(farthest) = responders.get(NB_CLOSEST_PEERS - 1) {
+            let farthest = distance(farthest.id, crc);
+            if queue.iter().all(|peer| distance(peer.id, crc) >= farthest) {
+                break;
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+// Ask a known set of peers for the owners of a file, without hopping any
+// further. That's the last step of a disjoint lookup, once the closest peers of
+// the file have been found through independent paths.
+pub async fn get_peers_from_peers<F, T>(
+    ctx: Arc<Context>,
+    peers: Vec<Peer>,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    crc: u32,
+    mut query_func: F,
+) -> AnyResult<FilePeers>
+where
+    F: FnMut(Arc<Context>, Peer, SocketAddr, u32, u32) -> T + Send + Copy + 'static,
+    T: Future<Output = AnyResult<FoundPeers>> + Send + 'static,
+{
+    let queries = peers
+        .into_iter()
+        .filter(|peer| peer.id != sender_id)
+        .map(|peer| {
+            let ctx = Arc::clone(&ctx);
+            spawn_scoped(async move {
+                let res = query_func(ctx, peer.clone(), sender_addr, sender_id, crc).await;
+                (peer, res)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut found = FilePeers::new();
+    for handle in queries {
+        if let (peer, Ok(FoundPeers { owners, .. })) = handle.await? {
+            merge_owners(&mut found, owners, &peer);
+        }
+    }
+
+    Ok(found)
+}
+
+// Query the owners of a file on a distant node, and update the current context.
+// Like find value, an unreachable peer is an error.
+pub async fn query_get_peers(
+    ctx: Arc<Context>,
+    peer: Peer,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    crc: u32,
+) -> AnyResult<FoundPeers> {
+    let slowness = ctx.settings.read().await.slowness;
+
+    let connexion = connect_to_peer(Arc::clone(&ctx), &peer).await?;
+    let stream = Arc::new(Mutex::new(connexion));
+    if let Some(wait_time) = slowness {
+        sleep(wait_time).await;
+    }
+    let found = handle_get_peers(Arc::clone(&ctx), Arc::clone(&stream), peer.id, crc).await?;
+
+    // The peer just answered us, let's add him into our dht.
+    add_queried_peer(Arc::clone(&ctx), stream, peer, sender_addr, sender_id).await;
+
+    Ok(found)
+}
+
+#[cfg(test)]
+#[path = "get_peers_test.rs"]
+mod get_peers_test;
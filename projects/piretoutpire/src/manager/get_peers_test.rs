@@ -0,0 +1,163 @@
+use super::*;
+use errors::bail;
+use std::collections::HashMap;
+
+// MOCKED FUNCTIONS ------------------------------------------------------------
+
+// Emulate the querying of another peer with get_peers.
+async fn mock_get_peers(
+    peers: HashMap<u32, Vec<u32>>,
+    owners: HashMap<u32, Vec<u32>>,
+    ctx: Arc<Context>,
+    peer: Peer,
+    crc: u32,
+) -> AnyResult<FoundPeers> {
+    if !peers.contains_key(&peer.id) {
+        bail!("peer {} is unreachable", peer.id);
+    }
+
+    // The peer just answered us, let's add him into our dht.
+    {
+        let mut dht = ctx.dht.lock().await;
+        dht.add_node(peer.id, peer.addr).await;
+    }
+
+    let owners = owners.get(&peer.id).map_or(vec![], |vec| {
+        vec.iter().map(|id| self::peer(*id)).collect::<Vec<_>>()
+    });
+    let mut nodes = peers.get(&peer.id).map_or(vec![], |vec| {
+        vec.iter().map(|id| self::peer(*id)).collect::<Vec<_>>()
+    });
+    nodes.sort_by_key(|peer| distance(peer.id, crc));
+    Ok(FoundPeers {
+        owners,
+        closest_peers: nodes.into_iter().take(4).collect(),
+    })
+}
+
+// This function own a predefined set of nodes, emulating a dht network.
+// 1 -- 2
+// | \ /
+// 5  3 - 4 - 7
+//     \      |
+//      6     8
+fn small_network() -> HashMap<u32, Vec<u32>> {
+    let mut peers = HashMap::<u32, Vec<u32>>::new();
+    peers.insert(1, vec![2, 3, 5]);
+    peers.insert(2, vec![1, 3]);
+    peers.insert(3, vec![1, 2, 4, 6]);
+    peers.insert(4, vec![3, 7]);
+    peers.insert(5, vec![1]);
+    peers.insert(6, vec![3]);
+    peers.insert(7, vec![4, 8]);
+    peers.insert(8, vec![7]);
+    peers
+}
+
+// 6 knows the file is owned by 100, 7 knows it's owned by 100 and 101.
+async fn mocked_query_get_peers(
+    ctx: Arc<Context>,
+    peer: Peer,
+    _sender_addr: SocketAddr,
+    _sender_id: u32,
+    crc: u32,
+) -> AnyResult<FoundPeers> {
+    let mut owners = HashMap::<u32, Vec<u32>>::new();
+    owners.insert(6, vec![100]);
+    owners.insert(7, vec![100, 101]);
+    mock_get_peers(small_network(), owners, ctx, peer, crc).await
+}
+
+// Nobody knows who owns the file.
+async fn mocked_query_get_peers_missing(
+    ctx: Arc<Context>,
+    peer: Peer,
+    _sender_addr: SocketAddr,
+    _sender_id: u32,
+    crc: u32,
+) -> AnyResult<FoundPeers> {
+    mock_get_peers(small_network(), HashMap::new(), ctx, peer, crc).await
+}
+
+fn peer(id: u32) -> Peer {
+    Peer {
+        id,
+        addr: "127.0.0.1:4000".parse().expect(""),
+    }
+}
+
+// TESTS -----------------------------------------------------------------------
+
+#[tokio::test]
+async fn test_get_peers_aggregated() -> AnyResult<()> {
+    let sender_id = 0;
+    let sender_addr = "127.0.0.1:4000".parse()?;
+    let crc = 7;
+
+    let ctx = Arc::new(Context::new_test(sender_id, false));
+    let res = get_peers_iteratively(
+        Arc::clone(&ctx),
+        vec![peer(1)],
+        sender_addr,
+        sender_id,
+        crc,
+        None,
+        mocked_query_get_peers,
+    )
+    .await?;
+
+    // 1 -> (5, 3, 2) -> (6, 4) -> 7: finding owners on 6 doesn't stop the
+    // lookup, and 100 is only reported once.
+    assert_eq!(vec![(peer(100), peer(6)), (peer(101), peer(7))], res);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_peers_missing() -> AnyResult<()> {
+    let sender_id = 0;
+    let sender_addr = "127.0.0.1:4000".parse()?;
+    let crc = 7;
+
+    let ctx = Arc::new(Context::new_test(sender_id, false));
+    let res = get_peers_iteratively(
+        Arc::clone(&ctx),
+        vec![peer(1)],
+        sender_addr,
+        sender_id,
+        crc,
+        None,
+        mocked_query_get_peers_missing,
+    )
+    .await?;
+    assert!(res.is_empty());
+
+    // 8 is farther than the 4 closest peers which answered (7, 6, 5, 4), it's
+    // never asked.
+    let dht = ctx.dht.lock().await;
+    assert_eq!(vec![1, 2, 3, 4, 5, 6, 7], dht.peer_ids().await);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_peers_from_peers() -> AnyResult<()> {
+    let sender_id = 0;
+    let sender_addr = "127.0.0.1:4000".parse()?;
+    let crc = 7;
+
+    let ctx = Arc::new(Context::new_test(sender_id, false));
+    let res = get_peers_from_peers(
+        ctx,
+        vec![peer(4), peer(6), peer(7)],
+        sender_addr,
+        sender_id,
+        crc,
+        mocked_query_get_peers,
+    )
+    .await?;
+
+    assert_eq!(vec![(peer(100), peer(6)), (peer(101), peer(7))], res);
+
+    Ok(())
+}
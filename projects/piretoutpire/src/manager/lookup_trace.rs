@@ -0,0 +1,30 @@
+use crate::network::protocol::Peer;
+use serde::Serialize;
+
+// What happened during a node or value lookup, hop by hop: which peers were asked, what
+// they answered, and how close to the target it went. Only used to debug the
+// routing, it's printed as JSON.
+#[derive(Debug, Default, Serialize)]
+pub struct LookupTrace {
+    pub target: u32,
+    pub hops: Vec<TraceHop>,
+}
+
+// Peers queried at the same time during a lookup.
+#[derive(Debug, Default, Serialize)]
+pub struct TraceHop {
+    pub queries: Vec<TracedQuery>,
+    // Distance to the target of the closest peer found so far, if any.
+    pub best_distance: Option<u32>,
+}
+
+// A single query of a lookup, and what the peer answered.
+#[derive(Debug, Serialize)]
+pub struct TracedQuery {
+    pub peer: Peer,
+    pub latency_ms: u64,
+    pub returned: Vec<Peer>,
+    // Whether the peer answered with the value looked for, for value lookups.
+    pub has_value: bool,
+    pub error: Option<String>,
+}
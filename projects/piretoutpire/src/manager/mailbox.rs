@@ -0,0 +1,122 @@
+use super::{
+    client::{connect_to_peer, handle_ack_mail, handle_get_mail, handle_put_mail},
+    context::Context,
+};
+use crate::network::protocol::{Peer, StoredValue};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::sync::Mutex;
+
+// Number of closest peers of a recipient keeping its mails.
+pub const NB_MAILBOXES: usize = 4;
+
+// What became of a message sent to a peer.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MessageDelivery {
+    // The recipient acknowledged it.
+    Delivered,
+    // The recipient couldn't be reached, the message is kept by this many of
+    // its closest peers until it fetches it.
+    Stored(usize),
+    // Neither the recipient nor its closest peers took it.
+    Lost,
+}
+
+// Pick the peers keeping the mails of a recipient among the result of a lookup
+// around it, the closest first. Senders and recipient run the same lookup, so
+// they agree on the mailboxes.
+pub fn pick_mailboxes(closest_peers: Vec<Peer>, recipient: u32) -> Vec<Peer> {
+    closest_peers
+        .into_iter()
+        .filter(|peer| peer.id != recipient)
+        .take(NB_MAILBOXES)
+        .collect()
+}
+
+// Leave a mail on the given peers, for a recipient we can't reach. Return how
+// many of them accepted to keep it.
+pub async fn store_mail_on_peers(
+    ctx: Arc<Context>,
+    peers: Vec<Peer>,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    recipient: u32,
+    mail: StoredValue,
+) -> usize {
+    let mut nb_stored = 0;
+    for peer in peers {
+        let stream = match connect_to_peer(Arc::clone(&ctx), &peer).await {
+            Ok(stream) => Arc::new(Mutex::new(stream)),
+            Err(_) => continue,
+        };
+        let stored = handle_put_mail(
+            Arc::clone(&ctx),
+            stream,
+            peer.id,
+            sender_addr,
+            sender_id,
+            recipient,
+            mail.clone(),
+        )
+        .await;
+        if stored.is_ok() {
+            nb_stored += 1;
+        }
+    }
+    nb_stored
+}
+
+// Fetch the mails the given peers keep for us, the oldest first. A mail left on
+// many peers is only given once, and only mails signed by their sender are
+// kept. Each peer is told which mails we received, so it forgets them.
+pub async fn fetch_mails_from_peers(
+    ctx: Arc<Context>,
+    peers: Vec<Peer>,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+) -> Vec<StoredValue> {
+    let mut mails = Vec::<StoredValue>::new();
+    for peer in peers {
+        let stream = match connect_to_peer(Arc::clone(&ctx), &peer).await {
+            Ok(stream) => Arc::new(Mutex::new(stream)),
+            Err(_) => continue,
+        };
+        let fetched = match handle_get_mail(
+            Arc::clone(&ctx),
+            Arc::clone(&stream),
+            peer.id,
+            sender_addr,
+            sender_id,
+        )
+        .await
+        {
+            Ok(fetched) => fetched,
+            Err(_) => continue,
+        };
+        if !fetched.is_empty() {
+            let mail_ids = fetched.iter().map(StoredValue::mail_id).collect();
+            let _ = handle_ack_mail(
+                Arc::clone(&ctx),
+                stream,
+                peer.id,
+                sender_addr,
+                sender_id,
+                mail_ids,
+            )
+            .await;
+        }
+        // Mails not signed by their sender are forged by the peer keeping them.
+        for mail in fetched
+            .into_iter()
+            .filter(|mail| mail.has_valid_signature(sender_id))
+        {
+            let known = mails
+                .iter()
+                .any(|known| known.publisher == mail.publisher && known.value == mail.value);
+            if !known {
+                mails.push(mail);
+            }
+        }
+    }
+    mails.sort_by_key(|mail| mail.timestamp);
+    mails
+}
@@ -1,22 +1,52 @@
 use super::{
     client::{
-        handle_announce, handle_file_chunk, handle_file_info, handle_find_value, handle_get_peers,
-        handle_message, handle_ping, handle_store,
+        connect_to_peer, handle_announce, handle_file_chunk, handle_file_info, handle_message, handle_onion,
+        handle_ping, handle_public_key, handle_put_mutable, handle_store,
     },
     command_handler::listen_to_command,
     context::{
-        Context, DEFAULT_CONNECTION_TIMEOUT_MS, DEFAULT_DHT_DUMP_FREQUENCY_MS, DEFAULT_READ_TIMEOUT_MS,
-        DEFAULT_WRITE_TIMEOUT_MS,
+        Context, DEFAULT_BULK_READ_TIMEOUT_MS, DEFAULT_BULK_WRITE_TIMEOUT_MS, DEFAULT_CONNECTION_TIMEOUT_MS,
+        DEFAULT_DHT_DUMP_FREQUENCY_MS, DEFAULT_READ_TIMEOUT_MS, DEFAULT_WRITE_TIMEOUT_MS,
     },
-    find_node::{find_closest_node, query_find_node},
+    deadline::{bounded, spawn_scoped, CancelToken, OperationLimits},
+    disjoint_lookup::{find_closest_nodes_disjoint, NB_DISJOINT_PATHS},
+    find_node::{find_closest_node, find_closest_node_traced, query_find_node, query_find_node_around},
+    find_value::{
+        find_value_from_peers, find_value_iteratively, find_value_iteratively_traced, query_find_immutable,
+        query_find_value, query_get_mutable,
+    },
+    get_peers::{get_peers_from_peers, get_peers_iteratively, query_get_peers, FilePeers},
+    lookup_trace::LookupTrace,
+    mailbox::{fetch_mails_from_peers, pick_mailboxes, store_mail_on_peers, MessageDelivery},
+    relay::{connect_relay, serve_relayed},
+    replication::replicate_to,
 };
 use crate::{
-    dht::peer_node::PeerNode,
+    dht::{
+        dht::{DEFAULT_ANNOUNCE_TTL, DEFAULT_MAIL_TTL},
+        identity::node_id,
+        immutable::{immutable_hash, immutable_key, is_immutable_value_of, ImmutableHash},
+        ip_id::IpIdPolicy,
+        mutable::{mutable_hash, mutable_key, MutableHash},
+        peer_node::{PeerNode, LOW_REPUTATION},
+        quota::StorageQuota,
+    },
     file::{file_chunk::FileChunk, torrent_file::TorrentFile},
-    network::protocol::{FileInfo, Peer},
+    network::{
+        api::connect,
+        onion::{wrap_onion, OnionHop},
+        protocol::{Announcement, FileInfo, MutableValue, Peer, StoredValue},
+        retry::RetryPolicy,
+    },
+    utils::{distance, unix_timestamp},
 };
-use errors::AnyResult;
+use ed25519_dalek::Keypair;
+use errors::{bail, AnyResult};
+use rand::seq::SliceRandom;
+use socket2::{Domain, Protocol, Socket, Type};
 use std::{
+    cmp::Reverse,
+    collections::HashMap,
     fs,
     net::SocketAddr,
     ops::{Deref, DerefMut},
@@ -28,6 +58,7 @@ use tokio::{
     self,
     net::{TcpListener, TcpStream},
     sync::Mutex,
+    task::JoinHandle,
 };
 
 // Handle everything about peer. RPC calls, connection handling, and
@@ -35,24 +66,40 @@ use tokio::{
 pub struct Manager {
     id: u32,
     addr: SocketAddr,
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     max_hop: Option<u32>,
+    secure_lookup: bool,
+    limits: OperationLimits,
+    relay: Option<JoinHandle<()>>,
 }
 
 impl Manager {
     // CONSTRUCTOR -------------------------------------------------------------
 
     // Create a new manager. Expect an address like: "127.0.0.1:8080".parse()
-    pub fn new(id: u32, addr: SocketAddr, dht_config_filename: String, working_directory: String) -> Self {
+    // or "[::1]:8080".parse(), and optionally a second one on the other IP
+    // family to be dual-stack. The id of this peer is derived from its keypair.
+    pub fn new(
+        keypair: Keypair,
+        addr: SocketAddr,
+        alt_addr: Option<SocketAddr>,
+        dht_config_filename: String,
+        working_directory: String,
+    ) -> Self {
         Self {
-            id,
+            id: node_id(&keypair.public),
             addr,
-            ctx: Arc::new(Mutex::new(Context::new(
+            ctx: Arc::new(Context::new(
                 dht_config_filename,
                 working_directory,
-                id,
-            ))),
+                keypair,
+                addr,
+                alt_addr,
+            )),
             max_hop: None,
+            secure_lookup: false,
+            limits: OperationLimits::default(),
+            relay: None,
         }
     }
 
@@ -66,6 +113,16 @@ impl Manager {
         self.addr
     }
 
+    // Get the address of this peer on the other IP family, if dual-stack.
+    pub fn alt_addr(&self) -> Option<SocketAddr> {
+        self.ctx.alt_addr
+    }
+
+    // Get the address told to other peers in place of the main one.
+    pub async fn public_addr(&self) -> SocketAddr {
+        self.ctx.public_addr.lock().await.resolve(self.addr)
+    }
+
     // OPTIONS -----------------------------------------------------------------
 
     // Set the max hop possible when searchin for a node.
@@ -75,50 +132,144 @@ impl Manager {
         self.max_hop = max_hop;
     }
 
+    // Enable the secure lookups. The closest peers of a key are then searched
+    // through many disjoint paths, so a single poisoned peer can't steer the
+    // whole lookup. Used when searching values, file peers and messages
+    // recipients.
+    pub fn set_secure_lookup(&mut self, value: bool) {
+        self.secure_lookup = value;
+    }
+
+    // Set the max duration of each operation (find node, store value, download
+    // file...), whatever the number of requests it makes. Once reached, the
+    // operation is aborted with a deadline exceeded error.
+    // None = default behavior (operations are not bounded).
+    pub fn set_deadline(&mut self, value: Option<u64>) {
+        self.limits.deadline = value.map(Duration::from_millis);
+    }
+
+    // Set the token allowing to cancel operations from the outside. Once
+    // cancelled, the running operations and the next ones are aborted with a
+    // cancelled error.
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.limits.cancel_token = token;
+    }
+
     // Enable the recent peer cache. On small network, with non uniform id
     // distribution, caching peers could be hard. The "recent" peers cache is
     // used on top of the routing table, to help finding peers. On big network,
     // it's usually not needed and could be disactivated.
     pub async fn set_recent_peers_cache_enable(&mut self, value: bool) {
-        let mut guard = self.ctx.lock().await;
-        let ctx = guard.deref_mut();
-        ctx.dht.set_recent_peers_cache_enable(value);
+        let mut dht = self.ctx.dht.lock().await;
+        dht.set_recent_peers_cache_enable(value);
     }
 
     // Force this peer to wait X ms before answering each rpc (for debug
     // purpose).
     pub async fn set_slowness(&mut self, value: Option<u64>) {
-        let mut guard = self.ctx.lock().await;
-        let ctx = guard.deref_mut();
-        ctx.slowness = value.map(|val| Duration::from_millis(val));
+        self.ctx.settings.write().await.slowness = value.map(Duration::from_millis);
+    }
+
+    /// Addresses told to other peers to reach this one, at most one per IP
+    /// family, rather than the ones it listens on. Without them, the address
+    /// is learned from what the pinged peers report.
+    pub async fn set_advertise_addrs(&mut self, addrs: Vec<SocketAddr>) {
+        self.ctx.public_addr.lock().await.set_advertised(addrs);
+    }
+
+    /// Reach this peer through a relay, when it can't accept connections: a
+    /// connection to the relay is kept to serve the requests it forwards, the
+    /// relay address is told to other peers, and the server doesn't listen.
+    /// Fails if the relay refuses.
+    pub async fn set_relay(&mut self, relay_addr: SocketAddr) -> AnyResult<()> {
+        let ctx = Arc::clone(&self.ctx);
+        let (own_addr, own_id) = (self.addr, self.id);
+        let stream = connect_relay(Arc::clone(&ctx), relay_addr, own_addr, own_id).await?;
+        let relay = tokio::spawn(serve_relayed(ctx, stream, relay_addr, own_addr, own_id));
+        if let Some(previous) = self.relay.replace(relay) {
+            previous.abort();
+        }
+        Ok(())
+    }
+
+    /// Relay the requests sent to peers which can't accept connections, when
+    /// they ask for it (default is false).
+    pub async fn set_accept_relays(&mut self, value: bool) {
+        self.ctx.settings.write().await.accept_relays = value;
     }
 
     /// Max wait time for initiating a connection (default is 200 ms).
     pub async fn set_connection_timeout(&mut self, value: Option<u64>) {
-        let mut guard = self.ctx.lock().await;
-        let ctx = guard.deref_mut();
-        ctx.connection_timeout = Duration::from_millis(value.unwrap_or(DEFAULT_CONNECTION_TIMEOUT_MS));
+        self.ctx.settings.write().await.connection_timeout =
+            Duration::from_millis(value.unwrap_or(DEFAULT_CONNECTION_TIMEOUT_MS));
     }
 
-    /// Max wait time for sending a query (default is 200 ms).
+    /// Max wait time for sending a control query (default is 200 ms).
     pub async fn set_write_timeout(&mut self, value: Option<u64>) {
-        let mut guard = self.ctx.lock().await;
-        let ctx = guard.deref_mut();
-        ctx.write_timeout = Duration::from_millis(value.unwrap_or(DEFAULT_WRITE_TIMEOUT_MS));
+        self.ctx.settings.write().await.write_timeout =
+            Duration::from_millis(value.unwrap_or(DEFAULT_WRITE_TIMEOUT_MS));
     }
 
-    /// Max wait time for receiving a query (default is 200 ms).
+    /// Max wait time for receiving a control query (default is 200 ms).
     pub async fn set_read_timeout(&mut self, value: Option<u64>) {
-        let mut guard = self.ctx.lock().await;
-        let ctx = guard.deref_mut();
-        ctx.read_timeout = Duration::from_millis(value.unwrap_or(DEFAULT_READ_TIMEOUT_MS));
+        self.ctx.settings.write().await.read_timeout =
+            Duration::from_millis(value.unwrap_or(DEFAULT_READ_TIMEOUT_MS));
+    }
+
+    /// Max wait time for sending a file chunk query (default is 2 sec).
+    pub async fn set_bulk_write_timeout(&mut self, value: Option<u64>) {
+        self.ctx.settings.write().await.bulk_write_timeout =
+            Duration::from_millis(value.unwrap_or(DEFAULT_BULK_WRITE_TIMEOUT_MS));
+    }
+
+    /// Max wait time for receiving a file chunk query (default is 2 sec).
+    pub async fn set_bulk_read_timeout(&mut self, value: Option<u64>) {
+        self.ctx.settings.write().await.bulk_read_timeout =
+            Duration::from_millis(value.unwrap_or(DEFAULT_BULK_READ_TIMEOUT_MS));
+    }
+
+    /// Adapt the wait time for receiving a control query to the round-trip
+    /// time measured for each peer, up to the read timeout.
+    pub async fn set_adaptive_timeouts(&mut self, value: bool) {
+        self.ctx.settings.write().await.adaptive_timeouts = value;
     }
 
     /// Frequency at which the dht is dump into the disk.
     pub async fn set_dht_dump_frequency(&mut self, value: Option<u64>) {
-        let mut guard = self.ctx.lock().await;
-        let ctx = guard.deref_mut();
-        ctx.dht_dump_frequency = Duration::from_millis(value.unwrap_or(DEFAULT_DHT_DUMP_FREQUENCY_MS));
+        self.ctx.settings.write().await.dht_dump_frequency =
+            Duration::from_millis(value.unwrap_or(DEFAULT_DHT_DUMP_FREQUENCY_MS));
+    }
+
+    /// How long a file announcement is kept, unless announced again (default
+    /// is 30 min). Shared files are announced again before it elapses.
+    pub async fn set_announce_ttl(&mut self, value: Option<u64>) {
+        let mut dht = self.ctx.dht.lock().await;
+        dht.set_announce_ttl(value.map_or(DEFAULT_ANNOUNCE_TTL, Duration::from_secs));
+    }
+
+    /// How long a mail is kept for a peer which couldn't be reached, unless it
+    /// fetches it (default is 3 days).
+    pub async fn set_mail_ttl(&mut self, value: Option<u64>) {
+        let mut dht = self.ctx.dht.lock().await;
+        dht.set_mail_ttl(value.map_or(DEFAULT_MAIL_TTL, Duration::from_secs));
+    }
+
+    /// How failed requests to other peers are tried again.
+    pub async fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.ctx.settings.write().await.retry_policy = policy;
+    }
+
+    /// Limits applied on what other peers are allowed to store on us.
+    pub async fn set_storage_quota(&mut self, quota: StorageQuota) {
+        let mut dht = self.ctx.dht.lock().await;
+        dht.set_storage_quota(quota);
+    }
+
+    // Set what to do with peers whose id doesn't match their IP address, like
+    // BEP42 does. Only a few peers sharing the same IP address are kept then.
+    pub async fn set_ip_id_policy(&mut self, policy: IpIdPolicy) {
+        let mut dht = self.ctx.dht.lock().await;
+        dht.set_ip_id_policy(policy);
     }
 
     // CONFIG ------------------------------------------------------------------
@@ -131,30 +282,22 @@ impl Manager {
 
     // Reload the dht from a given file.
     pub async fn load_dht(&mut self, path: &Path) -> AnyResult<()> {
-        let mut guard = self.ctx.lock().await;
-        let ctx = guard.deref_mut();
-        ctx.dht.load_from_file(path).await?;
+        let mut dht = self.ctx.dht.lock().await;
+        dht.load_from_file(path).await?;
         Ok(())
     }
 
     // Get the number of known peers.
     pub async fn known_peers(&mut self) -> impl Iterator<Item = PeerNode> {
-        let mut guard = self.ctx.lock().await;
-        let ctx = guard.deref_mut();
-        let peers = ctx
-            .dht
-            .known_peers()
-            .await
-            .map(|peer| peer.clone())
-            .collect::<Vec<_>>();
-        peers.into_iter()
+        // Collected, as the peers can't outlive the lock of the dht.
+        let dht = self.ctx.dht.lock().await;
+        dht.known_peers().await.collect::<Vec<_>>().into_iter()
     }
 
     // Get the number of known peers.
     pub async fn known_peers_count(&mut self) -> usize {
-        let mut guard = self.ctx.lock().await;
-        let ctx = guard.deref_mut();
-        ctx.dht.known_peers().await.count()
+        let dht = self.ctx.dht.lock().await;
+        dht.known_peers().await.count()
     }
 
     // LOCAL FILES -------------------------------------------------------------
@@ -168,10 +311,11 @@ impl Manager {
         let chunks = FileChunk::open_existing(&torrent.metadata.original_file)?;
 
         let file_crc = torrent.metadata.file_crc;
-        {
-            let mut ctx = self.ctx.lock().await;
-            ctx.available_torrents.insert(file_crc, (torrent, chunks));
-        }
+        self.ctx
+            .available_torrents
+            .lock()
+            .await
+            .insert(file_crc, Arc::new(Mutex::new((torrent, chunks))));
         // Then let's declare we're now sharing it as well.
         self.announce(file_crc).await?;
 
@@ -197,16 +341,24 @@ impl Manager {
 
     // SERVER ------------------------------------------------------------------
 
-    // Start the backend server to listen to command and seed.
+    // Start the backend server to listen to command and seed, until the cancel
+    // token is cancelled. A relayed peer doesn't listen, it serves the commands
+    // its relay forwards instead.
     pub async fn start_server(&self) -> AnyResult<()> {
-        let dht_dump_frequency = {
-            let guard = self.ctx.lock().await;
-            let ctx = guard.deref();
-            ctx.dht_dump_frequency
+        let dht_dump_frequency = self.ctx.settings.read().await.dht_dump_frequency;
+        let announce_ttl = self.ctx.dht.lock().await.announce_ttl();
+
+        let (listener, alt_listener) = match self.relay {
+            Some(_) => (None, None),
+            None => (
+                Some(bind(self.addr)?),
+                match self.ctx.alt_addr {
+                    Some(alt_addr) => Some(bind(alt_addr)?),
+                    None => None,
+                },
+            ),
         };
 
-        let listener = TcpListener::bind(self.addr).await?;
-
         // Let's write the peers list regularly on the disk.
         let ctx = Arc::clone(&self.ctx);
         tokio::spawn(async move {
@@ -217,10 +369,40 @@ impl Manager {
             }
         });
 
+        // Let's forget the outdated announcements, and announce again the files
+        // we share before others forget them.
+        let ctx = Arc::clone(&self.ctx);
+        let (own_addr, own_id, max_hop) = (self.addr, self.id, self.max_hop);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval((announce_ttl / 2).max(Duration::from_secs(1)));
+            // Shared files have just been announced.
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                ctx.dht.lock().await.expire_file_peers();
+                let crcs = ctx
+                    .available_torrents
+                    .lock()
+                    .await
+                    .keys()
+                    .copied()
+                    .collect::<Vec<_>>();
+                for crc in crcs {
+                    let _ = announce(Arc::clone(&ctx), own_addr, own_id, crc, max_hop).await;
+                }
+            }
+        });
+
         // Accept all incoming connection, and spawn a new thread for each.
+        // The server is not bounded by the deadline, but stops once cancelled.
         let own_id = self.id;
+        let cancel_token = self.limits.cancel_token.clone();
         loop {
-            let (stream, _) = listener.accept().await?;
+            let (stream, _) = tokio::select! {
+                accepted = accept(listener.as_ref()) => accepted?,
+                accepted = accept(alt_listener.as_ref()) => accepted?,
+                _ = cancel_token.cancelled() => return Ok(()),
+            };
             let ctx = Arc::clone(&self.ctx);
             tokio::spawn(async move { listen_to_command(ctx, stream, own_id).await });
         }
@@ -231,308 +413,598 @@ impl Manager {
     // Start to bootstrap the DHT from an entry point (any available peers).
     // Start by pinging it, then send a find_node on ourself.
     pub async fn bootstrap(&mut self, peer_addr: SocketAddr) -> AnyResult<Option<Peer>> {
-        let peer = Peer {
-            id: u32::MAX,
-            addr: peer_addr,
-        };
-
-        // As we don't know the id of the peer yet, let's ask him, and put that
-        // into our dht.
-        let target = ping(Arc::clone(&self.ctx), peer, self.addr, self.id()).await?;
-
-        let peer = Peer {
-            id: target,
-            addr: peer_addr,
-        };
+        bounded(self.limits.clone(), async {
+            // As we don't know the id of the peer yet, let's ask him, and put that
+            // into our dht.
+            let target = ping(Arc::clone(&self.ctx), peer_addr, None, self.addr, self.id()).await?;
+
+            let peer = Peer {
+                id: target,
+                addr: peer_addr,
+            };
 
-        // Ask for the entry node for ourself. He will add us into its table,
-        // then give back 4 close nodes.
-        let peer = find_closest_node(
-            Arc::clone(&self.ctx),
-            peer,
-            self.addr,
-            self.id(),
-            self.id(),
-            self.max_hop,
-            query_find_node,
-        )
-        .await?;
+            // Ask for the entry node for ourself. He will add us into its table,
+            // then give back 4 close nodes.
+            let peer = find_closest_node(
+                Arc::clone(&self.ctx),
+                peer,
+                self.addr,
+                self.id(),
+                self.id(),
+                self.max_hop,
+                query_find_node,
+            )
+            .await?;
 
-        Ok(peer)
+            Ok(peer)
+        })
+        .await
     }
 
     // Allow to directly ask a peer by its address, for its closest node.
     pub async fn direct_find_node(&mut self, peer_addr: SocketAddr, target: u32) -> AnyResult<Option<Peer>> {
-        let peer = Peer {
-            id: 0,
-            addr: peer_addr,
-        };
+        bounded(self.limits.clone(), async {
+            let peer = Peer {
+                id: 0,
+                addr: peer_addr,
+            };
 
-        let peer = find_closest_node(
-            Arc::clone(&self.ctx),
-            peer,
-            self.addr,
-            self.id(),
-            target,
-            self.max_hop,
-            query_find_node,
-        )
-        .await?;
-        Ok(peer)
+            let peer = find_closest_node(
+                Arc::clone(&self.ctx),
+                peer,
+                self.addr,
+                self.id(),
+                target,
+                self.max_hop,
+                query_find_node,
+            )
+            .await?;
+            Ok(peer)
+        })
+        .await
     }
 
     // Find node will try to return the wanted peer, or the 4 most closest ones
     // if he's not found.
     pub async fn find_node(&self, target: u32) -> AnyResult<Vec<Peer>> {
-        // Get the closest possible node from the target, to start the search.
-        let peer = {
-            let guard = self.ctx.lock().await;
-            let ctx = guard.deref();
-            ctx.dht.find_closest_peer(target).await
-        };
+        let (peers, _) = self.find_node_traced(target).await?;
+        Ok(peers)
+    }
 
-        match peer {
-            Some(peer) if peer.id() == target => {
-                // We already have it, so no need to make any RPC
-                Ok(vec![peer.into()])
+    // Same as find node, but also return the trace of the lookup, to debug the
+    // routing. The trace is empty if no peer had to be queried.
+    pub async fn find_node_traced(&self, target: u32) -> AnyResult<(Vec<Peer>, LookupTrace)> {
+        bounded(self.limits.clone(), async {
+            // Get the closest possible node from the target, to start the search.
+            let peer = {
+                let dht = self.ctx.dht.lock().await;
+                dht.find_closest_peer(target).await
+            };
+            let no_trace = LookupTrace { target, hops: vec![] };
+
+            match peer {
+                Some(peer) if peer.id() == target => {
+                    // We already have it, so no need to make any RPC
+                    Ok((vec![peer.into()], no_trace))
+                }
+                Some(peer) => {
+                    // Let's start the search
+                    let (found, trace) = find_closest_node_traced(
+                        Arc::clone(&self.ctx),
+                        peer.into(),
+                        self.addr,
+                        self.id(),
+                        target,
+                        self.max_hop,
+                        query_find_node,
+                    )
+                    .await?;
+                    match found {
+                        Some(peer) => Ok((vec![peer], trace)),
+                        None => {
+                            let dht = self.ctx.dht.lock().await;
+                            let peers = dht.find_closest_peers(target, 4).await.map(Into::into).collect();
+                            Ok((peers, trace))
+                        }
+                    }
+                }
+                None => {
+                    // No local peer to start the search.
+                    Ok((vec![], no_trace))
+                }
             }
-            Some(peer) => {
-                // Let's start the search
-                let found = find_closest_node(
+        })
+        .await
+    }
+
+    // Ping a peer by its id. Return if we know the peer.
+    pub async fn ping(&self, target: u32) -> AnyResult<bool> {
+        bounded(self.limits.clone(), async {
+            let peer = {
+                let dht = self.ctx.dht.lock().await;
+                dht.find_closest_peer(target)
+                    .await
+                    .filter(|peer| peer.id() == target)
+            };
+
+            if let Some(peer) = peer {
+                Ok(ping(
                     Arc::clone(&self.ctx),
-                    peer.into(),
+                    peer.addr(),
+                    Some(target),
                     self.addr,
                     self.id(),
-                    target,
-                    self.max_hop,
-                    query_find_node,
                 )
-                .await?;
-                match found {
-                    Some(peer) => Ok(vec![peer]),
-                    None => {
-                        let guard = self.ctx.lock().await;
-                        let ctx = guard.deref();
-                        Ok(ctx
-                            .dht
-                            .find_closest_peers(target, 4)
-                            .await
-                            .map(Into::into)
-                            .collect())
+                .await
+                .is_err())
+            } else {
+                Ok(false)
+            }
+        })
+        .await
+    }
+
+    // Send a message to a peer. When the peer can't be reached, the message is
+    // left to its closest peers instead, until it fetches it. Return what
+    // became of the message.
+    pub async fn send_message(&self, target: u32, message: String) -> AnyResult<MessageDelivery> {
+        bounded(self.limits.clone(), async {
+            let closest_peers = self.lookup_around(target).await?;
+            let peer = closest_peers.iter().find(|peer| peer.id == target);
+            if let Some(peer) = peer {
+                let delivered = match connect(Arc::clone(&self.ctx), peer.addr).await {
+                    Ok(stream) => {
+                        let stream = Arc::new(Mutex::new(stream));
+                        handle_message(Arc::clone(&self.ctx), stream, peer.id, message.clone()).await
                     }
+                    Err(err) => Err(err),
+                };
+                if delivered.is_ok() {
+                    return Ok(MessageDelivery::Delivered);
                 }
             }
-            None => {
-                // No local peer to start the search.
-                Ok(vec![])
+
+            let mailboxes = pick_mailboxes(closest_peers, target);
+            let mail = StoredValue::new(&self.ctx.keypair, target, unix_timestamp(), message);
+            let nb_stored = store_mail_on_peers(
+                Arc::clone(&self.ctx),
+                mailboxes,
+                self.addr,
+                self.id(),
+                target,
+                mail,
+            )
+            .await;
+            if nb_stored == 0 {
+                return Ok(MessageDelivery::Lost);
             }
-        }
+            Ok(MessageDelivery::Stored(nb_stored))
+        })
+        .await
     }
 
-    // Ping a peer by its id. Return if we know the peer.
-    pub async fn ping(&self, target: u32) -> AnyResult<bool> {
-        let peer = {
-            let mut guard = self.ctx.lock().await;
-            let ctx = guard.deref_mut();
-            ctx.dht
-                .find_closest_peer(target)
-                .await
-                .filter(|peer| peer.id() == target)
-        };
-
-        if let Some(peer) = peer {
-            Ok(ping(Arc::clone(&self.ctx), peer.into(), self.addr, self.id())
-                .await
-                .is_err())
-        } else {
-            Ok(false)
-        }
+    // Fetch the mails our closest peers kept for us while we couldn't be
+    // reached, the oldest first. They're forgotten once acknowledged.
+    pub async fn fetch_mails(&self) -> AnyResult<Vec<StoredValue>> {
+        bounded(self.limits.clone(), async {
+            let closest_peers = self.lookup_around(self.id()).await?;
+            let mails = fetch_mails_from_peers(
+                Arc::clone(&self.ctx),
+                pick_mailboxes(closest_peers, self.id()),
+                self.addr,
+                self.id(),
+            )
+            .await;
+            Ok(mails)
+        })
+        .await
     }
 
-    // Send a message to a peer. Return if the peer acknowledge it.
-    pub async fn send_message(&self, target: u32, message: String) -> AnyResult<bool> {
-        let closest_peers = self.find_node(target).await?;
-        let peer = closest_peers.iter().find(|peer| peer.id == target);
-        if let Some(peer) = peer {
-            let stream = Arc::new(Mutex::new(TcpStream::connect(peer.addr).await?));
-            handle_message(Arc::clone(&self.ctx), stream, message).await?;
-            return Ok(true);
-        }
-        Ok(false)
+    // Send a message to a peer through many hops picked at random among the
+    // known peers, each one only knowing the previous and the next one. The
+    // message is wrapped in one encrypted layer per hop, so only the recipient
+    // can read it. The recipient is never contacted by us: it's searched
+    // without being asked, and its key is asked to the peers around it.
+    // Return if the first hop took the message.
+    pub async fn forward_message(&self, target: u32, message: String, nb_hops: usize) -> AnyResult<bool> {
+        bounded(self.limits.clone(), async {
+            let closest_peers = self.lookup_around(target).await?;
+            let recipient = match closest_peers.iter().find(|peer| peer.id == target) {
+                Some(recipient) => recipient.clone(),
+                None => return Ok(false),
+            };
+
+            let hops = {
+                let dht = self.ctx.dht.lock().await;
+                let candidates = dht
+                    .known_peers()
+                    .await
+                    .filter(|peer| peer.id() != target && peer.id() != self.id)
+                    .map(Peer::from)
+                    .collect::<Vec<_>>();
+                candidates
+                    .choose_multiple(&mut rand::thread_rng(), nb_hops)
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            if hops.len() < nb_hops {
+                bail!(
+                    "not enough known peers to pick {} hops, only {}",
+                    nb_hops,
+                    hops.len()
+                );
+            }
+
+            // Keys are checked against the peers id before being used. The
+            // hops give theirs, the recipient one is given by its closest peers.
+            let mut route = Vec::with_capacity(hops.len() + 1);
+            for peer in &hops {
+                let public_key = handle_public_key(Arc::clone(&self.ctx), peer, peer.id).await?;
+                route.push(OnionHop {
+                    addr: peer.addr,
+                    public_key,
+                });
+            }
+            let mut recipient_key = None;
+            for peer in closest_peers.iter().filter(|peer| peer.id != target) {
+                if let Ok(public_key) = handle_public_key(Arc::clone(&self.ctx), peer, target).await {
+                    recipient_key = Some(public_key);
+                    break;
+                }
+            }
+            match recipient_key {
+                Some(public_key) => route.push(OnionHop {
+                    addr: recipient.addr,
+                    public_key,
+                }),
+                None => bail!("no peer gave the public key of {}", target),
+            }
+            let onion = wrap_onion(&route, message)?;
+
+            let first_hop = hops.first().unwrap_or(&recipient);
+            let stream = Arc::new(Mutex::new(
+                connect_to_peer(Arc::clone(&self.ctx), first_hop).await?,
+            ));
+            handle_onion(Arc::clone(&self.ctx), stream, onion).await?;
+            Ok(true)
+        })
+        .await
     }
 
     // Return a file description from its crc
     pub async fn file_info(&mut self, crc: u32) -> AnyResult<Option<FileInfo>> {
-        self.get_peers(crc).await?;
-
-        let peers: Option<Vec<_>> = {
-            let guard = self.ctx.lock().await;
-            let ctx = guard.deref();
-            ctx.dht
-                .get_file_peers(crc)
-                .map(|it| it.map(Clone::clone).collect())
-        };
-
-        match peers {
-            Some(peers) => {
-                let mut fileinfo = None;
-                for peer in peers {
-                    if let Ok(connection) = TcpStream::connect(peer.addr).await {
-                        let stream = Arc::new(Mutex::new(connection));
-
-                        let res = handle_file_info(Arc::clone(&self.ctx), stream, crc).await?;
-                        if res.is_some() {
-                            fileinfo = res;
+        bounded(self.limits.clone(), async {
+            let peers = self.file_owners(crc).await?;
+
+            match peers {
+                Some(peers) => {
+                    let mut fileinfo = None;
+                    for peer in self.rank_file_peers(peers).await {
+                        if let Ok(connection) = connect(Arc::clone(&self.ctx), peer.addr).await {
+                            let stream = Arc::new(Mutex::new(connection));
+
+                            let res = handle_file_info(Arc::clone(&self.ctx), stream, peer.id, crc).await?;
+                            if res.is_some() {
+                                fileinfo = res;
+                            }
                         }
                     }
+                    Ok(fileinfo)
                 }
-                Ok(fileinfo)
+                None => Ok(None),
             }
-            None => Ok(None),
-        }
+        })
+        .await
     }
 
     // Start downloading a file, or resume downloading
     pub async fn download_file(&mut self, crc: u32) -> AnyResult<Option<(u32, u32)>> {
-        let ctx = Arc::clone(&self.ctx);
-        let peers: Option<Vec<_>> = {
-            self.get_peers(crc).await?;
-            let guard = self.ctx.lock().await;
-            let ctx = guard.deref();
-            ctx.dht
-                .get_file_peers(crc)
-                .map(|it| it.map(Clone::clone).collect())
-        };
+        bounded(self.limits.clone(), async {
+            let ctx = Arc::clone(&self.ctx);
+            let peers = self.file_owners(crc).await?;
+
+            if let Some(peers) = peers {
+                // We're trusting them to all share the same file, the one with the
+                // best reputation describes it.
+                let peers = self.rank_file_peers(peers).await;
+                let file_info = if let Some(peer) = peers.first() {
+                    let stream = Arc::new(Mutex::new(connect(Arc::clone(&self.ctx), peer.addr).await?));
+                    handle_file_info(Arc::clone(&self.ctx), stream, peer.id, crc).await?
+                } else {
+                    return Ok(None);
+                };
 
-        if let Some(peers) = peers {
-            // We're trusting them to all share the same file.
-            let file_info = if let Some(peer) = peers.first() {
-                let stream = Arc::new(Mutex::new(TcpStream::connect(peer.addr).await?));
-                handle_file_info(Arc::clone(&self.ctx), stream, crc).await?
-            } else {
-                return Ok(None);
-            };
+                if let Some(file_info) = file_info {
+                    // If the file is available, put it into our local store.
+                    // Create metadata file and preallocate the file.
+                    {
+                        let working_directory = ctx.settings.read().await.working_directory.clone();
+                        let file_to_preallocate =
+                            format!("{}/{}", working_directory, file_info.original_filename);
+                        let torrent_file = format!("{}.torrent", file_to_preallocate);
+                        let torrent = (
+                            TorrentFile::new(torrent_file, file_to_preallocate.clone(), &file_info)?,
+                            FileChunk::open_new(file_to_preallocate, file_info.file_size)?,
+                        );
 
-            if let Some(file_info) = file_info {
-                // If the file is available, put it into our local store.
-                // Create metadata file and preallocate the file.
-                {
-                    let mut guard = ctx.lock().await;
-                    let ctx = guard.deref_mut();
+                        ctx.available_torrents
+                            .lock()
+                            .await
+                            .insert(crc, Arc::new(Mutex::new(torrent)));
+                    }
 
-                    let file_to_preallocate =
-                        format!("{}/{}", ctx.working_directory, file_info.original_filename);
-                    let torrent_file = format!("{}.torrent", file_to_preallocate);
+                    // Then let's declare we're now sharing it as well.
+                    self.announce(crc).await?;
 
-                    ctx.available_torrents.insert(
-                        crc,
-                        (
-                            TorrentFile::new(torrent_file, file_to_preallocate.clone(), &file_info)?,
-                            FileChunk::open_new(file_to_preallocate, file_info.file_size)?,
-                        ),
-                    );
+                    // Then download it!
+                    let nb_chunks = file_info.nb_chunks();
+                    let nb_succeed = download_file_from_peers(ctx, &peers, crc, nb_chunks).await?;
+                    return Ok(Some((nb_succeed, nb_chunks)));
                 }
+            }
+
+            Ok(None)
+        })
+        .await
+    }
+
+    // Find all the values of the given key. Search locally, then if not found,
+    // ask peers for the values, hopping toward the key until one of them has
+    // some.
+    pub async fn find_value(&mut self, target: u32) -> AnyResult<Vec<StoredValue>> {
+        let (values, _) = self.find_values(target, None).await?;
+        Ok(values)
+    }
+
+    // Same as find value, but also return the trace of the lookup, to debug the
+    // routing. The trace is empty if the value is held locally.
+    pub async fn find_value_traced(&mut self, target: u32) -> AnyResult<(Vec<StoredValue>, LookupTrace)> {
+        self.find_values(target, None).await
+    }
 
-                // Then let's declare we're now sharing it as well.
-                self.announce(crc).await?;
+    // Find an immutable value from the hash of its content. Values not matching
+    // the hash are dropped, and the peers which sent them are penalised.
+    pub async fn find_immutable(&mut self, hash: ImmutableHash) -> AnyResult<Vec<StoredValue>> {
+        let (values, _) = self.find_values(immutable_key(&hash), Some(hash)).await?;
+        Ok(values)
+    }
 
-                // Then download it!
-                let nb_chunks = file_info.nb_chunks();
-                let nb_succeed = download_file_from_peers(ctx, &peers, crc, nb_chunks).await?;
-                return Ok(Some((nb_succeed, nb_chunks)));
+    // Find the values of a key, only keeping the ones matching an immutable
+    // hash, if any. The trace of the lookup is returned as well.
+    async fn find_values(
+        &mut self,
+        target: u32,
+        immutable: Option<ImmutableHash>,
+    ) -> AnyResult<(Vec<StoredValue>, LookupTrace)> {
+        bounded(self.limits.clone(), async {
+            let (values, closest_peers) = {
+                let dht = self.ctx.dht.lock().await;
+                (
+                    dht.get_values(target)
+                        .map(|values| {
+                            values
+                                .iter()
+                                .filter(|stored| {
+                                    immutable.map_or(true, |hash| is_immutable_value_of(&hash, &stored.value))
+                                })
+                                .cloned()
+                                .collect::<Vec<_>>()
+                        })
+                        .filter(|values| !values.is_empty()),
+                    dht.find_closest_peers(target, 4).await.map(Peer::from).collect(),
+                )
+            };
+            // We already have this value locally
+            if let Some(values) = values {
+                return Ok((values, LookupTrace { target, hops: vec![] }));
             }
-        }
 
-        Ok(None)
+            // Starting for the 4 closest peers, search for this value
+            let (lookup, trace) = if self.secure_lookup {
+                let closest_peers = self.disjoint_lookup(target).await?;
+                if let Some(hash) = immutable {
+                    find_value_from_peers(
+                        Arc::clone(&self.ctx),
+                        closest_peers,
+                        self.addr,
+                        self.id(),
+                        target,
+                        move |ctx, peer, sender_addr, sender_id, _| {
+                            query_find_immutable(ctx, peer, sender_addr, sender_id, hash)
+                        },
+                    )
+                    .await?
+                } else {
+                    find_value_from_peers(
+                        Arc::clone(&self.ctx),
+                        closest_peers,
+                        self.addr,
+                        self.id(),
+                        target,
+                        query_find_value,
+                    )
+                    .await?
+                }
+            } else if let Some(hash) = immutable {
+                find_value_iteratively_traced(
+                    Arc::clone(&self.ctx),
+                    closest_peers,
+                    self.addr,
+                    self.id(),
+                    target,
+                    self.max_hop,
+                    move |ctx, peer, sender_addr, sender_id, _| {
+                        query_find_immutable(ctx, peer, sender_addr, sender_id, hash)
+                    },
+                )
+                .await?
+            } else {
+                find_value_iteratively_traced(
+                    Arc::clone(&self.ctx),
+                    closest_peers,
+                    self.addr,
+                    self.id(),
+                    target,
+                    self.max_hop,
+                    query_find_value,
+                )
+                .await?
+            };
+
+            // Cache the values on the closest peer which didn't have them, the next
+            // lookups will end sooner.
+            let values = lookup.value.unwrap_or_default();
+            if let Some(peer) = lookup.closest_without_value.filter(|_| !values.is_empty()) {
+                if let Ok(connection) = connect_to_peer(Arc::clone(&self.ctx), &peer).await {
+                    let stream = Arc::new(Mutex::new(connection));
+                    for value in &values {
+                        let _ = handle_store(
+                            Arc::clone(&self.ctx),
+                            Arc::clone(&stream),
+                            peer.id,
+                            self.addr,
+                            self.id(),
+                            target,
+                            value.clone(),
+                        )
+                        .await;
+                    }
+                }
+            }
+
+            Ok((values, trace))
+        })
+        .await
     }
 
-    // Find the given value by its key. Search locally, then if not found, ask
-    // peers for the value.
-    pub async fn find_value(&mut self, target: u32) -> AnyResult<Option<String>> {
-        let (value, closest_peers) = {
-            let guard = self.ctx.lock().await;
-            let ctx = guard.deref();
-            (
-                ctx.dht.get_value(target).map(Clone::clone),
-                ctx.dht.find_closest_peers(target, 4).await,
-            )
-        };
-        // We already have this value locally
-        if value.is_some() {
-            return Ok(value);
-        }
+    // Store an immutable value, its key being the beginning of the sha256 of
+    // its content. Return the whole hash, and how many peers stored it.
+    pub async fn store_immutable(&mut self, message: String) -> AnyResult<(ImmutableHash, usize)> {
+        let hash = immutable_hash(&message);
+        let nb_store = self.store_value(immutable_key(&hash), message).await?;
+        Ok((hash, nb_store))
+    }
 
-        // Starting for the 4 closest peers, search for this value
-        for peer in closest_peers {
-            find_closest_node(
+    // Find the most recent version of a mutable value from its hash. Search
+    // locally and on the peers, hopping toward its key, as an old version could
+    // be stored locally. Values not matching the hash are dropped, and the
+    // peers which sent them are penalised.
+    pub async fn get_mutable(&mut self, hash: MutableHash) -> AnyResult<Option<MutableValue>> {
+        bounded(self.limits.clone(), async {
+            let target = mutable_key(&hash);
+            let (local_value, closest_peers) = {
+                let dht = self.ctx.dht.lock().await;
+                (
+                    dht.get_mutable(&hash).cloned(),
+                    dht.find_closest_peers(target, 4).await.map(Peer::from).collect(),
+                )
+            };
+
+            // Starting for the 4 closest peers, search for this value
+            let lookup = find_value_iteratively(
                 Arc::clone(&self.ctx),
-                peer.into(),
+                closest_peers,
                 self.addr,
                 self.id(),
                 target,
                 self.max_hop,
-                query_find_node,
+                move |ctx, peer, sender_addr, sender_id, _| {
+                    query_get_mutable(ctx, peer, sender_addr, sender_id, hash)
+                },
             )
             .await?;
 
-            let closest_peers = {
-                let guard = self.ctx.lock().await;
-                let ctx = guard.deref();
-                ctx.dht.find_closest_peers(target, 4).await
+            let value = match (local_value, lookup.value) {
+                (Some(local), Some(found)) if local.seq >= found.seq => Some(local),
+                (local, None) => local,
+                (_, found) => found,
             };
-            for close_peer in closest_peers {
-                let stream = Arc::new(Mutex::new(TcpStream::connect(close_peer.addr()).await?));
-                let message =
-                    handle_find_value(Arc::clone(&self.ctx), stream, self.addr, self.id(), target).await?;
-                if message.is_some() {
-                    return Ok(message);
+
+            if let Some(value) = &value {
+                // Keep the most recent version we know, and cache it on the closest
+                // peer which didn't have it, the next lookups will end sooner.
+                {
+                    let mut dht = self.ctx.dht.lock().await;
+                    let _ = dht.store_mutable(value.clone(), false);
+                }
+                if let Some(peer) = lookup.closest_without_value {
+                    if let Ok(connection) = connect_to_peer(Arc::clone(&self.ctx), &peer).await {
+                        let stream = Arc::new(Mutex::new(connection));
+                        let _ = handle_put_mutable(
+                            Arc::clone(&self.ctx),
+                            stream,
+                            peer.id,
+                            self.addr,
+                            self.id(),
+                            value.clone(),
+                        )
+                        .await;
+                    }
                 }
             }
-        }
 
-        Ok(None)
+            Ok(value)
+        })
+        .await
     }
 
-    // Find the given value by its key. Search locally, then if not found, ask
-    // peers for the value.
-    pub async fn store_value(&mut self, target: u32, message: String) -> AnyResult<usize> {
-        // Store the value for us
-        let closest_peer = {
-            let mut guard = self.ctx.lock().await;
-            let ctx = guard.deref_mut();
-            ctx.dht.store_value(target, message.clone());
-            ctx.dht.find_closest_peers(target, 1).await
-        };
+    // Publish a new version of a mutable value owned by the given keypair. The
+    // sequence number follows the most recent version found on the network.
+    // Return the hash of the value and how many peers stored it.
+    pub async fn put_mutable(
+        &mut self,
+        keypair: &Keypair,
+        salt: String,
+        value: String,
+    ) -> AnyResult<(MutableHash, usize)> {
+        bounded(self.limits.clone(), async {
+            let hash = mutable_hash(keypair.public.as_bytes(), &salt);
+            let key = mutable_key(&hash);
+            let seq = self.get_mutable(hash).await?.map_or(0, |found| found.seq + 1);
+            let mutable_value = MutableValue::new(keypair, salt, seq, value);
+
+            // Store the value for us
+            let closest_peer = {
+                let mut dht = self.ctx.dht.lock().await;
+                dht.store_mutable(mutable_value.clone(), true)?;
+                dht.find_closest_peer(key).await
+            };
 
-        if let Some(peer) = closest_peer.take(1).collect::<Vec<PeerNode>>().pop() {
-            // Let's find the 4 closest nodes to us, and then ask them to store our
-            // value.
+            let peer = match closest_peer {
+                Some(peer) => peer,
+                None => return Ok((hash, 0)),
+            };
+
+            // Let's find the 4 closest nodes to the key, and then ask them to
+            // store our value.
             find_closest_node(
                 Arc::clone(&self.ctx),
-                peer.clone().into(),
+                peer.into(),
                 self.addr,
                 self.id(),
-                target,
+                key,
                 self.max_hop,
                 query_find_node,
             )
             .await?;
 
             let closest_peers = {
-                let guard = self.ctx.lock().await;
-                let ctx = guard.deref();
-                ctx.dht.find_closest_peers(target, 4).await
+                let dht = self.ctx.dht.lock().await;
+                dht.find_closest_peers(key, 4).await
             };
             let mut nb_store = 0;
-            for close_peer in closest_peers.chain(std::iter::once(peer)) {
-                let stream = Arc::new(Mutex::new(TcpStream::connect(close_peer.addr()).await?));
-                if handle_store(
+            for close_peer in closest_peers {
+                let connection = match connect(Arc::clone(&self.ctx), close_peer.addr()).await {
+                    Ok(connection) => connection,
+                    Err(_) => continue,
+                };
+                if handle_put_mutable(
                     Arc::clone(&self.ctx),
-                    stream,
+                    Arc::new(Mutex::new(connection)),
+                    close_peer.id(),
                     self.addr,
                     self.id(),
-                    target,
-                    message.clone(),
+                    mutable_value.clone(),
                 )
                 .await
                 .is_ok()
@@ -540,150 +1012,386 @@ impl Manager {
                     nb_store += 1;
                 }
             }
-            return Ok(nb_store);
-        }
 
-        Ok(0)
+            Ok((hash, nb_store))
+        })
+        .await
     }
 
-    // Declare to closest peers that we're sharing a file.
-    pub async fn announce(&mut self, crc: u32) -> AnyResult<usize> {
-        // Store the value for us
-        let closest_peer = {
-            let mut guard = self.ctx.lock().await;
-            let ctx = guard.deref_mut();
-            ctx.dht.store_file_peer(
-                crc,
-                Peer {
-                    id: self.id(),
-                    addr: self.addr,
-                },
-            );
-            ctx.dht.find_closest_peers(crc, 1).await
-        };
+    // Store a value for the given key, alongside the values other peers may
+    // have already published for it.
+    pub async fn store_value(&mut self, target: u32, message: String) -> AnyResult<usize> {
+        bounded(self.limits.clone(), async {
+            let stored_value = StoredValue::new(&self.ctx.keypair, target, unix_timestamp(), message);
+
+            // Store the value for us
+            let closest_peer = {
+                let mut dht = self.ctx.dht.lock().await;
+                dht.store_value(target, stored_value.clone())?;
+                dht.find_closest_peers(target, 1).await
+            };
 
-        if let Some(peer) = closest_peer.take(1).collect::<Vec<PeerNode>>().pop() {
-            // Let's find the 4 closest nodes to us, and then ask them to store our
-            // value.
-            find_closest_node(
-                Arc::clone(&self.ctx),
-                peer.into(),
-                self.addr,
-                self.id(),
-                crc,
-                self.max_hop,
-                query_find_node,
-            )
-            .await?;
+            if let Some(peer) = closest_peer.take(1).collect::<Vec<PeerNode>>().pop() {
+                // Let's find the 4 closest nodes to us, and then ask them to store our
+                // value.
+                find_closest_node(
+                    Arc::clone(&self.ctx),
+                    peer.clone().into(),
+                    self.addr,
+                    self.id(),
+                    target,
+                    self.max_hop,
+                    query_find_node,
+                )
+                .await?;
 
-            let closest_peers = {
-                let guard = self.ctx.lock().await;
-                let ctx = guard.deref();
-                ctx.dht.find_closest_peers(crc, 4).await
-            };
-            let mut nb_store = 0;
-            for close_peer in closest_peers {
-                if let Ok(connection) = TcpStream::connect(close_peer.addr()).await {
-                    let stream = Arc::new(Mutex::new(connection));
-                    if handle_announce(Arc::clone(&self.ctx), stream, self.addr, self.id(), crc)
-                        .await
-                        .is_ok()
+                let closest_peers = {
+                    let dht = self.ctx.dht.lock().await;
+                    dht.find_closest_peers(target, 4).await
+                };
+                let mut nb_store = 0;
+                for close_peer in closest_peers.chain(std::iter::once(peer)) {
+                    let stream = Arc::new(Mutex::new(
+                        connect(Arc::clone(&self.ctx), close_peer.addr()).await?,
+                    ));
+                    if handle_store(
+                        Arc::clone(&self.ctx),
+                        stream,
+                        close_peer.id(),
+                        self.addr,
+                        self.id(),
+                        target,
+                        stored_value.clone(),
+                    )
+                    .await
+                    .is_ok()
                     {
                         nb_store += 1;
                     }
                 }
+                return Ok(nb_store);
             }
-            return Ok(nb_store);
-        }
 
-        Ok(0)
+            Ok(0)
+        })
+        .await
     }
 
-    // Get all peers who owned a file, given its crc.
-    pub async fn get_peers(&mut self, crc: u32) -> AnyResult<()> {
-        // Start to search locally.
-        let closest_peers = {
-            let guard = self.ctx.lock().await;
-            let ctx = guard.deref();
-            if ctx.dht.get_file_peers(crc).is_some() {
-                return Ok(());
+    // Declare to closest peers that we're sharing a file.
+    pub async fn announce(&mut self, crc: u32) -> AnyResult<usize> {
+        let announce = announce(Arc::clone(&self.ctx), self.addr, self.id(), crc, self.max_hop);
+        bounded(self.limits.clone(), announce).await
+    }
+
+    // Get all peers who own a file, given its crc, each one with the peer which
+    // reported it, ourself for the ones we already know. All the closest peers
+    // of the file are asked. What they report isn't stored: only the owners
+    // themselves can announce they own a file.
+    pub async fn get_peers(&mut self, crc: u32) -> AnyResult<FilePeers> {
+        bounded(self.limits.clone(), async {
+            let myself = Peer {
+                id: self.id(),
+                addr: self.addr,
+            };
+            let (mut found, closest_peers) = {
+                let dht = self.ctx.dht.lock().await;
+                let local_peers = dht
+                    .get_file_peers(crc)
+                    .map(|peers| peers.map(|peer| (peer.clone(), myself.clone())).collect())
+                    .unwrap_or_else(FilePeers::new);
+                let closest_peers = dht.find_closest_peers(crc, 4).await.map(Peer::from).collect();
+                (local_peers, closest_peers)
+            };
+
+            // Search the closest peers through disjoint paths, then ask them.
+            let lookup = if self.secure_lookup {
+                let closest_peers = self.disjoint_lookup(crc).await?;
+                get_peers_from_peers(
+                    Arc::clone(&self.ctx),
+                    closest_peers,
+                    self.addr,
+                    self.id(),
+                    crc,
+                    query_get_peers,
+                )
+                .await?
+            } else {
+                get_peers_iteratively(
+                    Arc::clone(&self.ctx),
+                    closest_peers,
+                    self.addr,
+                    self.id(),
+                    crc,
+                    self.max_hop,
+                    query_get_peers,
+                )
+                .await?
+            };
+
+            for (peer, responder) in lookup {
+                if !found.iter().any(|(known, _)| known.id == peer.id) {
+                    found.push((peer, responder));
+                }
             }
 
-            ctx.dht.find_closest_peers(crc, 4).await
+            Ok(found)
+        })
+        .await
+    }
+
+    // Get the peers owning a file, if any, from the ones we know and the ones
+    // the closest peers of the file know.
+    async fn file_owners(&mut self, crc: u32) -> AnyResult<Option<Vec<Peer>>> {
+        let owners = self
+            .get_peers(crc)
+            .await?
+            .into_iter()
+            .map(|(owner, _)| owner)
+            .collect::<Vec<_>>();
+        Ok(Some(owners).filter(|owners| !owners.is_empty()))
+    }
+
+    // Order the peers owning a file, the fastest first, then by reputation.
+    // Peers we never measured come last. Banned peers are dropped, and the ones
+    // with a low reputation are only kept if there's nobody else.
+    async fn rank_file_peers(&self, peers: Vec<Peer>) -> Vec<Peer> {
+        let dht = self.ctx.dht.lock().await;
+        let known_peers = dht
+            .known_peers()
+            .await
+            .map(|peer| (peer.id(), peer))
+            .collect::<HashMap<_, _>>();
+        let mut ranked = Vec::with_capacity(peers.len());
+        for peer in peers {
+            if dht.is_banned(peer.id) {
+                continue;
+            }
+            let known = known_peers.get(&peer.id);
+            let reputation = known.map(PeerNode::reputation).unwrap_or_default();
+            let srtt = known.and_then(PeerNode::srtt).unwrap_or(Duration::MAX);
+            ranked.push((reputation, srtt, peer));
+        }
+        ranked.sort_by_key(|(reputation, srtt, _)| (*srtt, Reverse(*reputation)));
+        if ranked
+            .iter()
+            .any(|(reputation, _, _)| *reputation > LOW_REPUTATION)
+        {
+            ranked.retain(|(reputation, _, _)| *reputation > LOW_REPUTATION);
+        }
+        ranked.into_iter().map(|(_, _, peer)| peer).collect()
+    }
+
+    // Search the closest peers of a target, the target included when found,
+    // without asking the target itself. Return the peers which answered, the
+    // closest first.
+    async fn lookup_around(&self, target: u32) -> AnyResult<Vec<Peer>> {
+        let initial_peers = {
+            let dht = self.ctx.dht.lock().await;
+            dht.find_closest_peers(target, 4 * NB_DISJOINT_PATHS + 1)
+                .await
+                .filter(|peer| peer.id() != target)
+                .map(Peer::from)
+                .collect::<Vec<_>>()
+        };
+        let initial_peer = match initial_peers.first() {
+            Some(initial_peer) => initial_peer.clone(),
+            None => return Ok(vec![]),
         };
 
-        // Starting for the 4 closest peers, search for this value
-        for peer in closest_peers {
-            find_closest_node(
+        if self.secure_lookup {
+            return find_closest_nodes_disjoint(
                 Arc::clone(&self.ctx),
-                peer.into(),
+                initial_peers,
                 self.addr,
                 self.id(),
-                crc,
+                target,
                 self.max_hop,
-                query_find_node,
+                query_find_node_around,
             )
-            .await?;
-
-            let closest_peers = {
-                let guard = self.ctx.lock().await;
-                let ctx = guard.deref();
-                ctx.dht.find_closest_peers(crc, 4).await
-            };
-            for close_peer in closest_peers {
-                if let Ok(connection) = TcpStream::connect(close_peer.addr()).await {
-                    let stream = Arc::new(Mutex::new(connection));
-                    let message = handle_get_peers(Arc::clone(&self.ctx), stream, crc).await?;
-                    if let Some(found_peers) = message {
-                        let mut guard = self.ctx.lock().await;
-                        let ctx = guard.deref_mut();
-                        for found_peer in found_peers {
-                            ctx.dht.store_file_peer(crc, found_peer);
-                        }
-                    }
-                }
-            }
+            .await;
         }
+        let (found, trace) = find_closest_node_traced(
+            Arc::clone(&self.ctx),
+            initial_peer,
+            self.addr,
+            self.id(),
+            target,
+            self.max_hop,
+            query_find_node_around,
+        )
+        .await?;
+        let mut peers = found
+            .into_iter()
+            .chain(
+                trace
+                    .hops
+                    .into_iter()
+                    .flat_map(|hop| hop.queries)
+                    .filter(|query| query.error.is_none() && query.peer.id != target)
+                    .map(|query| query.peer),
+            )
+            .collect::<Vec<_>>();
+        peers.sort_by_key(|peer| distance(peer.id, target));
+        peers.dedup_by_key(|peer| peer.id);
+        Ok(peers)
+    }
 
-        Ok(())
+    // Search the closest peers of a target through disjoint paths, starting
+    // from the closest peers we know.
+    async fn disjoint_lookup(&self, target: u32) -> AnyResult<Vec<Peer>> {
+        let initial_peers = {
+            let dht = self.ctx.dht.lock().await;
+            dht.find_closest_peers(target, 4 * NB_DISJOINT_PATHS)
+                .await
+                .map(Peer::from)
+                .collect()
+        };
+
+        find_closest_nodes_disjoint(
+            Arc::clone(&self.ctx),
+            initial_peers,
+            self.addr,
+            self.id(),
+            target,
+            self.max_hop,
+            query_find_node,
+        )
+        .await
+    }
+}
+
+// The connection to the relay, if any, is closed with the manager.
+impl Drop for Manager {
+    fn drop(&mut self) {
+        if let Some(relay) = &self.relay {
+            relay.abort();
+        }
     }
 }
 
 // Helpers ---------------------------------------------------------------------
 
+// Listen on an address. An IPv6 socket only accepts IPv6 connections, so the
+// same port can be listened on both families, each with its own address.
+pub fn bind(addr: SocketAddr) -> AnyResult<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+// Accept a connection on a listener, if any. Without listener, wait forever.
+async fn accept(listener: Option<&TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Declare to closest peers that we're sharing a file.
+async fn announce(
+    ctx: Arc<Context>,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    crc: u32,
+    max_hop: Option<u32>,
+) -> AnyResult<usize> {
+    // Store the value for us, with the address others reach us on. It's signed
+    // so the peers we announce it to can replicate it.
+    let public_addr = ctx.public_addr.lock().await.resolve(sender_addr);
+    let announcement = Announcement::new(&ctx.keypair, crc, public_addr, unix_timestamp());
+    let closest_peer = {
+        let mut dht = ctx.dht.lock().await;
+        dht.store_file_peer(crc, announcement.clone())?;
+        dht.find_closest_peers(crc, 1).await
+    };
+
+    if let Some(peer) = closest_peer.take(1).collect::<Vec<PeerNode>>().pop() {
+        // Let's find the 4 closest nodes to us, and then ask them to store our
+        // value.
+        find_closest_node(
+            Arc::clone(&ctx),
+            peer.into(),
+            sender_addr,
+            sender_id,
+            crc,
+            max_hop,
+            query_find_node,
+        )
+        .await?;
+
+        let closest_peers = {
+            let dht = ctx.dht.lock().await;
+            dht.find_closest_peers(crc, 4).await
+        };
+        let mut nb_store = 0;
+        for close_peer in closest_peers {
+            if let Ok(connection) = connect(Arc::clone(&ctx), close_peer.addr()).await {
+                let stream = Arc::new(Mutex::new(connection));
+                if handle_announce(
+                    Arc::clone(&ctx),
+                    stream,
+                    close_peer.id(),
+                    sender_addr,
+                    sender_id,
+                    crc,
+                    announcement.clone(),
+                )
+                .await
+                .is_ok()
+                {
+                    nb_store += 1;
+                }
+            }
+        }
+        return Ok(nb_store);
+    }
+
+    Ok(0)
+}
+
 // Ping a peer from its real address, ask him its id and put it into our dht.
+// The ping is signed for the peer when its id is already known.
 async fn ping(
-    ctx: Arc<Mutex<Context>>,
-    peer: Peer,
+    ctx: Arc<Context>,
+    peer_addr: SocketAddr,
+    peer_id: Option<u32>,
     sender_addr: SocketAddr,
     sender_id: u32,
 ) -> AnyResult<u32> {
-    let stream = Arc::new(Mutex::new(TcpStream::connect(peer.addr).await?));
-    let target = handle_ping(Arc::clone(&ctx), stream, sender_addr, sender_id).await?;
+    let stream = Arc::new(Mutex::new(connect(Arc::clone(&ctx), peer_addr).await?));
+    let target = handle_ping(Arc::clone(&ctx), stream, peer_id, sender_addr, sender_id).await?;
 
     // The peer just answered us, let's add him into our dht.
-    {
-        let mut guard = ctx.lock().await;
-        let ctx = guard.deref_mut();
-        ctx.dht.add_node(target, peer.addr).await;
-        let _ = ctx.dht.dump_to_file(Path::new(&ctx.dht_config_filename)).await;
-    }
+    let replication = ctx.dht.lock().await.add_node(target, peer_addr).await;
+    let _ = ctx.dump_dht().await;
+    replicate_to(
+        Arc::clone(&ctx),
+        Peer {
+            id: target,
+            addr: peer_addr,
+        },
+        replication,
+    );
 
     Ok(target)
 }
 
 // Dump the dht into a file.
-async fn dump_dht(ctx: Arc<Mutex<Context>>) -> AnyResult<()> {
-    let guard = ctx.lock().await;
-    let ctx = guard.deref();
-    ctx.dht.dump_to_file(Path::new(&ctx.dht_config_filename)).await?;
-    Ok(())
+async fn dump_dht(ctx: Arc<Context>) -> AnyResult<()> {
+    ctx.dump_dht().await
 }
 
 // Download a file from a group of peers. Favor fastest peers.
-// Care: naive implementation! It will not handle peer deconnection.
+// A chunk a peer fails to send is given back to the others, and this peer is no
+// longer asked for any. Chunks none of the peers could send stay missing.
 async fn download_file_from_peers(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     peers: &[Peer],
     file_crc: u32,
     nb_chunks: u32,
@@ -692,31 +1400,47 @@ async fn download_file_from_peers(
 
     let mut handles = Vec::with_capacity(peers.len());
     for peer in peers {
-        let connection = TcpStream::connect(peer.addr).await;
+        let connection = connect_to_peer(Arc::clone(&ctx), peer).await;
         let stream = if let Ok(stream) = connection {
             Arc::new(Mutex::new(stream))
         } else {
             continue;
         };
+        let peer_id = peer.id;
 
         let peer_ctx = Arc::clone(&ctx);
         let peer_jobs_queue = Arc::clone(&jobs_queue);
-        let handle = tokio::spawn(async move {
+        let handle = spawn_scoped(async move {
             loop {
-                let local_ctx = Arc::clone(&peer_ctx);
-                let local_jobs = Arc::clone(&peer_jobs_queue);
-                let mut guard = local_jobs.lock().await;
+                // A banned peer is no longer asked for anything.
+                if peer_ctx.dht.lock().await.is_banned(peer_id) {
+                    break;
+                }
+
+                // Only hold the queue while taking a job, not while it's done.
+                let chunk_id = match peer_jobs_queue.lock().await.0.pop() {
+                    Some(chunk_id) => chunk_id,
+                    None => break,
+                };
+
+                let result = handle_file_chunk(
+                    Arc::clone(&peer_ctx),
+                    Arc::clone(&stream),
+                    peer_id,
+                    file_crc,
+                    chunk_id,
+                )
+                .await;
+                let mut guard = peer_jobs_queue.lock().await;
                 let (jobs, nb_succeed) = guard.deref_mut();
-                if let Some(chunk_id) = jobs.pop() {
-                    if let Ok(succeed) =
-                        handle_file_chunk(local_ctx, Arc::clone(&stream), file_crc, chunk_id).await
-                    {
-                        if succeed {
-                            *nb_succeed += 1;
-                        }
+                match result {
+                    Ok(true) => *nb_succeed += 1,
+                    // The peer failed or doesn't have the chunk: leave it to
+                    // the other peers and stop asking this one.
+                    _ => {
+                        jobs.push(chunk_id);
+                        break;
                     }
-                } else {
-                    break;
                 }
             }
         });
@@ -732,3 +1456,7 @@ async fn download_file_from_peers(
 
     Ok(*nb_succeed)
 }
+
+#[cfg(test)]
+#[path = "manager_test.rs"]
+mod manager_test;
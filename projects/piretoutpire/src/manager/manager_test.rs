@@ -0,0 +1,244 @@
+use super::*;
+use crate::dht::identity::generate_node_keypair;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use temp_file::TempFile;
+
+// Get the node keypair generated from a seed, the same for each seed.
+fn keypair(seed: u8) -> Keypair {
+    generate_node_keypair(&mut ChaCha8Rng::seed_from_u64(seed.into()), |_| true)
+}
+
+// Create a manager dumping its dht into a temporary file, kept as long as the
+// manager is used.
+fn manager(seed: u8, addr: &str, alt_addr: Option<&str>) -> AnyResult<(Manager, TempFile)> {
+    let dht_file = temp_file::empty();
+    let alt_addr = match alt_addr {
+        Some(alt_addr) => Some(alt_addr.parse()?),
+        None => None,
+    };
+    let manager = Manager::new(
+        keypair(seed),
+        addr.parse()?,
+        alt_addr,
+        dht_file.path().display().to_string(),
+        ".".to_owned(),
+    );
+    Ok((manager, dht_file))
+}
+
+// Loopback hosts the tests involving many peers are run on, one per IP family.
+const LOCALHOSTS: [&str; 2] = ["127.0.0.1", "[::1]"];
+
+// Start the server of a manager in the background, and get its id.
+async fn start_server(manager: Manager) -> u32 {
+    let id = manager.id();
+    tokio::spawn(async move { manager.start_server().await });
+    // Let the server listen.
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    id
+}
+
+#[tokio::test]
+async fn test_bootstrap_on_ipv6() -> AnyResult<()> {
+    let (server, _server_file) = manager(1, "[::1]:47101", None)?;
+    let server_id = start_server(server).await;
+
+    let (mut client, _client_file) = manager(2, "[::1]:47102", None)?;
+    client.bootstrap("[::1]:47101".parse()?).await?;
+
+    let peers = client.known_peers().await.collect::<Vec<_>>();
+    assert_eq!(1, peers.len());
+    assert_eq!(server_id, peers[0].id());
+    assert_eq!("[::1]:47101".parse::<SocketAddr>()?, peers[0].addr());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bootstrap_on_dual_stack() -> AnyResult<()> {
+    let (server, _server_file) = manager(3, "127.0.0.1:47103", Some("[::1]:47103"))?;
+    let server_id = start_server(server).await;
+
+    // The server is reached from both families.
+    for (seed, client_addr, server_addr) in [
+        (4, "127.0.0.1:47104", "127.0.0.1:47103"),
+        (5, "[::1]:47105", "[::1]:47103"),
+    ] {
+        let server_addr: SocketAddr = server_addr.parse()?;
+        let (mut client, _client_file) = manager(seed, client_addr, None)?;
+        client.bootstrap(server_addr).await?;
+
+        let peers = client.known_peers().await.collect::<Vec<_>>();
+        assert!(peers
+            .iter()
+            .any(|peer| peer.id() == server_id && peer.addr() == server_addr));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_learn_public_addr() -> AnyResult<()> {
+    let (mut client, _client_file) = manager(6, "0.0.0.0:47106", None)?;
+    assert_eq!("0.0.0.0:47106".parse::<SocketAddr>()?, client.public_addr().await);
+
+    // Once enough pinged peers, on distinct subnets, agree on the address they
+    // see us from, it's the one told to the others.
+    for (seed, server_addr) in [
+        (7, "127.0.1.1:47107"),
+        (8, "127.0.2.1:47108"),
+        (20, "127.0.3.1:47120"),
+        (21, "127.0.4.1:47121"),
+    ] {
+        let (server, _server_file) = manager(seed, server_addr, None)?;
+        start_server(server).await;
+        client.bootstrap(server_addr.parse()?).await?;
+    }
+    assert_eq!(
+        "127.0.0.1:47106".parse::<SocketAddr>()?,
+        client.public_addr().await
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reach_peer_through_relay() -> AnyResult<()> {
+    for host in LOCALHOSTS {
+        reach_peer_through_relay(host).await?;
+    }
+    Ok(())
+}
+
+async fn reach_peer_through_relay(host: &str) -> AnyResult<()> {
+    let relay_addr: SocketAddr = format!("{}:47109", host).parse()?;
+    let (mut relay, _relay_file) = manager(9, &format!("{}:47109", host), None)?;
+    relay.set_accept_relays(true).await;
+    start_server(relay).await;
+
+    // The relayed peer never listens, it's told to others at its relay address.
+    let (mut relayed, _relayed_file) = manager(10, &format!("{}:47110", host), None)?;
+    relayed.set_relay(relay_addr).await?;
+    let public_addr = relayed.public_addr().await;
+    assert_eq!(relay_addr.ip(), public_addr.ip());
+    assert_ne!(relay_addr.port(), public_addr.port());
+    assert!(TcpStream::connect(format!("{}:47110", host)).await.is_err());
+
+    let shared = temp_file::with_contents(b"relayed file");
+    relayed.bootstrap(relay_addr).await?;
+    let crc = relayed.share_file(shared.path()).await?;
+
+    // Messages and chunks reach it through the relay.
+    let working_directory = std::env::temp_dir().join("piretoutpire_relay_test");
+    fs::create_dir_all(&working_directory)?;
+    let mut client = Manager::new(
+        keypair(11),
+        format!("{}:47111", host).parse()?,
+        None,
+        working_directory.join("dht").display().to_string(),
+        working_directory.display().to_string(),
+    );
+    client.bootstrap(relay_addr).await?;
+    let relayed_peer = client.find_node(relayed.id()).await?;
+    let expected = Peer {
+        id: relayed.id(),
+        addr: public_addr,
+    };
+    assert_eq!(vec![expected], relayed_peer);
+    assert_eq!(
+        MessageDelivery::Delivered,
+        client.send_message(relayed.id(), "hello".to_owned()).await?
+    );
+    let downloaded = client.download_file(crc).await;
+
+    let content = fs::read(working_directory.join(shared.path().file_name().expect("file name")));
+    let _ = fs::remove_dir_all(&working_directory);
+    let _ = fs::remove_file(format!("{}.torrent", shared.path().display()));
+    assert_eq!(Some((1, 1)), downloaded?);
+    assert_eq!(b"relayed file".to_vec(), content?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_forward_message_through_hops() -> AnyResult<()> {
+    for host in LOCALHOSTS {
+        forward_message_through_hops(host).await?;
+    }
+    Ok(())
+}
+
+async fn forward_message_through_hops(host: &str) -> AnyResult<()> {
+    let (first_hop, _first_hop_file) = manager(13, &format!("{}:47113", host), None)?;
+    start_server(first_hop).await;
+    let (second_hop, _second_hop_file) = manager(14, &format!("{}:47114", host), None)?;
+    start_server(second_hop).await;
+
+    // The recipient key is given by the peers it contacted, not by itself.
+    let (mut recipient, _recipient_file) = manager(12, &format!("{}:47112", host), None)?;
+    for port in [47113, 47114] {
+        recipient.bootstrap(format!("{}:{}", host, port).parse()?).await?;
+    }
+    let recipient_id = start_server(recipient).await;
+
+    let (mut sender, _sender_file) = manager(15, &format!("{}:47115", host), None)?;
+    for port in [47112, 47113, 47114] {
+        sender.bootstrap(format!("{}:{}", host, port).parse()?).await?;
+    }
+
+    // The two other peers are the only hops available.
+    assert!(
+        sender
+            .forward_message(recipient_id, "hello".to_owned(), 2)
+            .await?
+    );
+    assert!(sender
+        .forward_message(recipient_id, "hello".to_owned(), 3)
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_offline_mailbox() -> AnyResult<()> {
+    for host in LOCALHOSTS {
+        offline_mailbox(host).await?;
+    }
+    Ok(())
+}
+
+async fn offline_mailbox(host: &str) -> AnyResult<()> {
+    // The peers keeping the mail know each other, so a lookup from one finds the
+    // other.
+    let mut mailboxes = vec![];
+    for (seed, port) in [(17, 47117), (18, 47118)] {
+        let (mut mailbox, mailbox_file) = manager(seed, &format!("{}:{}", host, port), None)?;
+        if port == 47118 {
+            mailbox.bootstrap(format!("{}:47117", host).parse()?).await?;
+        }
+        start_server(mailbox).await;
+        mailboxes.push(mailbox_file);
+    }
+
+    // The recipient is offline, the message is kept by its closest peers.
+    let (mut recipient, _recipient_file) = manager(16, &format!("{}:47116", host), None)?;
+    let (mut sender, _sender_file) = manager(19, &format!("{}:47119", host), None)?;
+    sender.bootstrap(format!("{}:47117", host).parse()?).await?;
+    assert_eq!(
+        MessageDelivery::Stored(2),
+        sender.send_message(recipient.id(), "hello".to_owned()).await?
+    );
+
+    // Once back, the recipient gets it a single time, from both of them, and
+    // acknowledges it so they forget it.
+    recipient.bootstrap(format!("{}:47117", host).parse()?).await?;
+    let mails = recipient.fetch_mails().await?;
+    assert_eq!(1, mails.len());
+    assert_eq!(sender.id(), mails[0].publisher);
+    assert_eq!("hello", mails[0].value);
+    assert!(recipient.fetch_mails().await?.is_empty());
+
+    Ok(())
+}
@@ -1,6 +1,15 @@
 mod client;
 mod command_handler;
 pub mod context;
+pub mod deadline;
+mod disjoint_lookup;
 mod find_node;
+mod find_value;
+mod get_peers;
+pub mod lookup_trace;
+pub mod mailbox;
 pub mod manager;
+pub mod public_addr;
+mod relay;
+mod replication;
 mod server;
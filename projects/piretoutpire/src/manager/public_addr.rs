@@ -0,0 +1,96 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+// Number of peers whose report is kept, the latest ones.
+const MAX_VOTES: usize = 16;
+
+// Number of subnets which must report the same address before it's trusted.
+pub const MIN_VOTES: usize = 4;
+
+// Number of highest bits of an IP address telling its subnet: the block
+// usually given to a single customer. Voters on the same subnet only have one
+// vote, so one owning a few addresses can't win the vote alone.
+const IPV4_SUBNET_BITS: u32 = 24;
+const IPV6_SUBNET_BITS: u32 = 48;
+
+// Address this peer tells others to reach it on, rather than the one it listens
+// on: both differ behind a NAT, or when listening on 0.0.0.0. It's either given,
+// or learned from the address the peers we ping report they saw us from, once
+// most of them agree. A voter is known by the IP address we reached it at,
+// not by the id it claims, which costs nothing to change.
+#[derive(Debug, Default)]
+pub struct PublicAddr {
+    // Addresses given, at most one per IP family.
+    advertised: Vec<SocketAddr>,
+    // Latest address reported from each subnet, the oldest first.
+    votes: VecDeque<(IpAddr /*voter subnet*/, IpAddr)>,
+}
+
+impl PublicAddr {
+    // Create a new public address, nothing known yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Set the addresses to tell, whatever the peers report.
+    pub fn set_advertised(&mut self, addrs: Vec<SocketAddr>) {
+        self.advertised = addrs;
+    }
+
+    // Record the address a peer, reached at a given IP address, saw us from.
+    // Only its IP counts, its port is the one our connection came from, not
+    // the one we listen on. A subnet only has one vote, its latest report.
+    pub fn vote(&mut self, voter: IpAddr, observed: SocketAddr) {
+        let subnet = subnet(voter);
+        self.votes.retain(|(known, _)| *known != subnet);
+        self.votes.push_back((subnet, observed.ip()));
+        if self.votes.len() > MAX_VOTES {
+            self.votes.pop_front();
+        }
+    }
+
+    // Get the IP address of a family reported by most of the peers, if enough
+    // of them agree.
+    pub fn elected_ip(&self, ipv4: bool) -> Option<IpAddr> {
+        let mut counts = HashMap::<IpAddr, usize>::new();
+        for (_, ip) in self.votes.iter().filter(|(_, ip)| ip.is_ipv4() == ipv4) {
+            *counts.entry(*ip).or_default() += 1;
+        }
+        let nb_votes = counts.values().sum::<usize>();
+        counts
+            .into_iter()
+            .find(|(_, count)| *count >= MIN_VOTES && count * 2 > nb_votes)
+            .map(|(ip, _)| ip)
+    }
+
+    // Get the address to tell in place of one we listen on: the one given on
+    // its family, else the elected IP with the same port, else the listening
+    // address itself.
+    pub fn resolve(&self, listen_addr: SocketAddr) -> SocketAddr {
+        let advertised = self
+            .advertised
+            .iter()
+            .find(|addr| addr.is_ipv4() == listen_addr.is_ipv4());
+        if let Some(addr) = advertised {
+            return *addr;
+        }
+        match self.elected_ip(listen_addr.is_ipv4()) {
+            Some(ip) => SocketAddr::new(ip, listen_addr.port()),
+            None => listen_addr,
+        }
+    }
+}
+
+// Get the subnet of an IP address, its highest bits.
+fn subnet(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => Ipv4Addr::from(u32::from(ip) & (u32::MAX << (32 - IPV4_SUBNET_BITS))).into(),
+        IpAddr::V6(ip) => Ipv6Addr::from(u128::from(ip) & (u128::MAX << (128 - IPV6_SUBNET_BITS))).into(),
+    }
+}
+
+#[cfg(test)]
+#[path = "public_addr_test.rs"]
+mod public_addr_test;
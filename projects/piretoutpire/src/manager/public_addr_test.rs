@@ -0,0 +1,90 @@
+use super::*;
+use errors::AnyResult;
+
+// Get the IP address of a voter on its own subnet.
+fn voter(subnet: u8) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::new(10, 0, subnet, 1))
+}
+
+#[test]
+fn test_public_addr_majority() -> AnyResult<()> {
+    let listen_addr: SocketAddr = "0.0.0.0:4000".parse()?;
+    let mut public_addr = PublicAddr::new();
+    assert_eq!(listen_addr, public_addr.resolve(listen_addr));
+
+    // A few reports aren't enough, whatever the port they were seen from.
+    for subnet in 1..MIN_VOTES as u8 {
+        public_addr.vote(voter(subnet), format!("1.2.3.4:5100{}", subnet).parse()?);
+    }
+    assert_eq!(listen_addr, public_addr.resolve(listen_addr));
+    public_addr.vote(voter(MIN_VOTES as u8), "1.2.3.4:52000".parse()?);
+    assert_eq!(
+        "1.2.3.4:4000".parse::<SocketAddr>()?,
+        public_addr.resolve(listen_addr)
+    );
+
+    // Without a majority, nothing is elected.
+    for subnet in 11..15 {
+        public_addr.vote(voter(subnet), "5.6.7.8:53000".parse()?);
+    }
+    assert_eq!(listen_addr, public_addr.resolve(listen_addr));
+
+    // A subnet only has one vote, the latest.
+    public_addr.vote(voter(14), "1.2.3.4:54000".parse()?);
+    assert_eq!(
+        "1.2.3.4:4000".parse::<SocketAddr>()?,
+        public_addr.resolve(listen_addr)
+    );
+
+    // Each family has its own election.
+    let listen_addr_v6: SocketAddr = "[::]:4000".parse()?;
+    assert_eq!(listen_addr_v6, public_addr.resolve(listen_addr_v6));
+
+    Ok(())
+}
+
+#[test]
+fn test_public_addr_one_vote_per_subnet() -> AnyResult<()> {
+    let listen_addr: SocketAddr = "0.0.0.0:4000".parse()?;
+    let mut public_addr = PublicAddr::new();
+
+    // Many voters on the same subnet can't elect an address alone.
+    for host in 1..=MIN_VOTES as u8 {
+        public_addr.vote(
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, host)),
+            "6.6.6.6:51000".parse()?,
+        );
+    }
+    assert_eq!(listen_addr, public_addr.resolve(listen_addr));
+    for host in 1..=MIN_VOTES as u16 {
+        public_addr.vote(
+            IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, host, 0, 0, 0, 1)),
+            "6.6.6.6:51000".parse()?,
+        );
+    }
+    assert_eq!(listen_addr, public_addr.resolve(listen_addr));
+
+    Ok(())
+}
+
+#[test]
+fn test_public_addr_advertised() -> AnyResult<()> {
+    let listen_addr: SocketAddr = "0.0.0.0:4000".parse()?;
+    let mut public_addr = PublicAddr::new();
+    for subnet in 1..=MIN_VOTES as u8 {
+        public_addr.vote(voter(subnet), "1.2.3.4:51000".parse()?);
+    }
+
+    // The given address wins over the reported ones, on its family only.
+    public_addr.set_advertised(vec!["[2001:db8::1]:4000".parse()?, "9.9.9.9:5000".parse()?]);
+    assert_eq!(
+        "9.9.9.9:5000".parse::<SocketAddr>()?,
+        public_addr.resolve(listen_addr)
+    );
+    assert_eq!(
+        "[2001:db8::1]:4000".parse::<SocketAddr>()?,
+        public_addr.resolve("[::]:4000".parse()?)
+    );
+
+    Ok(())
+}
@@ -0,0 +1,217 @@
+use super::{
+    client::handle_relay,
+    command_handler::listen_to_relay,
+    context::{Context, RpcClass},
+    deadline::CancelToken,
+};
+use crate::{
+    network::{
+        api::{connect, send_raw_unary_once},
+        protocol::Command,
+    },
+    read_all,
+};
+use errors::{bail, AnyResult};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+    time::{interval, sleep, timeout},
+};
+
+// Wait time before connecting again to a relay, once the connection is lost.
+const RELAY_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// Period at which a relay checks the relayed peer is still connected.
+const RELAY_CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+// Relay side ------------------------------------------------------------------
+
+// Forward the requests received on the relay listener to the relayed peer, on
+// the connection it opened to us, until this connection breaks or the peer
+// asks for a new relay. The relayed peer never sends anything on its own, so
+// the connection is checked when idle, and its port is released once closed.
+pub async fn run_relay(
+    ctx: Arc<Context>,
+    peer_id: u32,
+    listener: TcpListener,
+    stream: TcpStream,
+) -> AnyResult<()> {
+    let cancel_token = CancelToken::new();
+    if let Some(previous) = ctx.relays.lock().await.insert(peer_id, cancel_token.clone()) {
+        previous.cancel();
+    }
+
+    let stream = Arc::new(Mutex::new(stream));
+    let mut check = interval(RELAY_CHECK_PERIOD);
+    loop {
+        let incoming = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = check.tick() => {
+                if is_closed(&stream).await {
+                    cancel_token.cancel();
+                    break;
+                }
+                continue;
+            }
+            _ = cancel_token.cancelled() => break,
+        };
+        match incoming {
+            Ok((incoming, _)) => {
+                let (ctx, stream, cancel_token) =
+                    (Arc::clone(&ctx), Arc::clone(&stream), cancel_token.clone());
+                tokio::spawn(async move { forward_requests(ctx, incoming, stream, cancel_token).await });
+            }
+            Err(err) => eprintln!("can't accept a connection for {}: {}", peer_id, err),
+        }
+    }
+
+    // Forget the peer, unless it's relayed again on a new connection.
+    let mut relays = ctx.relays.lock().await;
+    if relays.get(&peer_id).map_or(false, CancelToken::is_cancelled) {
+        relays.remove(&peer_id);
+    }
+    Ok(())
+}
+
+// Check if the relayed peer closed its connection, without waiting. While a
+// request is forwarded, the connection is busy, and known to be open.
+async fn is_closed(stream: &Mutex<TcpStream>) -> bool {
+    let stream = match stream.try_lock() {
+        Ok(stream) => stream,
+        Err(_) => return false,
+    };
+    let mut buf = [0u8; 1];
+    matches!(
+        timeout(Duration::ZERO, stream.peek(&mut buf)).await,
+        Ok(Ok(0)) | Ok(Err(_))
+    )
+}
+
+// Get the class of a request the relayed peer answers. Anything else is refused
+// by the relay itself: the relayed peer wouldn't answer it, and the relay would
+// be stopped waiting for this answer.
+fn forward_class(raw_request: &[u8]) -> Option<RpcClass> {
+    let request = match Command::try_from(raw_request) {
+        Ok(Command::SignedRequest(_, raw_request)) => match Command::try_from(raw_request.as_slice()) {
+            Ok(Command::SignedRequest(_, _)) | Err(_) => return None,
+            Ok(request) => request,
+        },
+        Ok(request) => request,
+        Err(_) => return None,
+    };
+    match request {
+        Command::ChunkRequest(_, _) | Command::GetMailRequest(_, _) => Some(RpcClass::Bulk),
+        Command::FileInfoRequest(_)
+        | Command::FindNodeRequest(_, _)
+        | Command::PingRequest(_)
+        | Command::StoreRequest(_, _, _, _)
+        | Command::FindValueRequest(_, _, _)
+        | Command::PutMutableRequest(_, _)
+        | Command::GetMutableRequest(_, _)
+        | Command::MessageRequest(_)
+        | Command::AnnounceRequest(_, _, _, _)
+        | Command::GetPeersRequest(_)
+        | Command::OnionRequest(_)
+        | Command::PublicKeyRequest()
+        | Command::PeerKeyRequest(_)
+        | Command::PutMailRequest(_, _, _, _)
+        | Command::MailChallengeRequest(_)
+        | Command::AckMailRequest(_, _) => Some(RpcClass::Control),
+        _ => None,
+    }
+}
+
+// Forward the requests of a connection to the relayed peer, one at a time, and
+// send back its answers. Requests are sent as they are, so signed ones are
+// still verified by the relayed peer. A request the relayed peer doesn't answer
+// in time may still be answered later on the shared connection, so the relay is
+// stopped rather than risking to give this answer to the next request.
+async fn forward_requests(
+    ctx: Arc<Context>,
+    mut incoming: TcpStream,
+    relayed: Arc<Mutex<TcpStream>>,
+    cancel_token: CancelToken,
+) -> AnyResult<()> {
+    let read_timeout = ctx.settings.read().await.read_timeout;
+    let (reader, writer) = incoming.split();
+    let mut reader = tokio::io::BufReader::new(reader);
+    let mut writer = tokio::io::BufWriter::new(writer);
+
+    loop {
+        let raw_request = read_all!(reader, read_timeout);
+        if raw_request.is_empty() || cancel_token.is_cancelled() {
+            break;
+        }
+
+        let class = match forward_class(&raw_request) {
+            Some(class) => class,
+            None => {
+                eprintln!("Unknown command received for a relayed peer!");
+                continue;
+            }
+        };
+        let raw_response =
+            match send_raw_unary_once(Arc::clone(&ctx), Arc::clone(&relayed), &raw_request, class).await {
+                Ok(raw_response) => raw_response,
+                Err(err) => {
+                    cancel_token.cancel();
+                    return Err(err);
+                }
+            };
+
+        let (write_timeout, _) = ctx.settings.read().await.timeouts(class);
+        timeout(write_timeout, writer.write_all(raw_response.as_slice())).await??;
+        timeout(write_timeout, writer.flush()).await??;
+    }
+
+    Ok(())
+}
+
+// Relayed side ----------------------------------------------------------------
+
+// Ask a peer to relay the requests sent to us, on a new connection. Once
+// accepted, the relay address is the one told to other peers.
+pub async fn connect_relay(
+    ctx: Arc<Context>,
+    relay_addr: SocketAddr,
+    own_addr: SocketAddr,
+    own_id: u32,
+) -> AnyResult<TcpStream> {
+    let stream = Arc::new(Mutex::new(connect(Arc::clone(&ctx), relay_addr).await?));
+    let public_addr = handle_relay(Arc::clone(&ctx), Arc::clone(&stream), own_addr, own_id).await?;
+    ctx.public_addr.lock().await.set_advertised(vec![public_addr]);
+
+    match Arc::try_unwrap(stream) {
+        Ok(stream) => Ok(stream.into_inner()),
+        Err(_) => bail!("relay connection still in use"),
+    }
+}
+
+// Serve the requests a relay forwards to us on a connection. When the
+// connection is lost, a new one is asked for, and a new relay address is told.
+pub async fn serve_relayed(
+    ctx: Arc<Context>,
+    mut stream: TcpStream,
+    relay_addr: SocketAddr,
+    own_addr: SocketAddr,
+    own_id: u32,
+) {
+    loop {
+        if let Err(err) = listen_to_relay(Arc::clone(&ctx), stream, own_id).await {
+            eprintln!("relay connection to {} lost: {}", relay_addr, err);
+        }
+        stream = loop {
+            sleep(RELAY_RETRY_DELAY).await;
+            match connect_relay(Arc::clone(&ctx), relay_addr, own_addr, own_id).await {
+                Ok(stream) => break stream,
+                Err(err) => eprintln!("can't be relayed by {}: {}", relay_addr, err),
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+#[path = "relay_test.rs"]
+mod relay_test;
@@ -0,0 +1,35 @@
+use super::*;
+use errors::AnyResult;
+
+#[tokio::test]
+async fn test_relay_stops_when_relayed_peer_leaves() -> AnyResult<()> {
+    let ctx = Arc::new(Context::new_test(0, false));
+
+    // The connection the relayed peer keeps open to us.
+    let server = TcpListener::bind("127.0.0.1:0").await?;
+    let relayed = TcpStream::connect(server.local_addr()?).await?;
+    let (stream, _) = server.accept().await?;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let relay_addr = listener.local_addr()?;
+    let relay = tokio::spawn(run_relay(Arc::clone(&ctx), 1, listener, stream));
+    sleep(Duration::from_millis(100)).await;
+    assert!(ctx.relays.lock().await.contains_key(&1));
+
+    // Requests the relayed peer wouldn't answer are refused, the relay goes on.
+    let mut incoming = TcpStream::connect(relay_addr).await?;
+    incoming.write_all(&[0xff; 16]).await?;
+    let response: Vec<u8> = Command::PingResponse(0, relay_addr).into();
+    incoming.write_all(&response).await?;
+    sleep(Duration::from_millis(100)).await;
+    assert!(ctx.relays.lock().await.contains_key(&1));
+    assert!(relayed.try_read(&mut [0; 16]).is_err());
+
+    // Once its connection is closed, the peer is forgotten, and its port freed.
+    drop(relayed);
+    timeout(RELAY_CHECK_PERIOD * 3, relay).await???;
+    assert!(ctx.relays.lock().await.is_empty());
+    assert!(TcpStream::connect(relay_addr).await.is_err());
+
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use super::{
+    client::{handle_announce, handle_put_mutable, handle_store},
+    context::Context,
+};
+use crate::{
+    dht::dht::Replication,
+    network::{api::connect, protocol::Peer},
+};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+// Push values and file announcements to a newly known peer, which is now one
+// of the closest to their keys. Without it, a find_value or a get_peers landing
+// on this peer would miss what was stored before it joined.
+// It's done in the background, and failures are ignored: other close peers
+// still hold the values.
+pub fn replicate_to(ctx: Arc<Context>, peer: Peer, mut replication: Replication) {
+    if replication.is_empty() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        replication.retain_closest();
+        if replication.is_empty() {
+            return;
+        }
+        let sender_id = ctx.dht.lock().await.id();
+        let sender_addr = ctx.addr;
+
+        let stream = match connect(Arc::clone(&ctx), peer.addr).await {
+            Ok(connection) => Arc::new(Mutex::new(connection)),
+            Err(_) => return,
+        };
+
+        for (key, value) in replication.values {
+            let _ = handle_store(
+                Arc::clone(&ctx),
+                Arc::clone(&stream),
+                peer.id,
+                sender_addr,
+                sender_id,
+                key,
+                value,
+            )
+            .await;
+        }
+
+        for value in replication.mutable_values {
+            let _ = handle_put_mutable(
+                Arc::clone(&ctx),
+                Arc::clone(&stream),
+                peer.id,
+                sender_addr,
+                sender_id,
+                value,
+            )
+            .await;
+        }
+
+        // Announce on behalf of the real file owners, as they signed it.
+        for (crc, announcement) in replication.file_peers {
+            let _ = handle_announce(
+                Arc::clone(&ctx),
+                Arc::clone(&stream),
+                peer.id,
+                sender_addr,
+                sender_id,
+                crc,
+                announcement,
+            )
+            .await;
+        }
+    });
+}
@@ -1,11 +1,30 @@
-use super::context::Context;
+use super::{client::handle_onion, context::Context, manager::bind, replication::replicate_to};
 use crate::{
-    dht::peer_node::PeerNode,
-    network::protocol::{Command, ErrorCode, FileInfo, Peer},
+    dht::{
+        dht::Replication,
+        identity::MAX_REQUEST_AGE,
+        mutable::{mutable_key, MutableHash, MutableRejected},
+        peer_node::PeerNode,
+    },
+    network::{
+        api::connect,
+        onion::{peel_onion, OnionLayer},
+        protocol::{
+            Announcement, Command, ErrorCode, FileInfo, FoundMutable, FoundPeers, FoundValue, MutableValue,
+            Peer, StoredValue,
+        },
+    },
+    utils::unix_timestamp,
 };
 use colored::Colorize;
-use std::{net::SocketAddr, ops::DerefMut, path::Path, sync::Arc};
-use tokio::sync::Mutex;
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{net::TcpListener, sync::Mutex};
+
+// Number of values sent back at once by a find value.
+const VALUES_PER_PAGE: usize = 8;
+
+// Max number of peers relayed at the same time.
+pub const MAX_RELAYED_PEERS: usize = 16;
 
 // Server API ------------------------------------------------------------------
 
@@ -22,20 +41,19 @@ macro_rules! log {
 }
 
 // Give the file metadata information given its id/crc.
-pub async fn serve_file_info(ctx: Arc<Mutex<Context>>, incoming_addr: SocketAddr, crc: u32) -> Command {
+pub async fn serve_file_info(ctx: Arc<Context>, incoming_addr: SocketAddr, crc: u32) -> Command {
     let header = "[FILE_INFO]".to_owned().cyan().on_truecolor(35, 38, 39).bold();
     let prefix = format!(" asked crc {} from {}", crc, incoming_addr,);
 
-    let mut guard = ctx.lock().await;
-    let ctx = guard.deref_mut();
-
-    match ctx.available_torrents.get(&crc) {
-        Some((torrent, _)) => {
+    match ctx.torrent(crc).await {
+        Some(torrent) => {
+            let (torrent, _) = &*torrent.lock().await;
             let file_info = FileInfo {
                 file_size: torrent.metadata.file_size,
                 chunk_size: torrent.metadata.chunk_size,
                 file_crc: torrent.metadata.file_crc,
                 original_filename: torrent.metadata.original_filename.clone(),
+                chunk_crcs: torrent.metadata.chunk_crcs.clone(),
             };
             log!(header, "{}, and send back {:?}", prefix, file_info);
             Command::FileInfoResponse(file_info)
@@ -49,7 +67,7 @@ pub async fn serve_file_info(ctx: Arc<Mutex<Context>>, incoming_addr: SocketAddr
 
 // Serve chunk asked by a client.
 pub async fn serve_file_chunk(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     incoming_addr: SocketAddr,
     crc: u32,
     chunk_id: u32,
@@ -57,11 +75,10 @@ pub async fn serve_file_chunk(
     let header = "[CHUNK]".to_owned().cyan().on_truecolor(35, 38, 39).bold();
     let prefix = format!(" asked by {} asking for {}/{}", incoming_addr, crc, chunk_id);
 
-    let mut guard = ctx.lock().await;
-    let ctx = guard.deref_mut();
-
-    match ctx.available_torrents.get_mut(&crc) {
-        Some((torrent, chunks)) => {
+    // Only this torrent is locked while reading its file.
+    match ctx.torrent(crc).await {
+        Some(torrent) => {
+            let (torrent, chunks) = &mut *torrent.lock().await;
             if chunk_id as usize >= torrent.metadata.completed_chunks.len() {
                 log!(header, "{}, but chunk was invalid", prefix);
                 Command::ErrorOccured(ErrorCode::InvalidChunk)
@@ -85,25 +102,48 @@ pub async fn serve_file_chunk(
     }
 }
 
-// Serve find a node. Will return the 3 closest node from the provided one.
+// Serve find a node. Will return the 3 closest node from the provided one,
+// with a token allowing the requester to write on us.
 pub async fn serve_find_node(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
+    incoming_addr: SocketAddr,
     sender_addr: SocketAddr,
     sender: u32,
+    verified: bool,
     target: u32,
 ) -> Command {
-    let mut guard = ctx.lock().await;
-    let ctx = guard.deref_mut();
-    let peers = ctx
-        .dht
-        .find_node(PeerNode::new(sender, sender_addr), target)
-        .await
+    let (peers, replication) = {
+        let mut dht = ctx.dht.lock().await;
+        // Only a sender which proved its id can enter our routing table.
+        if verified {
+            dht.find_node(PeerNode::new(sender, sender_addr), target).await
+        } else {
+            (
+                dht.find_closest_peers_towards(target, 4, incoming_addr)
+                    .await
+                    .collect(),
+                Replication::default(),
+            )
+        }
+    };
+    replicate_to(
+        Arc::clone(&ctx),
+        Peer {
+            id: sender,
+            addr: sender_addr,
+        },
+        replication,
+    );
+    let peers = peers
+        .into_iter()
         .map(|peer| Peer {
             id: peer.id(),
             addr: peer.addr(),
         })
         .collect::<Vec<_>>();
 
+    let token = ctx.tokens.lock().await.issue(incoming_addr.ip());
+
     let header = "[FIND_NODE]".to_owned().blue().on_truecolor(35, 38, 39).bold();
     log!(
         header,
@@ -113,20 +153,31 @@ pub async fn serve_find_node(
         target,
         peers
     );
-    Command::FindNodeResponse(peers)
+    Command::FindNodeResponse(token, peers)
 }
 
-// Received the sender id, and response with this server id.
+// Received the sender id, and response with this server id, and the address the
+// sender is seen from, so it can learn its public address.
 pub async fn serve_ping(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
+    incoming_addr: SocketAddr,
     sender_addr: SocketAddr,
     sender_id: u32,
+    verified: bool,
     own_id: u32,
 ) -> Command {
-    let mut guard = ctx.lock().await;
-    let ctx = guard.deref_mut();
-    ctx.dht.add_node(sender_id, sender_addr).await;
-    let _ = ctx.dht.dump_to_file(Path::new(&ctx.dht_config_filename)).await;
+    if verified {
+        let replication = ctx.dht.lock().await.add_node(sender_id, sender_addr).await;
+        replicate_to(
+            Arc::clone(&ctx),
+            Peer {
+                id: sender_id,
+                addr: sender_addr,
+            },
+            replication,
+        );
+    }
+    let _ = ctx.dump_dht().await;
 
     let header = "[PING]".to_owned().blue().on_truecolor(35, 38, 39).bold();
     log!(
@@ -136,124 +187,651 @@ pub async fn serve_ping(
         sender_addr,
         own_id
     );
-    Command::PingResponse(own_id)
+    Command::PingResponse(own_id, incoming_addr)
 }
 
-// Allow a client to put a value inside this server.
+// Allow a client to put a value inside this server. The client must send back
+// a token we gave it.
 pub async fn serve_store(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     sender_addr: SocketAddr,
     sender_id: u32,
+    verified: bool,
+    valid_token: bool,
     key: u32,
-    message: String,
+    mut stored_value: StoredValue,
 ) -> Command {
-    let mut guard = ctx.lock().await;
-    let ctx = guard.deref_mut();
-
     let header = "[STORE_VALUE]".to_owned().blue().on_truecolor(35, 38, 39).bold();
-    log!(
-        header,
-        " asked by {}({}), store {}={}",
-        sender_id,
-        sender_addr,
-        key,
-        &message
+    let prefix = format!(
+        " asked by {}({}), store {}={} published by {}",
+        sender_id, sender_addr, key, &stored_value.value, stored_value.publisher
     );
+    if !verified {
+        log!(header, "{}, but the sender id is not proven", prefix);
+        return Command::ErrorOccured(ErrorCode::InvalidSignature);
+    }
+    if !valid_token {
+        log!(header, "{}, but the write token is invalid", prefix);
+        return Command::ErrorOccured(ErrorCode::InvalidToken);
+    }
+    // The sender is the publisher, or a peer handing the value over to us: the
+    // publisher signature tells who really published it.
+    if !stored_value.has_valid_signature(key) {
+        log!(header, "{}, but the publisher signature is invalid", prefix);
+        return Command::ErrorOccured(ErrorCode::InvalidSignature);
+    }
 
-    ctx.dht.store_value(key, message);
-    ctx.dht.add_node(sender_id, sender_addr).await;
-    let _ = ctx.dht.dump_to_file(Path::new(&ctx.dht_config_filename)).await;
+    let mut dht = ctx.dht.lock().await;
+    // Don't trust dates from the future, it would keep the value from being
+    // the oldest one.
+    stored_value.timestamp = stored_value.timestamp.min(unix_timestamp());
 
-    Command::StoreResponse()
+    let replication = dht.add_node(sender_id, sender_addr).await;
+    replicate_to(
+        Arc::clone(&ctx),
+        Peer {
+            id: sender_id,
+            addr: sender_addr,
+        },
+        replication,
+    );
+    let res = match dht.store_value(key, stored_value) {
+        Ok(()) => {
+            log!(header, "{}", prefix);
+            Command::StoreResponse()
+        }
+        Err(err) => {
+            log!(header, "{}, but {}", prefix, err);
+            Command::ErrorOccured(ErrorCode::StorageFull)
+        }
+    };
+    drop(dht);
+    let _ = ctx.dump_dht().await;
+
+    res
 }
 
-// Allow a client to put a value inside this server.
+// Send back the requested page of values for the given key, or the closest
+// peers we know from this key.
 pub async fn serve_find_value(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     sender_addr: SocketAddr,
     sender_id: u32,
+    verified: bool,
     key: u32,
+    page: u32,
 ) -> Command {
     let header = "[GET]".to_owned().blue().on_truecolor(35, 38, 39).bold();
-    let prefix = format!(" {}({}) ask for {}", sender_id, sender_addr, key,);
-
-    let mut guard = ctx.lock().await;
-    let ctx = guard.deref_mut();
-    ctx.dht.add_node(sender_id, sender_addr).await;
-    let _ = ctx.dht.dump_to_file(Path::new(&ctx.dht_config_filename)).await;
-    let message = ctx.dht.get_value(key);
-
-    match message {
-        Some(message) => {
-            log!(header, "{}={}", prefix, message);
-            Command::FindValueResponse(message.clone())
+    let prefix = format!(" {}({}) ask for {} (page {})", sender_id, sender_addr, key, page);
+
+    if verified {
+        let replication = ctx.dht.lock().await.add_node(sender_id, sender_addr).await;
+        replicate_to(
+            Arc::clone(&ctx),
+            Peer {
+                id: sender_id,
+                addr: sender_addr,
+            },
+            replication,
+        );
+    }
+    let _ = ctx.dump_dht().await;
+
+    let dht = ctx.dht.lock().await;
+    let values = dht.get_values(key);
+
+    match values {
+        Some(values) => {
+            let nb_pages = values.chunks(VALUES_PER_PAGE).count() as u32;
+            let values = values
+                .chunks(VALUES_PER_PAGE)
+                .nth(page as usize)
+                .map_or(vec![], <[StoredValue]>::to_vec);
+            log!(header, "{}={:?}", prefix, values);
+            Command::FindValueResponse(FoundValue::Values(values, nb_pages))
         }
         None => {
-            log!(header, "{}, but the key was not found", prefix);
-            Command::ErrorOccured(ErrorCode::KeyNotFound)
+            // Help the sender to continue its lookup.
+            let peers = dht
+                .find_closest_peers_towards(key, 4, sender_addr)
+                .await
+                .map(Peer::from)
+                .collect::<Vec<_>>();
+            log!(
+                header,
+                "{}, but the key was not found, send back {:?}",
+                prefix,
+                peers
+            );
+            Command::FindValueResponse(FoundValue::ClosestPeers(peers))
+        }
+    }
+}
+
+// Allow a client to put a signed mutable value inside this server.
+pub async fn serve_put_mutable(
+    ctx: Arc<Context>,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    verified: bool,
+    mutable_value: MutableValue,
+) -> Command {
+    let mut dht = ctx.dht.lock().await;
+
+    let header = "[PUT_MUTABLE]".to_owned().blue().on_truecolor(35, 38, 39).bold();
+    let prefix = format!(
+        " asked by {}({}), store {}={} (seq {})",
+        sender_id,
+        sender_addr,
+        mutable_value.key(),
+        &mutable_value.value,
+        mutable_value.seq
+    );
+
+    if verified {
+        let replication = dht.add_node(sender_id, sender_addr).await;
+        replicate_to(
+            Arc::clone(&ctx),
+            Peer {
+                id: sender_id,
+                addr: sender_addr,
+            },
+            replication,
+        );
+    }
+    let res = match dht.store_mutable(mutable_value, false) {
+        Ok(()) => {
+            log!(header, "{}", prefix);
+            Command::PutMutableResponse()
+        }
+        Err(err) => {
+            log!(header, "{}, but {}", prefix, err);
+            Command::ErrorOccured(match err {
+                MutableRejected::InvalidSignature => ErrorCode::InvalidSignature,
+                MutableRejected::OutdatedSequence => ErrorCode::OutdatedSequence,
+                MutableRejected::TooManyOwners | MutableRejected::Quota(_) => ErrorCode::StorageFull,
+            })
+        }
+    };
+    drop(dht);
+    let _ = ctx.dump_dht().await;
+
+    res
+}
+
+// Send back the mutable value of the given hash, or the closest peers we know
+// from its key.
+pub async fn serve_get_mutable(
+    ctx: Arc<Context>,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    verified: bool,
+    hash: MutableHash,
+) -> Command {
+    let header = "[GET_MUTABLE]".to_owned().blue().on_truecolor(35, 38, 39).bold();
+    let key = mutable_key(&hash);
+    let prefix = format!(" {}({}) ask for {}", sender_id, sender_addr, key);
+
+    if verified {
+        let replication = ctx.dht.lock().await.add_node(sender_id, sender_addr).await;
+        replicate_to(
+            Arc::clone(&ctx),
+            Peer {
+                id: sender_id,
+                addr: sender_addr,
+            },
+            replication,
+        );
+    }
+    let _ = ctx.dump_dht().await;
+
+    let dht = ctx.dht.lock().await;
+
+    match dht.get_mutable(&hash) {
+        Some(mutable_value) => {
+            log!(
+                header,
+                "{}={} (seq {})",
+                prefix,
+                &mutable_value.value,
+                mutable_value.seq
+            );
+            Command::GetMutableResponse(FoundMutable::Value(mutable_value.clone()))
+        }
+        None => {
+            // Help the sender to continue its lookup.
+            let peers = dht
+                .find_closest_peers_towards(key, 4, sender_addr)
+                .await
+                .map(Peer::from)
+                .collect::<Vec<_>>();
+            log!(
+                header,
+                "{}, but the key was not found, send back {:?}",
+                prefix,
+                peers
+            );
+            Command::GetMutableResponse(FoundMutable::ClosestPeers(peers))
         }
     }
 }
 
 // Display the message the user send.
-pub async fn serve_message(_: Arc<Mutex<Context>>, incoming_addr: SocketAddr, message: String) -> Command {
+pub async fn serve_message(_: Arc<Context>, incoming_addr: SocketAddr, message: String) -> Command {
     let header = "[MESSAGE]".to_owned().yellow().on_truecolor(35, 38, 39).bold();
     log!(header, " user {}, send: \"{}\"", incoming_addr, message);
 
     Command::MessageResponse()
 }
 
+// Remove our layer of an onion, then either read the message it holds, or send
+// what's left to the next hop. Only the previous and the next hops are known,
+// not the sender, nor the recipient. The onion is acknowledged as soon as it's
+// taken, then forwarded: waiting for the next hops would make the previous ones
+// give up on long routes.
+pub async fn serve_onion(ctx: Arc<Context>, incoming_addr: SocketAddr, onion: Vec<u8>) -> Command {
+    let header = "[ONION]".to_owned().yellow().on_truecolor(35, 38, 39).bold();
+
+    match peel_onion(&ctx.keypair, &onion) {
+        Ok(OnionLayer::Deliver(message)) => {
+            log!(header, " through {}, send: \"{}\"", incoming_addr, message);
+            Command::OnionResponse()
+        }
+        Ok(OnionLayer::Forward(next_addr, inner)) => {
+            tokio::spawn(async move {
+                let forwarded = match connect(Arc::clone(&ctx), next_addr).await {
+                    Ok(connection) => {
+                        let stream = Arc::new(Mutex::new(connection));
+                        handle_onion(Arc::clone(&ctx), stream, inner).await
+                    }
+                    Err(err) => Err(err),
+                };
+                match forwarded {
+                    Ok(()) => {
+                        log!(header, " from {}, forwarded to {}", incoming_addr, next_addr);
+                    }
+                    Err(err) => {
+                        log!(
+                            header,
+                            " from {}, but can't forward it to {}: {}",
+                            incoming_addr,
+                            next_addr,
+                            err
+                        );
+                    }
+                }
+            });
+            Command::OnionResponse()
+        }
+        Err(err) => {
+            log!(header, " from {}, but it's invalid: {}", incoming_addr, err);
+            Command::ErrorOccured(ErrorCode::InvalidOnion)
+        }
+    }
+}
+
+// Give our public key, so others can send us onions.
+pub async fn serve_public_key(ctx: Arc<Context>, incoming_addr: SocketAddr) -> Command {
+    let header = "[PUBLIC_KEY]".to_owned().yellow().on_truecolor(35, 38, 39).bold();
+    log!(header, " asked by {}", incoming_addr);
+    Command::PublicKeyResponse(ctx.keypair.public.to_bytes().to_vec())
+}
+
+// Give the public key of a peer we know, so others can send it onions without
+// asking it, which would tell it who's about to send one.
+pub async fn serve_peer_key(
+    ctx: Arc<Context>,
+    incoming_addr: SocketAddr,
+    own_id: u32,
+    peer_id: u32,
+) -> Command {
+    let header = "[PEER_KEY]".to_owned().yellow().on_truecolor(35, 38, 39).bold();
+    let public_key = if peer_id == own_id {
+        Some(ctx.keypair.public)
+    } else {
+        ctx.public_keys.lock().await.get(peer_id)
+    };
+    match public_key {
+        Some(public_key) => {
+            log!(header, " asked by {}, for {}", incoming_addr, peer_id);
+            Command::PublicKeyResponse(public_key.to_bytes().to_vec())
+        }
+        None => {
+            log!(
+                header,
+                " asked by {}, for {}, but it's unknown",
+                incoming_addr,
+                peer_id
+            );
+            Command::ErrorOccured(ErrorCode::KeyNotFound)
+        }
+    }
+}
+
+// Keep a mail for a recipient which couldn't be reached, until it fetches it.
+// Only the owner of an id can leave mails on its behalf, and it must send back a
+// token we gave to its address.
+pub async fn serve_put_mail(
+    ctx: Arc<Context>,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    verified: bool,
+    valid_token: bool,
+    recipient: u32,
+    mut mail: StoredValue,
+) -> Command {
+    let header = "[PUT_MAIL]".to_owned().yellow().on_truecolor(35, 38, 39).bold();
+    let prefix = format!(
+        " peer {}({}), leave a mail for {}: \"{}\"",
+        sender_id, sender_addr, recipient, &mail.value
+    );
+    if !verified {
+        log!(header, "{}, but the sender id is not proven", prefix);
+        return Command::ErrorOccured(ErrorCode::InvalidSignature);
+    }
+    if !valid_token {
+        log!(header, "{}, but the write token is invalid", prefix);
+        return Command::ErrorOccured(ErrorCode::InvalidToken);
+    }
+
+    // The mail must be signed by the peer which proved its id, so its recipient
+    // can tell who wrote it. It can't be dated from the future, it would be
+    // kept longer.
+    if mail.publisher != sender_id || !mail.has_valid_signature(recipient) {
+        log!(header, "{}, but the mail isn't signed by its sender", prefix);
+        return Command::ErrorOccured(ErrorCode::InvalidSignature);
+    }
+    mail.timestamp = mail.timestamp.min(unix_timestamp());
+
+    let mut dht = ctx.dht.lock().await;
+    let replication = dht.add_node(sender_id, sender_addr).await;
+    replicate_to(
+        Arc::clone(&ctx),
+        Peer {
+            id: sender_id,
+            addr: sender_addr,
+        },
+        replication,
+    );
+    let res = match dht.store_mail(recipient, mail) {
+        Ok(()) => {
+            log!(header, "{}", prefix);
+            Command::PutMailResponse()
+        }
+        Err(err) => {
+            log!(header, "{}, but {}", prefix, err);
+            Command::ErrorOccured(ErrorCode::StorageFull)
+        }
+    };
+    drop(dht);
+    let _ = ctx.dump_dht().await;
+
+    res
+}
+
+// Hand out a challenge to a peer about to fetch its mails. It must be signed
+// in the fetch, from the same address, so a fetch can't be replayed elsewhere.
+pub async fn serve_mail_challenge(ctx: Arc<Context>, incoming_addr: SocketAddr, recipient: u32) -> Command {
+    let header = "[MAIL_CHALLENGE]"
+        .to_owned()
+        .yellow()
+        .on_truecolor(35, 38, 39)
+        .bold();
+    log!(header, " asked by {}, for {}", incoming_addr, recipient);
+    let challenge = ctx
+        .mail_challenges
+        .lock()
+        .await
+        .issue(recipient, incoming_addr.ip());
+    Command::MailChallengeResponse(challenge)
+}
+
+// Give a peer the mails kept for it. They're kept until it acknowledges them.
+// Only the owner of the id can fetch its mails, with a challenge we gave it.
+pub async fn serve_get_mail(
+    ctx: Arc<Context>,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    verified: bool,
+    valid_challenge: bool,
+) -> Command {
+    let header = "[GET_MAIL]".to_owned().yellow().on_truecolor(35, 38, 39).bold();
+    let prefix = format!(" peer {}({}), fetch its mails", sender_id, sender_addr);
+    if !verified {
+        log!(header, "{}, but the sender id is not proven", prefix);
+        return Command::ErrorOccured(ErrorCode::InvalidSignature);
+    }
+    if !valid_challenge {
+        log!(header, "{}, but the challenge is invalid", prefix);
+        return Command::ErrorOccured(ErrorCode::InvalidToken);
+    }
+
+    let mut dht = ctx.dht.lock().await;
+    let replication = dht.add_node(sender_id, sender_addr).await;
+    replicate_to(
+        Arc::clone(&ctx),
+        Peer {
+            id: sender_id,
+            addr: sender_addr,
+        },
+        replication,
+    );
+    let mails = dht.get_mails(sender_id);
+    drop(dht);
+    log!(header, "{}, {} found", prefix, mails.len());
+
+    Command::GetMailResponse(mails)
+}
+
+// Forget the mails a peer acknowledged to have received. Only the owner of the
+// id can acknowledge its mails.
+pub async fn serve_ack_mail(
+    ctx: Arc<Context>,
+    sender_addr: SocketAddr,
+    sender_id: u32,
+    verified: bool,
+    mail_ids: Vec<u32>,
+) -> Command {
+    let header = "[ACK_MAIL]".to_owned().yellow().on_truecolor(35, 38, 39).bold();
+    let prefix = format!(
+        " peer {}({}), acknowledge {} mails",
+        sender_id,
+        sender_addr,
+        mail_ids.len()
+    );
+    if !verified {
+        log!(header, "{}, but the sender id is not proven", prefix);
+        return Command::ErrorOccured(ErrorCode::InvalidSignature);
+    }
+
+    let nb_removed = ctx.dht.lock().await.remove_mails(sender_id, &mail_ids);
+    log!(header, "{}, {} forgotten", prefix, nb_removed);
+    if nb_removed > 0 {
+        let _ = ctx.dump_dht().await;
+    }
+
+    Command::AckMailResponse()
+}
+
 // Announce is a way to tell a peer that a given user own a file (by given the
-// crc as an identifier).
+// crc as an identifier). The sender address must be the one it's seen from, and
+// it must send back a token we gave to this address.
+// The owner announces for itself, or a peer replicates it on its behalf: the
+// announcement must then be signed by the owner, and it's trusted to be
+// reachable at the address it signed.
 pub async fn serve_announce(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     sender_addr: SocketAddr,
     sender_id: u32,
+    verified: bool,
+    valid_token: bool,
     crc: u32,
+    mut announcement: Announcement,
 ) -> Command {
-    let mut guard = ctx.lock().await;
-    let ctx = guard.deref_mut();
-
     let header = "[ANNOUNCE]".to_owned().cyan().on_truecolor(35, 38, 39).bold();
-    log!(
-        header,
-        " peer {}({}), announce: he has the file {}",
-        sender_addr,
-        sender_id,
-        crc
+    let prefix = format!(
+        " peer {}({}), announce: {} has the file {}",
+        sender_addr, sender_id, announcement.owner.id, crc
     );
 
-    ctx.dht.store_file_peer(
-        crc,
+    // Anyone could announce on behalf of someone else, only the owner of the
+    // id is trusted.
+    if !verified {
+        log!(header, "{}, but the sender id is not proven", prefix);
+        return Command::ErrorOccured(ErrorCode::InvalidSignature);
+    }
+    if !valid_token {
+        log!(header, "{}, but the write token is invalid", prefix);
+        return Command::ErrorOccured(ErrorCode::InvalidToken);
+    }
+    let is_replicated = announcement.owner.id != sender_id;
+    if is_replicated && !announcement.has_valid_signature(crc) {
+        log!(header, "{}, but it isn't signed by its owner", prefix);
+        return Command::ErrorOccured(ErrorCode::InvalidSignature);
+    }
+    // An announcement from the future would outlive its owner.
+    let now = unix_timestamp();
+    if announcement.announced_at > now.saturating_add(MAX_REQUEST_AGE) {
+        log!(header, "{}, but it's announced in the future", prefix);
+        return Command::ErrorOccured(ErrorCode::InvalidSignature);
+    }
+    if !is_replicated {
+        // The owner is registered where it's seen from. If it isn't where it
+        // signed, the announcement can't be announced further.
+        if announcement.owner.addr != sender_addr || !announcement.has_valid_signature(crc) {
+            announcement = Announcement::unsigned(
+                Peer {
+                    id: sender_id,
+                    addr: sender_addr,
+                },
+                announcement.announced_at,
+            );
+        }
+    }
+
+    let mut dht = ctx.dht.lock().await;
+    let replication = dht.add_node(sender_id, sender_addr).await;
+    replicate_to(
+        Arc::clone(&ctx),
         Peer {
             id: sender_id,
             addr: sender_addr,
         },
+        replication,
     );
-    ctx.dht.add_node(sender_id, sender_addr).await;
-    let _ = ctx.dht.dump_to_file(Path::new(&ctx.dht_config_filename)).await;
+    let res = match dht.store_file_peer(crc, announcement) {
+        Ok(()) => {
+            log!(header, "{}", prefix);
+            Command::AnnounceResponse()
+        }
+        Err(err) => {
+            log!(header, "{}, but {}", prefix, err);
+            Command::ErrorOccured(ErrorCode::StorageFull)
+        }
+    };
+    drop(dht);
+    let _ = ctx.dump_dht().await;
 
-    Command::AnnounceResponse()
+    res
 }
 
-// Get the list of all peers which are sharing a file, given its id/crc.
-pub async fn serve_get_peers(ctx: Arc<Mutex<Context>>, incoming_addr: SocketAddr, crc: u32) -> Command {
+// Get the list of all peers which are sharing a file, given its id/crc, and the
+// closest peers we know from it. Comes with a token allowing the requester to
+// announce it's sharing it as well.
+pub async fn serve_get_peers(ctx: Arc<Context>, incoming_addr: SocketAddr, crc: u32) -> Command {
     let header = "[GET_PEERS]".to_owned().cyan().on_truecolor(35, 38, 39).bold();
     let prefix = format!(" peer {}, ask peers for {}", incoming_addr, crc);
 
-    let mut guard = ctx.lock().await;
-    let ctx = guard.deref_mut();
-    let peers = ctx.dht.get_file_peers(crc);
-
-    match peers {
-        Some(peers) => {
-            let peers = peers.map(Clone::clone).collect();
-            log!(header, "{}={:?}", prefix, peers);
-            Command::GetPeersResponse(peers)
+    let found = {
+        let dht = ctx.dht.lock().await;
+        let owners = dht
+            .get_file_peers(crc)
+            .map_or_else(Vec::new, |peers| peers.map(Clone::clone).collect());
+        // Help the sender to continue its lookup, other peers may know other
+        // owners.
+        let closest_peers = dht
+            .find_closest_peers_towards(crc, 4, incoming_addr)
+            .await
+            .map(Peer::from)
+            .collect::<Vec<_>>();
+        log!(
+            header,
+            "{}={:?}, send back {:?} as well",
+            prefix,
+            owners,
+            closest_peers
+        );
+        FoundPeers {
+            owners,
+            closest_peers,
         }
-        None => {
-            log!(header, "{}, but the key was not found", prefix);
-            Command::ErrorOccured(ErrorCode::FileNotFound)
+    };
+
+    let token = ctx.tokens.lock().await.issue(incoming_addr.ip());
+    Command::GetPeersResponse(token, found)
+}
+
+// Accept to relay the requests sent to a peer which can't be reached. A new
+// port is listened on for it: it's the address the peer tells to the others,
+// and everything received there is forwarded to it. Only verified peers are
+// relayed, so the relay address can't be taken by someone else.
+pub async fn serve_relay(
+    ctx: Arc<Context>,
+    incoming_addr: SocketAddr,
+    sender_id: u32,
+    verified: bool,
+) -> (Command, Option<TcpListener>) {
+    let header = "[RELAY]".to_owned().blue().on_truecolor(35, 38, 39).bold();
+    let prefix = format!(" asked by {}({})", sender_id, incoming_addr);
+
+    if !verified {
+        log!(header, "{}, but the request isn't signed", prefix);
+        return (Command::ErrorOccured(ErrorCode::InvalidSignature), None);
+    }
+    if !ctx.settings.read().await.accept_relays {
+        log!(header, "{}, but relays are not accepted", prefix);
+        return (Command::ErrorOccured(ErrorCode::RelayRefused), None);
+    }
+    {
+        let relays = ctx.relays.lock().await;
+        if relays.len() >= MAX_RELAYED_PEERS && !relays.contains_key(&sender_id) {
+            log!(header, "{}, but too many peers are relayed", prefix);
+            return (Command::ErrorOccured(ErrorCode::RelayRefused), None);
         }
     }
+
+    let listener = match bind(SocketAddr::new(ctx.addr.ip(), 0)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            log!(header, "{}, but can't listen for it: {}", prefix, err);
+            return (Command::ErrorOccured(ErrorCode::Unknown), None);
+        }
+    };
+    let port = match listener.local_addr() {
+        Ok(local_addr) => local_addr.port(),
+        Err(err) => {
+            log!(header, "{}, but can't listen for it: {}", prefix, err);
+            return (Command::ErrorOccured(ErrorCode::Unknown), None);
+        }
+    };
+
+    // Other peers reach the relay on its public IP, and we know the peer is
+    // reachable there, whatever the IP its id was derived from.
+    let public_ip = ctx.public_addr.lock().await.resolve(ctx.addr).ip();
+    let relay_addr = SocketAddr::new(public_ip, port);
+    let replication = ctx.dht.lock().await.add_relayed_node(sender_id, relay_addr).await;
+    replicate_to(
+        Arc::clone(&ctx),
+        Peer {
+            id: sender_id,
+            addr: relay_addr,
+        },
+        replication,
+    );
+    log!(header, "{}, relaying it on {}", prefix, relay_addr);
+    (Command::RelayResponse(relay_addr), Some(listener))
 }
+
+#[cfg(test)]
+#[path = "server_test.rs"]
+mod server_test;
@@ -0,0 +1,155 @@
+use super::*;
+use crate::file::{file_chunk::FileChunk, torrent_file::TorrentFile};
+use errors::AnyResult;
+use std::time::Duration;
+use tokio::{sync::Mutex, time::timeout};
+
+#[tokio::test]
+async fn test_ping_while_serving_a_chunk() -> AnyResult<()> {
+    let tmp_file = temp_file::with_contents(&[0, 1, 2, 3, 4]);
+    let tmp_torrent_file = temp_file::empty();
+    let torrent = TorrentFile::from(
+        tmp_torrent_file.path().display().to_string(),
+        tmp_file.path().display().to_string(),
+    )?;
+    let chunks = FileChunk::open_existing(tmp_file.path())?;
+    let crc = torrent.metadata.file_crc;
+
+    let ctx = Arc::new(Context::new_test(0, false));
+    ctx.available_torrents
+        .lock()
+        .await
+        .insert(crc, Arc::new(Mutex::new((torrent, chunks))));
+
+    // Hold the torrent, like a long chunk reading would do.
+    let torrent = ctx.torrent(crc).await.expect("torrent is registered");
+    let _guard = torrent.lock().await;
+
+    // A ping doesn't need the torrent, and is answered anyway.
+    let sender_addr = "127.0.0.1:4001".parse()?;
+    let res = timeout(
+        Duration::from_secs(1),
+        serve_ping(Arc::clone(&ctx), sender_addr, sender_addr, 1, true, 0),
+    )
+    .await?;
+    assert!(matches!(res, Command::PingResponse(0, _)));
+
+    // While the same chunk is still waited for.
+    let res = timeout(
+        Duration::from_millis(50),
+        serve_file_chunk(Arc::clone(&ctx), sender_addr, crc, 0),
+    )
+    .await;
+    assert!(res.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_announce_requires_token() -> AnyResult<()> {
+    let ctx = Arc::new(Context::new_test(0, false));
+    let sender_addr = "127.0.0.1:4001".parse()?;
+
+    let owner = |id| Peer {
+        id,
+        addr: sender_addr,
+    };
+    let announcement = |id| Announcement::unsigned(owner(id), unix_timestamp());
+
+    let res = serve_announce(Arc::clone(&ctx), sender_addr, 1, true, false, 42, announcement(1)).await;
+    assert!(matches!(res, Command::ErrorOccured(ErrorCode::InvalidToken)));
+    assert!(ctx.dht.lock().await.get_file_peers(42).is_none());
+
+    // Announcing on behalf of another owner requires its signature.
+    let res = serve_announce(Arc::clone(&ctx), sender_addr, 1, true, true, 42, announcement(2)).await;
+    assert!(matches!(res, Command::ErrorOccured(ErrorCode::InvalidSignature)));
+    assert!(ctx.dht.lock().await.get_file_peers(42).is_none());
+
+    let res = serve_announce(Arc::clone(&ctx), sender_addr, 1, true, true, 42, announcement(1)).await;
+    assert!(matches!(res, Command::AnnounceResponse()));
+    assert_eq!(
+        vec![&owner(1)],
+        ctx.dht
+            .lock()
+            .await
+            .get_file_peers(42)
+            .expect("announced")
+            .collect::<Vec<_>>()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_is_opt_in() -> AnyResult<()> {
+    let ctx = Arc::new(Context::new_test(0, false));
+    let sender_addr = "127.0.0.1:4001".parse()?;
+
+    let (res, listener) = serve_relay(Arc::clone(&ctx), sender_addr, 1, true).await;
+    assert!(matches!(res, Command::ErrorOccured(ErrorCode::RelayRefused)));
+    assert!(listener.is_none());
+
+    ctx.settings.write().await.accept_relays = true;
+    let (res, listener) = serve_relay(Arc::clone(&ctx), sender_addr, 1, false).await;
+    assert!(matches!(res, Command::ErrorOccured(ErrorCode::InvalidSignature)));
+    assert!(listener.is_none());
+
+    // The peer is told where the relay listens for it.
+    let (res, listener) = serve_relay(Arc::clone(&ctx), sender_addr, 1, true).await;
+    let listener = listener.expect("relay is accepted");
+    match res {
+        Command::RelayResponse(relay_addr) => assert_eq!(listener.local_addr()?, relay_addr),
+        _ => panic!(),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_peer_key_is_given_when_known() -> AnyResult<()> {
+    let ctx = Arc::new(Context::new_test(0, false));
+    let sender_addr = "127.0.0.1:4001".parse()?;
+
+    let res = serve_peer_key(Arc::clone(&ctx), sender_addr, 0, 0).await;
+    assert!(matches!(res, Command::PublicKeyResponse(key) if key == ctx.keypair.public.to_bytes()));
+    let res = serve_peer_key(Arc::clone(&ctx), sender_addr, 0, 1).await;
+    assert!(matches!(res, Command::ErrorOccured(ErrorCode::KeyNotFound)));
+
+    ctx.public_keys.lock().await.insert(1, ctx.keypair.public);
+    let res = serve_peer_key(Arc::clone(&ctx), sender_addr, 0, 1).await;
+    assert!(matches!(res, Command::PublicKeyResponse(_)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mails_require_challenge_and_ack() -> AnyResult<()> {
+    let ctx = Arc::new(Context::new_test(0, false));
+    let sender_addr = "127.0.0.1:4001".parse()?;
+    let mail = StoredValue::new(&ctx.keypair, 1, unix_timestamp(), "hello".to_owned());
+    ctx.dht.lock().await.store_mail(1, mail.clone())?;
+
+    // A challenge is only valid for the recipient it was given to.
+    let challenge = match serve_mail_challenge(Arc::clone(&ctx), sender_addr, 1).await {
+        Command::MailChallengeResponse(challenge) => challenge,
+        command => panic!("unexpected response {:?}", command),
+    };
+    let mut challenges = ctx.mail_challenges.lock().await;
+    let valid_challenge = challenges.is_valid(challenge, 1, sender_addr.ip());
+    assert!(!challenges.is_valid(challenge, 2, sender_addr.ip()));
+    drop(challenges);
+    let res = serve_get_mail(Arc::clone(&ctx), sender_addr, 1, true, false).await;
+    assert!(matches!(res, Command::ErrorOccured(ErrorCode::InvalidToken)));
+    let res = serve_get_mail(Arc::clone(&ctx), sender_addr, 1, true, valid_challenge).await;
+    assert!(matches!(res, Command::GetMailResponse(mails) if mails == vec![mail.clone()]));
+
+    // The mail is kept until acknowledged by its recipient.
+    let res = serve_ack_mail(Arc::clone(&ctx), sender_addr, 1, false, vec![mail.mail_id()]).await;
+    assert!(matches!(res, Command::ErrorOccured(ErrorCode::InvalidSignature)));
+    assert_eq!(vec![mail.clone()], ctx.dht.lock().await.get_mails(1));
+    let res = serve_ack_mail(Arc::clone(&ctx), sender_addr, 1, true, vec![mail.mail_id()]).await;
+    assert!(matches!(res, Command::AckMailResponse()));
+    assert!(ctx.dht.lock().await.get_mails(1).is_empty());
+
+    Ok(())
+}
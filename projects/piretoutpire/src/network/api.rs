@@ -1,7 +1,16 @@
-use super::protocol::{Command, Peer};
-use crate::manager::context::Context;
-use errors::{bail, AnyResult};
-use std::{net::SocketAddr, ops::Deref, sync::Arc};
+use super::protocol::{Announcement, Command, Identity, MutableValue, Peer, StoredValue};
+use crate::{
+    dht::mutable::MutableHash,
+    manager::context::{Context, RpcClass},
+    utils::unix_timestamp,
+};
+use errors::AnyResult;
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
@@ -37,18 +46,121 @@ macro_rules! read_all {
     }};
 }
 
+// Wrap a request into a signed one, so the peer can trust the sender id we
+// claim is really ours. It's signed for its recipient (if known) and now, so it
+// can't be replayed to another peer or later on.
+async fn sign_request(ctx: Arc<Context>, recipient: Option<u32>, request: Command) -> Vec<u8> {
+    let request: Vec<u8> = request.into();
+    let identity = Identity::sign(&ctx.keypair, recipient, unix_timestamp(), &request);
+    Command::SignedRequest(identity, request).into()
+}
+
+// Get the sender of a request, with the address it can be reached back on from
+// the peer at the other end of the stream: our public address on its family.
+async fn sender_peer(ctx: &Context, stream: &Mutex<TcpStream>, sender: Peer) -> AnyResult<Peer> {
+    let peer_addr = stream.lock().await.peer_addr()?;
+    Ok(Peer {
+        id: sender.id,
+        addr: ctx.addr_towards(peer_addr, sender.addr).await,
+    })
+}
+
+// Count a new attempt to reach a peer into its liveness tracking, if it's a
+// peer we know.
+async fn peer_was_requested(ctx: Arc<Context>, addr: SocketAddr) {
+    let mut dht = ctx.dht.lock().await;
+    if let Some(peer_id) = dht.peer_id_at(addr).await {
+        dht.peer_was_requested(peer_id).await;
+    }
+}
+
+// Record the round-trip time of a request answered by a peer, if it's a peer
+// we know.
+async fn peer_rtt_measured(ctx: Arc<Context>, addr: SocketAddr, rtt: Duration) {
+    let mut dht = ctx.dht.lock().await;
+    if let Some(peer_id) = dht.peer_id_at(addr).await {
+        dht.peer_rtt_measured(peer_id, rtt).await;
+    }
+}
+
+// Open a connection to a peer, trying again as the retry policy allows. Each
+// attempt is limited by the connection timeout. The first attempt is counted by
+// the caller, the next ones are counted here.
+pub async fn connect(ctx: Arc<Context>, addr: SocketAddr) -> AnyResult<TcpStream> {
+    let (retry_policy, connection_timeout) = {
+        let settings = ctx.settings.read().await;
+        (settings.retry_policy.clone(), settings.connection_timeout)
+    };
+
+    let mut attempt = 1;
+    loop {
+        let error = match timeout(connection_timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(connection)) => return Ok(connection),
+            Ok(Err(error)) => error.into(),
+            Err(error) => error.into(),
+        };
+        if !retry_policy.should_retry(attempt, &error, false) {
+            return Err(error);
+        }
+        sleep(retry_policy.delay(attempt)).await;
+        attempt += 1;
+        peer_was_requested(Arc::clone(&ctx), addr).await;
+    }
+}
+
 // Send a raw request u8 encoded, and wait for a respone.
 // Return a raw buffer which must be interpreted.
+// On a network failure, the request is sent again on a new connection, as the
+// retry policy allows. The class of the request gives its timeouts.
 pub async fn send_raw_unary(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     stream: Arc<Mutex<TcpStream>>,
     request: &[u8],
+    class: RpcClass,
 ) -> AnyResult<Vec<u8>> {
-    let (slowness, read_timeout, write_timeout) = {
-        let guard = ctx.lock().await;
-        let ctx = guard.deref();
-        (ctx.slowness, ctx.read_timeout, ctx.write_timeout)
+    let (retry_policy, connection_timeout) = {
+        let settings = ctx.settings.read().await;
+        (settings.retry_policy.clone(), settings.connection_timeout)
     };
+    let addr = stream.lock().await.peer_addr()?;
+
+    let mut attempt = 1;
+    let mut res = send_raw_unary_once(Arc::clone(&ctx), Arc::clone(&stream), request, class).await;
+    loop {
+        let error = match res {
+            Ok(response) => return Ok(response),
+            Err(error) => error,
+        };
+        if !retry_policy.should_retry(attempt, &error, true) {
+            return Err(error);
+        }
+        sleep(retry_policy.delay(attempt)).await;
+        attempt += 1;
+        peer_was_requested(Arc::clone(&ctx), addr).await;
+
+        // The previous connection may be broken, or still get the previous
+        // answer, let's send the request on a new one.
+        res = match timeout(connection_timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(connection)) => {
+                *stream.lock().await = connection;
+                send_raw_unary_once(Arc::clone(&ctx), Arc::clone(&stream), request, class).await
+            }
+            Ok(Err(error)) => Err(error.into()),
+            Err(error) => Err(error.into()),
+        };
+    }
+}
+
+// Send a raw request once, and wait for a respone.
+pub async fn send_raw_unary_once(
+    ctx: Arc<Context>,
+    stream: Arc<Mutex<TcpStream>>,
+    request: &[u8],
+    class: RpcClass,
+) -> AnyResult<Vec<u8>> {
+    let slowness = ctx.settings.read().await.slowness;
+    let addr = stream.lock().await.peer_addr()?;
+    let (write_timeout, read_timeout) = ctx.request_timeouts(class, addr).await;
 
     let mut guard = stream.lock().await;
     let (reader, writer) = guard.split();
@@ -58,13 +170,22 @@ pub async fn send_raw_unary(
     if let Some(wait_time) = slowness {
         sleep(wait_time).await;
     }
+    let started_at = Instant::now();
     timeout(write_timeout, writer.write_all(request)).await??;
     timeout(write_timeout, writer.flush()).await??;
 
+    // A peer closing the connection without answering is a network failure, so
+    // the request can be tried again.
     let raw_chunk = read_all!(reader, read_timeout);
-    let len = raw_chunk.len();
-    if len == 0 {
-        bail!("invalid buffer");
+    if raw_chunk.is_empty() {
+        return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    drop(guard);
+
+    // Only the answers of control requests tell how fast the peer is: bulk
+    // ones mostly measure their own size.
+    if class == RpcClass::Control {
+        peer_rtt_measured(ctx, addr, started_at.elapsed()).await;
     }
 
     Ok(raw_chunk)
@@ -75,135 +196,295 @@ pub async fn send_raw_unary(
 
 // Ask for a chunk of a given file by its id.
 pub async fn file_chunk(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     stream: Arc<Mutex<TcpStream>>,
     crc: u32,
     chunk_id: u32,
 ) -> AnyResult<Command> {
     let request: Vec<u8> = Command::ChunkRequest(crc, chunk_id).into();
-    let raw_response = send_raw_unary(ctx, stream, request.as_slice()).await?;
+    let raw_response = send_raw_unary(ctx, stream, request.as_slice(), RpcClass::Bulk).await?;
     raw_response.as_slice().try_into()
 }
 
 // Ask for a chunk of a given file by its id.
-pub async fn file_info(
-    ctx: Arc<Mutex<Context>>,
-    stream: Arc<Mutex<TcpStream>>,
-    crc: u32,
-) -> AnyResult<Command> {
+pub async fn file_info(ctx: Arc<Context>, stream: Arc<Mutex<TcpStream>>, crc: u32) -> AnyResult<Command> {
     let request: Vec<u8> = Command::FileInfoRequest(crc).into();
-    let raw_response = send_raw_unary(ctx, stream, request.as_slice()).await?;
+    let raw_response = send_raw_unary(ctx, stream, request.as_slice(), RpcClass::Control).await?;
     raw_response.as_slice().try_into()
 }
 
 // Search for a given peer.
 pub async fn find_node(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     stream: Arc<Mutex<TcpStream>>,
-    sender_addr: SocketAddr,
-    sender_id: u32,
+    peer_id: u32,
+    sender: Peer,
     target: u32,
 ) -> AnyResult<Command> {
-    let peer = Peer {
-        id: sender_id,
-        addr: sender_addr,
-    };
+    let peer = sender_peer(&ctx, &stream, sender).await?;
 
-    let request: Vec<u8> = Command::FindNodeRequest(peer, target).into();
-    let raw_response = send_raw_unary(ctx, stream, request.as_slice()).await?;
+    let request = sign_request(
+        Arc::clone(&ctx),
+        Some(peer_id),
+        Command::FindNodeRequest(peer, target),
+    )
+    .await;
+    let raw_response = send_raw_unary(ctx, stream, request.as_slice(), RpcClass::Control).await?;
     raw_response.as_slice().try_into()
 }
 
-// Ping a peer, checking if he's alive and get its id.
+// Ping a peer, checking if he's alive and get its id. The request is signed for
+// the peer when its id is already known.
 pub async fn ping(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     stream: Arc<Mutex<TcpStream>>,
-    sender_addr: SocketAddr,
-    sender_id: u32,
+    peer_id: Option<u32>,
+    sender: Peer,
 ) -> AnyResult<Command> {
-    let peer = Peer {
-        id: sender_id,
-        addr: sender_addr,
-    };
+    let peer = sender_peer(&ctx, &stream, sender).await?;
 
-    let request: Vec<u8> = Command::PingRequest(peer).into();
-    let raw_response = send_raw_unary(ctx, stream, request.as_slice()).await?;
+    let request = sign_request(Arc::clone(&ctx), peer_id, Command::PingRequest(peer)).await;
+    let raw_response = send_raw_unary(ctx, stream, request.as_slice(), RpcClass::Control).await?;
     raw_response.as_slice().try_into()
 }
 
-// Store a value on a peer.
+// Store a value on a peer, with the token it gave us.
 pub async fn store(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     stream: Arc<Mutex<TcpStream>>,
-    sender_addr: SocketAddr,
-    sender_id: u32,
+    peer_id: u32,
+    sender: Peer,
+    token: u32,
     key: u32,
-    value: String,
+    value: StoredValue,
 ) -> AnyResult<Command> {
-    let peer = Peer {
-        id: sender_id,
-        addr: sender_addr,
-    };
+    let peer = sender_peer(&ctx, &stream, sender).await?;
 
-    let request: Vec<u8> = Command::StoreRequest(peer, key, value).into();
-    let raw_response = send_raw_unary(ctx, stream, request.as_slice()).await?;
+    let request = sign_request(
+        Arc::clone(&ctx),
+        Some(peer_id),
+        Command::StoreRequest(peer, token, key, value),
+    )
+    .await;
+    let raw_response = send_raw_unary(ctx, stream, request.as_slice(), RpcClass::Control).await?;
     raw_response.as_slice().try_into()
 }
 
-// Search a given value on a peer.
+// Search a given value on a peer, the page tells which values to get back when
+// there's a lot of them.
 pub async fn find_value(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     stream: Arc<Mutex<TcpStream>>,
-    sender_addr: SocketAddr,
-    sender_id: u32,
+    peer_id: u32,
+    sender: Peer,
     key: u32,
+    page: u32,
 ) -> AnyResult<Command> {
-    let peer = Peer {
-        id: sender_id,
-        addr: sender_addr,
-    };
+    let peer = sender_peer(&ctx, &stream, sender).await?;
 
-    let request: Vec<u8> = Command::FindValueRequest(peer, key).into();
-    let raw_response = send_raw_unary(ctx, stream, request.as_slice()).await?;
+    let request = sign_request(
+        Arc::clone(&ctx),
+        Some(peer_id),
+        Command::FindValueRequest(peer, key, page),
+    )
+    .await;
+    let raw_response = send_raw_unary(ctx, stream, request.as_slice(), RpcClass::Control).await?;
+    raw_response.as_slice().try_into()
+}
+
+// Put a signed mutable value on a peer.
+pub async fn put_mutable(
+    ctx: Arc<Context>,
+    stream: Arc<Mutex<TcpStream>>,
+    peer_id: u32,
+    sender: Peer,
+    value: MutableValue,
+) -> AnyResult<Command> {
+    let peer = sender_peer(&ctx, &stream, sender).await?;
+
+    let request = sign_request(
+        Arc::clone(&ctx),
+        Some(peer_id),
+        Command::PutMutableRequest(peer, value),
+    )
+    .await;
+    let raw_response = send_raw_unary(ctx, stream, request.as_slice(), RpcClass::Control).await?;
+    raw_response.as_slice().try_into()
+}
+
+// Search a given mutable value on a peer.
+pub async fn get_mutable(
+    ctx: Arc<Context>,
+    stream: Arc<Mutex<TcpStream>>,
+    peer_id: u32,
+    sender: Peer,
+    hash: MutableHash,
+) -> AnyResult<Command> {
+    let peer = sender_peer(&ctx, &stream, sender).await?;
+
+    let request = sign_request(
+        Arc::clone(&ctx),
+        Some(peer_id),
+        Command::GetMutableRequest(peer, hash),
+    )
+    .await;
+    let raw_response = send_raw_unary(ctx, stream, request.as_slice(), RpcClass::Control).await?;
     raw_response.as_slice().try_into()
 }
 
 // Send a message to a peer.
 pub async fn send_message(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     stream: Arc<Mutex<TcpStream>>,
     message: String,
 ) -> AnyResult<Command> {
     let request: Vec<u8> = Command::MessageRequest(message).into();
-    let raw_response = send_raw_unary(ctx, stream, request.as_slice()).await?;
+    let raw_response = send_raw_unary(ctx, stream, request.as_slice(), RpcClass::Control).await?;
     raw_response.as_slice().try_into()
 }
 
-// Send to a peer that a given peer own a file (by its crc).
+// Send to a peer that a given peer own a file (by its crc), with the token it
+// gave us. The announcement is signed by the owner, which can be someone else.
 pub async fn announce(
-    ctx: Arc<Mutex<Context>>,
+    ctx: Arc<Context>,
     stream: Arc<Mutex<TcpStream>>,
-    sender_addr: SocketAddr,
-    sender_id: u32,
+    peer_id: u32,
+    sender: Peer,
+    token: u32,
     crc: u32,
+    announcement: Announcement,
 ) -> AnyResult<Command> {
-    let peer = Peer {
-        id: sender_id,
-        addr: sender_addr,
-    };
+    let peer = sender_peer(&ctx, &stream, sender).await?;
 
-    let request: Vec<u8> = Command::AnnounceRequest(peer, crc).into();
-    let raw_response = send_raw_unary(ctx, stream, request.as_slice()).await?;
+    let request = sign_request(
+        Arc::clone(&ctx),
+        Some(peer_id),
+        Command::AnnounceRequest(peer, token, crc, announcement),
+    )
+    .await;
+    let raw_response = send_raw_unary(ctx, stream, request.as_slice(), RpcClass::Control).await?;
     raw_response.as_slice().try_into()
 }
 
 // Get the list of peers who own a given file (by its crc).
-pub async fn get_peers(
-    ctx: Arc<Mutex<Context>>,
+pub async fn get_peers(ctx: Arc<Context>, stream: Arc<Mutex<TcpStream>>, crc: u32) -> AnyResult<Command> {
+    let request: Vec<u8> = Command::GetPeersRequest(crc).into();
+    let raw_response = send_raw_unary(ctx, stream, request.as_slice(), RpcClass::Control).await?;
+    raw_response.as_slice().try_into()
+}
+
+// Ask a peer to relay the requests sent to us, on this connection.
+pub async fn relay(
+    ctx: Arc<Context>,
     stream: Arc<Mutex<TcpStream>>,
-    crc: u32,
+    peer_id: u32,
+    sender: Peer,
 ) -> AnyResult<Command> {
-    let request: Vec<u8> = Command::GetPeersRequest(crc).into();
-    let raw_response = send_raw_unary(ctx, stream, request.as_slice()).await?;
+    let peer = sender_peer(&ctx, &stream, sender).await?;
+
+    let request = sign_request(Arc::clone(&ctx), Some(peer_id), Command::RelayRequest(peer)).await;
+    let raw_response = send_raw_unary(ctx, stream, request.as_slice(), RpcClass::Control).await?;
+    raw_response.as_slice().try_into()
+}
+
+// Send an onion to its next hop, which answers once it took it. It's only
+// tried again when it couldn't be sent (RetryOn::Connection), whatever the
+// retry policy: each copy would go through all the next hops.
+pub async fn send_onion(
+    ctx: Arc<Context>,
+    stream: Arc<Mutex<TcpStream>>,
+    onion: Vec<u8>,
+) -> AnyResult<Command> {
+    let request: Vec<u8> = Command::OnionRequest(onion).into();
+    let raw_response = send_raw_unary_once(ctx, stream, request.as_slice(), RpcClass::Control).await?;
+    raw_response.as_slice().try_into()
+}
+
+// Ask a peer for its public key.
+pub async fn public_key(ctx: Arc<Context>, stream: Arc<Mutex<TcpStream>>) -> AnyResult<Command> {
+    let request: Vec<u8> = Command::PublicKeyRequest().into();
+    let raw_response = send_raw_unary(ctx, stream, request.as_slice(), RpcClass::Control).await?;
+    raw_response.as_slice().try_into()
+}
+
+// Ask a peer for the public key of another one.
+pub async fn peer_key(ctx: Arc<Context>, stream: Arc<Mutex<TcpStream>>, peer_id: u32) -> AnyResult<Command> {
+    let request: Vec<u8> = Command::PeerKeyRequest(peer_id).into();
+    let raw_response = send_raw_unary(ctx, stream, request.as_slice(), RpcClass::Control).await?;
+    raw_response.as_slice().try_into()
+}
+
+// Ask a peer to keep a mail for a recipient we can't reach, with the token it
+// gave us.
+pub async fn put_mail(
+    ctx: Arc<Context>,
+    stream: Arc<Mutex<TcpStream>>,
+    peer_id: u32,
+    sender: Peer,
+    token: u32,
+    recipient: u32,
+    mail: StoredValue,
+) -> AnyResult<Command> {
+    let peer = sender_peer(&ctx, &stream, sender).await?;
+
+    let request = sign_request(
+        Arc::clone(&ctx),
+        Some(peer_id),
+        Command::PutMailRequest(peer, token, recipient, mail),
+    )
+    .await;
+    let raw_response = send_raw_unary(ctx, stream, request.as_slice(), RpcClass::Control).await?;
+    raw_response.as_slice().try_into()
+}
+
+// Ask a peer for a challenge to fetch the mails it keeps for a recipient.
+pub async fn mail_challenge(
+    ctx: Arc<Context>,
+    stream: Arc<Mutex<TcpStream>>,
+    recipient: u32,
+) -> AnyResult<Command> {
+    let request: Vec<u8> = Command::MailChallengeRequest(recipient).into();
+    let raw_response = send_raw_unary(ctx, stream, request.as_slice(), RpcClass::Control).await?;
+    raw_response.as_slice().try_into()
+}
+
+// Ask a peer for the mails it keeps for us, with the challenge it gave us. The
+// answer may be large, as a bulk transfer.
+pub async fn get_mail(
+    ctx: Arc<Context>,
+    stream: Arc<Mutex<TcpStream>>,
+    peer_id: u32,
+    sender: Peer,
+    challenge: u32,
+) -> AnyResult<Command> {
+    let peer = sender_peer(&ctx, &stream, sender).await?;
+
+    let request = sign_request(
+        Arc::clone(&ctx),
+        Some(peer_id),
+        Command::GetMailRequest(peer, challenge),
+    )
+    .await;
+    let raw_response = send_raw_unary(ctx, stream, request.as_slice(), RpcClass::Bulk).await?;
+    raw_response.as_slice().try_into()
+}
+
+// Tell a peer the mails we received from it, so it can forget them.
+pub async fn ack_mail(
+    ctx: Arc<Context>,
+    stream: Arc<Mutex<TcpStream>>,
+    peer_id: u32,
+    sender: Peer,
+    mail_ids: Vec<u32>,
+) -> AnyResult<Command> {
+    let peer = sender_peer(&ctx, &stream, sender).await?;
+
+    let request = sign_request(
+        Arc::clone(&ctx),
+        Some(peer_id),
+        Command::AckMailRequest(peer, mail_ids),
+    )
+    .await;
+    let raw_response = send_raw_unary(ctx, stream, request.as_slice(), RpcClass::Control).await?;
     raw_response.as_slice().try_into()
 }
@@ -1,2 +1,4 @@
 pub mod api;
+pub mod onion;
 pub mod protocol;
+pub mod retry;
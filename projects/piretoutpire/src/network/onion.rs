@@ -0,0 +1,160 @@
+use crate::utils::{addr_to_u8_array, string_to_u8_array, u8_array_to_addr, u8_array_to_string};
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use ed25519_dalek::{ExpandedSecretKey, Keypair, PublicKey};
+use errors::{bail, reexports::eyre::ContextCompat, AnyResult};
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::Sha256;
+use std::net::SocketAddr;
+use x25519_dalek::{PublicKey as X25519PublicKey, SharedSecret, StaticSecret};
+
+// Onion constants -------------------------------------------------------------
+
+const KEY_SIZE: usize = 32;
+const TAG_SIZE: usize = 16;
+const HEADER_SIZE: usize = KEY_SIZE + TAG_SIZE; // ephemeral key(32) + tag(16)
+const NONCE: [u8; 12] = [0; 12];
+const KDF_CONTEXT: &[u8] = b"piretoutpire onion layer";
+
+// Layer tags.
+const FORWARD_LAYER: u8 = 0x0;
+const DELIVER_LAYER: u8 = 0x1;
+
+// Onion -----------------------------------------------------------------------
+//
+// A message sent through many hops is wrapped in layers, one per hop, the
+// recipient being the last one. Each layer is encrypted for a single peer, who
+// only learns what to do with it: forward the inner onion to the next hop, or
+// read the message. A layer is:
+//   ephemeral public key(32) + encrypted(tag(1) + content) + auth tag(16)
+//
+// The layer key is agreed with X25519, between a new ephemeral key and the
+// ed25519 key of the peer (the one its id is derived from), seen as a X25519
+// key, then derived with HKDF-SHA256. The content is encrypted and
+// authenticated with ChaCha20-Poly1305.
+
+// A peer a message goes through: where it's reached, and its key.
+#[derive(Debug, Clone)]
+pub struct OnionHop {
+    pub addr: SocketAddr,
+    pub public_key: PublicKey,
+}
+
+// What a peer finds once it removed its layer of an onion.
+#[derive(Debug, Eq, PartialEq)]
+pub enum OnionLayer {
+    // Send the inner onion to the next hop.
+    Forward(SocketAddr /*next hop*/, Vec<u8> /*inner onion*/),
+    // We're the recipient of the message.
+    Deliver(String /*message*/),
+}
+
+// Wrap a message for a route, the recipient being its last hop. The onion is
+// to be sent to the first hop.
+pub fn wrap_onion(route: &[OnionHop], message: String) -> AnyResult<Vec<u8>> {
+    let (recipient, hops) = route.split_last().context("empty onion route")?;
+
+    let mut layer = vec![DELIVER_LAYER];
+    layer.extend(string_to_u8_array(message));
+    let mut onion = seal(&recipient.public_key, layer)?;
+
+    let mut next_addr = recipient.addr;
+    for hop in hops.iter().rev() {
+        let mut layer = vec![FORWARD_LAYER];
+        layer.extend(addr_to_u8_array(next_addr));
+        layer.extend(onion);
+        onion = seal(&hop.public_key, layer)?;
+        next_addr = hop.addr;
+    }
+    Ok(onion)
+}
+
+// Remove our layer of an onion. Fails if it isn't encrypted for us, or has been
+// tampered with.
+pub fn peel_onion(keypair: &Keypair, onion: &[u8]) -> AnyResult<OnionLayer> {
+    let layer = open(keypair, onion)?;
+    match layer.first() {
+        Some(&FORWARD_LAYER) => {
+            let raw = &layer[1..];
+            let next_addr = u8_array_to_addr(raw)?;
+            let addr_size = addr_to_u8_array(next_addr).len();
+            Ok(OnionLayer::Forward(next_addr, raw[addr_size..].to_vec()))
+        }
+        Some(&DELIVER_LAYER) => Ok(OnionLayer::Deliver(u8_array_to_string(&layer[1..])?)),
+        Some(tag) => bail!("can't decode onion layer, unknown tag {}", tag),
+        None => bail!("empty onion layer"),
+    }
+}
+
+// Helpers ---------------------------------------------------------------------
+
+// Encrypt a layer for the owner of a public key.
+fn seal(public_key: &PublicKey, layer: Vec<u8>) -> AnyResult<Vec<u8>> {
+    let peer_key = CompressedEdwardsY(public_key.to_bytes())
+        .decompress()
+        .context("invalid public key")?
+        .to_montgomery();
+
+    let mut secret = [0u8; KEY_SIZE];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let ephemeral = StaticSecret::from(secret);
+    let ephemeral_key = X25519PublicKey::from(&ephemeral);
+    let shared_key = ephemeral.diffie_hellman(&X25519PublicKey::from(peer_key.to_bytes()));
+    let cipher = layer_cipher(&shared_key, &ephemeral_key)?;
+
+    let encrypted = match cipher.encrypt(Nonce::from_slice(&NONCE), layer.as_slice()) {
+        Ok(encrypted) => encrypted,
+        Err(_) => bail!("can't encrypt onion layer"),
+    };
+    let mut onion = Vec::with_capacity(KEY_SIZE + encrypted.len());
+    onion.extend(ephemeral_key.as_bytes());
+    onion.extend(encrypted);
+    Ok(onion)
+}
+
+// Decrypt a layer encrypted for us, after checking it's authentic.
+fn open(keypair: &Keypair, onion: &[u8]) -> AnyResult<Vec<u8>> {
+    if onion.len() < HEADER_SIZE {
+        bail!(
+            "can't decode onion, size too low ({} < {})",
+            onion.len(),
+            HEADER_SIZE
+        );
+    }
+    let ephemeral_key = X25519PublicKey::from(<[u8; KEY_SIZE]>::try_from(&onion[..KEY_SIZE])?);
+
+    // The first half of the expanded ed25519 secret is the X25519 one.
+    let expanded = ExpandedSecretKey::from(&keypair.secret).to_bytes();
+    let own_key = StaticSecret::from(<[u8; KEY_SIZE]>::try_from(&expanded[..KEY_SIZE])?);
+    let cipher = layer_cipher(&own_key.diffie_hellman(&ephemeral_key), &ephemeral_key)?;
+
+    match cipher.decrypt(Nonce::from_slice(&NONCE), &onion[KEY_SIZE..]) {
+        Ok(layer) => Ok(layer),
+        Err(_) => bail!("onion layer isn't authentic"),
+    }
+}
+
+// Get the cipher of a layer, from the agreed key. Each key is only used once,
+// for a single layer, so the nonce is always zero.
+fn layer_cipher(shared_key: &SharedSecret, ephemeral_key: &X25519PublicKey) -> AnyResult<ChaCha20Poly1305> {
+    // A low order ephemeral key gives a key anyone can guess.
+    if !shared_key.was_contributory() {
+        bail!("invalid ephemeral key");
+    }
+    let mut key = [0u8; KEY_SIZE];
+    if Hkdf::<Sha256>::new(Some(ephemeral_key.as_bytes()), shared_key.as_bytes())
+        .expand(KDF_CONTEXT, &mut key)
+        .is_err()
+    {
+        bail!("can't derive onion layer key");
+    }
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+#[cfg(test)]
+#[path = "onion_test.rs"]
+mod onion_test;
@@ -0,0 +1,70 @@
+use super::*;
+use ed25519_dalek::SecretKey;
+
+fn keypair(seed: u8) -> Keypair {
+    let secret = SecretKey::from_bytes(&[seed; 32]).expect("valid secret key");
+    let public = (&secret).into();
+    Keypair { secret, public }
+}
+
+fn hop(keypair: &Keypair, addr: &str) -> OnionHop {
+    OnionHop {
+        addr: addr.parse().expect("valid address"),
+        public_key: keypair.public,
+    }
+}
+
+#[test]
+fn test_onion_layers() -> AnyResult<()> {
+    let (first, second, recipient) = (keypair(1), keypair(2), keypair(3));
+    let route = [
+        hop(&first, "127.0.0.1:4001"),
+        hop(&second, "127.0.0.1:4002"),
+        hop(&recipient, "127.0.0.1:4003"),
+    ];
+    let onion = wrap_onion(&route, "hello".to_owned())?;
+
+    // Each hop only learns the next one.
+    let onion = match peel_onion(&first, &onion)? {
+        OnionLayer::Forward(next_addr, inner) => {
+            assert_eq!(route[1].addr, next_addr);
+            inner
+        }
+        _ => panic!(),
+    };
+    // A layer can't be removed by another peer than its hop.
+    assert!(peel_onion(&recipient, &onion).is_err());
+    let onion = match peel_onion(&second, &onion)? {
+        OnionLayer::Forward(next_addr, inner) => {
+            assert_eq!(route[2].addr, next_addr);
+            inner
+        }
+        _ => panic!(),
+    };
+    assert_eq!(
+        OnionLayer::Deliver("hello".to_owned()),
+        peel_onion(&recipient, &onion)?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_tampered_onion() -> AnyResult<()> {
+    let recipient = keypair(3);
+    let route = [hop(&recipient, "127.0.0.1:4003")];
+    let onion = wrap_onion(&route, "hello".to_owned())?;
+
+    // The message is not readable as is.
+    assert!(!onion.windows(5).any(|window| window == b"hello"));
+
+    for idx in [0, KEY_SIZE, onion.len() - 1] {
+        let mut tampered = onion.clone();
+        tampered[idx] ^= 1;
+        assert!(peel_onion(&recipient, &tampered).is_err());
+    }
+    assert!(peel_onion(&recipient, &onion[..HEADER_SIZE - 1]).is_err());
+    assert!(wrap_onion(&[], "hello".to_owned()).is_err());
+
+    Ok(())
+}
@@ -1,10 +1,11 @@
 use crate::{
-    dht::peer_node::PeerNode,
+    dht::{mutable::MutableHash, peer_node::PeerNode},
     utils::{
         addr_to_u8_array, div_ceil, string_to_u8_array, u32_to_u8_array, u8_array_to_addr,
-        u8_array_to_string, u8_array_to_u32,
+        u8_array_to_string, u8_array_to_u32, unix_timestamp,
     },
 };
+use crc32fast::Hasher;
 use errors::{bail, AnyError};
 use serde::{Deserialize, Serialize};
 use std::{fmt::Display, net::SocketAddr};
@@ -12,32 +13,64 @@ use std::{fmt::Display, net::SocketAddr};
 // Protocol constants ----------------------------------------------------------
 
 const ORDER_SIZE: usize = 1; // u8
+const TAG_SIZE: usize = 1; // u8
 const INT_SIZE: usize = 4; // 4 u8
 const PEER_SIZE: usize = 4 + 4; // id(4) + str(4+)
 const STR_SIZE: usize = 4; // at least 4 bytes for the strlen
 const LIST_SIZE: usize = 4; // at least 4 bytes for a list length
 const BUFFER_SIZE: usize = 0; // at least 0 bytes for the buffer
 const ACK_SIZE: usize = 0; // acknowledge is empty
-const FILE_INFO_SIZE: usize = 4 + 4 + 4 + STR_SIZE; // 3*u32 + str(4+)
+const FILE_INFO_SIZE: usize = 4 + 4 + 4 + STR_SIZE + LIST_SIZE; // 3*u32 + str(4+) + chunk crcs(4+)
+const PUBLIC_KEY_SIZE: usize = 32;
+const HASH_SIZE: usize = 32;
+const SIGNATURE_SIZE: usize = 64;
+// publisher(4) + timestamp(4) + str(4+) + public_key(32) + signature(64)
+const STORED_VALUE_SIZE: usize = 4 + 4 + STR_SIZE + PUBLIC_KEY_SIZE + SIGNATURE_SIZE;
+// public_key(32) + salt str(4+) + seq(4) + value str(4+) + signature(64)
+const MUTABLE_VALUE_SIZE: usize = PUBLIC_KEY_SIZE + STR_SIZE + INT_SIZE + STR_SIZE + SIGNATURE_SIZE;
+// owner(8+) + announced_at(4) + public_key(32) + signature(64)
+const ANNOUNCEMENT_SIZE: usize = PEER_SIZE + INT_SIZE + PUBLIC_KEY_SIZE + SIGNATURE_SIZE;
+// public_key(32) + tag + recipient(4) + timestamp(4) + signature(64)
+const IDENTITY_SIZE: usize = PUBLIC_KEY_SIZE + TAG_SIZE + INT_SIZE + INT_SIZE + SIGNATURE_SIZE;
 
 const FILEINFO_REQUEST_SIZE: usize = INT_SIZE;
 const FILEINFO_RESPONSE_SIZE: usize = FILE_INFO_SIZE;
 const CHUNK_REQUEST_SIZE: usize = INT_SIZE + INT_SIZE;
 const CHUNK_RESPONSE_SIZE: usize = INT_SIZE + INT_SIZE + BUFFER_SIZE;
-const ANNOUNCE_REQUEST_SIZE: usize = PEER_SIZE + INT_SIZE;
+const ANNOUNCE_REQUEST_SIZE: usize = PEER_SIZE + INT_SIZE + INT_SIZE + ANNOUNCEMENT_SIZE;
 const ANNOUNCE_RESPONSE_SIZE: usize = ACK_SIZE;
 const GET_PEERS_REQUEST_SIZE: usize = INT_SIZE;
-const GET_PEERS_RESPONSE_SIZE: usize = LIST_SIZE;
+const GET_PEERS_RESPONSE_SIZE: usize = INT_SIZE + LIST_SIZE + LIST_SIZE; // token + owners(4+) + closest(4+)
 const PING_REQUEST_SIZE: usize = PEER_SIZE;
-const PING_RESPONSE_SIZE: usize = INT_SIZE;
+const PING_RESPONSE_SIZE: usize = INT_SIZE + STR_SIZE; // target + observed addr(4+)
 const FIND_NODE_REQUEST_SIZE: usize = PEER_SIZE + INT_SIZE;
-const FIND_NODE_RESPONSE_SIZE: usize = LIST_SIZE;
-const STORE_REQUEST_SIZE: usize = PEER_SIZE + INT_SIZE + STR_SIZE;
+const FIND_NODE_RESPONSE_SIZE: usize = INT_SIZE + LIST_SIZE;
+const STORE_REQUEST_SIZE: usize = PEER_SIZE + INT_SIZE + INT_SIZE + STORED_VALUE_SIZE;
 const STORE_RESPONSE_SIZE: usize = ACK_SIZE;
-const FIND_VALUE_REQUEST_SIZE: usize = PEER_SIZE + INT_SIZE;
-const FIND_VALUE_RESPONSE_SIZE: usize = STR_SIZE;
+const FIND_VALUE_REQUEST_SIZE: usize = PEER_SIZE + INT_SIZE + INT_SIZE;
+const FIND_VALUE_RESPONSE_SIZE: usize = TAG_SIZE + LIST_SIZE; // tag + list(4+)
 const MESSAGE_REQUEST_SIZE: usize = STR_SIZE;
 const MESSAGE_RESPONSE_SIZE: usize = ACK_SIZE;
+const PUT_MUTABLE_REQUEST_SIZE: usize = PEER_SIZE + MUTABLE_VALUE_SIZE;
+const PUT_MUTABLE_RESPONSE_SIZE: usize = ACK_SIZE;
+const GET_MUTABLE_REQUEST_SIZE: usize = PEER_SIZE + HASH_SIZE;
+const GET_MUTABLE_RESPONSE_SIZE: usize = TAG_SIZE + LIST_SIZE; // tag + value or list(4+)
+const SIGNED_REQUEST_SIZE: usize = IDENTITY_SIZE + ORDER_SIZE; // identity + request(1+)
+const RELAY_REQUEST_SIZE: usize = PEER_SIZE;
+const RELAY_RESPONSE_SIZE: usize = STR_SIZE; // relay addr(4+)
+const ONION_REQUEST_SIZE: usize = BUFFER_SIZE;
+const ONION_RESPONSE_SIZE: usize = ACK_SIZE;
+const PUBLIC_KEY_REQUEST_SIZE: usize = ACK_SIZE;
+const PUBLIC_KEY_RESPONSE_SIZE: usize = PUBLIC_KEY_SIZE;
+const PEER_KEY_REQUEST_SIZE: usize = INT_SIZE;
+const PUT_MAIL_REQUEST_SIZE: usize = PEER_SIZE + INT_SIZE + INT_SIZE + STORED_VALUE_SIZE;
+const PUT_MAIL_RESPONSE_SIZE: usize = ACK_SIZE;
+const GET_MAIL_REQUEST_SIZE: usize = PEER_SIZE + INT_SIZE;
+const GET_MAIL_RESPONSE_SIZE: usize = LIST_SIZE;
+const MAIL_CHALLENGE_REQUEST_SIZE: usize = INT_SIZE;
+const MAIL_CHALLENGE_RESPONSE_SIZE: usize = INT_SIZE;
+const ACK_MAIL_REQUEST_SIZE: usize = PEER_SIZE + LIST_SIZE;
+const ACK_MAIL_RESPONSE_SIZE: usize = ACK_SIZE;
 
 const MIN_PEER_SIZE: usize = ORDER_SIZE + PEER_SIZE;
 const MIN_FILEINFO_REQUEST_SIZE: usize = ORDER_SIZE + FILEINFO_REQUEST_SIZE;
@@ -58,6 +91,26 @@ const MIN_FIND_VALUE_REQUEST_SIZE: usize = ORDER_SIZE + FIND_VALUE_REQUEST_SIZE;
 const MIN_FIND_VALUE_RESPONSE_SIZE: usize = ORDER_SIZE + FIND_VALUE_RESPONSE_SIZE;
 const MIN_MESSAGE_REQUEST_SIZE: usize = ORDER_SIZE + MESSAGE_REQUEST_SIZE;
 const MIN_MESSAGE_RESPONSE_SIZE: usize = ORDER_SIZE + MESSAGE_RESPONSE_SIZE;
+const MIN_PUT_MUTABLE_REQUEST_SIZE: usize = ORDER_SIZE + PUT_MUTABLE_REQUEST_SIZE;
+const MIN_PUT_MUTABLE_RESPONSE_SIZE: usize = ORDER_SIZE + PUT_MUTABLE_RESPONSE_SIZE;
+const MIN_GET_MUTABLE_REQUEST_SIZE: usize = ORDER_SIZE + GET_MUTABLE_REQUEST_SIZE;
+const MIN_GET_MUTABLE_RESPONSE_SIZE: usize = ORDER_SIZE + GET_MUTABLE_RESPONSE_SIZE;
+const MIN_SIGNED_REQUEST_SIZE: usize = ORDER_SIZE + SIGNED_REQUEST_SIZE;
+const MIN_RELAY_REQUEST_SIZE: usize = ORDER_SIZE + RELAY_REQUEST_SIZE;
+const MIN_RELAY_RESPONSE_SIZE: usize = ORDER_SIZE + RELAY_RESPONSE_SIZE;
+const MIN_ONION_REQUEST_SIZE: usize = ORDER_SIZE + ONION_REQUEST_SIZE;
+const MIN_ONION_RESPONSE_SIZE: usize = ORDER_SIZE + ONION_RESPONSE_SIZE;
+const MIN_PUBLIC_KEY_REQUEST_SIZE: usize = ORDER_SIZE + PUBLIC_KEY_REQUEST_SIZE;
+const MIN_PUBLIC_KEY_RESPONSE_SIZE: usize = ORDER_SIZE + PUBLIC_KEY_RESPONSE_SIZE;
+const MIN_PEER_KEY_REQUEST_SIZE: usize = ORDER_SIZE + PEER_KEY_REQUEST_SIZE;
+const MIN_PUT_MAIL_REQUEST_SIZE: usize = ORDER_SIZE + PUT_MAIL_REQUEST_SIZE;
+const MIN_PUT_MAIL_RESPONSE_SIZE: usize = ORDER_SIZE + PUT_MAIL_RESPONSE_SIZE;
+const MIN_GET_MAIL_REQUEST_SIZE: usize = ORDER_SIZE + GET_MAIL_REQUEST_SIZE;
+const MIN_GET_MAIL_RESPONSE_SIZE: usize = ORDER_SIZE + GET_MAIL_RESPONSE_SIZE;
+const MIN_MAIL_CHALLENGE_REQUEST_SIZE: usize = ORDER_SIZE + MAIL_CHALLENGE_REQUEST_SIZE;
+const MIN_MAIL_CHALLENGE_RESPONSE_SIZE: usize = ORDER_SIZE + MAIL_CHALLENGE_RESPONSE_SIZE;
+const MIN_ACK_MAIL_REQUEST_SIZE: usize = ORDER_SIZE + ACK_MAIL_REQUEST_SIZE;
+const MIN_ACK_MAIL_RESPONSE_SIZE: usize = ORDER_SIZE + ACK_MAIL_RESPONSE_SIZE;
 
 // File protocol.
 const FILEINFO_REQUEST: u8 = 0x1;
@@ -78,9 +131,39 @@ const FIND_NODE_REQUEST: u8 = 0x9;
 const FIND_NODE_RESPONSE: u8 = 0xA;
 const FIND_VALUE_REQUEST: u8 = 0xB;
 const FIND_VALUE_RESPONSE: u8 = 0xC;
+const PUT_MUTABLE_REQUEST: u8 = 0x13;
+const PUT_MUTABLE_RESPONSE: u8 = 0x14;
+const GET_MUTABLE_REQUEST: u8 = 0x15;
+const GET_MUTABLE_RESPONSE: u8 = 0x16;
+// Find value, get mutable and get peers response tags.
+const VALUES_FOUND: u8 = 0x0;
+const CLOSEST_PEERS_FOUND: u8 = 0x1;
 // Message protocol.
 const MESSAGE_REQUEST: u8 = 0xD;
 const MESSAGE_RESPONSE: u8 = 0xE;
+// Identity protocol.
+const SIGNED_REQUEST: u8 = 0x17;
+// Identity recipient tags.
+const NO_RECIPIENT: u8 = 0x0;
+const RECIPIENT: u8 = 0x1;
+// Relay protocol.
+const RELAY_REQUEST: u8 = 0x18;
+const RELAY_RESPONSE: u8 = 0x19;
+// Onion protocol.
+const ONION_REQUEST: u8 = 0x1A;
+const ONION_RESPONSE: u8 = 0x1B;
+const PUBLIC_KEY_REQUEST: u8 = 0x1C;
+const PUBLIC_KEY_RESPONSE: u8 = 0x1D;
+const PEER_KEY_REQUEST: u8 = 0x22;
+// Mailbox protocol.
+const PUT_MAIL_REQUEST: u8 = 0x1E;
+const PUT_MAIL_RESPONSE: u8 = 0x1F;
+const GET_MAIL_REQUEST: u8 = 0x20;
+const GET_MAIL_RESPONSE: u8 = 0x21;
+const MAIL_CHALLENGE_REQUEST: u8 = 0x23;
+const MAIL_CHALLENGE_RESPONSE: u8 = 0x24;
+const ACK_MAIL_REQUEST: u8 = 0x25;
+const ACK_MAIL_RESPONSE: u8 = 0x26;
 
 const ERROR_OCCURED: u8 = 0x80;
 
@@ -97,24 +180,87 @@ pub enum Command {
     FileInfoResponse(FileInfo),
     ChunkRequest(u32 /*crc*/, u32 /*chunk_id*/),
     ChunkResponse(u32 /*crc*/, u32 /*chunk_id*/, Vec<u8> /*chunk*/),
-    AnnounceRequest(Peer /*sender*/, u32 /*crc*/),
+    AnnounceRequest(
+        Peer, /*sender*/
+        u32,  /*token*/
+        u32,  /*crc*/
+        Announcement,
+    ),
     AnnounceResponse(),
     GetPeersRequest(u32 /*crc*/),
-    GetPeersResponse(Vec<Peer> /*peers_found*/),
+    GetPeersResponse(u32 /*token*/, FoundPeers),
 
     // DHT protocol
     PingRequest(Peer /*sender*/),
-    PingResponse(u32 /*target*/),
+    PingResponse(u32 /*target*/, SocketAddr /*observed sender address*/),
     FindNodeRequest(Peer /*sender*/, u32 /*target*/),
-    FindNodeResponse(Vec<Peer> /*peers_found*/),
-    StoreRequest(Peer /*sender*/, u32 /*key*/, String /*message*/),
+    FindNodeResponse(u32 /*token*/, Vec<Peer> /*peers_found*/),
+    StoreRequest(
+        Peer,        /*sender*/
+        u32,         /*token*/
+        u32,         /*key*/
+        StoredValue, /*value*/
+    ),
     StoreResponse(),
-    FindValueRequest(Peer /*sender*/, u32 /*key*/),
-    FindValueResponse(String /*message*/),
+    FindValueRequest(Peer /*sender*/, u32 /*key*/, u32 /*page*/),
+    FindValueResponse(FoundValue),
+    PutMutableRequest(Peer /*sender*/, MutableValue /*value*/),
+    PutMutableResponse(),
+    GetMutableRequest(Peer /*sender*/, MutableHash /*hash*/),
+    GetMutableResponse(FoundMutable),
 
     // Message protocol
     MessageRequest(String /*message*/),
     MessageResponse(),
+
+    // Identity protocol
+    SignedRequest(Identity /*signer*/, Vec<u8> /*raw request*/),
+
+    // Relay protocol
+    RelayRequest(Peer /*sender*/),
+    RelayResponse(SocketAddr /*relay address*/),
+
+    // Onion protocol
+    OnionRequest(Vec<u8> /*onion*/),
+    OnionResponse(),
+    PublicKeyRequest(),
+    PublicKeyResponse(Vec<u8> /*public key*/),
+    PeerKeyRequest(u32 /*peer id*/),
+
+    // Mailbox protocol
+    PutMailRequest(
+        Peer,        /*sender*/
+        u32,         /*token*/
+        u32,         /*recipient*/
+        StoredValue, /*mail*/
+    ),
+    PutMailResponse(),
+    GetMailRequest(Peer /*sender*/, u32 /*challenge*/),
+    GetMailResponse(Vec<StoredValue> /*mails*/),
+    MailChallengeRequest(u32 /*recipient*/),
+    MailChallengeResponse(u32 /*challenge*/),
+    AckMailRequest(Peer /*sender*/, Vec<u32> /*mail ids*/),
+    AckMailResponse(),
+}
+
+impl Command {
+    // Get the peer a request claims to be sent by, if any.
+    pub fn sender(&self) -> Option<&Peer> {
+        match self {
+            Command::AnnounceRequest(sender, _, _, _)
+            | Command::PingRequest(sender)
+            | Command::FindNodeRequest(sender, _)
+            | Command::StoreRequest(sender, _, _, _)
+            | Command::FindValueRequest(sender, _, _)
+            | Command::PutMutableRequest(sender, _)
+            | Command::GetMutableRequest(sender, _)
+            | Command::RelayRequest(sender)
+            | Command::PutMailRequest(sender, _, _, _)
+            | Command::GetMailRequest(sender, _)
+            | Command::AckMailRequest(sender, _) => Some(sender),
+            _ => None,
+        }
+    }
 }
 
 // Convert a raw buffer into a command.
@@ -198,8 +344,14 @@ impl TryFrom<&[u8]> for Command {
 
                     let slice: [u8; 4] =
                         core::array::from_fn(|idx| value[idx + ORDER_SIZE + PEER_SIZE + shift]);
+                    let token = u8_array_to_u32(&slice);
+                    let slice: [u8; 4] =
+                        core::array::from_fn(|idx| value[idx + ORDER_SIZE + PEER_SIZE + shift + INT_SIZE]);
                     let crc = u8_array_to_u32(&slice);
-                    Self::AnnounceRequest(sender, crc)
+                    let announcement = Announcement::try_from(
+                        &value[ORDER_SIZE + PEER_SIZE + shift + INT_SIZE + INT_SIZE..],
+                    )?;
+                    Self::AnnounceRequest(sender, token, crc, announcement)
                 }
                 ANNOUNCE_RESPONSE => {
                     if value.len() < MIN_ANNOUNCE_RESPONSE_SIZE {
@@ -233,22 +385,17 @@ impl TryFrom<&[u8]> for Command {
                     }
 
                     let slice: [u8; 4] = core::array::from_fn(|idx| value[idx + ORDER_SIZE]);
-                    let list_size = u8_array_to_u32(&slice) as usize;
-                    let res = (0..list_size).try_fold(
-                        (Vec::<Peer>::new(), ORDER_SIZE + INT_SIZE),
-                        |(mut acc, shift), _| {
-                            let raw = &value[shift..];
-                            // size of addr is after id (in pos 4).
-                            let slice: [u8; 4] = core::array::from_fn(|idx| value[shift + idx + INT_SIZE]);
-                            let addr_size = u8_array_to_u32(&slice) as usize;
-
-                            let peer = Peer::try_from(raw)?;
-                            acc.push(peer);
-                            Ok::<(Vec<Peer>, usize), AnyError>((acc, shift + PEER_SIZE + addr_size))
+                    let token = u8_array_to_u32(&slice);
+                    let raw = &value[ORDER_SIZE + INT_SIZE..];
+                    let (owners, size) = u8_array_to_sized_peers(raw)?;
+                    let closest_peers = u8_array_to_peers(&raw[size..])?;
+                    Self::GetPeersResponse(
+                        token,
+                        FoundPeers {
+                            owners,
+                            closest_peers,
                         },
-                    )?;
-                    let (peers_list, _) = res;
-                    Self::GetPeersResponse(peers_list)
+                    )
                 }
                 FIND_NODE_REQUEST => {
                     if value.len() < MIN_FIND_NODE_REQUEST_SIZE {
@@ -278,22 +425,9 @@ impl TryFrom<&[u8]> for Command {
                     }
 
                     let slice: [u8; 4] = core::array::from_fn(|idx| value[idx + ORDER_SIZE]);
-                    let list_size = u8_array_to_u32(&slice) as usize;
-                    let res = (0..list_size).try_fold(
-                        (Vec::<Peer>::new(), ORDER_SIZE + INT_SIZE),
-                        |(mut acc, shift), _| {
-                            let raw = &value[shift..];
-                            // size of addr is after id (in pos 4).
-                            let slice: [u8; 4] = core::array::from_fn(|idx| value[shift + idx + INT_SIZE]);
-                            let addr_size = u8_array_to_u32(&slice) as usize;
-
-                            let peer = Peer::try_from(raw)?;
-                            acc.push(peer);
-                            Ok::<(Vec<Peer>, usize), AnyError>((acc, shift + PEER_SIZE + addr_size))
-                        },
-                    )?;
-                    let (peers_list, _) = res;
-                    Self::FindNodeResponse(peers_list)
+                    let token = u8_array_to_u32(&slice);
+                    let peers_list = u8_array_to_peers(&value[ORDER_SIZE + INT_SIZE..])?;
+                    Self::FindNodeResponse(token, peers_list)
                 }
                 PING_REQUEST => {
                     if value.len() < MIN_PING_REQUEST_SIZE {
@@ -317,7 +451,8 @@ impl TryFrom<&[u8]> for Command {
                     }
                     let slice: [u8; 4] = core::array::from_fn(|idx| value[idx + ORDER_SIZE]);
                     let crc = u8_array_to_u32(&slice);
-                    Self::PingResponse(crc)
+                    let observed = u8_array_to_addr(&value[ORDER_SIZE + INT_SIZE..])?;
+                    Self::PingResponse(crc, observed)
                 }
                 STORE_REQUEST => {
                     if value.len() < MIN_STORE_REQUEST_SIZE {
@@ -333,14 +468,14 @@ impl TryFrom<&[u8]> for Command {
 
                     let slice: [u8; 4] =
                         core::array::from_fn(|idx| value[idx + ORDER_SIZE + PEER_SIZE + shift]);
-                    let crc = u8_array_to_u32(&slice);
-                    let raw_str = value
-                        .iter()
-                        .skip(INT_SIZE + ORDER_SIZE + PEER_SIZE + shift)
-                        .map(|ch| *ch)
-                        .collect::<Vec<u8>>();
-                    let message = u8_array_to_string(raw_str.as_slice())?;
-                    Self::StoreRequest(sender, crc, message)
+                    let token = u8_array_to_u32(&slice);
+                    let slice: [u8; 4] =
+                        core::array::from_fn(|idx| value[idx + ORDER_SIZE + PEER_SIZE + shift + INT_SIZE]);
+                    let key = u8_array_to_u32(&slice);
+                    let stored_value = StoredValue::try_from(
+                        &value[ORDER_SIZE + PEER_SIZE + shift + INT_SIZE + INT_SIZE..],
+                    )?;
+                    Self::StoreRequest(sender, token, key, stored_value)
                 }
                 STORE_RESPONSE => {
                     if value.len() < MIN_STORE_RESPONSE_SIZE {
@@ -368,7 +503,10 @@ impl TryFrom<&[u8]> for Command {
                     let slice: [u8; 4] =
                         core::array::from_fn(|idx| value[idx + ORDER_SIZE + PEER_SIZE + shift]);
                     let key = u8_array_to_u32(&slice);
-                    Self::FindValueRequest(sender, key)
+                    let slice: [u8; 4] =
+                        core::array::from_fn(|idx| value[idx + ORDER_SIZE + PEER_SIZE + shift + INT_SIZE]);
+                    let page = u8_array_to_u32(&slice);
+                    Self::FindValueRequest(sender, key, page)
                 }
                 FIND_VALUE_RESPONSE => {
                     if value.len() < MIN_FIND_VALUE_RESPONSE_SIZE {
@@ -378,9 +516,22 @@ impl TryFrom<&[u8]> for Command {
                             MIN_FIND_VALUE_RESPONSE_SIZE
                         );
                     }
-                    let raw_str = value.iter().skip(ORDER_SIZE).map(|ch| *ch).collect::<Vec<u8>>();
-                    let message = u8_array_to_string(raw_str.as_slice())?;
-                    Self::FindValueResponse(message)
+                    let raw = &value[ORDER_SIZE + TAG_SIZE..];
+                    match value[ORDER_SIZE] {
+                        VALUES_FOUND => {
+                            if raw.len() < INT_SIZE + LIST_SIZE {
+                                bail!("can't decode find_value_response, missing values");
+                            }
+                            let slice: [u8; 4] = core::array::from_fn(|idx| raw[idx]);
+                            let nb_pages = u8_array_to_u32(&slice);
+                            let values = u8_array_to_stored_values(&raw[INT_SIZE..])?;
+                            Self::FindValueResponse(FoundValue::Values(values, nb_pages))
+                        }
+                        CLOSEST_PEERS_FOUND => {
+                            Self::FindValueResponse(FoundValue::ClosestPeers(u8_array_to_peers(raw)?))
+                        }
+                        tag => bail!("can't decode find_value_response, unknown tag {}", tag),
+                    }
                 }
                 MESSAGE_REQUEST => {
                     if value.len() < MIN_MESSAGE_REQUEST_SIZE {
@@ -404,6 +555,281 @@ impl TryFrom<&[u8]> for Command {
                     }
                     Self::MessageResponse()
                 }
+                PUT_MUTABLE_REQUEST => {
+                    if value.len() < MIN_PUT_MUTABLE_REQUEST_SIZE {
+                        bail!(
+                            "can't decode put_mutable_request, size too low ({} < {})",
+                            value.len(),
+                            MIN_PUT_MUTABLE_REQUEST_SIZE
+                        );
+                    }
+
+                    let raw = &value[ORDER_SIZE..];
+                    let sender = Peer::try_from(raw)?;
+                    let shift = sender.addr.to_string().len();
+
+                    let mutable_value = MutableValue::try_from(&value[ORDER_SIZE + PEER_SIZE + shift..])?;
+                    Self::PutMutableRequest(sender, mutable_value)
+                }
+                PUT_MUTABLE_RESPONSE => {
+                    if value.len() < MIN_PUT_MUTABLE_RESPONSE_SIZE {
+                        bail!(
+                            "can't decode put_mutable_response, size too low ({} < {})",
+                            value.len(),
+                            MIN_PUT_MUTABLE_RESPONSE_SIZE
+                        );
+                    }
+                    Self::PutMutableResponse()
+                }
+                GET_MUTABLE_REQUEST => {
+                    if value.len() < MIN_GET_MUTABLE_REQUEST_SIZE {
+                        bail!(
+                            "can't decode get_mutable_request, size too low ({} < {})",
+                            value.len(),
+                            MIN_GET_MUTABLE_REQUEST_SIZE
+                        );
+                    }
+
+                    let raw = &value[ORDER_SIZE..];
+                    let sender = Peer::try_from(raw)?;
+                    let shift = sender.addr.to_string().len();
+
+                    if value.len() < MIN_GET_MUTABLE_REQUEST_SIZE + shift {
+                        bail!("can't decode get_mutable_request, truncated hash");
+                    }
+                    let hash: MutableHash =
+                        core::array::from_fn(|idx| value[idx + ORDER_SIZE + PEER_SIZE + shift]);
+                    Self::GetMutableRequest(sender, hash)
+                }
+                GET_MUTABLE_RESPONSE => {
+                    if value.len() < MIN_GET_MUTABLE_RESPONSE_SIZE {
+                        bail!(
+                            "can't decode get_mutable_response, size too low ({} < {})",
+                            value.len(),
+                            MIN_GET_MUTABLE_RESPONSE_SIZE
+                        );
+                    }
+                    let raw = &value[ORDER_SIZE + TAG_SIZE..];
+                    match value[ORDER_SIZE] {
+                        VALUES_FOUND => {
+                            Self::GetMutableResponse(FoundMutable::Value(MutableValue::try_from(raw)?))
+                        }
+                        CLOSEST_PEERS_FOUND => {
+                            Self::GetMutableResponse(FoundMutable::ClosestPeers(u8_array_to_peers(raw)?))
+                        }
+                        tag => bail!("can't decode get_mutable_response, unknown tag {}", tag),
+                    }
+                }
+
+                SIGNED_REQUEST => {
+                    if value.len() < MIN_SIGNED_REQUEST_SIZE {
+                        bail!(
+                            "can't decode signed_request, size too low ({} < {})",
+                            value.len(),
+                            MIN_SIGNED_REQUEST_SIZE
+                        );
+                    }
+
+                    let identity = Identity::try_from(&value[ORDER_SIZE..])?;
+                    let request = value[ORDER_SIZE + IDENTITY_SIZE..].to_vec();
+                    Self::SignedRequest(identity, request)
+                }
+
+                RELAY_REQUEST => {
+                    if value.len() < MIN_RELAY_REQUEST_SIZE {
+                        bail!(
+                            "can't decode relay_request, size too low ({} < {})",
+                            value.len(),
+                            MIN_RELAY_REQUEST_SIZE
+                        );
+                    }
+                    let raw = &value[ORDER_SIZE..];
+                    let sender = Peer::try_from(raw)?;
+                    Self::RelayRequest(sender)
+                }
+                RELAY_RESPONSE => {
+                    if value.len() < MIN_RELAY_RESPONSE_SIZE {
+                        bail!(
+                            "can't decode relay_response, size too low ({} < {})",
+                            value.len(),
+                            MIN_RELAY_RESPONSE_SIZE
+                        );
+                    }
+                    let relay_addr = u8_array_to_addr(&value[ORDER_SIZE..])?;
+                    Self::RelayResponse(relay_addr)
+                }
+
+                ONION_REQUEST => {
+                    if value.len() < MIN_ONION_REQUEST_SIZE {
+                        bail!(
+                            "can't decode onion_request, size too low ({} < {})",
+                            value.len(),
+                            MIN_ONION_REQUEST_SIZE
+                        );
+                    }
+                    Self::OnionRequest(value[ORDER_SIZE..].to_vec())
+                }
+                ONION_RESPONSE => {
+                    if value.len() < MIN_ONION_RESPONSE_SIZE {
+                        bail!(
+                            "can't decode onion_response, size too low ({} < {})",
+                            value.len(),
+                            MIN_ONION_RESPONSE_SIZE
+                        );
+                    }
+                    Self::OnionResponse()
+                }
+                PUBLIC_KEY_REQUEST => {
+                    if value.len() < MIN_PUBLIC_KEY_REQUEST_SIZE {
+                        bail!(
+                            "can't decode public_key_request, size too low ({} < {})",
+                            value.len(),
+                            MIN_PUBLIC_KEY_REQUEST_SIZE
+                        );
+                    }
+                    Self::PublicKeyRequest()
+                }
+                PUBLIC_KEY_RESPONSE => {
+                    if value.len() < MIN_PUBLIC_KEY_RESPONSE_SIZE {
+                        bail!(
+                            "can't decode public_key_response, size too low ({} < {})",
+                            value.len(),
+                            MIN_PUBLIC_KEY_RESPONSE_SIZE
+                        );
+                    }
+                    Self::PublicKeyResponse(value[ORDER_SIZE..MIN_PUBLIC_KEY_RESPONSE_SIZE].to_vec())
+                }
+                PEER_KEY_REQUEST => {
+                    if value.len() < MIN_PEER_KEY_REQUEST_SIZE {
+                        bail!(
+                            "can't decode peer_key_request, size too low ({} < {})",
+                            value.len(),
+                            MIN_PEER_KEY_REQUEST_SIZE
+                        );
+                    }
+                    let slice: [u8; 4] = core::array::from_fn(|idx| value[idx + ORDER_SIZE]);
+                    Self::PeerKeyRequest(u8_array_to_u32(&slice))
+                }
+
+                PUT_MAIL_REQUEST => {
+                    if value.len() < MIN_PUT_MAIL_REQUEST_SIZE {
+                        bail!(
+                            "can't decode put_mail_request, size too low ({} < {})",
+                            value.len(),
+                            MIN_PUT_MAIL_REQUEST_SIZE
+                        );
+                    }
+                    let raw = &value[ORDER_SIZE..];
+                    let sender = Peer::try_from(raw)?;
+                    let shift = sender.addr.to_string().len();
+
+                    let slice: [u8; 4] =
+                        core::array::from_fn(|idx| value[idx + ORDER_SIZE + PEER_SIZE + shift]);
+                    let token = u8_array_to_u32(&slice);
+                    let slice: [u8; 4] =
+                        core::array::from_fn(|idx| value[idx + ORDER_SIZE + PEER_SIZE + shift + INT_SIZE]);
+                    let recipient = u8_array_to_u32(&slice);
+                    let mail = StoredValue::try_from(
+                        &value[ORDER_SIZE + PEER_SIZE + shift + INT_SIZE + INT_SIZE..],
+                    )?;
+                    Self::PutMailRequest(sender, token, recipient, mail)
+                }
+                PUT_MAIL_RESPONSE => {
+                    if value.len() < MIN_PUT_MAIL_RESPONSE_SIZE {
+                        bail!(
+                            "can't decode put_mail_response, size too low ({} < {})",
+                            value.len(),
+                            MIN_PUT_MAIL_RESPONSE_SIZE
+                        );
+                    }
+                    Self::PutMailResponse()
+                }
+                GET_MAIL_REQUEST => {
+                    if value.len() < MIN_GET_MAIL_REQUEST_SIZE {
+                        bail!(
+                            "can't decode get_mail_request, size too low ({} < {})",
+                            value.len(),
+                            MIN_GET_MAIL_REQUEST_SIZE
+                        );
+                    }
+                    let raw = &value[ORDER_SIZE..];
+                    let sender = Peer::try_from(raw)?;
+                    let shift = ORDER_SIZE + PEER_SIZE + sender.addr.to_string().len();
+                    if value.len() < shift + INT_SIZE {
+                        bail!("can't decode get_mail_request, truncated challenge");
+                    }
+                    let slice: [u8; 4] = core::array::from_fn(|idx| value[idx + shift]);
+                    Self::GetMailRequest(sender, u8_array_to_u32(&slice))
+                }
+                GET_MAIL_RESPONSE => {
+                    if value.len() < MIN_GET_MAIL_RESPONSE_SIZE {
+                        bail!(
+                            "can't decode get_mail_response, size too low ({} < {})",
+                            value.len(),
+                            MIN_GET_MAIL_RESPONSE_SIZE
+                        );
+                    }
+                    let mails = u8_array_to_stored_values(&value[ORDER_SIZE..])?;
+                    Self::GetMailResponse(mails)
+                }
+                MAIL_CHALLENGE_REQUEST => {
+                    if value.len() < MIN_MAIL_CHALLENGE_REQUEST_SIZE {
+                        bail!(
+                            "can't decode mail_challenge_request, size too low ({} < {})",
+                            value.len(),
+                            MIN_MAIL_CHALLENGE_REQUEST_SIZE
+                        );
+                    }
+                    let slice: [u8; 4] = core::array::from_fn(|idx| value[idx + ORDER_SIZE]);
+                    Self::MailChallengeRequest(u8_array_to_u32(&slice))
+                }
+                MAIL_CHALLENGE_RESPONSE => {
+                    if value.len() < MIN_MAIL_CHALLENGE_RESPONSE_SIZE {
+                        bail!(
+                            "can't decode mail_challenge_response, size too low ({} < {})",
+                            value.len(),
+                            MIN_MAIL_CHALLENGE_RESPONSE_SIZE
+                        );
+                    }
+                    let slice: [u8; 4] = core::array::from_fn(|idx| value[idx + ORDER_SIZE]);
+                    Self::MailChallengeResponse(u8_array_to_u32(&slice))
+                }
+                ACK_MAIL_REQUEST => {
+                    if value.len() < MIN_ACK_MAIL_REQUEST_SIZE {
+                        bail!(
+                            "can't decode ack_mail_request, size too low ({} < {})",
+                            value.len(),
+                            MIN_ACK_MAIL_REQUEST_SIZE
+                        );
+                    }
+                    let raw = &value[ORDER_SIZE..];
+                    let sender = Peer::try_from(raw)?;
+                    let shift = ORDER_SIZE + PEER_SIZE + sender.addr.to_string().len();
+                    if value.len() < shift + LIST_SIZE {
+                        bail!("can't decode ack_mail_request, truncated mail ids");
+                    }
+                    let slice: [u8; 4] = core::array::from_fn(|idx| value[idx + shift]);
+                    let nb_ids = u8_array_to_u32(&slice) as usize;
+                    let shift = shift + LIST_SIZE;
+                    if (value.len() - shift) / INT_SIZE < nb_ids {
+                        bail!("can't decode ack_mail_request, truncated mail ids");
+                    }
+                    let ids = value[shift..shift + nb_ids * INT_SIZE]
+                        .chunks(INT_SIZE)
+                        .map(|raw| u8_array_to_u32(&core::array::from_fn(|i| raw[i])))
+                        .collect();
+                    Self::AckMailRequest(sender, ids)
+                }
+                ACK_MAIL_RESPONSE => {
+                    if value.len() < MIN_ACK_MAIL_RESPONSE_SIZE {
+                        bail!(
+                            "can't decode ack_mail_response, size too low ({} < {})",
+                            value.len(),
+                            MIN_ACK_MAIL_RESPONSE_SIZE
+                        );
+                    }
+                    Self::AckMailResponse()
+                }
 
                 // Errors
                 error if error >= ERROR_OCCURED => Self::ErrorOccured((error - ERROR_OCCURED).into()),
@@ -444,10 +870,12 @@ impl From<Command> for Vec<u8> {
                 res.extend(chunk_buf);
                 res
             }
-            Command::AnnounceRequest(sender, crc) => {
+            Command::AnnounceRequest(sender, token, crc, announcement) => {
                 let mut res = vec![ANNOUNCE_REQUEST];
                 res.extend(Vec::<u8>::from(sender));
+                res.extend(u32_to_u8_array(token));
                 res.extend(u32_to_u8_array(crc));
+                res.extend(Vec::<u8>::from(announcement));
                 res
             }
             Command::AnnounceResponse() => {
@@ -458,14 +886,12 @@ impl From<Command> for Vec<u8> {
                 res.extend(u32_to_u8_array(crc));
                 res
             }
-            Command::GetPeersResponse(peers_found) => {
+            Command::GetPeersResponse(token, found) => {
                 let mut res = vec![GET_PEERS_RESPONSE];
-                res.extend(u32_to_u8_array(peers_found.len() as u32));
-                peers_found.into_iter().fold(res, |mut acc, peer| {
-                    let raw: Vec<u8> = peer.into();
-                    acc.extend(raw);
-                    acc
-                })
+                res.extend(u32_to_u8_array(token));
+                res.extend(peers_to_u8_array(found.owners));
+                res.extend(peers_to_u8_array(found.closest_peers));
+                res
             }
             Command::FindNodeRequest(sender, target) => {
                 let mut res = vec![FIND_NODE_REQUEST];
@@ -473,44 +899,54 @@ impl From<Command> for Vec<u8> {
                 res.extend(u32_to_u8_array(target));
                 res
             }
-            Command::FindNodeResponse(peers_found) => {
+            Command::FindNodeResponse(token, peers_found) => {
                 let mut res = vec![FIND_NODE_RESPONSE];
-                res.extend(u32_to_u8_array(peers_found.len() as u32));
-                peers_found.into_iter().fold(res, |mut acc, peer| {
-                    let raw: Vec<u8> = peer.into();
-                    acc.extend(raw);
-                    acc
-                })
+                res.extend(u32_to_u8_array(token));
+                res.extend(peers_to_u8_array(peers_found));
+                res
             }
             Command::PingRequest(sender) => {
                 let mut res = vec![PING_REQUEST];
                 res.extend(Vec::<u8>::from(sender));
                 res
             }
-            Command::PingResponse(crc) => {
+            Command::PingResponse(crc, observed) => {
                 let mut res = vec![PING_RESPONSE];
                 res.extend(u32_to_u8_array(crc));
+                res.extend(addr_to_u8_array(observed));
                 res
             }
-            Command::StoreRequest(sender, key, message) => {
+            Command::StoreRequest(sender, token, key, stored_value) => {
                 let mut res = vec![STORE_REQUEST];
                 res.extend(Vec::<u8>::from(sender));
+                res.extend(u32_to_u8_array(token));
                 res.extend(u32_to_u8_array(key));
-                res.extend(string_to_u8_array(message));
+                res.extend(Vec::<u8>::from(stored_value));
                 res
             }
             Command::StoreResponse() => {
                 vec![STORE_RESPONSE]
             }
-            Command::FindValueRequest(sender, key) => {
+            Command::FindValueRequest(sender, key, page) => {
                 let mut res = vec![FIND_VALUE_REQUEST];
                 res.extend(Vec::<u8>::from(sender));
                 res.extend(u32_to_u8_array(key));
+                res.extend(u32_to_u8_array(page));
                 res
             }
-            Command::FindValueResponse(message) => {
-                let mut res = vec![FIND_VALUE_RESPONSE];
-                res.extend(string_to_u8_array(message));
+            Command::FindValueResponse(FoundValue::Values(values, nb_pages)) => {
+                let mut res = vec![FIND_VALUE_RESPONSE, VALUES_FOUND];
+                res.extend(u32_to_u8_array(nb_pages));
+                res.extend(u32_to_u8_array(values.len() as u32));
+                values.into_iter().fold(res, |mut acc, stored_value| {
+                    let raw: Vec<u8> = stored_value.into();
+                    acc.extend(raw);
+                    acc
+                })
+            }
+            Command::FindValueResponse(FoundValue::ClosestPeers(peers_found)) => {
+                let mut res = vec![FIND_VALUE_RESPONSE, CLOSEST_PEERS_FOUND];
+                res.extend(peers_to_u8_array(peers_found));
                 res
             }
             Command::MessageRequest(message) => {
@@ -521,6 +957,120 @@ impl From<Command> for Vec<u8> {
             Command::MessageResponse() => {
                 vec![MESSAGE_RESPONSE]
             }
+            Command::PutMutableRequest(sender, mutable_value) => {
+                let mut res = vec![PUT_MUTABLE_REQUEST];
+                res.extend(Vec::<u8>::from(sender));
+                res.extend(Vec::<u8>::from(mutable_value));
+                res
+            }
+            Command::PutMutableResponse() => {
+                vec![PUT_MUTABLE_RESPONSE]
+            }
+            Command::GetMutableRequest(sender, hash) => {
+                let mut res = vec![GET_MUTABLE_REQUEST];
+                res.extend(Vec::<u8>::from(sender));
+                res.extend(hash);
+                res
+            }
+            Command::GetMutableResponse(FoundMutable::Value(mutable_value)) => {
+                let mut res = vec![GET_MUTABLE_RESPONSE, VALUES_FOUND];
+                res.extend(Vec::<u8>::from(mutable_value));
+                res
+            }
+            Command::GetMutableResponse(FoundMutable::ClosestPeers(peers_found)) => {
+                let mut res = vec![GET_MUTABLE_RESPONSE, CLOSEST_PEERS_FOUND];
+                res.extend(peers_to_u8_array(peers_found));
+                res
+            }
+
+            Command::SignedRequest(identity, request) => {
+                let mut res = vec![SIGNED_REQUEST];
+                res.extend(Vec::<u8>::from(identity));
+                res.extend(request);
+                res
+            }
+
+            Command::RelayRequest(sender) => {
+                let mut res = vec![RELAY_REQUEST];
+                res.extend(Vec::<u8>::from(sender));
+                res
+            }
+            Command::RelayResponse(relay_addr) => {
+                let mut res = vec![RELAY_RESPONSE];
+                res.extend(addr_to_u8_array(relay_addr));
+                res
+            }
+
+            Command::OnionRequest(onion) => {
+                let mut res = vec![ONION_REQUEST];
+                res.extend(onion);
+                res
+            }
+            Command::OnionResponse() => {
+                vec![ONION_RESPONSE]
+            }
+            Command::PublicKeyRequest() => {
+                vec![PUBLIC_KEY_REQUEST]
+            }
+            Command::PublicKeyResponse(public_key) => {
+                let mut res = vec![PUBLIC_KEY_RESPONSE];
+                res.extend(public_key);
+                res
+            }
+            Command::PeerKeyRequest(peer_id) => {
+                let mut res = vec![PEER_KEY_REQUEST];
+                res.extend(u32_to_u8_array(peer_id));
+                res
+            }
+
+            Command::PutMailRequest(sender, token, recipient, mail) => {
+                let mut res = vec![PUT_MAIL_REQUEST];
+                res.extend(Vec::<u8>::from(sender));
+                res.extend(u32_to_u8_array(token));
+                res.extend(u32_to_u8_array(recipient));
+                res.extend(Vec::<u8>::from(mail));
+                res
+            }
+            Command::PutMailResponse() => {
+                vec![PUT_MAIL_RESPONSE]
+            }
+            Command::GetMailRequest(sender, challenge) => {
+                let mut res = vec![GET_MAIL_REQUEST];
+                res.extend(Vec::<u8>::from(sender));
+                res.extend(u32_to_u8_array(challenge));
+                res
+            }
+            Command::GetMailResponse(mails) => {
+                let mut res = vec![GET_MAIL_RESPONSE];
+                res.extend(u32_to_u8_array(mails.len() as u32));
+                mails.into_iter().fold(res, |mut acc, mail| {
+                    let raw: Vec<u8> = mail.into();
+                    acc.extend(raw);
+                    acc
+                })
+            }
+            Command::MailChallengeRequest(recipient) => {
+                let mut res = vec![MAIL_CHALLENGE_REQUEST];
+                res.extend(u32_to_u8_array(recipient));
+                res
+            }
+            Command::MailChallengeResponse(challenge) => {
+                let mut res = vec![MAIL_CHALLENGE_RESPONSE];
+                res.extend(u32_to_u8_array(challenge));
+                res
+            }
+            Command::AckMailRequest(sender, ids) => {
+                let mut res = vec![ACK_MAIL_REQUEST];
+                res.extend(Vec::<u8>::from(sender));
+                res.extend(u32_to_u8_array(ids.len() as u32));
+                for id in ids {
+                    res.extend(u32_to_u8_array(id));
+                }
+                res
+            }
+            Command::AckMailResponse() => {
+                vec![ACK_MAIL_RESPONSE]
+            }
 
             // Errors
             Command::ErrorOccured(error) => vec![ERROR_OCCURED + error as u8],
@@ -537,12 +1087,31 @@ pub struct FileInfo {
     pub chunk_size: u32,
     pub file_crc: u32,
     pub original_filename: String,
+    // Crc of each chunk, to check them one by one as they're downloaded.
+    pub chunk_crcs: Vec<u32>,
 }
 
 impl FileInfo {
     pub fn nb_chunks(&self) -> u32 {
         div_ceil(self.file_size, self.chunk_size)
     }
+
+    // Check there's a crc for each chunk, and that together they give the crc
+    // of the whole file, which identifies it. A peer can't give false chunk
+    // crcs without changing the file.
+    pub fn has_valid_chunk_crcs(&self) -> bool {
+        if self.chunk_size == 0 || self.chunk_crcs.len() != self.nb_chunks() as usize {
+            return false;
+        }
+        let mut hasher = Hasher::new();
+        let mut remaining = self.file_size;
+        for crc in &self.chunk_crcs {
+            let len = remaining.min(self.chunk_size);
+            hasher.combine(&Hasher::new_with_initial_len(*crc, len.into()));
+            remaining -= len;
+        }
+        hasher.finalize() == self.file_crc
+    }
 }
 
 // Convert a raw buffer into a command.
@@ -568,11 +1137,27 @@ impl TryFrom<&[u8]> for FileInfo {
         let raw_str = value.iter().skip(4 + 4 + 4).map(|ch| *ch).collect::<Vec<u8>>();
         let original_filename = u8_array_to_string(raw_str.as_slice())?;
 
+        let shift = 4 + 4 + 4 + STR_SIZE + original_filename.len();
+        if value.len() < shift + LIST_SIZE {
+            bail!("can't decode file_info, truncated chunk crcs");
+        }
+        let slice: [u8; 4] = core::array::from_fn(|i| value[shift + i]);
+        let nb_chunks = u8_array_to_u32(&slice) as usize;
+        let shift = shift + LIST_SIZE;
+        if (value.len() - shift) / INT_SIZE < nb_chunks {
+            bail!("can't decode file_info, truncated chunk crcs");
+        }
+        let chunk_crcs = value[shift..shift + nb_chunks * INT_SIZE]
+            .chunks(INT_SIZE)
+            .map(|raw| u8_array_to_u32(&core::array::from_fn(|i| raw[i])))
+            .collect();
+
         Ok(Self {
             file_size,
             chunk_size,
             file_crc,
             original_filename,
+            chunk_crcs,
         })
     }
 }
@@ -584,6 +1169,10 @@ impl From<FileInfo> for Vec<u8> {
         res.extend(u32_to_u8_array(value.chunk_size));
         res.extend(u32_to_u8_array(value.file_crc));
         res.extend(string_to_u8_array(value.original_filename));
+        res.extend(u32_to_u8_array(value.chunk_crcs.len() as u32));
+        for crc in value.chunk_crcs {
+            res.extend(u32_to_u8_array(crc));
+        }
         res
     }
 }
@@ -638,6 +1227,371 @@ impl From<PeerNode> for Peer {
     }
 }
 
+// Decode a list of peers, prefixed by its length.
+fn u8_array_to_peers(value: &[u8]) -> Result<Vec<Peer>, AnyError> {
+    Ok(u8_array_to_sized_peers(value)?.0)
+}
+
+// Decode a list of peers, prefixed by its length, along with the number of
+// bytes it takes, so what follows it can be decoded too.
+fn u8_array_to_sized_peers(value: &[u8]) -> Result<(Vec<Peer>, usize), AnyError> {
+    if value.len() < LIST_SIZE {
+        bail!(
+            "can't decode peer list, size too low ({} < {})",
+            value.len(),
+            LIST_SIZE
+        );
+    }
+
+    let slice: [u8; 4] = core::array::from_fn(|idx| value[idx]);
+    let list_size = u8_array_to_u32(&slice) as usize;
+    let (peers_list, size) =
+        (0..list_size).try_fold((Vec::<Peer>::new(), LIST_SIZE), |(mut acc, shift), _| {
+            if value.len() < shift + PEER_SIZE {
+                bail!("can't decode peer list, truncated peer");
+            }
+            let raw = &value[shift..];
+            // size of addr is after id (in pos 4).
+            let slice: [u8; 4] = core::array::from_fn(|idx| value[shift + idx + INT_SIZE]);
+            let addr_size = u8_array_to_u32(&slice) as usize;
+
+            let peer = Peer::try_from(raw)?;
+            acc.push(peer);
+            Ok::<(Vec<Peer>, usize), AnyError>((acc, shift + PEER_SIZE + addr_size))
+        })?;
+    if value.len() < size {
+        bail!("can't decode peer list, truncated peer");
+    }
+    Ok((peers_list, size))
+}
+
+// Encode a list of peers, prefixed by its length.
+fn peers_to_u8_array(peers: Vec<Peer>) -> Vec<u8> {
+    let res = u32_to_u8_array(peers.len() as u32).to_vec();
+    peers.into_iter().fold(res, |mut acc, peer| {
+        let raw: Vec<u8> = peer.into();
+        acc.extend(raw);
+        acc
+    })
+}
+
+// StoredValue -----------------------------------------------------------------
+
+// A value stored in the dht, along with the peer who published it, and when
+// (seconds since epoch). The publisher signs the value with its key, so the
+// value can be handed over by other peers.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StoredValue {
+    pub publisher: u32,
+    pub timestamp: u32,
+    pub value: String,
+    #[serde(default)]
+    pub public_key: Vec<u8>,
+    #[serde(default)]
+    pub signature: Vec<u8>,
+}
+
+// Convert a raw buffer into a stored value.
+impl TryFrom<&[u8]> for StoredValue {
+    type Error = AnyError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        if value.len() < STORED_VALUE_SIZE {
+            bail!(
+                "can't decode stored value, size too low ({} < {})",
+                value.len(),
+                STORED_VALUE_SIZE
+            );
+        }
+
+        let slice: [u8; 4] = core::array::from_fn(|i| value[i]);
+        let publisher = u8_array_to_u32(&slice);
+        let slice: [u8; 4] = core::array::from_fn(|i| value[i + 4]);
+        let timestamp = u8_array_to_u32(&slice);
+        let stored_value = u8_array_to_string(&value[4 + 4..])?;
+        let shift = 4 + 4 + STR_SIZE + stored_value.len();
+
+        if value.len() < shift + PUBLIC_KEY_SIZE + SIGNATURE_SIZE {
+            bail!("can't decode stored value, truncated signature");
+        }
+        let public_key = value[shift..shift + PUBLIC_KEY_SIZE].to_vec();
+        let shift = shift + PUBLIC_KEY_SIZE;
+        let signature = value[shift..shift + SIGNATURE_SIZE].to_vec();
+
+        Ok(Self {
+            publisher,
+            timestamp,
+            value: stored_value,
+            public_key,
+            signature,
+        })
+    }
+}
+
+impl From<StoredValue> for Vec<u8> {
+    fn from(value: StoredValue) -> Self {
+        let mut res = Vec::with_capacity(STORED_VALUE_SIZE + value.value.len());
+        res.extend(u32_to_u8_array(value.publisher));
+        res.extend(u32_to_u8_array(value.timestamp));
+        res.extend(string_to_u8_array(value.value));
+        res.extend(value.public_key);
+        res.extend(value.signature);
+        res
+    }
+}
+
+// Decode a list of stored values, prefixed by its length.
+fn u8_array_to_stored_values(value: &[u8]) -> Result<Vec<StoredValue>, AnyError> {
+    if value.len() < LIST_SIZE {
+        bail!(
+            "can't decode value list, size too low ({} < {})",
+            value.len(),
+            LIST_SIZE
+        );
+    }
+
+    let slice: [u8; 4] = core::array::from_fn(|idx| value[idx]);
+    let list_size = u8_array_to_u32(&slice) as usize;
+    let (values, _) =
+        (0..list_size).try_fold((Vec::<StoredValue>::new(), LIST_SIZE), |(mut acc, shift), _| {
+            if value.len() < shift + STORED_VALUE_SIZE {
+                bail!("can't decode value list, truncated value");
+            }
+            let stored_value = StoredValue::try_from(&value[shift..])?;
+            let next_shift = shift + STORED_VALUE_SIZE + stored_value.value.len();
+            acc.push(stored_value);
+            Ok::<(Vec<StoredValue>, usize), AnyError>((acc, next_shift))
+        })?;
+    Ok(values)
+}
+
+// FoundValue ------------------------------------------------------------------
+
+// Answer to a find value: either a page of all the values stored for the key,
+// or the closest peers the responder knows from the key, so the lookup can go
+// on.
+#[derive(Debug, Eq, PartialEq)]
+pub enum FoundValue {
+    Values(Vec<StoredValue> /*page*/, u32 /*nb_pages*/),
+    ClosestPeers(Vec<Peer>),
+}
+
+// MutableValue ----------------------------------------------------------------
+
+// A value only its owner can update. It's identified by the owner public key
+// and a salt, so an owner can hold many of them. Each update must increase the
+// sequence number, and is signed by the owner.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MutableValue {
+    pub public_key: Vec<u8>,
+    pub salt: String,
+    pub seq: u32,
+    pub value: String,
+    pub signature: Vec<u8>,
+}
+
+// Convert a raw buffer into a mutable value.
+impl TryFrom<&[u8]> for MutableValue {
+    type Error = AnyError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        if value.len() < MUTABLE_VALUE_SIZE {
+            bail!(
+                "can't decode mutable value, size too low ({} < {})",
+                value.len(),
+                MUTABLE_VALUE_SIZE
+            );
+        }
+
+        let public_key = value[..PUBLIC_KEY_SIZE].to_vec();
+        let salt = u8_array_to_string(&value[PUBLIC_KEY_SIZE..])?;
+        let shift = PUBLIC_KEY_SIZE + STR_SIZE + salt.len();
+
+        if value.len() < shift + INT_SIZE + STR_SIZE {
+            bail!("can't decode mutable value, truncated seq");
+        }
+        let slice: [u8; 4] = core::array::from_fn(|i| value[shift + i]);
+        let seq = u8_array_to_u32(&slice);
+        let mutable_value = u8_array_to_string(&value[shift + INT_SIZE..])?;
+        let shift = shift + INT_SIZE + STR_SIZE + mutable_value.len();
+
+        if value.len() < shift + SIGNATURE_SIZE {
+            bail!("can't decode mutable value, truncated signature");
+        }
+        let signature = value[shift..shift + SIGNATURE_SIZE].to_vec();
+
+        Ok(Self {
+            public_key,
+            salt,
+            seq,
+            value: mutable_value,
+            signature,
+        })
+    }
+}
+
+impl From<MutableValue> for Vec<u8> {
+    fn from(value: MutableValue) -> Self {
+        let mut res = Vec::with_capacity(MUTABLE_VALUE_SIZE + value.salt.len() + value.value.len());
+        res.extend(value.public_key);
+        res.extend(string_to_u8_array(value.salt));
+        res.extend(u32_to_u8_array(value.seq));
+        res.extend(string_to_u8_array(value.value));
+        res.extend(value.signature);
+        res
+    }
+}
+
+// Announcement ----------------------------------------------------------------
+
+// A file owner, with the time it announced the file, signed by this owner so
+// other peers can announce it on its behalf. Owners saved without signature are
+// kept, but never announced further. Files saved without time are considered as
+// just announced.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Announcement {
+    #[serde(flatten)]
+    pub owner: Peer,
+    #[serde(default = "unix_timestamp")]
+    pub announced_at: u32,
+    #[serde(default)]
+    pub public_key: Vec<u8>,
+    #[serde(default)]
+    pub signature: Vec<u8>,
+}
+
+// Convert a raw buffer into an announcement.
+impl TryFrom<&[u8]> for Announcement {
+    type Error = AnyError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        if value.len() < ANNOUNCEMENT_SIZE {
+            bail!(
+                "can't decode announcement, size too low ({} < {})",
+                value.len(),
+                ANNOUNCEMENT_SIZE
+            );
+        }
+
+        let owner = Peer::try_from(value)?;
+        let shift = PEER_SIZE + owner.addr.to_string().len();
+        if value.len() < shift + INT_SIZE + PUBLIC_KEY_SIZE + SIGNATURE_SIZE {
+            bail!("can't decode announcement, truncated signature");
+        }
+        let slice: [u8; 4] = core::array::from_fn(|i| value[shift + i]);
+        let announced_at = u8_array_to_u32(&slice);
+        let shift = shift + INT_SIZE;
+        let public_key = value[shift..shift + PUBLIC_KEY_SIZE].to_vec();
+        let shift = shift + PUBLIC_KEY_SIZE;
+        let signature = value[shift..shift + SIGNATURE_SIZE].to_vec();
+
+        Ok(Self {
+            owner,
+            announced_at,
+            public_key,
+            signature,
+        })
+    }
+}
+
+impl From<Announcement> for Vec<u8> {
+    fn from(announcement: Announcement) -> Self {
+        let mut res = Vec::with_capacity(ANNOUNCEMENT_SIZE);
+        res.extend(Vec::<u8>::from(announcement.owner));
+        res.extend(u32_to_u8_array(announcement.announced_at));
+        res.extend(announcement.public_key);
+        res.extend(announcement.signature);
+        res
+    }
+}
+
+// Identity --------------------------------------------------------------------
+
+// Proof that a request has been sent by the owner of a public key, to a given
+// peer, at a given time (seconds since epoch). The id of a node is derived from
+// its public key, so it can't be claimed by anyone else. The recipient is
+// unknown when pinging an address.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Identity {
+    pub public_key: Vec<u8>,
+    pub recipient: Option<u32>,
+    pub timestamp: u32,
+    pub signature: Vec<u8>,
+}
+
+// Convert a raw buffer into an identity.
+impl TryFrom<&[u8]> for Identity {
+    type Error = AnyError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        if value.len() < IDENTITY_SIZE {
+            bail!(
+                "can't decode identity, size too low ({} < {})",
+                value.len(),
+                IDENTITY_SIZE
+            );
+        }
+
+        let shift = PUBLIC_KEY_SIZE;
+        let slice: [u8; 4] = core::array::from_fn(|i| value[shift + TAG_SIZE + i]);
+        let recipient = match value[shift] {
+            NO_RECIPIENT => None,
+            RECIPIENT => Some(u8_array_to_u32(&slice)),
+            tag => bail!("can't decode identity, unknown recipient tag {}", tag),
+        };
+        let shift = shift + TAG_SIZE + INT_SIZE;
+        let slice: [u8; 4] = core::array::from_fn(|i| value[shift + i]);
+        let timestamp = u8_array_to_u32(&slice);
+        let shift = shift + INT_SIZE;
+
+        Ok(Self {
+            public_key: value[..PUBLIC_KEY_SIZE].to_vec(),
+            recipient,
+            timestamp,
+            signature: value[shift..IDENTITY_SIZE].to_vec(),
+        })
+    }
+}
+
+impl From<Identity> for Vec<u8> {
+    fn from(identity: Identity) -> Self {
+        let mut res = Vec::with_capacity(IDENTITY_SIZE);
+        res.extend(identity.public_key);
+        match identity.recipient {
+            Some(recipient) => {
+                res.push(RECIPIENT);
+                res.extend(u32_to_u8_array(recipient));
+            }
+            None => {
+                res.push(NO_RECIPIENT);
+                res.extend(u32_to_u8_array(0));
+            }
+        }
+        res.extend(u32_to_u8_array(identity.timestamp));
+        res.extend(identity.signature);
+        res
+    }
+}
+
+// FoundMutable ----------------------------------------------------------------
+
+// Answer to a get mutable: either the mutable value, or the closest peers the
+// responder knows from the key, so the lookup can go on.
+#[derive(Debug, Eq, PartialEq)]
+pub enum FoundMutable {
+    Value(MutableValue),
+    ClosestPeers(Vec<Peer>),
+}
+
+// Answer to a get peers: the peers owning the file the responder knows of, along
+// with the closest peers it knows from the file crc, so the lookup can go on
+// toward the owners others may know.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct FoundPeers {
+    pub owners: Vec<Peer>,
+    pub closest_peers: Vec<Peer>,
+}
+
 // Error codes -----------------------------------------------------------------
 
 #[repr(u8)]
@@ -648,6 +1602,13 @@ pub enum ErrorCode {
     ChunkNotFound = 2,
     InvalidChunk = 3,
     KeyNotFound = 4,
+    StorageFull = 5,
+    InvalidSignature = 6,
+    OutdatedSequence = 7,
+    InvalidToken = 8,
+    RelayRefused = 9,
+    InvalidOnion = 10,
+    OnionNotDelivered = 11,
 }
 
 impl From<u8> for ErrorCode {
@@ -657,6 +1618,13 @@ impl From<u8> for ErrorCode {
             2 => Self::ChunkNotFound,
             3 => Self::InvalidChunk,
             4 => Self::KeyNotFound,
+            5 => Self::StorageFull,
+            6 => Self::InvalidSignature,
+            7 => Self::OutdatedSequence,
+            8 => Self::InvalidToken,
+            9 => Self::RelayRefused,
+            10 => Self::InvalidOnion,
+            11 => Self::OnionNotDelivered,
             _ => Self::Unknown,
         }
     }
@@ -670,6 +1638,13 @@ impl Display for ErrorCode {
             ErrorCode::ChunkNotFound => write!(fmt, "chunk not found"),
             ErrorCode::InvalidChunk => write!(fmt, "invalid chunk"),
             ErrorCode::KeyNotFound => write!(fmt, "key not found"),
+            ErrorCode::StorageFull => write!(fmt, "storage full"),
+            ErrorCode::InvalidSignature => write!(fmt, "invalid signature"),
+            ErrorCode::OutdatedSequence => write!(fmt, "outdated sequence number"),
+            ErrorCode::InvalidToken => write!(fmt, "invalid write token"),
+            ErrorCode::RelayRefused => write!(fmt, "relay refused"),
+            ErrorCode::InvalidOnion => write!(fmt, "invalid onion"),
+            ErrorCode::OnionNotDelivered => write!(fmt, "onion not delivered"),
         }
     }
 }
@@ -10,6 +10,7 @@ fn test_file_info_protocol() -> AnyResult<()> {
         chunk_size: 2,
         file_crc: 3613099103,
         original_filename: "my_file.txt".to_owned(),
+        chunk_crcs: vec![7, 8],
     };
 
     let raw_buf: Vec<u8> = file_info.into();
@@ -19,7 +20,8 @@ fn test_file_info_protocol() -> AnyResult<()> {
             0, 0, 4, 210,
             0, 0, 0, 2,
             215, 91, 132, 95,
-            0, 0, 0, 11, 109, 121, 95, 102, 105, 108, 101, 46, 116, 120, 116
+            0, 0, 0, 11, 109, 121, 95, 102, 105, 108, 101, 46, 116, 120, 116,
+            0, 0, 0, 2, 0, 0, 0, 7, 0, 0, 0, 8
         ],
         raw_buf
     );
@@ -29,10 +31,36 @@ fn test_file_info_protocol() -> AnyResult<()> {
     assert_eq!(2, decoded_file_info.chunk_size);
     assert_eq!(3613099103, decoded_file_info.file_crc);
     assert_eq!("my_file.txt".to_owned(), decoded_file_info.original_filename);
+    assert_eq!(vec![7, 8], decoded_file_info.chunk_crcs);
+
+    // A truncated list of crcs is refused.
+    assert!(FileInfo::try_from(&raw_buf[..raw_buf.len() - 1]).is_err());
 
     Ok(())
 }
 
+#[test]
+fn test_file_info_chunk_crcs() {
+    let content = b"some file content";
+    let mut file_info = FileInfo {
+        file_size: content.len() as u32,
+        chunk_size: 5,
+        file_crc: crc32fast::hash(content),
+        original_filename: "my_file.txt".to_owned(),
+        chunk_crcs: content.chunks(5).map(crc32fast::hash).collect(),
+    };
+    assert!(file_info.has_valid_chunk_crcs());
+
+    // Forged, missing or unusable crcs are detected.
+    file_info.chunk_crcs[1] += 1;
+    assert!(!file_info.has_valid_chunk_crcs());
+    file_info.chunk_crcs = content.chunks(5).map(crc32fast::hash).collect();
+    file_info.chunk_crcs.pop();
+    assert!(!file_info.has_valid_chunk_crcs());
+    file_info.chunk_size = 0;
+    assert!(!file_info.has_valid_chunk_crcs());
+}
+
 #[test]
 fn test_peer_protocol() -> AnyResult<()> {
     let peer = Peer {
@@ -57,6 +85,30 @@ fn test_peer_protocol() -> AnyResult<()> {
     Ok(())
 }
 
+#[test]
+fn test_ipv6_peer_protocol() -> AnyResult<()> {
+    let peer = Peer {
+        id: 1234,
+        addr: "[::1]:4000".parse()?,
+    };
+
+    let raw_buf: Vec<u8> = peer.into();
+    let raw_buf = raw_buf.as_slice();
+    #[rustfmt::skip]
+    assert_eq!(&[
+            0, 0, 4, 210,
+            0, 0, 0, 10, 91, 58, 58, 49, 93, 58, 52, 48, 48, 48
+        ],
+        raw_buf
+    );
+
+    let decoded_peer = Peer::try_from(raw_buf)?;
+    assert_eq!(1234, decoded_peer.id);
+    assert_eq!("[::1]:4000".parse::<SocketAddr>()?, decoded_peer.addr);
+
+    Ok(())
+}
+
 // Messages --------------------------------------------------------------------
 
 #[test]
@@ -88,6 +140,7 @@ fn test_file_info_response_protocol() -> AnyResult<()> {
         chunk_size: 42,
         file_crc: 99887766,
         original_filename: "filename".to_owned(),
+        chunk_crcs: vec![],
     });
     let raw_buf: Vec<u8> = cmd.into();
     let raw_buf = raw_buf.as_slice();
@@ -98,7 +151,8 @@ fn test_file_info_response_protocol() -> AnyResult<()> {
             0, 0, 0, 38,
             0, 0, 0, 42,
             5, 244, 42, 150,
-            0, 0, 0, 8, 102, 105, 108, 101, 110, 97, 109, 101
+            0, 0, 0, 8, 102, 105, 108, 101, 110, 97, 109, 101,
+            0, 0, 0, 0
         ],
         raw_buf
     );
@@ -202,7 +256,7 @@ fn test_ping_request_protocol() -> AnyResult<()> {
 
 #[test]
 fn test_ping_response_protocol() -> AnyResult<()> {
-    let cmd = Command::PingResponse(1234);
+    let cmd = Command::PingResponse(1234, "127.0.0.1:4000".parse()?);
     let raw_buf: Vec<u8> = cmd.into();
     let raw_buf = raw_buf.as_slice();
 
@@ -210,13 +264,15 @@ fn test_ping_response_protocol() -> AnyResult<()> {
     assert_eq!(&[
             6,
             0, 0, 4, 210,
+            0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 52, 48, 48, 48
         ],
         raw_buf
     );
 
     match Command::try_from(raw_buf)? {
-        Command::PingResponse(target) => {
+        Command::PingResponse(target, observed) => {
             assert_eq!(1234, target);
+            assert_eq!("127.0.0.1:4000".parse::<SocketAddr>()?, observed);
         }
         _ => panic!(),
     }
@@ -258,16 +314,20 @@ fn test_find_node_request_protocol() -> AnyResult<()> {
 
 #[test]
 fn test_find_node_response_protocol() -> AnyResult<()> {
-    let cmd = Command::FindNodeResponse(vec![Peer {
-        id: 1234,
-        addr: "127.0.0.1:4000".parse()?,
-    }]);
+    let cmd = Command::FindNodeResponse(
+        42,
+        vec![Peer {
+            id: 1234,
+            addr: "127.0.0.1:4000".parse()?,
+        }],
+    );
     let raw_buf: Vec<u8> = cmd.into();
     let raw_buf = raw_buf.as_slice();
 
     #[rustfmt::skip]
     assert_eq!(&[
             10,
+            0, 0, 0, 42,
             0, 0, 0, 1,
                 0, 0, 4, 210,
                 0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 52, 48, 48, 48
@@ -276,7 +336,8 @@ fn test_find_node_response_protocol() -> AnyResult<()> {
     );
 
     match Command::try_from(raw_buf)? {
-        Command::FindNodeResponse(peers) => {
+        Command::FindNodeResponse(token, peers) => {
+            assert_eq!(42, token);
             assert_eq!(1, peers.len());
             let peer = &peers[0];
             assert_eq!(1234, peer.id);
@@ -290,22 +351,26 @@ fn test_find_node_response_protocol() -> AnyResult<()> {
 
 #[test]
 fn test_find_node_list_response_protocol() -> AnyResult<()> {
-    let cmd = Command::FindNodeResponse(vec![
-        Peer {
-            id: 1234,
-            addr: "127.0.0.1:4000".parse()?,
-        },
-        Peer {
-            id: 4567,
-            addr: "127.0.0.1:5000".parse()?,
-        },
-    ]);
+    let cmd = Command::FindNodeResponse(
+        42,
+        vec![
+            Peer {
+                id: 1234,
+                addr: "127.0.0.1:4000".parse()?,
+            },
+            Peer {
+                id: 4567,
+                addr: "127.0.0.1:5000".parse()?,
+            },
+        ],
+    );
     let raw_buf: Vec<u8> = cmd.into();
     let raw_buf = raw_buf.as_slice();
 
     #[rustfmt::skip]
     assert_eq!(&[
             10,
+            0, 0, 0, 42,
             0, 0, 0, 2,
                 0, 0, 4, 210,
                 0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 52, 48, 48, 48,
@@ -316,7 +381,8 @@ fn test_find_node_list_response_protocol() -> AnyResult<()> {
     );
 
     match Command::try_from(raw_buf)? {
-        Command::FindNodeResponse(peers) => {
+        Command::FindNodeResponse(token, peers) => {
+            assert_eq!(42, token);
             assert_eq!(2, peers.len());
             let peer = &peers[0];
             assert_eq!(1234, peer.id);
@@ -331,6 +397,36 @@ fn test_find_node_list_response_protocol() -> AnyResult<()> {
     Ok(())
 }
 
+#[test]
+fn test_find_node_mixed_families_response_protocol() -> AnyResult<()> {
+    let peers = vec![
+        Peer {
+            id: 1234,
+            addr: "127.0.0.1:4000".parse()?,
+        },
+        Peer {
+            id: 4567,
+            addr: "[2001:db8::1]:5000".parse()?,
+        },
+        Peer {
+            id: 8910,
+            addr: "[::1]:6000".parse()?,
+        },
+    ];
+    let cmd = Command::FindNodeResponse(42, peers.clone());
+    let raw_buf: Vec<u8> = cmd.into();
+
+    match Command::try_from(raw_buf.as_slice())? {
+        Command::FindNodeResponse(token, decoded_peers) => {
+            assert_eq!(42, token);
+            assert_eq!(peers, decoded_peers);
+        }
+        _ => panic!(),
+    }
+
+    Ok(())
+}
+
 #[test]
 fn test_store_request_protocol() -> AnyResult<()> {
     let peer = Peer {
@@ -338,26 +434,39 @@ fn test_store_request_protocol() -> AnyResult<()> {
         addr: "127.0.0.1:4000".parse()?,
     };
 
-    let cmd = Command::StoreRequest(peer.clone(), 666, "hello".to_owned());
+    let stored_value = StoredValue {
+        publisher: 42,
+        timestamp: 1000,
+        value: "hello".to_owned(),
+        public_key: vec![1; PUBLIC_KEY_SIZE],
+        signature: vec![2; SIGNATURE_SIZE],
+    };
+
+    let cmd = Command::StoreRequest(peer.clone(), 42, 666, stored_value.clone());
     let raw_buf: Vec<u8> = cmd.into();
     let raw_buf = raw_buf.as_slice();
 
     #[rustfmt::skip]
-    assert_eq!(&[
-            7,
-            0, 0, 4, 210,
-            0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 52, 48, 48, 48,
-            0, 0, 2, 154,
-            0, 0, 0, 5, 104, 101, 108, 108, 111
-        ],
-        raw_buf
-    );
+    let mut expected = vec![
+        7,
+        0, 0, 4, 210,
+        0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 52, 48, 48, 48,
+        0, 0, 0, 42,
+        0, 0, 2, 154,
+        0, 0, 0, 42,
+        0, 0, 3, 232,
+        0, 0, 0, 5, 104, 101, 108, 108, 111,
+    ];
+    expected.extend([1; PUBLIC_KEY_SIZE]);
+    expected.extend([2; SIGNATURE_SIZE]);
+    assert_eq!(expected.as_slice(), raw_buf);
 
     match Command::try_from(raw_buf)? {
-        Command::StoreRequest(sender, key, message) => {
+        Command::StoreRequest(sender, token, key, value) => {
             assert_eq!(peer, sender);
+            assert_eq!(42, token);
             assert_eq!(666, key);
-            assert_eq!("hello", message);
+            assert_eq!(stored_value, value);
         }
         _ => panic!(),
     }
@@ -393,7 +502,7 @@ fn test_find_value_request_protocol() -> AnyResult<()> {
         addr: "127.0.0.1:4000".parse()?,
     };
 
-    let cmd = Command::FindValueRequest(peer.clone(), 666);
+    let cmd = Command::FindValueRequest(peer.clone(), 666, 2);
     let raw_buf: Vec<u8> = cmd.into();
     let raw_buf = raw_buf.as_slice();
 
@@ -402,15 +511,17 @@ fn test_find_value_request_protocol() -> AnyResult<()> {
             11,
             0, 0, 4, 210,
             0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 52, 48, 48, 48,
-            0, 0, 2, 154
+            0, 0, 2, 154,
+            0, 0, 0, 2
         ],
         raw_buf
     );
 
     match Command::try_from(raw_buf)? {
-        Command::FindValueRequest(sender, key) => {
+        Command::FindValueRequest(sender, key, page) => {
             assert_eq!(peer, sender);
             assert_eq!(666, key);
+            assert_eq!(2, page);
         }
         _ => panic!(),
     }
@@ -420,25 +531,92 @@ fn test_find_value_request_protocol() -> AnyResult<()> {
 
 #[test]
 fn test_find_value_response_protocol() -> AnyResult<()> {
-    let cmd = Command::FindValueResponse("hello".to_owned());
+    let values = vec![
+        StoredValue {
+            publisher: 42,
+            timestamp: 1000,
+            value: "hello".to_owned(),
+            public_key: vec![1; PUBLIC_KEY_SIZE],
+            signature: vec![2; SIGNATURE_SIZE],
+        },
+        StoredValue {
+            publisher: 43,
+            timestamp: 1001,
+            value: "world".to_owned(),
+            public_key: vec![1; PUBLIC_KEY_SIZE],
+            signature: vec![2; SIGNATURE_SIZE],
+        },
+    ];
+    let cmd = Command::FindValueResponse(FoundValue::Values(values.clone(), 3));
+    let raw_buf: Vec<u8> = cmd.into();
+    let raw_buf = raw_buf.as_slice();
+
+    #[rustfmt::skip]
+    let mut expected = vec![
+        12,
+        0,
+        0, 0, 0, 3,
+        0, 0, 0, 2,
+            0, 0, 0, 42,
+            0, 0, 3, 232,
+            0, 0, 0, 5, 104, 101, 108, 108, 111,
+    ];
+    expected.extend([1; PUBLIC_KEY_SIZE]);
+    expected.extend([2; SIGNATURE_SIZE]);
+    #[rustfmt::skip]
+    expected.extend([
+            0, 0, 0, 43,
+            0, 0, 3, 233,
+            0, 0, 0, 5, 119, 111, 114, 108, 100,
+    ]);
+    expected.extend([1; PUBLIC_KEY_SIZE]);
+    expected.extend([2; SIGNATURE_SIZE]);
+    assert_eq!(expected.as_slice(), raw_buf);
+
+    match Command::try_from(raw_buf)? {
+        Command::FindValueResponse(FoundValue::Values(page, nb_pages)) => {
+            assert_eq!(values, page);
+            assert_eq!(3, nb_pages);
+        }
+        _ => panic!(),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_find_value_closest_peers_response_protocol() -> AnyResult<()> {
+    let cmd = Command::FindValueResponse(FoundValue::ClosestPeers(vec![Peer {
+        id: 1234,
+        addr: "127.0.0.1:4000".parse()?,
+    }]));
     let raw_buf: Vec<u8> = cmd.into();
     let raw_buf = raw_buf.as_slice();
 
     #[rustfmt::skip]
     assert_eq!(&[
             12,
-            0, 0, 0, 5, 104, 101, 108, 108, 111
+            1,
+            0, 0, 0, 1,
+                0, 0, 4, 210,
+                0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 52, 48, 48, 48
         ],
         raw_buf
     );
 
     match Command::try_from(raw_buf)? {
-        Command::FindValueResponse(message) => {
-            assert_eq!("hello", message);
+        Command::FindValueResponse(FoundValue::ClosestPeers(peers)) => {
+            assert_eq!(1, peers.len());
+            let peer = &peers[0];
+            assert_eq!(1234, peer.id);
+            assert_eq!("127.0.0.1:4000".parse::<SocketAddr>()?, peer.addr);
         }
         _ => panic!(),
     }
 
+    // Unknown tag.
+    assert!(Command::try_from([12u8, 2, 0, 0, 0, 0].as_slice()).is_err());
+
     Ok(())
 }
 
@@ -494,7 +672,14 @@ fn test_announce_request_protocol() -> AnyResult<()> {
         addr: "127.0.0.1:4000".parse()?,
     };
 
-    let cmd = Command::AnnounceRequest(peer.clone(), 4567);
+    let announcement = Announcement {
+        owner: peer.clone(),
+        announced_at: 1000,
+        public_key: vec![1; PUBLIC_KEY_SIZE],
+        signature: vec![2; SIGNATURE_SIZE],
+    };
+
+    let cmd = Command::AnnounceRequest(peer.clone(), 42, 4567, announcement.clone());
     let raw_buf: Vec<u8> = cmd.into();
     let raw_buf = raw_buf.as_slice();
 
@@ -503,19 +688,30 @@ fn test_announce_request_protocol() -> AnyResult<()> {
             15,
             0, 0, 4, 210,
             0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 52, 48, 48, 48,
-            0, 0, 17, 215
+            0, 0, 0, 42,
+            0, 0, 17, 215,
+            0, 0, 4, 210,
+            0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 52, 48, 48, 48,
+            0, 0, 3, 232,
         ],
-        raw_buf
+        &raw_buf[..57]
     );
+    assert_eq!(&[1; PUBLIC_KEY_SIZE], &raw_buf[57..89]);
+    assert_eq!(&[2; SIGNATURE_SIZE], &raw_buf[89..]);
 
     match Command::try_from(raw_buf)? {
-        Command::AnnounceRequest(sender, crc) => {
+        Command::AnnounceRequest(sender, token, crc, announced) => {
             assert_eq!(peer, sender);
+            assert_eq!(42, token);
             assert_eq!(4567, crc);
+            assert_eq!(announcement, announced);
         }
         _ => panic!(),
     }
 
+    // A truncated signature can't be decoded.
+    assert!(Command::try_from(&raw_buf[..raw_buf.len() - 1]).is_err());
+
     Ok(())
 }
 
@@ -564,65 +760,87 @@ fn test_get_peers_request_protocol() -> AnyResult<()> {
 
 #[test]
 fn test_get_peers_response_protocol() -> AnyResult<()> {
-    let cmd = Command::GetPeersResponse(vec![Peer {
-        id: 1234,
-        addr: "127.0.0.1:4000".parse()?,
-    }]);
+    let cmd = Command::GetPeersResponse(
+        42,
+        FoundPeers {
+            owners: vec![Peer {
+                id: 1234,
+                addr: "127.0.0.1:4000".parse()?,
+            }],
+            closest_peers: vec![],
+        },
+    );
     let raw_buf: Vec<u8> = cmd.into();
     let raw_buf = raw_buf.as_slice();
 
     #[rustfmt::skip]
     assert_eq!(&[
             18,
+            0, 0, 0, 42,
             0, 0, 0, 1,
                 0, 0, 4, 210,
-                0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 52, 48, 48, 48
+                0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 52, 48, 48, 48,
+            0, 0, 0, 0
         ],
         raw_buf
     );
 
     match Command::try_from(raw_buf)? {
-        Command::GetPeersResponse(peers) => {
-            assert_eq!(1, peers.len());
-            let peer = &peers[0];
+        Command::GetPeersResponse(token, found) => {
+            assert_eq!(42, token);
+            assert_eq!(1, found.owners.len());
+            let peer = &found.owners[0];
             assert_eq!(1234, peer.id);
             assert_eq!("127.0.0.1:4000".parse::<SocketAddr>()?, peer.addr);
+            assert!(found.closest_peers.is_empty());
         }
         _ => panic!(),
     }
 
+    // The closest peers can't be missing.
+    assert!(Command::try_from(&raw_buf[..raw_buf.len() - 1]).is_err());
+
     Ok(())
 }
 
 #[test]
 fn test_get_peers_list_response_protocol() -> AnyResult<()> {
-    let cmd = Command::GetPeersResponse(vec![
-        Peer {
-            id: 1234,
-            addr: "127.0.0.1:4000".parse()?,
-        },
-        Peer {
-            id: 4567,
-            addr: "127.0.0.1:5000".parse()?,
+    let cmd = Command::GetPeersResponse(
+        42,
+        FoundPeers {
+            owners: vec![
+                Peer {
+                    id: 1234,
+                    addr: "127.0.0.1:4000".parse()?,
+                },
+                Peer {
+                    id: 4567,
+                    addr: "127.0.0.1:5000".parse()?,
+                },
+            ],
+            closest_peers: vec![],
         },
-    ]);
+    );
     let raw_buf: Vec<u8> = cmd.into();
     let raw_buf = raw_buf.as_slice();
 
     #[rustfmt::skip]
     assert_eq!(&[
             18,
+            0, 0, 0, 42,
             0, 0, 0, 2,
                 0, 0, 4, 210,
                 0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 52, 48, 48, 48,
                 0, 0, 17, 215,
-                0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 53, 48, 48, 48
+                0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 53, 48, 48, 48,
+            0, 0, 0, 0
         ],
         raw_buf
     );
 
     match Command::try_from(raw_buf)? {
-        Command::GetPeersResponse(peers) => {
+        Command::GetPeersResponse(token, FoundPeers { owners: peers, .. }) => {
+            assert_eq!(42, token);
             assert_eq!(2, peers.len());
             let peer = &peers[0];
             assert_eq!(1234, peer.id);
@@ -636,3 +854,524 @@ fn test_get_peers_list_response_protocol() -> AnyResult<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_get_peers_owners_and_closest_peers_response_protocol() -> AnyResult<()> {
+    let cmd = Command::GetPeersResponse(
+        42,
+        FoundPeers {
+            owners: vec![Peer {
+                id: 4567,
+                addr: "127.0.0.1:5000".parse()?,
+            }],
+            closest_peers: vec![Peer {
+                id: 1234,
+                addr: "127.0.0.1:4000".parse()?,
+            }],
+        },
+    );
+    let raw_buf: Vec<u8> = cmd.into();
+    let raw_buf = raw_buf.as_slice();
+
+    #[rustfmt::skip]
+    assert_eq!(&[
+            18,
+            0, 0, 0, 42,
+            0, 0, 0, 1,
+                0, 0, 17, 215,
+                0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 53, 48, 48, 48,
+            0, 0, 0, 1,
+                0, 0, 4, 210,
+                0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 52, 48, 48, 48
+        ],
+        raw_buf
+    );
+
+    match Command::try_from(raw_buf)? {
+        Command::GetPeersResponse(token, found) => {
+            assert_eq!(42, token);
+            assert_eq!(1, found.owners.len());
+            assert_eq!(4567, found.owners[0].id);
+            assert_eq!(1, found.closest_peers.len());
+            assert_eq!(1234, found.closest_peers[0].id);
+        }
+        _ => panic!(),
+    }
+
+    Ok(())
+}
+
+fn mutable_value() -> MutableValue {
+    MutableValue {
+        public_key: vec![1; PUBLIC_KEY_SIZE],
+        salt: "salt".to_owned(),
+        seq: 3,
+        value: "hello".to_owned(),
+        signature: vec![2; SIGNATURE_SIZE],
+    }
+}
+
+#[test]
+fn test_put_mutable_request_protocol() -> AnyResult<()> {
+    let peer = Peer {
+        id: 1234,
+        addr: "127.0.0.1:4000".parse()?,
+    };
+
+    let cmd = Command::PutMutableRequest(peer.clone(), mutable_value());
+    let raw_buf: Vec<u8> = cmd.into();
+    let raw_buf = raw_buf.as_slice();
+
+    #[rustfmt::skip]
+    let mut expected = vec![
+        19,
+        0, 0, 4, 210,
+        0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 52, 48, 48, 48,
+    ];
+    expected.extend([1; PUBLIC_KEY_SIZE]);
+    #[rustfmt::skip]
+    expected.extend([
+        0, 0, 0, 4, 115, 97, 108, 116,
+        0, 0, 0, 3,
+        0, 0, 0, 5, 104, 101, 108, 108, 111,
+    ]);
+    expected.extend([2; SIGNATURE_SIZE]);
+    assert_eq!(expected.as_slice(), raw_buf);
+
+    match Command::try_from(raw_buf)? {
+        Command::PutMutableRequest(sender, value) => {
+            assert_eq!(peer, sender);
+            assert_eq!(mutable_value(), value);
+        }
+        _ => panic!(),
+    }
+
+    // A truncated signature is refused.
+    assert!(Command::try_from(&raw_buf[..raw_buf.len() - 1]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_put_mutable_response_protocol() -> AnyResult<()> {
+    let cmd = Command::PutMutableResponse();
+    let raw_buf: Vec<u8> = cmd.into();
+    let raw_buf = raw_buf.as_slice();
+
+    assert_eq!(&[20], raw_buf);
+
+    match Command::try_from(raw_buf)? {
+        Command::PutMutableResponse() => {}
+        _ => panic!(),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_get_mutable_request_protocol() -> AnyResult<()> {
+    let peer = Peer {
+        id: 1234,
+        addr: "127.0.0.1:4000".parse()?,
+    };
+
+    let hash: MutableHash = core::array::from_fn(|idx| idx as u8);
+    let cmd = Command::GetMutableRequest(peer.clone(), hash);
+    let raw_buf: Vec<u8> = cmd.into();
+    let raw_buf = raw_buf.as_slice();
+
+    #[rustfmt::skip]
+    assert_eq!(&[
+            21,
+            0, 0, 4, 210,
+            0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 52, 48, 48, 48,
+            0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+            16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+        ],
+        raw_buf
+    );
+
+    match Command::try_from(raw_buf)? {
+        Command::GetMutableRequest(sender, found_hash) => {
+            assert_eq!(peer, sender);
+            assert_eq!(hash, found_hash);
+        }
+        _ => panic!(),
+    }
+
+    // A truncated hash is refused.
+    assert!(Command::try_from(&raw_buf[..raw_buf.len() - 1]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_get_mutable_response_protocol() -> AnyResult<()> {
+    let cmd = Command::GetMutableResponse(FoundMutable::Value(mutable_value()));
+    let raw_buf: Vec<u8> = cmd.into();
+    let raw_buf = raw_buf.as_slice();
+
+    assert_eq!(&[22, 0], &raw_buf[..2]);
+    match Command::try_from(raw_buf)? {
+        Command::GetMutableResponse(FoundMutable::Value(value)) => assert_eq!(mutable_value(), value),
+        _ => panic!(),
+    }
+
+    let cmd = Command::GetMutableResponse(FoundMutable::ClosestPeers(vec![Peer {
+        id: 1234,
+        addr: "127.0.0.1:4000".parse()?,
+    }]));
+    let raw_buf: Vec<u8> = cmd.into();
+    let raw_buf = raw_buf.as_slice();
+
+    #[rustfmt::skip]
+    assert_eq!(&[
+            22,
+            1,
+            0, 0, 0, 1,
+                0, 0, 4, 210,
+                0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 52, 48, 48, 48
+        ],
+        raw_buf
+    );
+
+    match Command::try_from(raw_buf)? {
+        Command::GetMutableResponse(FoundMutable::ClosestPeers(peers)) => assert_eq!(1, peers.len()),
+        _ => panic!(),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_signed_request_protocol() -> AnyResult<()> {
+    let mut rng = <rand_chacha::ChaCha8Rng as rand::SeedableRng>::seed_from_u64(1);
+    let keypair = crate::dht::identity::generate_node_keypair(&mut rng, |_| true);
+    let public = keypair.public;
+    let peer = Peer {
+        id: crate::dht::identity::node_id(&public),
+        addr: "127.0.0.1:4000".parse()?,
+    };
+
+    let request: Vec<u8> = Command::PingRequest(peer.clone()).into();
+    let identity = Identity::sign(&keypair, Some(42), 1000, &request);
+    let cmd = Command::SignedRequest(identity.clone(), request.clone());
+    let raw_buf: Vec<u8> = cmd.into();
+    let raw_buf = raw_buf.as_slice();
+
+    assert_eq!(&[23], &raw_buf[..1]);
+    assert_eq!(public.as_bytes(), &raw_buf[1..33]);
+    assert_eq!(&[1, 0, 0, 0, 42, 0, 0, 3, 232], &raw_buf[33..42]);
+    assert_eq!(request.as_slice(), &raw_buf[1 + 32 + 9 + 64..]);
+
+    match Command::try_from(raw_buf)? {
+        Command::SignedRequest(signer, raw_request) => {
+            assert_eq!(identity, signer);
+            assert_eq!(Some(peer.id), signer.verify(&raw_request));
+            match Command::try_from(raw_request.as_slice())? {
+                Command::PingRequest(sender) => assert_eq!(peer, sender),
+                _ => panic!(),
+            }
+        }
+        _ => panic!(),
+    }
+
+    // The signature doesn't match another request.
+    let other_request: Vec<u8> = Command::PingRequest(Peer {
+        id: 1234,
+        addr: "127.0.0.1:4000".parse()?,
+    })
+    .into();
+    assert_eq!(None, identity.verify(&other_request));
+
+    // Nor another recipient or time.
+    let replayed = Identity {
+        recipient: Some(43),
+        ..identity.clone()
+    };
+    assert_eq!(None, replayed.verify(&request));
+    let replayed = Identity {
+        timestamp: 1001,
+        ..identity.clone()
+    };
+    assert_eq!(None, replayed.verify(&request));
+    assert!(identity.is_recent(1000 + 120));
+    assert!(!identity.is_recent(1000 + 121));
+
+    // A request to an unknown recipient.
+    let identity = Identity::sign(&keypair, None, 1000, &request);
+    let raw_buf: Vec<u8> = Command::SignedRequest(identity.clone(), request.clone()).into();
+    assert_eq!(&[0, 0, 0, 0, 0], &raw_buf[33..38]);
+    match Command::try_from(raw_buf.as_slice())? {
+        Command::SignedRequest(signer, _) => assert_eq!(identity, signer),
+        _ => panic!(),
+    }
+
+    // An identity alone isn't a signed request.
+    assert!(Command::try_from(&raw_buf[..1 + 32 + 9 + 64]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_relay_request_protocol() -> AnyResult<()> {
+    let peer = Peer {
+        id: 1234,
+        addr: "127.0.0.1:4000".parse()?,
+    };
+
+    let cmd = Command::RelayRequest(peer.clone());
+    let raw_buf: Vec<u8> = cmd.into();
+    let raw_buf = raw_buf.as_slice();
+
+    #[rustfmt::skip]
+    assert_eq!(&[
+            24,
+            0, 0, 4, 210,
+            0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 52, 48, 48, 48,
+        ],
+        raw_buf
+    );
+
+    match Command::try_from(raw_buf)? {
+        Command::RelayRequest(sender) => {
+            assert_eq!(peer, sender);
+        }
+        _ => panic!(),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_relay_response_protocol() -> AnyResult<()> {
+    let cmd = Command::RelayResponse("127.0.0.1:4000".parse()?);
+    let raw_buf: Vec<u8> = cmd.into();
+    let raw_buf = raw_buf.as_slice();
+
+    #[rustfmt::skip]
+    assert_eq!(&[
+            25,
+            0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 52, 48, 48, 48
+        ],
+        raw_buf
+    );
+
+    match Command::try_from(raw_buf)? {
+        Command::RelayResponse(relay_addr) => {
+            assert_eq!("127.0.0.1:4000".parse::<SocketAddr>()?, relay_addr);
+        }
+        _ => panic!(),
+    }
+
+    // A refusal is an error like the others.
+    let raw_buf: Vec<u8> = Command::ErrorOccured(ErrorCode::RelayRefused).into();
+    match Command::try_from(raw_buf.as_slice())? {
+        Command::ErrorOccured(error) => assert_eq!(ErrorCode::RelayRefused, error),
+        _ => panic!(),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_onion_request_protocol() -> AnyResult<()> {
+    let cmd = Command::OnionRequest(vec![1, 2, 3]);
+    let raw_buf: Vec<u8> = cmd.into();
+    let raw_buf = raw_buf.as_slice();
+
+    assert_eq!(&[26, 1, 2, 3], raw_buf);
+
+    match Command::try_from(raw_buf)? {
+        Command::OnionRequest(onion) => assert_eq!(vec![1, 2, 3], onion),
+        _ => panic!(),
+    }
+
+    let raw_buf: Vec<u8> = Command::OnionResponse().into();
+    assert_eq!(&[27], raw_buf.as_slice());
+    assert!(matches!(
+        Command::try_from(raw_buf.as_slice())?,
+        Command::OnionResponse()
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_public_key_protocol() -> AnyResult<()> {
+    let raw_buf: Vec<u8> = Command::PublicKeyRequest().into();
+    assert_eq!(&[28], raw_buf.as_slice());
+    assert!(matches!(
+        Command::try_from(raw_buf.as_slice())?,
+        Command::PublicKeyRequest()
+    ));
+
+    let cmd = Command::PublicKeyResponse(vec![7; 32]);
+    let raw_buf: Vec<u8> = cmd.into();
+    let raw_buf = raw_buf.as_slice();
+
+    assert_eq!(&[29], &raw_buf[..1]);
+    assert_eq!(&[7; 32], &raw_buf[1..]);
+
+    match Command::try_from(raw_buf)? {
+        Command::PublicKeyResponse(public_key) => assert_eq!(vec![7; 32], public_key),
+        _ => panic!(),
+    }
+    // A public key is always 32 bytes.
+    assert!(Command::try_from(&raw_buf[..32]).is_err());
+
+    let raw_buf: Vec<u8> = Command::PeerKeyRequest(0x01020304).into();
+    assert_eq!(&[34, 1, 2, 3, 4], raw_buf.as_slice());
+    assert!(matches!(
+        Command::try_from(raw_buf.as_slice())?,
+        Command::PeerKeyRequest(0x01020304)
+    ));
+    assert!(Command::try_from(&raw_buf[..4]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_put_mail_request_protocol() -> AnyResult<()> {
+    let peer = Peer {
+        id: 1234,
+        addr: "127.0.0.1:4000".parse()?,
+    };
+    let mail = StoredValue {
+        publisher: 1234,
+        timestamp: 1000,
+        value: "hello".to_owned(),
+        public_key: vec![1; PUBLIC_KEY_SIZE],
+        signature: vec![2; SIGNATURE_SIZE],
+    };
+
+    let cmd = Command::PutMailRequest(peer.clone(), 42, 666, mail.clone());
+    let raw_buf: Vec<u8> = cmd.into();
+    let raw_buf = raw_buf.as_slice();
+
+    #[rustfmt::skip]
+    let mut expected = vec![
+        30,
+        0, 0, 4, 210,
+        0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 52, 48, 48, 48,
+        0, 0, 0, 42,
+        0, 0, 2, 154,
+        0, 0, 4, 210,
+        0, 0, 3, 232,
+        0, 0, 0, 5, 104, 101, 108, 108, 111,
+    ];
+    expected.extend([1; PUBLIC_KEY_SIZE]);
+    expected.extend([2; SIGNATURE_SIZE]);
+    assert_eq!(expected.as_slice(), raw_buf);
+
+    match Command::try_from(raw_buf)? {
+        Command::PutMailRequest(sender, token, recipient, value) => {
+            assert_eq!(peer, sender);
+            assert_eq!(42, token);
+            assert_eq!(666, recipient);
+            assert_eq!(mail, value);
+        }
+        _ => panic!(),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_get_mail_protocol() -> AnyResult<()> {
+    let peer = Peer {
+        id: 1234,
+        addr: "127.0.0.1:4000".parse()?,
+    };
+    let raw_buf: Vec<u8> = Command::GetMailRequest(peer.clone(), 42).into();
+    match Command::try_from(raw_buf.as_slice())? {
+        Command::GetMailRequest(sender, challenge) => {
+            assert_eq!(peer, sender);
+            assert_eq!(42, challenge);
+        }
+        _ => panic!(),
+    }
+    // The challenge is required.
+    assert!(Command::try_from(&raw_buf[..raw_buf.len() - 1]).is_err());
+
+    let mails = vec![StoredValue {
+        publisher: 42,
+        timestamp: 1000,
+        value: "hello".to_owned(),
+        public_key: vec![1; PUBLIC_KEY_SIZE],
+        signature: vec![2; SIGNATURE_SIZE],
+    }];
+    let cmd = Command::GetMailResponse(mails.clone());
+    let raw_buf: Vec<u8> = cmd.into();
+    let raw_buf = raw_buf.as_slice();
+
+    #[rustfmt::skip]
+    let mut expected = vec![
+        33,
+        0, 0, 0, 1,
+            0, 0, 0, 42,
+            0, 0, 3, 232,
+            0, 0, 0, 5, 104, 101, 108, 108, 111,
+    ];
+    expected.extend([1; PUBLIC_KEY_SIZE]);
+    expected.extend([2; SIGNATURE_SIZE]);
+    assert_eq!(expected.as_slice(), raw_buf);
+
+    match Command::try_from(raw_buf)? {
+        Command::GetMailResponse(found) => assert_eq!(mails, found),
+        _ => panic!(),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_ack_mail_protocol() -> AnyResult<()> {
+    let raw_buf: Vec<u8> = Command::MailChallengeRequest(1234).into();
+    assert_eq!(&[35, 0, 0, 4, 210], raw_buf.as_slice());
+    assert!(matches!(
+        Command::try_from(raw_buf.as_slice())?,
+        Command::MailChallengeRequest(1234)
+    ));
+    let raw_buf: Vec<u8> = Command::MailChallengeResponse(42).into();
+    assert_eq!(&[36, 0, 0, 0, 42], raw_buf.as_slice());
+    assert!(matches!(
+        Command::try_from(raw_buf.as_slice())?,
+        Command::MailChallengeResponse(42)
+    ));
+
+    let peer = Peer {
+        id: 1234,
+        addr: "127.0.0.1:4000".parse()?,
+    };
+    let raw_buf: Vec<u8> = Command::AckMailRequest(peer.clone(), vec![1, 2]).into();
+
+    #[rustfmt::skip]
+    let expected = vec![
+        37,
+        0, 0, 4, 210,
+        0, 0, 0, 14, 49, 50, 55, 46, 48, 46, 48, 46, 49, 58, 52, 48, 48, 48,
+        0, 0, 0, 2,
+            0, 0, 0, 1,
+            0, 0, 0, 2,
+    ];
+    assert_eq!(expected, raw_buf);
+
+    match Command::try_from(raw_buf.as_slice())? {
+        Command::AckMailRequest(sender, ids) => {
+            assert_eq!(peer, sender);
+            assert_eq!(vec![1, 2], ids);
+        }
+        _ => panic!(),
+    }
+    assert!(Command::try_from(&raw_buf[..raw_buf.len() - 1]).is_err());
+
+    let raw_buf: Vec<u8> = Command::AckMailResponse().into();
+    assert_eq!(&[38], raw_buf.as_slice());
+    assert!(matches!(
+        Command::try_from(raw_buf.as_slice())?,
+        Command::AckMailResponse()
+    ));
+
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use errors::AnyError;
+use rand::Rng;
+use std::{fmt::Display, str::FromStr, time::Duration};
+use tokio::time::error::Elapsed;
+
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+pub const DEFAULT_BASE_DELAY_MS: u64 = 50;
+pub const DEFAULT_MAX_DELAY_MS: u64 = 1000; // 1 sec
+pub const DEFAULT_JITTER: f64 = 0.5;
+
+// Which failures are worth trying again.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RetryOn {
+    // Only the failures to connect: the request has never been sent, so it's
+    // always safe to send it again.
+    Connection,
+    // Also the timeouts and broken connections while sending a request or
+    // waiting for its answer. A peer may then receive the same request twice.
+    Network,
+}
+
+// Most requests aren't idempotent (stores, announces, messages...), so only
+// the ones never sent are tried again by default.
+impl Default for RetryOn {
+    fn default() -> Self {
+        Self::Connection
+    }
+}
+
+impl FromStr for RetryOn {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "connection" => Ok(Self::Connection),
+            "network" => Ok(Self::Network),
+            _ => Err(format!(
+                "unknown errors {}, expected connection or network",
+                value
+            )),
+        }
+    }
+}
+
+impl Display for RetryOn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Connection => write!(f, "connection"),
+            Self::Network => write!(f, "network"),
+        }
+    }
+}
+
+// How requests sent to other peers are tried again when they fail. The delay
+// between two attempts doubles each time, up to a max, and is randomly
+// shortened so peers failing at the same time don't retry all together.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    // Number of attempts, the first one included. 1 means no retry.
+    pub max_attempts: u32,
+    // Delay before the first retry.
+    pub base_delay: Duration,
+    // Longest delay between two attempts.
+    pub max_delay: Duration,
+    // Part of the delay which is random, from 0 (fixed delay) to 1.
+    pub jitter: f64,
+    // Which failures are tried again.
+    pub retry_on: RetryOn,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            base_delay: Duration::from_millis(DEFAULT_BASE_DELAY_MS),
+            max_delay: Duration::from_millis(DEFAULT_MAX_DELAY_MS),
+            jitter: DEFAULT_JITTER,
+            retry_on: RetryOn::default(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // A policy which never tries again.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    // Check if a request which failed after a given number of attempts should
+    // be tried again. `sent` tells if the error happened once connected, while
+    // sending the request or waiting for its answer.
+    pub fn should_retry(&self, attempt: u32, error: &AnyError, sent: bool) -> bool {
+        if attempt >= self.max_attempts || !is_network_error(error) {
+            return false;
+        }
+        !sent || self.retry_on == RetryOn::Network
+    }
+
+    // Get the delay to wait before the next attempt, after a given number of
+    // failed attempts.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = self.base_delay.saturating_mul(1 << exponent).min(self.max_delay);
+        let jitter = self.jitter.clamp(0., 1.) * rand::thread_rng().gen::<f64>();
+        delay.mul_f64(1. - jitter)
+    }
+}
+
+// Check if an error comes from the network rather than from the peer answer.
+pub fn is_network_error(error: &AnyError) -> bool {
+    error.downcast_ref::<std::io::Error>().is_some() || error.downcast_ref::<Elapsed>().is_some()
+}
+
+#[cfg(test)]
+#[path = "retry_test.rs"]
+mod retry_test;
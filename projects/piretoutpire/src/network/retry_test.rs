@@ -0,0 +1,108 @@
+use super::*;
+use crate::{
+    manager::context::{Context, RpcClass},
+    network::api::send_raw_unary,
+};
+use errors::{AnyError, AnyResult};
+use std::{
+    io::{Error, ErrorKind},
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
+
+fn network_error() -> AnyError {
+    Error::new(ErrorKind::ConnectionReset, "reset").into()
+}
+
+#[test]
+fn test_retry_backoff() {
+    let policy = RetryPolicy {
+        max_attempts: 10,
+        base_delay: Duration::from_millis(100),
+        max_delay: Duration::from_millis(500),
+        jitter: 0.,
+        retry_on: RetryOn::Network,
+    };
+
+    assert_eq!(Duration::from_millis(100), policy.delay(1));
+    assert_eq!(Duration::from_millis(200), policy.delay(2));
+    assert_eq!(Duration::from_millis(400), policy.delay(3));
+    assert_eq!(Duration::from_millis(500), policy.delay(4));
+    assert_eq!(Duration::from_millis(500), policy.delay(40));
+}
+
+#[test]
+fn test_retry_jitter() {
+    let policy = RetryPolicy {
+        jitter: 0.5,
+        base_delay: Duration::from_millis(100),
+        ..RetryPolicy::default()
+    };
+
+    for _ in 0..100 {
+        let delay = policy.delay(1);
+        assert!(delay > Duration::from_millis(50));
+        assert!(delay <= Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn test_retryable_errors() {
+    let policy = RetryPolicy {
+        max_attempts: 3,
+        retry_on: RetryOn::Connection,
+        ..RetryPolicy::default()
+    };
+
+    assert!(policy.should_retry(1, &network_error(), false));
+    assert!(policy.should_retry(2, &network_error(), false));
+    // No attempt left.
+    assert!(!policy.should_retry(3, &network_error(), false));
+    // The request may have been received.
+    assert!(!policy.should_retry(1, &network_error(), true));
+    // The peer answered, there's no point asking again.
+    assert!(!policy.should_retry(1, &AnyError::msg("invalid buffer"), false));
+    // Requests which may have been received aren't sent again by default.
+    assert_eq!(RetryOn::Connection, RetryPolicy::default().retry_on);
+
+    let policy = RetryPolicy {
+        retry_on: RetryOn::Network,
+        ..policy
+    };
+    assert!(policy.should_retry(1, &network_error(), true));
+    assert!(!RetryPolicy::none().should_retry(1, &network_error(), false));
+}
+
+#[tokio::test]
+async fn test_retry_when_closed_without_answer() -> AnyResult<()> {
+    // The peer closes the first connection without answering, and answers on
+    // the next one.
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        let mut buf = [0; 64];
+        if let Ok((mut stream, _)) = listener.accept().await {
+            let _ = stream.read(&mut buf).await;
+        }
+        if let Ok((mut stream, _)) = listener.accept().await {
+            let _ = stream.read(&mut buf).await;
+            let _ = stream.write_all(b"pong").await;
+        }
+    });
+
+    let ctx = Arc::new(Context::new_test(0, false));
+    ctx.settings.write().await.retry_policy = RetryPolicy {
+        base_delay: Duration::from_millis(1),
+        retry_on: RetryOn::Network,
+        ..RetryPolicy::default()
+    };
+    let stream = Arc::new(Mutex::new(TcpStream::connect(addr).await?));
+    let response = send_raw_unary(ctx, stream, b"ping", RpcClass::Control).await?;
+    assert_eq!(b"pong".to_vec(), response);
+
+    Ok(())
+}
@@ -0,0 +1,38 @@
+use crate::dht::{identity::generate_node_keypair, ip_id::generate_keypair_for_ip};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey};
+use errors::AnyResult;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    net::IpAddr,
+    path::Path,
+};
+
+// Load an ed25519 keypair from a file holding its raw secret key. If the file
+// doesn't exist yet, a new node keypair is generated and its secret key saved
+// in it, so the same identity is used next time. When an IP address is given,
+// the new keypair is the one of an id matching it.
+pub fn load_or_generate_keypair(path: &Path, ip: Option<IpAddr>) -> AnyResult<Keypair> {
+    if path.exists() {
+        let secret = SecretKey::from_bytes(&fs::read(path)?)?;
+        let public = PublicKey::from(&secret);
+        return Ok(Keypair { secret, public });
+    }
+
+    let keypair = match ip {
+        Some(ip) => generate_keypair_for_ip(ip),
+        None => generate_node_keypair(&mut rand::thread_rng(), |_| true),
+    };
+    save_secret(path, keypair.secret.as_bytes())?;
+    Ok(keypair)
+}
+
+// Save a secret key in a new file, only readable by its owner.
+fn save_secret(path: &Path, secret: &[u8]) -> AnyResult<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(secret)?;
+    Ok(())
+}
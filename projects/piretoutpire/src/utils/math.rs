@@ -1,3 +1,5 @@
+use std::{cmp::Reverse, time::Duration};
+
 // Divide two integer, but round it to the ceil instead of the floor.
 // Dividing by 0 will panic.
 pub const fn div_ceil(lhs: u32, rhs: u32) -> u32 {
@@ -16,6 +18,19 @@ pub const fn distance(lhs: u32, rhs: u32) -> u32 {
     lhs ^ rhs
 }
 
+// Sort key of a peer for a lookup toward a target, the lowest being the best.
+// Peers are first sorted by how close they are, peers sharing the same bucket
+// being at a similar distance. Among them, the ones with the lowest round-trip
+// time come first, the ones never measured last.
+pub fn relevancy(id: u32, srtt: Option<Duration>, target: u32) -> (Reverse<u32>, Duration, u32) {
+    let distance = distance(id, target);
+    (
+        Reverse(distance.leading_zeros()),
+        srtt.unwrap_or(Duration::MAX),
+        distance,
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -45,4 +60,18 @@ mod tests {
 
         assert_eq!(u32::MAX - 1, middle_point(u32::MAX - 2, u32::MAX));
     }
+
+    #[test]
+    fn test_relevancy() {
+        let fast = Some(Duration::from_millis(5));
+        let slow = Some(Duration::from_millis(50));
+
+        // Closer buckets first.
+        assert!(relevancy(1, slow, 0) < relevancy(2, fast, 0));
+        // Then the fastest in the same bucket.
+        assert!(relevancy(5, fast, 0) < relevancy(4, slow, 0));
+        assert!(relevancy(5, slow, 0) < relevancy(4, None, 0));
+        // Then the closest.
+        assert!(relevancy(4, fast, 0) < relevancy(5, fast, 0));
+    }
 }
@@ -1,8 +1,14 @@
 mod math;
-pub use math::{distance, div_ceil, middle_point};
+pub use math::{distance, div_ceil, middle_point, relevancy};
 
 mod codec;
 pub use codec::{
     addr_to_u8_array, string_to_u8_array, u32_list_to_u8_array, u32_list_to_u8_array_unfailable,
     u32_to_u8_array, u8_array_to_addr, u8_array_to_string, u8_array_to_u32, u8_array_to_u32_list,
 };
+
+mod time;
+pub use time::unix_timestamp;
+
+mod keypair;
+pub use keypair::load_or_generate_keypair;